@@ -0,0 +1,1020 @@
+use crate::fast::Tag;
+use crate::heap::{
+    ALLOC_LIVE, Allocation, FLAG_FINALIZED, RtHeap, rt_heap_allocate, rt_heap_destroy,
+    rt_heap_free_all_unreachable, rt_heap_mark_all_unreachable,
+};
+use crate::mach::{Binop, Cmd, Function, NativeFn, NativeInterface, Program, Rc, Type, Value, Var};
+use libc::c_char;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+pub const STRING_TYPE: u16 = 0;
+pub const MIN_GC_THRESHOLD: usize = 1 << 20;
+pub const STACK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AotError {
+    Unsupported { function: String, what: String },
+    UnresolvedSymbol { name: String },
+    NoMain,
+    MissingRuntime { path: String },
+    Compiler { status: Option<i32>, stderr: String },
+}
+impl std::fmt::Display for AotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AotError::Unsupported { function, what } => {
+                write!(f, "{} in {} cannot be compiled ahead of time", what, function)
+            }
+            AotError::UnresolvedSymbol { name } => write!(f, "unresolved symbol {}", name),
+            AotError::NoMain => write!(f, "program has no main function"),
+            AotError::MissingRuntime { path } => {
+                write!(f, "runtime library {} not found, set BEAM_RUNTIME", path)
+            }
+            AotError::Compiler { status, stderr } => {
+                write!(f, "c compiler failed ({:?}):\n{}", status, stderr)
+            }
+        }
+    }
+}
+impl std::error::Error for AotError {}
+
+const PRELUDE: &str = r#"#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define BEAM_UNUSED __attribute__((unused))
+
+typedef struct {
+    uint64_t tag;
+    union {
+        int64_t i;
+        double f;
+        void *p;
+    } v;
+} BeamVar;
+typedef struct {
+    uint16_t in_use, reachable, type_idx, num_objects, magic, flags;
+    uint32_t size;
+} BeamAlloc;
+typedef struct BeamRt BeamRt;
+
+extern BeamRt *beam_rt_init(BeamVar *stack, BeamVar **sp, const uint8_t *finalizers, size_t num_types,
+                            const char *const *natives, size_t num_natives);
+extern BeamVar *beam_rt_alloc(BeamRt *rt, uint16_t type_idx, uint16_t num_fields);
+extern BeamVar beam_rt_str_concat(BeamRt *rt, BeamVar a, BeamVar b);
+extern BeamVar beam_rt_str_repeat(BeamRt *rt, BeamVar a, int64_t n);
+extern bool beam_rt_poll(BeamRt *rt);
+extern bool beam_rt_next_finalizer(BeamRt *rt, BeamVar *out);
+extern BeamVar beam_rt_native(BeamRt *rt, size_t idx, const BeamVar *args, size_t n);
+extern void beam_rt_panic(const char *msg);
+extern int beam_rt_exit(BeamRt *rt, BeamVar result);
+
+static BeamRt *beam_rt;
+static BeamVar *beam_sp;
+static BeamVar *beam_stack_end;
+static bool beam_finalizing;
+
+static inline BeamVar beam_var(uint64_t tag, int64_t i) {
+    BeamVar v;
+    v.tag = tag;
+    v.v.i = i;
+    return v;
+}
+static inline BeamVar beam_unit(void) { return beam_var(0, 0); }
+static inline BeamVar beam_int(int64_t i) { return beam_var(1, i); }
+static inline BeamVar beam_float(double f) {
+    BeamVar v;
+    v.tag = 2;
+    v.v.f = f;
+    return v;
+}
+static inline BeamVar beam_bool(bool b) { return beam_var(3, b ? 1 : 0); }
+static inline BeamVar beam_string(void *p) {
+    BeamVar v;
+    v.tag = 4;
+    v.v.p = p;
+    return v;
+}
+static inline BeamVar beam_ptr(void *p) {
+    BeamVar v;
+    v.tag = 5;
+    v.v.p = p;
+    return v;
+}
+static inline BeamVar beam_weak(void *p) {
+    BeamVar v;
+    v.tag = 7;
+    v.v.p = p;
+    return v;
+}
+static inline BeamVar *beam_field(BeamVar o, size_t i) {
+    if (!o.v.p) beam_rt_panic("null pointer access");
+    return (BeamVar *)o.v.p + i + 1;
+}
+static inline int64_t beam_iadd(int64_t a, int64_t b) { return (int64_t)((uint64_t)a + (uint64_t)b); }
+static inline int64_t beam_isub(int64_t a, int64_t b) { return (int64_t)((uint64_t)a - (uint64_t)b); }
+static inline int64_t beam_imul(int64_t a, int64_t b) { return (int64_t)((uint64_t)a * (uint64_t)b); }
+static inline int64_t beam_idiv(int64_t a, int64_t b) {
+    if (b == 0) beam_rt_panic("attempt to divide by zero");
+    if (b == -1 && a == INT64_MIN) beam_rt_panic("attempt to divide with overflow");
+    return a / b;
+}
+static inline bool beam_str_eq(BeamVar a, BeamVar b) {
+    BeamAlloc *x = a.v.p, *y = b.v.p;
+    return x->size == y->size && memcmp(x + 1, y + 1, x->size) == 0;
+}
+"#;
+
+struct StructInfo {
+    idx: u16,
+    name: String,
+    fields: Vec<Type>,
+    finalizer: Option<String>,
+}
+
+struct Codegen<'a> {
+    progs: &'a [Program],
+    structs: Vec<StructInfo>,
+    struct_idx: HashMap<(usize, u64), u16>,
+    functions: BTreeMap<String, (usize, &'a Function, String)>,
+    strings: Vec<Rc<str>>,
+    string_idx: HashMap<Rc<str>, usize>,
+    natives: Vec<String>,
+    native_idx: HashMap<String, usize>,
+    out: String,
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b == b' ' {
+            out.push(b as char);
+        } else {
+            write!(out, "\\{:03o}", b).unwrap();
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl<'a> Codegen<'a> {
+    fn new(progs: &'a [Program]) -> Result<Self, AotError> {
+        let mut out = Self {
+            progs,
+            structs: Vec::new(),
+            struct_idx: HashMap::new(),
+            functions: BTreeMap::new(),
+            strings: Vec::new(),
+            string_idx: HashMap::new(),
+            natives: Vec::new(),
+            native_idx: HashMap::new(),
+            out: String::new(),
+        };
+        for (pi, p) in progs.iter().enumerate() {
+            for (ti, (name, t)) in p.types.iter().enumerate() {
+                let Type::Struct { fields, finalizer, .. } = t else {
+                    continue;
+                };
+                let idx = out.structs.len() as u16 + 1;
+                out.struct_idx.insert((pi, ti as u64), idx);
+                out.structs.push(StructInfo {
+                    idx,
+                    name: name.to_string(),
+                    fields: fields.iter().map(|f| f.1.as_type(&p.types)).collect(),
+                    finalizer: finalizer.as_ref().map(|f| f.to_string()),
+                });
+            }
+        }
+        for (pi, p) in progs.iter().enumerate() {
+            for (name, f) in &p.functions {
+                if f.is_header {
+                    continue;
+                }
+                if out.functions.contains_key(name) {
+                    return Err(AotError::Unsupported {
+                        function: name.clone(),
+                        what: "duplicate definition".into(),
+                    });
+                }
+                out.functions.insert(name.clone(), (pi, f, String::new()));
+            }
+        }
+        for (i, (name, f)) in out.functions.iter_mut().enumerate() {
+            f.2 = format!("beam_fn_{}_{}", i, sanitize(name));
+        }
+        Ok(out)
+    }
+    fn function(&self, name: &str) -> Result<&(usize, &'a Function, String), AotError> {
+        self.functions
+            .get(name)
+            .ok_or_else(|| AotError::UnresolvedSymbol { name: name.into() })
+    }
+    fn string(&mut self, s: &Rc<str>) -> usize {
+        if let Some(i) = self.string_idx.get(s) {
+            return *i;
+        }
+        self.strings.push(s.clone());
+        self.string_idx.insert(s.clone(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+    fn native(&mut self, name: &str) -> usize {
+        if let Some(i) = self.native_idx.get(name) {
+            return *i;
+        }
+        self.natives.push(name.into());
+        self.native_idx.insert(name.into(), self.natives.len() - 1);
+        self.natives.len() - 1
+    }
+    fn default(&self, t: &Type, fname: &str) -> Result<String, AotError> {
+        Ok(match t {
+            Type::Void => "beam_unit()".into(),
+            Type::Integer => "beam_int(0)".into(),
+            Type::Float => "beam_float(0.0)".into(),
+            Type::Bool => "beam_bool(false)".into(),
+            Type::String => "beam_string(&beam_str_empty.h)".into(),
+            Type::Ptr { .. } => "beam_ptr(NULL)".into(),
+            Type::Weak { .. } => "beam_weak(NULL)".into(),
+            _ => {
+                return Err(AotError::Unsupported {
+                    function: fname.into(),
+                    what: format!("value of type {:?}", t),
+                });
+            }
+        })
+    }
+    fn expr(&mut self, pi: usize, v: &Var, fname: &str) -> Result<String, AotError> {
+        Ok(match v {
+            Var::Stack { index, .. } => format!("l[{}]", index),
+            Var::ConstInt { value } => format!("beam_int((int64_t){}ull)", *value as u64),
+            Var::ConstFloat { value } => {
+                format!("beam_var(2, (int64_t){}ull)", value.to_bits())
+            }
+            Var::ConstString { value } => {
+                format!("beam_string(&beam_str_{}.h)", self.string(value))
+            }
+            Var::ConstBool { value } => format!("beam_bool({})", value),
+            Var::Unit => "beam_unit()".into(),
+            Var::FieldAccess { of, index, .. } => {
+                format!("(*beam_field({}, {}))", self.expr(pi, of, fname)?, index)
+            }
+            Var::OperatorNew { new_type } => {
+                let Some(idx) = self.struct_idx.get(&(pi, new_type.index)) else {
+                    return Err(AotError::Unsupported {
+                        function: fname.into(),
+                        what: format!("new of non struct {}", new_type.name),
+                    });
+                };
+                format!("beam_new_{}()", idx)
+            }
+            Var::MakeWeak { of } => format!("beam_weak(({}).v.p)", self.expr(pi, of, fname)?),
+            Var::Upgrade { of } => format!("beam_ptr(({}).v.p)", self.expr(pi, of, fname)?),
+            Var::Alive { of } => format!("beam_bool(({}).v.p != NULL)", self.expr(pi, of, fname)?),
+            Var::FunctionLiteral { .. } | Var::SelfPid | Var::Now => {
+                return Err(AotError::Unsupported {
+                    function: fname.into(),
+                    what: format!("{:?}", v),
+                });
+            }
+        })
+    }
+    fn lvalue(&mut self, pi: usize, v: &Var, fname: &str) -> Result<String, AotError> {
+        match v {
+            Var::Stack { .. } | Var::FieldAccess { .. } => self.expr(pi, v, fname),
+            _ => Err(AotError::Unsupported {
+                function: fname.into(),
+                what: format!("assignment to {:?}", v),
+            }),
+        }
+    }
+    fn binop(&mut self, pi: usize, l: &Var, r: &Var, op: &Binop, fname: &str) -> Result<String, AotError> {
+        let lt = l.get_type(&self.progs[pi].types);
+        let a = self.expr(pi, l, fname)?;
+        let b = self.expr(pi, r, fname)?;
+        let cmp = |op: &Binop| match op {
+            Binop::Equal => Some("=="),
+            Binop::NotEqual => Some("!="),
+            Binop::Less => Some("<"),
+            Binop::Greater => Some(">"),
+            _ => None,
+        };
+        let out = match (&lt, op) {
+            (Type::Integer, Binop::Add) => format!("beam_int(beam_iadd(({}).v.i, ({}).v.i))", a, b),
+            (Type::Integer, Binop::Sub) => format!("beam_int(beam_isub(({}).v.i, ({}).v.i))", a, b),
+            (Type::Integer, Binop::Mul) => format!("beam_int(beam_imul(({}).v.i, ({}).v.i))", a, b),
+            (Type::Integer, Binop::Div) => format!("beam_int(beam_idiv(({}).v.i, ({}).v.i))", a, b),
+            (Type::Integer, op) if cmp(op).is_some() => {
+                format!("beam_bool(({}).v.i {} ({}).v.i)", a, cmp(op).unwrap(), b)
+            }
+            (Type::Float, Binop::Add) => format!("beam_float(({}).v.f + ({}).v.f)", a, b),
+            (Type::Float, Binop::Sub) => format!("beam_float(({}).v.f - ({}).v.f)", a, b),
+            (Type::Float, Binop::Mul) => format!("beam_float(({}).v.f * ({}).v.f)", a, b),
+            (Type::Float, Binop::Div) => format!("beam_float(({}).v.f / ({}).v.f)", a, b),
+            (Type::Float, op) if cmp(op).is_some() => {
+                format!("beam_bool(({}).v.f {} ({}).v.f)", a, cmp(op).unwrap(), b)
+            }
+            (Type::Bool, Binop::Equal) => format!("beam_bool(({}).v.i == ({}).v.i)", a, b),
+            (Type::Bool, Binop::NotEqual) => format!("beam_bool(({}).v.i != ({}).v.i)", a, b),
+            (Type::Bool, Binop::And) => format!("beam_bool(({}).v.i && ({}).v.i)", a, b),
+            (Type::Bool, Binop::Or) => format!("beam_bool(({}).v.i || ({}).v.i)", a, b),
+            (Type::String, Binop::Add) => format!("beam_rt_str_concat(beam_rt, {}, {})", a, b),
+            (Type::String, Binop::Mul) => format!("beam_rt_str_repeat(beam_rt, {}, ({}).v.i)", a, b),
+            (Type::String, Binop::Equal) => format!("beam_bool(beam_str_eq({}, {}))", a, b),
+            (Type::String, Binop::NotEqual) => format!("beam_bool(!beam_str_eq({}, {}))", a, b),
+            _ => {
+                return Err(AotError::Unsupported {
+                    function: fname.into(),
+                    what: format!("{:?} on {:?}", op, lt),
+                });
+            }
+        };
+        Ok(out)
+    }
+    fn label(f: &Function, to: &str) -> Result<usize, AotError> {
+        f.labels
+            .get(to)
+            .copied()
+            .ok_or_else(|| AotError::UnresolvedSymbol { name: to.into() })
+    }
+    fn emit_function(&mut self, name: &str) -> Result<(), AotError> {
+        let (pi, f, ident) = self.function(name)?.clone();
+        let mut declared = Vec::new();
+        for i in &f.cmds {
+            if let Cmd::DeclareVariables { values } = i {
+                declared.extend(values.iter().cloned());
+            }
+        }
+        let nargs = f.arguments.len();
+        let frame = nargs + declared.len();
+        let mut targets = Vec::new();
+        for i in &f.cmds {
+            match i {
+                Cmd::Jmp { to, .. } | Cmd::JmpCond { to, .. } => targets.push(Self::label(f, to)?),
+                _ => {}
+            }
+        }
+        let mut body = String::new();
+        writeln!(body, "static BEAM_UNUSED BeamVar {}(void) {{", ident).unwrap();
+        writeln!(body, "    BeamVar *l = beam_sp;").unwrap();
+        writeln!(
+            body,
+            "    if (beam_stack_end - l < {}) beam_rt_panic(\"stack overflow\");",
+            frame + 1
+        )
+        .unwrap();
+        writeln!(body, "    beam_sp = l + {};", frame).unwrap();
+        for (i, t) in declared.iter().enumerate() {
+            writeln!(body, "    l[{}] = {};", nargs + i, self.default(t, name)?).unwrap();
+        }
+        for (k, cmd) in f.cmds.iter().enumerate() {
+            if targets.contains(&k) {
+                writeln!(body, "L{}:;", k).unwrap();
+            }
+            self.emit_cmd(&mut body, pi, f, cmd, name)?;
+        }
+        if targets.contains(&f.cmds.len()) {
+            writeln!(body, "L{}:;", f.cmds.len()).unwrap();
+        }
+        writeln!(body, "    beam_sp = l;\n    return beam_unit();\n}}\n").unwrap();
+        self.out.push_str(&body);
+        Ok(())
+    }
+    fn emit_cmd(&mut self, body: &mut String, pi: usize, f: &Function, cmd: &Cmd, name: &str) -> Result<(), AotError> {
+        match cmd {
+            Cmd::Binop { l, r, out, op } => {
+                let v = self.binop(pi, l, r, op, name)?;
+                let o = self.lvalue(pi, out, name)?;
+                writeln!(body, "    {} = {};", o, v).unwrap();
+                if matches!(l.get_type(&self.progs[pi].types), Type::String)
+                    && matches!(op, Binop::Add | Binop::Mul)
+                {
+                    writeln!(body, "    beam_safepoint();").unwrap();
+                }
+            }
+            Cmd::Assign { l, r } => {
+                let v = self.expr(pi, r, name)?;
+                let o = self.lvalue(pi, l, name)?;
+                writeln!(body, "    {} = {};", o, v).unwrap();
+                if matches!(r, Var::OperatorNew { .. }) {
+                    writeln!(body, "    beam_safepoint();").unwrap();
+                }
+            }
+            Cmd::Jmp { to, .. } => {
+                writeln!(body, "    goto L{};", Self::label(f, to)?).unwrap();
+            }
+            Cmd::JmpCond { cond, to, .. } => {
+                let c = self.expr(pi, cond, name)?;
+                writeln!(body, "    if (({}).v.i) goto L{};", c, Self::label(f, to)?).unwrap();
+            }
+            Cmd::DeclareVariables { .. } => {}
+            Cmd::Call { to_call, returned, args } => {
+                let Var::FunctionLiteral { name: callee, .. } = to_call else {
+                    return Err(AotError::Unsupported {
+                        function: name.into(),
+                        what: format!("call of {:?}", to_call),
+                    });
+                };
+                let (_, cf, ident) = self.function(callee)?.clone();
+                if cf.arguments.len() != args.len() {
+                    return Err(AotError::Unsupported {
+                        function: name.into(),
+                        what: format!("call of {} with {} arguments", callee, args.len()),
+                    });
+                }
+                writeln!(body, "    {{").unwrap();
+                for (i, a) in args.iter().enumerate() {
+                    writeln!(body, "        beam_sp[{}] = {};", i, self.expr(pi, a, name)?).unwrap();
+                }
+                writeln!(body, "        BeamVar r = {}();", ident).unwrap();
+                if *returned != Var::Unit {
+                    writeln!(body, "        {} = r;", self.lvalue(pi, returned, name)?).unwrap();
+                } else {
+                    writeln!(body, "        (void)r;").unwrap();
+                }
+                writeln!(body, "    }}").unwrap();
+            }
+            Cmd::CallNative { to_call, returned, args } => {
+                if crate::io::is_io_native(to_call) {
+                    return Err(AotError::Unsupported {
+                        function: name.into(),
+                        what: format!("io native {}", to_call),
+                    });
+                }
+                let idx = self.native(to_call);
+                writeln!(body, "    {{").unwrap();
+                let argv = if args.is_empty() {
+                    "NULL".to_string()
+                } else {
+                    let mut vals = Vec::new();
+                    for a in args.iter() {
+                        vals.push(self.expr(pi, a, name)?);
+                    }
+                    writeln!(body, "        BeamVar a[] = {{{}}};", vals.join(", ")).unwrap();
+                    "a".to_string()
+                };
+                writeln!(
+                    body,
+                    "        BeamVar r = beam_rt_native(beam_rt, {}, {}, {});",
+                    idx,
+                    argv,
+                    args.len()
+                )
+                .unwrap();
+                if *returned != Var::Unit {
+                    writeln!(body, "        {} = r;", self.lvalue(pi, returned, name)?).unwrap();
+                } else {
+                    writeln!(body, "        (void)r;").unwrap();
+                }
+                writeln!(body, "    }}\n    beam_safepoint();").unwrap();
+            }
+            Cmd::Return { to_return } => {
+                let v = self.expr(pi, to_return, name)?;
+                writeln!(body, "    {{\n        BeamVar r = {};\n        beam_sp = l;\n        return r;\n    }}", v)
+                    .unwrap();
+            }
+            Cmd::Spawn { .. }
+            | Cmd::Send { .. }
+            | Cmd::Receive { .. }
+            | Cmd::SpawnNamed { .. }
+            | Cmd::Link { .. }
+            | Cmd::Monitor { .. }
+            | Cmd::TrapExit
+            | Cmd::Exit { .. }
+            | Cmd::Fail { .. }
+            | Cmd::Sleep { .. } => {
+                return Err(AotError::Unsupported {
+                    function: name.into(),
+                    what: "process command".into(),
+                });
+            }
+        }
+        Ok(())
+    }
+    fn generate(mut self) -> Result<String, AotError> {
+        let main = self
+            .functions
+            .iter()
+            .find(|f| f.1.1.display_name == "main")
+            .map(|f| f.1.2.clone())
+            .ok_or(AotError::NoMain)?;
+        let names: Vec<String> = self.functions.keys().cloned().collect();
+        for i in &names {
+            self.emit_function(i)?;
+        }
+        let mut finalizers = vec!["NULL".to_string()];
+        for s in &self.structs {
+            let Some(fin) = &s.finalizer else {
+                finalizers.push("NULL".into());
+                continue;
+            };
+            let (_, f, ident) = self.function(fin)?;
+            if f.arguments.len() != 1 {
+                return Err(AotError::Unsupported {
+                    function: fin.clone(),
+                    what: format!("finalizer for {} with {} arguments", s.name, f.arguments.len()),
+                });
+            }
+            finalizers.push(ident.clone());
+        }
+        let mut out = String::from(PRELUDE);
+        writeln!(out, "\nstatic BEAM_UNUSED struct {{\n    BeamAlloc h;\n    char s[1];\n}} beam_str_empty = {{{{0, 1, {}, 0, {:#x}, 0, 0}}, \"\"}};", STRING_TYPE, ALLOC_LIVE).unwrap();
+        for (i, s) in self.strings.iter().enumerate() {
+            writeln!(
+                out,
+                "static BEAM_UNUSED struct {{\n    BeamAlloc h;\n    char s[{}];\n}} beam_str_{} = {{{{0, 1, {}, 0, {:#x}, 0, {}}}, {}}};",
+                s.len().max(1),
+                i,
+                STRING_TYPE,
+                ALLOC_LIVE,
+                s.len(),
+                c_string(s)
+            )
+            .unwrap();
+        }
+        out.push('\n');
+        for (_, _, ident) in self.functions.values() {
+            writeln!(out, "static BEAM_UNUSED BeamVar {}(void);", ident).unwrap();
+        }
+        writeln!(
+            out,
+            "\nstatic BeamVar (*const beam_finalizers[])(void) = {{{}}};\n",
+            finalizers.join(", ")
+        )
+        .unwrap();
+        out.push_str(
+            "static BEAM_UNUSED void beam_safepoint(void) {
+    BeamVar obj;
+    if (!beam_rt_poll(beam_rt) || beam_finalizing) return;
+    beam_finalizing = true;
+    while (beam_rt_next_finalizer(beam_rt, &obj)) {
+        beam_sp[0] = obj;
+        beam_finalizers[((BeamAlloc *)obj.v.p)->type_idx]();
+    }
+    beam_finalizing = false;
+}
+\n",
+        );
+        for s in &self.structs {
+            writeln!(out, "static BEAM_UNUSED BeamVar beam_new_{}(void) {{", s.idx).unwrap();
+            writeln!(
+                out,
+                "    BeamVar *o = beam_rt_alloc(beam_rt, {}, {});",
+                s.idx,
+                s.fields.len()
+            )
+            .unwrap();
+            for (i, t) in s.fields.iter().enumerate() {
+                writeln!(out, "    o[{}] = {};", i + 1, self.default(t, &s.name)?).unwrap();
+            }
+            writeln!(out, "    return beam_ptr(o);\n}}\n").unwrap();
+        }
+        out.push_str(&self.out);
+        let natives: Vec<String> = self.natives.iter().map(|n| c_string(n)).collect();
+        writeln!(out, "int main(void) {{").unwrap();
+        writeln!(
+            out,
+            "    static const uint8_t finalizers[] = {{{}}};",
+            finalizers
+                .iter()
+                .map(|f| if f == "NULL" { "0" } else { "1" })
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .unwrap();
+        if natives.is_empty() {
+            writeln!(out, "    static const char *const *natives = NULL;").unwrap();
+        } else {
+            writeln!(out, "    static const char *const natives[] = {{{}}};", natives.join(", ")).unwrap();
+        }
+        writeln!(out, "    BeamVar *stack = malloc(sizeof(BeamVar) * {});", STACK_SIZE).unwrap();
+        writeln!(out, "    beam_sp = stack;\n    beam_stack_end = stack + {};", STACK_SIZE).unwrap();
+        writeln!(
+            out,
+            "    beam_rt = beam_rt_init(stack, &beam_sp, finalizers, {}, natives, {});",
+            finalizers.len(),
+            natives.len()
+        )
+        .unwrap();
+        writeln!(out, "    BeamVar r = {}();", main).unwrap();
+        writeln!(out, "    return beam_rt_exit(beam_rt, r);\n}}").unwrap();
+        Ok(out)
+    }
+}
+
+pub fn compile_to_c(progs: &[Program]) -> Result<String, AotError> {
+    Codegen::new(progs)?.generate()
+}
+
+pub fn runtime_library() -> Result<PathBuf, AotError> {
+    let path = match std::env::var("BEAM_RUNTIME") {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => std::env::current_exe()
+            .ok()
+            .and_then(|p| p.parent().map(|p| p.join("libbeam.a")))
+            .unwrap_or_else(|| PathBuf::from("libbeam.a")),
+    };
+    if !path.exists() {
+        return Err(AotError::MissingRuntime {
+            path: path.display().to_string(),
+        });
+    }
+    Ok(path)
+}
+
+pub fn build_native(progs: &[Program], out: &str) -> Result<(), Box<dyn Error>> {
+    build_native_with(progs, out, &runtime_library()?)
+}
+
+pub fn build_native_with(progs: &[Program], out: &str, lib: &Path) -> Result<(), Box<dyn Error>> {
+    let src = compile_to_c(progs)?;
+    let c_path = format!("{}.c", out);
+    std::fs::write(&c_path, src)?;
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".into());
+    let o = std::process::Command::new(cc)
+        .args(["-O2", "-o", out, &c_path])
+        .arg(lib)
+        .args(["-lm", "-lpthread", "-ldl"])
+        .output()?;
+    if !o.status.success() {
+        return Err(Box::new(AotError::Compiler {
+            status: o.status.code(),
+            stderr: String::from_utf8_lossy(&o.stderr).into(),
+        }));
+    }
+    Ok(())
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AotVar {
+    pub tag: u64,
+    pub bits: u64,
+}
+impl AotVar {
+    fn new(tag: Tag, bits: u64) -> Self {
+        Self { tag: tag as u64, bits }
+    }
+}
+
+
+pub struct AotRuntime {
+    heap: RtHeap,
+    stack: *mut AotVar,
+    sp: *mut *mut AotVar,
+    finalizers: Vec<bool>,
+    natives: Vec<(String, Option<NativeFn>)>,
+    finalize_queue: Vec<*mut Allocation>,
+    threshold: usize,
+}
+impl AotRuntime {
+    fn alloc_str(&mut self, s: &[u8]) -> AotVar {
+        let al = rt_heap_allocate(&mut self.heap, s.len(), 0, STRING_TYPE) as *mut Allocation;
+        unsafe {
+            std::ptr::copy_nonoverlapping(s.as_ptr(), al.add(1) as *mut u8, s.len());
+        }
+        AotVar::new(Tag::String, al as u64)
+    }
+    fn str_bytes(&self, v: AotVar) -> &[u8] {
+        unsafe {
+            let al = v.bits as *const Allocation;
+            std::slice::from_raw_parts(al.add(1) as *const u8, (*al).size as usize)
+        }
+    }
+    fn to_value(&self, v: AotVar) -> Value {
+        match v.tag {
+            x if x == Tag::Integer as u64 => Value::Integer { v: v.bits as i64 },
+            x if x == Tag::Float as u64 => Value::Float {
+                v: f64::from_bits(v.bits),
+            },
+            x if x == Tag::Bool as u64 => Value::Bool { v: v.bits != 0 },
+            x if x == Tag::String as u64 => Value::String {
+                v: String::from_utf8_lossy(self.str_bytes(v)).into(),
+            },
+            x if x == Tag::Ptr as u64 => Value::Object { ptr: v.bits },
+            x if x == Tag::Weak as u64 => Value::Weak { ptr: v.bits },
+            _ => Value::Unit,
+        }
+    }
+    fn var_from(&mut self, v: Value) -> AotVar {
+        match v {
+            Value::Unit => AotVar::new(Tag::Void, 0),
+            Value::Integer { v } => AotVar::new(Tag::Integer, v as u64),
+            Value::Float { v } => AotVar::new(Tag::Float, v.to_bits()),
+            Value::Bool { v } => AotVar::new(Tag::Bool, v as u64),
+            Value::String { v } => self.alloc_str(v.as_bytes()),
+            Value::Object { ptr } => AotVar::new(Tag::Ptr, ptr),
+            Value::Weak { ptr } => AotVar::new(Tag::Weak, ptr),
+            Value::Pid { .. } | Value::ObjectHeader { .. } => {
+                fail(&format!("native returned unsupported value {:?}", v))
+            }
+        }
+    }
+    fn roots(&mut self) -> &mut [AotVar] {
+        unsafe {
+            let len = (*self.sp).offset_from(self.stack) as usize;
+            std::slice::from_raw_parts_mut(self.stack, len)
+        }
+    }
+    fn mark(v: &AotVar, stack: &mut Vec<*mut Allocation>) {
+        if v.bits == 0 {
+            return;
+        }
+        let al = v.bits as *mut Allocation;
+        unsafe {
+            if v.tag == Tag::String as u64 {
+                (*al).reachable = 1;
+            } else if v.tag == Tag::Ptr as u64 && (*al).reachable == 0 {
+                (*al).reachable = 1;
+                stack.push(al);
+            }
+        }
+    }
+    fn drain(stack: &mut Vec<*mut Allocation>) {
+        while let Some(al) = stack.pop() {
+            unsafe {
+                let base = al as *mut AotVar;
+                for i in 1..(*al).num_objects as usize + 1 {
+                    Self::mark(&*base.add(i), stack);
+                }
+            }
+        }
+    }
+    fn clear_weak(v: &mut AotVar) {
+        if v.tag != Tag::Weak as u64 || v.bits == 0 {
+            return;
+        }
+        if unsafe { (*(v.bits as *const Allocation)).reachable } == 0 {
+            v.bits = 0;
+        }
+    }
+    fn collect(&mut self) {
+        rt_heap_mark_all_unreachable(&mut self.heap);
+        let mut stack = Vec::new();
+        for v in self.roots().iter() {
+            Self::mark(v, &mut stack);
+        }
+        for al in &self.finalize_queue {
+            Self::mark(&AotVar::new(Tag::Ptr, *al as u64), &mut stack);
+        }
+        Self::drain(&mut stack);
+        unsafe {
+            for al in self.heap.allocations().to_vec() {
+                if (*al).reachable != 0
+                    || (*al).flags & FLAG_FINALIZED != 0
+                    || !self.finalizers.get((*al).type_idx as usize).copied().unwrap_or(false)
+                {
+                    continue;
+                }
+                (*al).flags |= FLAG_FINALIZED;
+                self.finalize_queue.push(al);
+                Self::mark(&AotVar::new(Tag::Ptr, al as u64), &mut stack);
+            }
+        }
+        Self::drain(&mut stack);
+        self.roots().iter_mut().for_each(Self::clear_weak);
+        unsafe {
+            for al in self.heap.allocations() {
+                if (**al).reachable == 0 {
+                    continue;
+                }
+                let base = *al as *mut AotVar;
+                for i in 1..(**al).num_objects as usize + 1 {
+                    Self::clear_weak(&mut *base.add(i));
+                }
+            }
+        }
+        if let Err(e) = rt_heap_free_all_unreachable(&mut self.heap) {
+            fail(&e.to_string());
+        }
+        self.threshold = (self.heap.bytes * 2).max(MIN_GC_THRESHOLD);
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("beam runtime error: {}", msg);
+    std::process::exit(101)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_init(
+    stack: *mut AotVar,
+    sp: *mut *mut AotVar,
+    finalizers: *const u8,
+    num_types: usize,
+    natives: *const *const c_char,
+    num_natives: usize,
+) -> *mut AotRuntime {
+    let interface = NativeInterface::new();
+    let finalizers = unsafe { std::slice::from_raw_parts(finalizers, num_types) };
+    let mut resolved = Vec::new();
+    for i in 0..num_natives {
+        let name = unsafe { std::ffi::CStr::from_ptr(*natives.add(i)) }
+            .to_string_lossy()
+            .to_string();
+        let f = interface.funcs.get(&name).cloned();
+        resolved.push((name, f));
+    }
+    Box::into_raw(Box::new(AotRuntime {
+        heap: RtHeap::new(),
+        stack,
+        sp,
+        finalizers: finalizers.iter().map(|f| *f != 0).collect(),
+        natives: resolved,
+        finalize_queue: Vec::new(),
+        threshold: MIN_GC_THRESHOLD,
+    }))
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_alloc(rt: &mut AotRuntime, type_idx: u16, num_fields: u16) -> *mut AotVar {
+    rt_heap_allocate(
+        &mut rt.heap,
+        num_fields as usize * size_of::<AotVar>(),
+        num_fields,
+        type_idx,
+    ) as *mut AotVar
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_str_concat(rt: &mut AotRuntime, a: AotVar, b: AotVar) -> AotVar {
+    let mut s = rt.str_bytes(a).to_vec();
+    s.extend_from_slice(rt.str_bytes(b));
+    rt.alloc_str(&s)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_str_repeat(rt: &mut AotRuntime, a: AotVar, n: i64) -> AotVar {
+    if n < 0 {
+        fail("string repeated a negative number of times");
+    }
+    let s = rt.str_bytes(a).repeat(n as usize);
+    rt.alloc_str(&s)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_poll(rt: &mut AotRuntime) -> bool {
+    if rt.heap.bytes < rt.threshold {
+        return false;
+    }
+    rt.collect();
+    true
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_next_finalizer(rt: &mut AotRuntime, out: &mut AotVar) -> bool {
+    if rt.finalize_queue.is_empty() {
+        return false;
+    }
+    *out = AotVar::new(Tag::Ptr, rt.finalize_queue.remove(0) as u64);
+    true
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_native(rt: &mut AotRuntime, idx: usize, args: *const AotVar, n: usize) -> AotVar {
+    let args = if n == 0 {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(args, n) }
+    };
+    let vals: Vec<Value> = args.iter().map(|a| rt.to_value(*a)).collect();
+    let Some(f) = rt.natives[idx].1.clone() else {
+        fail(&format!("unknown native {}", rt.natives[idx].0));
+    };
+    let rv = f(&vals);
+    rt.var_from(rv)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_panic(msg: *const c_char) -> ! {
+    fail(&unsafe { std::ffi::CStr::from_ptr(msg) }.to_string_lossy())
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn beam_rt_exit(rt: *mut AotRuntime, result: AotVar) -> i32 {
+    let mut rt = unsafe { Box::from_raw(rt) };
+    println!("exited with:{:#?}", rt.to_value(result));
+    rt_heap_destroy(&mut rt.heap);
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mach::RunResult;
+
+    const PROGRAM: &str = "struct Node
+	next Node
+	value int
+	name string
+end
+fn int fib n int:
+	b:bool = n < 2
+	if b goto base
+	a:int = n - 1
+	a = fib(a)
+	c:int = n - 2
+	c = fib(c)
+	a = a + c
+	return a
+	label base
+	return n
+end
+fn int build k int:
+	head:Node = new Node
+	n:Node = head
+	t:Node = head
+	i:int = 0
+	s:int = 0
+	v:int = 0
+	b:bool = false
+	label top
+	b = i < k
+	if b goto body
+	return s
+	label body
+	n = new Node
+	n.value = i
+	n.name = \"node\"
+	n.next = head
+	head = n
+	t = n.next
+	v = t.value
+	s = s + v
+	i = i + 1
+	goto top
+end
+fn int main:
+	r:int = 0
+	j:int = 0
+	k:int = 0
+	b:bool = false
+	f:float = 1.5
+	s:string = \"ab\"
+	label rounds
+	b = j < 50
+	if b goto round
+	k = fib(20)
+	r = r + k
+	f = f * 3.0
+	b = f < 4.0
+	if b goto small
+	r = r * 2
+	label small
+	s = s + \"c\"
+	b = s == \"abc\"
+	if b goto same
+	return 0
+	label same
+	r = r - 1
+	return r
+	label round
+	k = build(2000)
+	r = r + k
+	j = j + 1
+	goto rounds
+end
+";
+
+    #[test]
+    fn native_code_matches_the_interpreter() {
+        // cargo puts the staticlib one directory above the test binary
+        let lib = std::env::current_exe().unwrap().parent().unwrap().with_file_name("libbeam.a");
+        if !lib.exists() {
+            eprintln!("skipping, {} has not been built", lib.display());
+            return;
+        }
+        let p = crate::parser::parse_to_program(PROGRAM.to_string(), "test.beam".into()).unwrap();
+        let ir = crate::fast::compile_mach_to_ir(std::slice::from_ref(&p)).unwrap();
+        let mut rt = crate::fast::rt_from_intermediate_rt(ir);
+        let RunResult::Finished(want) = rt.run(u64::MAX) else {
+            panic!("interpreter did not finish");
+        };
+
+        let dir = std::env::temp_dir().join(format!("beam-aot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("parity");
+        build_native_with(&[p], exe.to_str().unwrap(), &lib).unwrap();
+        let o = std::process::Command::new(&exe).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(o.status.success(), "{}", String::from_utf8_lossy(&o.stderr));
+        let stdout = String::from_utf8_lossy(&o.stdout);
+        assert_eq!(stdout.trim_end(), format!("exited with:{:#?}", want));
+    }
+
+    #[test]
+    fn processes_are_not_compiled() {
+        let src = "fn int main:\n\tp:pid = self\n\treturn 0\nend\n";
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        assert!(matches!(compile_to_c(&[p]), Err(AotError::Unsupported { .. })));
+    }
+}
@@ -0,0 +1,653 @@
+use crate::fast::{BStr, IntermediateRt, RT, RetInfo, Tag, Var, rt_from_intermediate_rt};
+use crate::heap::{Allocation, RtHeap};
+use crate::mach::Value;
+use crate::process::{IDLE_PID, Message, NO_MESSAGE, Process};
+use crate::reload::CodeTable;
+use crate::timer::SystemClock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    sync::Arc,
+};
+
+pub const CHECKPOINT_VERSION: u32 = 4;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SavedVar {
+    Void,
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    String(Option<Vec<u8>>),
+    Ptr(Option<u32>),
+    Weak(Option<u32>),
+    Pid(u64),
+    VarSlot(usize),
+    Field { object: u32, index: usize },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedObject {
+    pub type_idx: u16,
+    pub flags: u16,
+    pub fields: Vec<SavedVar>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedProcess {
+    pub pid: u64,
+    pub ip: usize,
+    pub op_stack: Vec<SavedVar>,
+    pub var_stack: Vec<SavedVar>,
+    pub var_base_ptr: usize,
+    pub ret_info: Vec<(usize, usize, usize, bool)>,
+    pub finalize_queue: Vec<(u32, usize)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedWaiting {
+    pub process: SavedProcess,
+    pub type_idx: u16,
+    pub deadline: Option<u64>,
+    pub resume_ip: usize,
+    pub timeout_ip: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub code: IntermediateRt,
+    pub code_table: CodeTable,
+    pub now: u64,
+    pub halted: bool,
+    pub result: Option<SavedVar>,
+    pub current: Option<SavedProcess>,
+    pub runnable: Vec<SavedProcess>,
+    pub waiting: Vec<SavedWaiting>,
+    pub mailboxes: Vec<(u64, Vec<u32>)>,
+    pub links: Vec<(u64, Vec<u64>)>,
+    pub monitors: Vec<(u64, Vec<u64>)>,
+    pub trap_exit: Vec<u64>,
+    pub next_pid: u64,
+    pub threads: usize,
+    pub budget: usize,
+    pub objects: Vec<SavedObject>,
+    pub handles: Vec<(u64, SavedVar)>,
+    pub next_handle: u64,
+}
+
+struct Saver {
+    ids: HashMap<*mut Var, u32>,
+    order: Vec<*mut Var>,
+    spans: Vec<(usize, usize, u32)>,
+}
+impl Saver {
+    fn id(&mut self, obj: *mut Var) -> Option<u32> {
+        if obj.is_null() {
+            return None;
+        }
+        if let Some(id) = self.ids.get(&obj) {
+            return Some(*id);
+        }
+        let id = self.order.len() as u32;
+        self.ids.insert(obj, id);
+        self.order.push(obj);
+        Some(id)
+    }
+    fn discover(&mut self, v: &Var) {
+        if v.get().tag == Tag::Ptr {
+            self.id(v.get_ptr() as *mut Var);
+        }
+    }
+    fn close(&mut self) {
+        let mut k = 0;
+        while k < self.order.len() {
+            let obj = self.order[k];
+            let n = unsafe { (*(obj as *mut Allocation)).num_objects } as usize;
+            for i in 1..=n {
+                let f = unsafe { (*obj.add(i)).clone() };
+                self.discover(&f);
+            }
+            k += 1;
+        }
+        self.spans = self
+            .order
+            .iter()
+            .map(|o| {
+                let n = unsafe { (*(*o as *mut Allocation)).num_objects } as usize;
+                (*o as usize, *o as usize + n * size_of::<Var>(), self.ids[o])
+            })
+            .collect();
+        self.spans.sort();
+    }
+    fn field_of(&self, at: usize) -> Option<(u32, usize)> {
+        let k = self.spans.partition_point(|s| s.0 < at);
+        let (start, end, id) = *self.spans.get(k.checked_sub(1)?)?;
+        if at > end {
+            return None;
+        }
+        Some((id, (at - start) / size_of::<Var>()))
+    }
+    fn save(&self, v: &Var, slots: &[Var]) -> SavedVar {
+        let id = |p: *const Var| self.ids.get(&(p as *mut Var)).copied();
+        match v.get().tag {
+            Tag::Void => SavedVar::Void,
+            Tag::Integer => SavedVar::Integer(v.get_int()),
+            Tag::Float => SavedVar::Float(v.get_float()),
+            Tag::Bool => SavedVar::Bool(v.get_bool()),
+            Tag::String => {
+                let s = v.get_string();
+                SavedVar::String(if s.is_null() {
+                    None
+                } else {
+                    Some(unsafe { (*s).as_bytes().to_vec() })
+                })
+            }
+            Tag::Ptr => SavedVar::Ptr(id(v.get_ptr())),
+            Tag::Weak => SavedVar::Weak(id(v.get_weak().unwrap_or(std::ptr::null()))),
+            Tag::Pid => SavedVar::Pid(v.get_pid().unwrap_or_default()),
+            Tag::LValue => {
+                let at = v.get_l_value() as *const Var as usize;
+                let base = slots.as_ptr() as usize;
+                if at >= base && at < base + std::mem::size_of_val(slots) {
+                    return SavedVar::VarSlot((at - base) / size_of::<Var>());
+                }
+                match self.field_of(at) {
+                    Some((object, index)) => SavedVar::Field { object, index },
+                    None => SavedVar::Void,
+                }
+            }
+        }
+    }
+    fn save_value(&mut self, v: &Value) -> SavedVar {
+        match v {
+            Value::Unit => SavedVar::Void,
+            Value::Integer { v } => SavedVar::Integer(*v),
+            Value::Float { v } => SavedVar::Float(*v),
+            Value::Bool { v } => SavedVar::Bool(*v),
+            Value::String { v } => SavedVar::String(Some(v.as_bytes().to_vec())),
+            Value::Object { ptr } => SavedVar::Ptr(self.id(*ptr as *mut Var)),
+            Value::Weak { ptr } => SavedVar::Weak(self.ids.get(&(*ptr as *mut Var)).copied()),
+            Value::Pid { v } => SavedVar::Pid(*v),
+            Value::ObjectHeader { .. } => SavedVar::Void,
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn save_process(
+        &self,
+        pid: u64,
+        ip: usize,
+        op_stack: &[Var],
+        var_stack: &[Var],
+        var_stack_ptr: usize,
+        var_base_ptr: usize,
+        ret_info: &[RetInfo],
+        finalize_queue: &[(*mut Var, usize)],
+    ) -> SavedProcess {
+        SavedProcess {
+            pid,
+            ip,
+            op_stack: op_stack.iter().map(|v| self.save(v, var_stack)).collect(),
+            var_stack: var_stack[..var_stack_ptr]
+                .iter()
+                .map(|v| self.save(v, var_stack))
+                .collect(),
+            var_base_ptr,
+            ret_info: ret_info
+                .iter()
+                .map(|r| (r.ip, r.var_sp, r.var_bp, r.discard))
+                .collect(),
+            finalize_queue: finalize_queue
+                .iter()
+                .map(|(o, loc)| (self.ids[o], *loc))
+                .collect(),
+        }
+    }
+}
+
+fn saved_process(sv: &Saver, p: &Process) -> SavedProcess {
+    sv.save_process(
+        p.pid,
+        p.ip,
+        &p.op_stack.as_slice()[..p.op_stack_ptr],
+        p.var_stack.as_slice(),
+        p.var_stack_ptr,
+        p.var_base_ptr,
+        &p.ret_info.as_slice()[..p.ret_info_ptr],
+        &p.finalize_queue,
+    )
+}
+
+impl RT {
+    pub fn checkpoint(&self) -> Checkpoint {
+        let s = self.scheduler.lock();
+        let mut sv = Saver {
+            ids: HashMap::new(),
+            order: Vec::new(),
+            spans: Vec::new(),
+        };
+        let idle = self.scheduler.is_idle();
+        if !idle {
+            for v in &self.op_stack.as_slice()[..self.op_stack_ptr] {
+                sv.discover(v);
+            }
+            for v in &self.var_stack.as_slice()[..self.var_stack_ptr] {
+                sv.discover(v);
+            }
+            for (obj, _) in &self.gc_info.finalize_queue {
+                sv.id(*obj);
+            }
+        }
+        for p in s.processes() {
+            for v in p.live_vars() {
+                sv.discover(v);
+            }
+            for (obj, _) in &p.finalize_queue {
+                sv.id(*obj);
+            }
+        }
+        let mut pids: Vec<u64> = s.mailboxes.keys().copied().collect();
+        pids.sort();
+        for pid in &pids {
+            for m in &s.mailboxes[pid] {
+                sv.id(m.obj);
+            }
+        }
+        let handles = self.natives.handles.v.borrow().clone();
+        let mut saved_handles: Vec<(u64, SavedVar)> = handles
+            .slots
+            .iter()
+            .map(|(h, v)| (*h, sv.save_value(v)))
+            .collect();
+        saved_handles.sort_by_key(|h| h.0);
+        let result = self.result.as_ref().map(|v| sv.save_value(v));
+        sv.close();
+        let objects = sv
+            .order
+            .iter()
+            .map(|o| unsafe {
+                let al = *o as *mut Allocation;
+                let n = (*al).num_objects as usize;
+                SavedObject {
+                    type_idx: (*al).type_idx,
+                    flags: (*al).flags,
+                    fields: (1..=n).map(|i| sv.save(&*o.add(i), &[])).collect(),
+                }
+            })
+            .collect();
+        let current = (!idle).then(|| {
+            sv.save_process(
+                self.scheduler.current,
+                self.ip,
+                &self.op_stack.as_slice()[..self.op_stack_ptr],
+                self.var_stack.as_slice(),
+                self.var_stack_ptr,
+                self.var_base_ptr,
+                &self.ret_info.as_slice()[..self.ret_info_ptr],
+                &self.gc_info.finalize_queue,
+            )
+        });
+        let mut waiting: Vec<SavedWaiting> = s
+            .waiting
+            .values()
+            .map(|w| SavedWaiting {
+                process: saved_process(&sv, &w.process),
+                type_idx: w.type_idx,
+                deadline: w.deadline,
+                resume_ip: w.resume_ip,
+                timeout_ip: w.timeout_ip,
+            })
+            .collect();
+        waiting.sort_by_key(|w| w.process.pid);
+        let sorted = |m: &HashMap<u64, Vec<u64>>| {
+            let mut out: Vec<(u64, Vec<u64>)> = m.iter().map(|(k, v)| (*k, v.clone())).collect();
+            out.sort();
+            out
+        };
+        let links = s
+            .links
+            .iter()
+            .map(|(k, v)| (*k, v.iter().copied().collect()))
+            .collect();
+        let mut trap_exit: Vec<u64> = s.trap_exit.iter().copied().collect();
+        trap_exit.sort();
+        let mut code = self.code_image();
+        code.ip = self.ip as u64;
+        Checkpoint {
+            version: CHECKPOINT_VERSION,
+            code,
+            code_table: self.code.clone(),
+            now: self.scheduler.now(),
+            halted: self.halted,
+            result,
+            current,
+            runnable: s
+                .run_queues
+                .iter()
+                .flatten()
+                .map(|p| saved_process(&sv, p))
+                .collect(),
+            waiting,
+            mailboxes: pids
+                .iter()
+                .map(|pid| (*pid, s.mailboxes[pid].iter().map(|m| sv.ids[&m.obj]).collect()))
+                .collect(),
+            links: sorted(&links),
+            monitors: sorted(&s.monitors),
+            trap_exit,
+            next_pid: s.next_pid,
+            threads: self.scheduler.threads,
+            budget: self.scheduler.budget,
+            objects,
+            handles: saved_handles,
+            next_handle: handles.next,
+        }
+    }
+    pub fn save_checkpoint(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, rmp_serde::to_vec(&self.checkpoint())?)?;
+        Ok(())
+    }
+}
+
+fn saved_ref(v: &SavedVar) -> Option<u32> {
+    match v {
+        SavedVar::Ptr(Some(id)) | SavedVar::Weak(Some(id)) => Some(*id),
+        SavedVar::Field { object, .. } => Some(*object),
+        _ => None,
+    }
+}
+
+struct Loader<'a> {
+    saved: &'a [SavedObject],
+    objects: Vec<*mut Var>,
+}
+impl Loader<'_> {
+    /// Allocates everything reachable from `roots` that has not been placed
+    /// yet into `heap`. Heaps never point into each other, so the first owner
+    /// to reach an object is the one whose heap it was saved from.
+    fn place(&mut self, rt: &mut RT, heap: &mut RtHeap, roots: impl IntoIterator<Item = u32>) {
+        let mut placed = Vec::new();
+        let mut todo: Vec<u32> = roots.into_iter().collect();
+        while let Some(id) = todo.pop() {
+            let k = id as usize;
+            if !self.objects[k].is_null() {
+                continue;
+            }
+            let o = &self.saved[k];
+            let n = o.fields.len();
+            let obj = crate::heap::rt_heap_allocate(heap, n * size_of::<Var>(), n as u16, o.type_idx)
+                as *mut Var;
+            unsafe {
+                (*(obj as *mut Allocation)).flags = o.flags;
+            }
+            self.objects[k] = obj;
+            placed.push(k);
+            todo.extend(o.fields.iter().filter_map(saved_ref));
+        }
+        rt.with_heap(heap, |rt| {
+            for k in placed {
+                for (i, f) in self.saved[k].fields.iter().enumerate() {
+                    let v = self.load(rt, f, std::ptr::null_mut());
+                    unsafe {
+                        *self.objects[k].add(i + 1) = v;
+                    }
+                }
+            }
+        });
+    }
+    fn load(&self, rt: &mut RT, v: &SavedVar, slots: *mut Var) -> Var {
+        let obj = |id: &Option<u32>| id.map_or(std::ptr::null_mut(), |i| self.objects[i as usize]);
+        match v {
+            SavedVar::Void => Var::new(),
+            SavedVar::Integer(i) => Var::integer(*i),
+            SavedVar::Float(f) => Var::float(*f),
+            SavedVar::Bool(b) => Var::boolean(*b),
+            SavedVar::String(None) => Var::string(std::ptr::null::<BStr>()),
+            SavedVar::String(Some(s)) => rt.allocate_bytes(s),
+            SavedVar::Ptr(id) => Var::ptr(obj(id)),
+            SavedVar::Weak(id) => Var::weak(obj(id)),
+            SavedVar::Pid(p) => Var::pid(*p),
+            SavedVar::VarSlot(i) => Var::l_value(unsafe { slots.add(*i) }),
+            SavedVar::Field { object, index } => {
+                Var::l_value(unsafe { self.objects[*object as usize].add(*index) })
+            }
+        }
+    }
+    fn load_value(&self, v: &SavedVar) -> Value {
+        match v {
+            SavedVar::Integer(v) => Value::Integer { v: *v },
+            SavedVar::Float(v) => Value::Float { v: *v },
+            SavedVar::Bool(v) => Value::Bool { v: *v },
+            SavedVar::String(Some(v)) => Value::String {
+                v: String::from_utf8_lossy(v).as_ref().into(),
+            },
+            SavedVar::Ptr(id) => Value::Object {
+                ptr: id.map_or(0, |i| self.objects[i as usize] as u64),
+            },
+            SavedVar::Weak(id) => Value::Weak {
+                ptr: id.map_or(0, |i| self.objects[i as usize] as u64),
+            },
+            SavedVar::Pid(v) => Value::Pid { v: *v },
+            _ => Value::Unit,
+        }
+    }
+    fn process(&mut self, rt: &mut RT, sp: &SavedProcess) -> Process {
+        let mut p = Process::new(sp.pid, sp.ip);
+        p.heap = rt.new_heap();
+        p.gc_threshold = rt.gc_info.min_threshold;
+        let roots = sp.var_stack.iter().chain(&sp.op_stack).filter_map(saved_ref);
+        self.place(rt, &mut p.heap, roots.chain(sp.finalize_queue.iter().map(|f| f.0)));
+        p.var_stack.grow(sp.var_stack.len(), Var::new());
+        p.op_stack.grow(sp.op_stack.len(), Var::new());
+        p.ret_info.grow(
+            sp.ret_info.len(),
+            RetInfo {
+                ip: 0,
+                var_sp: 0,
+                var_bp: 0,
+                discard: false,
+            },
+        );
+        let slots = p.var_stack.as_slice_mut().as_mut_ptr();
+        rt.with_heap(&mut p.heap, |rt| {
+            for (i, v) in sp.var_stack.iter().enumerate() {
+                p.var_stack[i] = self.load(rt, v, slots);
+            }
+            for (i, v) in sp.op_stack.iter().enumerate() {
+                p.op_stack[i] = self.load(rt, v, slots);
+            }
+        });
+        for (i, r) in sp.ret_info.iter().enumerate() {
+            p.ret_info[i] = RetInfo {
+                ip: r.0,
+                var_sp: r.1,
+                var_bp: r.2,
+                discard: r.3,
+            };
+        }
+        p.var_stack_ptr = sp.var_stack.len();
+        p.op_stack_ptr = sp.op_stack.len();
+        p.ret_info_ptr = sp.ret_info.len();
+        p.var_base_ptr = sp.var_base_ptr;
+        p.finalize_queue = sp
+            .finalize_queue
+            .iter()
+            .map(|(o, loc)| (self.objects[*o as usize], *loc))
+            .collect();
+        p
+    }
+    fn message(&mut self, rt: &mut RT, id: u32) -> Message {
+        let mut heap = rt.new_heap();
+        self.place(rt, &mut heap, [id]);
+        Message {
+            obj: self.objects[id as usize],
+            heap,
+        }
+    }
+}
+
+pub fn restore(cp: Checkpoint) -> Result<RT, Box<dyn Error>> {
+    if cp.version != CHECKPOINT_VERSION {
+        return Err(format!("unsupported checkpoint version {}", cp.version).into());
+    }
+    let mut rt = rt_from_intermediate_rt(cp.code);
+    rt.code = cp.code_table;
+    rt.halted = cp.halted;
+    rt.set_scheduler_threads(cp.threads);
+    rt.set_reduction_budget(cp.budget);
+    let mut ld = Loader {
+        saved: &cp.objects,
+        objects: vec![std::ptr::null_mut(); cp.objects.len()],
+    };
+    let mut blank = Process::empty(IDLE_PID);
+    rt.swap_process(&mut blank);
+    blank.release();
+    if let Some(sp) = &cp.current {
+        let mut p = ld.process(&mut rt, sp);
+        rt.swap_process(&mut p);
+    }
+    rt.set_clock(Arc::new(SystemClock::starting_at(cp.now)));
+    let mut runnable = Vec::new();
+    for sp in &cp.runnable {
+        runnable.push(ld.process(&mut rt, sp));
+    }
+    let mut waiting = Vec::new();
+    for w in &cp.waiting {
+        waiting.push((ld.process(&mut rt, &w.process), w));
+    }
+    let mut mailboxes = HashMap::new();
+    for (pid, msgs) in &cp.mailboxes {
+        let mb: VecDeque<Message> = msgs.iter().map(|m| ld.message(&mut rt, *m)).collect();
+        mailboxes.insert(*pid, mb);
+    }
+    let mut rest = rt.new_heap();
+    std::mem::swap(&mut rt.heap, &mut rest);
+    let pinned = cp.handles.iter().map(|h| &h.1).chain(&cp.result);
+    ld.place(&mut rt, &mut rest, pinned.filter_map(saved_ref).collect::<Vec<_>>());
+    std::mem::swap(&mut rt.heap, &mut rest);
+    rt.result = cp.result.as_ref().map(|v| ld.load_value(v));
+    let shared = rt.scheduler.shared.clone();
+    let mut s = shared.state.lock().unwrap();
+    for q in s.run_queues.iter_mut() {
+        q.clear();
+    }
+    for p in runnable {
+        s.push(0, p);
+    }
+    for (mut p, w) in waiting {
+        if w.type_idx == NO_MESSAGE && w.deadline.is_none() {
+            p.ip = w.resume_ip;
+            s.push(0, p);
+        } else {
+            s.wait(p, w.type_idx, w.deadline, w.resume_ip, w.timeout_ip);
+        }
+    }
+    s.mailboxes = mailboxes;
+    s.links = cp
+        .links
+        .iter()
+        .map(|(k, v)| (*k, v.iter().copied().collect::<HashSet<_>>()))
+        .collect();
+    s.monitors = cp.monitors.iter().cloned().collect();
+    s.trap_exit = cp.trap_exit.iter().copied().collect();
+    s.next_pid = cp.next_pid;
+    drop(s);
+    let mut handles = rt.natives.handles.v.borrow_mut();
+    handles.next = cp.next_handle;
+    for (h, v) in &cp.handles {
+        handles.slots.insert(*h, ld.load_value(v));
+    }
+    drop(handles);
+    Ok(rt)
+}
+
+pub fn load_checkpoint(path: &str) -> Result<RT, Box<dyn Error>> {
+    let cp: Checkpoint = rmp_serde::from_slice(&std::fs::read(path)?)?;
+    restore(cp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast::compile_mach_to_ir;
+    use crate::mach::RunResult;
+    use crate::timer::VirtualClock;
+
+    const SRC: &str = "struct Node
+	next Node
+	value int
+end
+fn void worker parent pid n int:
+	head:Node = new Node
+	i:int = 0
+	b:bool = false
+	label top
+	b = i < n
+	if b goto body
+	send parent head
+	return unit
+	label body
+	t:Node = new Node
+	t.value = i + 1
+	t.next = head
+	head = t
+	i = i + 1
+	sleep 0
+	goto top
+end
+fn int main:
+	me:pid = self
+	a:pid = spawn worker(me 30)
+	c:pid = spawn worker(me 50)
+	sum:int = 0
+	k:int = 0
+	b:bool = false
+	label recv
+	b = k < 2
+	if b goto take
+	return sum
+	label take
+	m:Node = receive Node
+	k = k + 1
+	label walk
+	v:int = m.value
+	sum = sum + v
+	b = v > 0
+	m = m.next
+	if b goto walk
+	goto recv
+end
+";
+
+    fn fresh() -> RT {
+        let p = crate::parser::parse_to_program(SRC.to_string(), "test.beam".into()).unwrap();
+        let mut rt = rt_from_intermediate_rt(compile_mach_to_ir(&[p]).unwrap());
+        rt.set_clock(Arc::new(VirtualClock::new()));
+        rt
+    }
+
+    fn bytes(cp: &Checkpoint) -> Vec<u8> {
+        rmp_serde::to_vec(cp).unwrap()
+    }
+
+    #[test]
+    fn checkpoint_round_trip_is_identical() {
+        let RunResult::Finished(want) = fresh().run(1_000_000) else {
+            panic!("program did not finish");
+        };
+        assert_eq!(want, Value::Integer { v: 465 + 1275 });
+        for steps in [1, 50, 400, 1500] {
+            let mut rt = fresh();
+            assert!(matches!(rt.run(steps), RunResult::Paused));
+            let cp = rt.checkpoint();
+            let mut back = restore(cp.clone()).unwrap();
+            back.set_clock(Arc::new(VirtualClock::new()));
+            assert_eq!(bytes(&back.checkpoint()), bytes(&cp), "after {} steps", steps);
+            let RunResult::Finished(got) = back.run(1_000_000) else {
+                panic!("restored program did not finish");
+            };
+            assert_eq!(got, want, "after {} steps", steps);
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use crate::heap::{Allocation, RtHeap};
+use crate::jit::Jit;
+use crate::profile::OpcodeProfile;
+use crate::reload::CodeTable;
+use crate::process::Scheduler;
 use core::slice;
 use std::{
     cell::UnsafeCell,
     collections::HashMap,
     fmt::Debug,
-    mem::MaybeUninit,
     ops::{Index, IndexMut, Range},
     ptr::NonNull,
     string,
@@ -74,9 +77,92 @@ pub enum Instr {
     StrEq,
     StrNeq,
     New,
+    CallNative,
+    Pop,
+    DefLocalWeak,
+    StoreWeak,
+    MakeWeak,
+    Upgrade,
+    Alive,
+    DefLocalPid,
+    LoadPid,
+    StorePid,
+    Spawn,
+    SelfPid,
+    Send,
+    Receive,
+    ReceiveAfter,
+    SpawnNamed,
+    Link,
+    Monitor,
+    TrapExit,
+    ExitSignal,
+    Fail,
+    PidEq,
+    PidNeq,
+    Sleep,
+    Now,
+    RegMov,
+    RegNew,
+    RegSend,
+    RegConstInt,
+    RegConstFloat,
+    RegConstBool,
+    RegLoadMember,
+    RegStoreMember,
+    RegJmpCond,
+    RegIntAdd,
+    RegIntSub,
+    RegIntMul,
+    RegIntDiv,
+    RegIntEq,
+    RegIntNEq,
+    RegIntLess,
+    RegIntGreater,
+    RegIntAddImm,
+    RegIntSubImm,
+    RegIntMulImm,
+    RegIntDivImm,
+    RegIntEqImm,
+    RegIntNEqImm,
+    RegIntLessImm,
+    RegIntGreaterImm,
+    RegFloatAdd,
+    RegFloatSub,
+    RegFloatMul,
+    RegFloatDiv,
+    RegFloatEq,
+    RegFloatNeq,
+    RegFloatLess,
+    RegFloatGreater,
+    RegFloatAddImm,
+    RegFloatSubImm,
+    RegFloatMulImm,
+    RegFloatDivImm,
+    RegFloatEqImm,
+    RegFloatNeqImm,
+    RegFloatLessImm,
+    RegFloatGreaterImm,
+    RegBoolEq,
+    RegBoolNeq,
+    RegBoolAnd,
+    RegBoolOr,
+    RegIntEqJmp,
+    RegIntNEqJmp,
+    RegIntLessJmp,
+    RegIntGreaterJmp,
+    RegIntEqImmJmp,
+    RegIntNEqImmJmp,
+    RegIntLessImmJmp,
+    RegIntGreaterImmJmp,
+    RegIntAddLessJmp,
+    RegIntAddLessImmJmp,
+    RegArgs,
+    RegPop,
+    RegRet,
 }
 #[repr(u64)]
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Tag {
     Void,
     Integer,
@@ -85,6 +171,8 @@ pub enum Tag {
     String,
     Ptr,
     LValue,
+    Weak,
+    Pid,
 }
 #[repr(C)]
 #[derive(Clone)]
@@ -94,11 +182,13 @@ pub struct BStr {
     len: usize,
 }
 impl BStr {
-    pub fn as_str(&self) -> &str {
-        unsafe {
-            let slice = std::slice::from_raw_parts(self.start, self.len);
-            return str::from_utf8(slice).unwrap();
-        }
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.start, self.len) }
+    }
+    /// The string as text. Bytes that are not valid UTF-8, such as binary
+    /// data from `io_read`, are replaced rather than trusted.
+    pub fn as_str(&self) -> std::borrow::Cow<'_, str> {
+        string::String::from_utf8_lossy(self.as_bytes())
     }
 }
 
@@ -130,7 +220,7 @@ use serde::{Deserialize, Serialize};
 use Instr::*;
 use Tag::*;
 
-use crate::mach::{Binop, Program, Type};
+use crate::mach::{Binop, Handle, NativeInterface, Program, RunResult, ShallowType, Type};
 
 impl Var {
     pub const fn new() -> Self {
@@ -169,6 +259,20 @@ impl Var {
             value: Value { ptr: v },
         }))
     }
+    pub fn weak(v: *mut Var) -> Self {
+        Var(UnsafeCell::new(InternalVar {
+            tag: Weak,
+            value: Value { ptr: v },
+        }))
+    }
+    pub fn pid(v: u64) -> Self {
+        Var(UnsafeCell::new(InternalVar {
+            tag: Pid,
+            value: Value {
+                integer: v as i64,
+            },
+        }))
+    }
     pub fn void(v: ()) -> Self {
         Var(UnsafeCell::new(InternalVar {
             tag: Void,
@@ -240,6 +344,12 @@ impl Var {
             }
         }
     }
+    pub fn get_weak(&self) -> Option<*const Var> {
+        unsafe { (self.get().tag == Weak).then(|| self.get().value.ptr) }
+    }
+    pub fn get_pid(&self) -> Option<u64> {
+        unsafe { (self.get().tag == Pid).then(|| self.get().value.integer as u64) }
+    }
     pub fn get_l_value(&self) -> &Var {
         unsafe {
             if self.get().tag == LValue {
@@ -277,7 +387,7 @@ impl Debug for Var {
                 } else {
                     let header = ptr as *const crate::heap::Allocation;
                     let len = (*header).num_objects;
-                    for i in 0..len as usize {
+                    for i in 1..len as usize + 1 {
                         write!(f, "{:#?}", &*(ptr.add(i)))?;
                     }
                     Ok(())
@@ -286,6 +396,12 @@ impl Debug for Var {
             LValue => {
                 write!(f, "{:#?}", self.get_l_value())
             }
+            Weak => {
+                write!(f, "weak:{:#?}", self.get_weak().unwrap_or(std::ptr::null()))
+            }
+            Pid => {
+                write!(f, "pid:{:#?}", self.get_pid().unwrap_or_default())
+            }
         }
     }
 }
@@ -306,6 +422,27 @@ impl<T> OwnedSlice<T> {
         // println!("slice created at {:#?}", ptr);
         Self { ptr, len }
     }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn grow(&mut self, new_len: usize, fill: T)
+    where
+        T: Clone,
+    {
+        if new_len <= self.len {
+            return;
+        }
+        unsafe {
+            let old = Box::from_raw(self.as_slice_mut() as *mut [T]);
+            let mut v = old.into_vec();
+            v.resize(new_len, fill);
+            self.ptr = NonNull::new(Box::leak(v.into_boxed_slice()).as_mut_ptr()).unwrap();
+            self.len = new_len;
+        }
+    }
     pub fn from_box(v: Box<[T]>) -> Self {
         let len = v.len();
         let ptr = NonNull::new(Box::leak(v).as_mut_ptr()).unwrap();
@@ -331,6 +468,9 @@ impl<T> OwnedSlice<T> {
     pub unsafe fn get_mut_unchecked(&mut self, idx: usize) -> &mut T {
         unsafe { Some(self.ptr.add(idx).as_mut()).unwrap_unchecked() }
     }
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
     pub fn as_slice(&self) -> &[T] {
         unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
     }
@@ -389,19 +529,172 @@ impl<T> AsMut<[T]> for OwnedSlice<T> {
         self.as_slice_mut()
     }
 }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RtType {
+    pub name: string::String,
+    pub fields: Vec<Tag>,
+    pub finalizer: Option<string::String>,
+}
 #[repr(C)]
 #[derive(Clone)]
 pub struct RetInfo {
     pub ip: usize,
     pub var_sp: usize,
     pub var_bp: usize,
+    pub discard: bool,
 }
 #[repr(C)]
 #[derive(Clone)]
-pub struct GcInfo {}
+pub struct GcInfo {
+    pub phase: GcPhase,
+    pub mark_stack: Vec<*mut Allocation>,
+    pub slice_budget: usize,
+    pub threshold: usize,
+    pub min_threshold: usize,
+    pub finalize_queue: Vec<(*mut Var, usize)>,
+    pub deferred: bool,
+    pub fault: Option<RtError>,
+}
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub heap_bytes: Option<usize>,
+    pub op_stack: usize,
+    pub var_stack: usize,
+    pub call_depth: usize,
+    pub string_bytes: Option<usize>,
+    pub processes: Option<usize>,
+    /// Whether programs may call the `io_*` natives. Off unless asked for.
+    pub io: bool,
+}
+pub const INITIAL_STACK_SIZE: usize = 256;
+pub const OPCODE_WIDTH: usize = 8;
+pub const DEFAULT_STACK_LIMIT: usize = 4096 * 16;
+pub const DEFAULT_GC_THRESHOLD: usize = 1 << 20;
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            heap_bytes: None,
+            op_stack: DEFAULT_STACK_LIMIT,
+            var_stack: DEFAULT_STACK_LIMIT,
+            call_depth: DEFAULT_STACK_LIMIT,
+            string_bytes: None,
+            processes: None,
+            io: false,
+        }
+    }
+}
+impl Limits {
+    pub fn unlimited() -> Self {
+        Self {
+            fuel: None,
+            heap_bytes: None,
+            op_stack: usize::MAX,
+            var_stack: usize::MAX,
+            call_depth: usize::MAX,
+            string_bytes: None,
+            processes: None,
+            io: true,
+        }
+    }
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum RtError {
+    OutOfFuel,
+    Deadlock,
+    ProcessFailed { pid: u64, reason: string::String },
+    HeapExhausted { requested: usize, limit: usize },
+    OpStackOverflow { limit: usize },
+    VarStackOverflow { limit: usize },
+    CallStackOverflow { limit: usize },
+    StringTooLarge { len: usize, limit: usize },
+    TooManyProcesses { limit: usize },
+    IoDisabled { name: string::String },
+    ReplayDiverged(Box<crate::replay::Divergence>),
+    Heap { ip: usize, error: crate::heap::HeapError },
+    UnknownNative { name: string::String },
+    NullPointer { ip: usize },
+    DivideByZero { ip: usize },
+    IntegerOverflow { ip: usize },
+    TypeMismatch { ip: usize, expected: Tag, found: Tag },
+}
+impl std::fmt::Display for RtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RtError::OutOfFuel => write!(f, "out of fuel"),
+            RtError::Deadlock => write!(f, "all processes are blocked in receive"),
+            RtError::ProcessFailed { pid, reason } => {
+                write!(f, "process {} failed: {}", pid, reason)
+            }
+            RtError::HeapExhausted { requested, limit } => {
+                write!(f, "heap limit of {} bytes exceeded allocating {} bytes", limit, requested)
+            }
+            RtError::OpStackOverflow { limit } => {
+                write!(f, "operand stack overflow (limit {})", limit)
+            }
+            RtError::VarStackOverflow { limit } => {
+                write!(f, "variable stack overflow (limit {})", limit)
+            }
+            RtError::CallStackOverflow { limit } => {
+                write!(f, "call stack overflow (limit {})", limit)
+            }
+            RtError::StringTooLarge { len, limit } => {
+                write!(f, "string of {} bytes exceeds limit of {}", len, limit)
+            }
+            RtError::TooManyProcesses { limit } => {
+                write!(f, "process limit of {} reached", limit)
+            }
+            RtError::IoDisabled { name } => write!(f, "{} called but io is not enabled", name),
+            RtError::ReplayDiverged(d) => write!(f, "replay diverged: {}", d),
+            RtError::Heap { ip, error } => {
+                write!(f, "heap verification failed at ip {}: {}", ip, error)
+            }
+            RtError::UnknownNative { name } => write!(f, "unknown native {}", name),
+            RtError::NullPointer { ip } => write!(f, "null pointer dereference at ip {}", ip),
+            RtError::DivideByZero { ip } => write!(f, "integer division by zero at ip {}", ip),
+            RtError::IntegerOverflow { ip } => write!(f, "integer overflow at ip {}", ip),
+            RtError::TypeMismatch { ip, expected, found } => {
+                write!(f, "expected {:?} but found {:?} at ip {}", expected, found, ip)
+            }
+        }
+    }
+}
+impl std::error::Error for RtError {}
+impl RtError {
+    pub fn is_process_fault(&self) -> bool {
+        !matches!(
+            self,
+            RtError::OutOfFuel
+                | RtError::Deadlock
+                | RtError::ReplayDiverged(_)
+                | RtError::Heap { .. }
+        )
+    }
+    pub fn reason(&self) -> string::String {
+        match self {
+            RtError::ProcessFailed { pid: _, reason } => reason.clone(),
+            _ => self.to_string(),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GcPhase {
+    Idle,
+    Marking,
+}
 impl GcInfo {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            phase: GcPhase::Idle,
+            mark_stack: Vec::new(),
+            slice_budget: 64,
+            threshold: DEFAULT_GC_THRESHOLD,
+            min_threshold: DEFAULT_GC_THRESHOLD,
+            finalize_queue: Vec::new(),
+            deferred: false,
+            fault: None,
+        }
     }
 }
 
@@ -419,21 +712,142 @@ pub struct RT {
     pub ret_info_ptr: usize,
     pub halted: bool,
     pub symbol_table: HashMap<string::String, usize>,
+    pub arity: HashMap<string::String, usize>,
     pub heap: RtHeap,
     pub strings: OwnedSlice<string::String>,
+    pub types: OwnedSlice<RtType>,
     pub gc_info: GcInfo,
+    pub natives: NativeInterface,
+    pub limits: Limits,
+    pub result: Option<crate::mach::Value>,
+    pub scheduler: Scheduler,
+    pub heap_locking: bool,
+    pub held_lock: *mut Var,
+    pub code: CodeTable,
+    pub profile: Option<Box<OpcodeProfile>>,
+    pub jit: Option<Box<Jit>>,
 }
+const _: () = assert!(size_of::<Allocation>() == size_of::<Var>());
 impl Drop for RT {
     fn drop(&mut self) {
-        crate::heap::rt_heap_mark_all_unreachable(&mut self.heap);
-        crate::heap::rt_heap_free_all_unreachable(&mut self.heap);
+        if self.heap.verifier.is_some() {
+            self.print_leak_report();
+        }
+        crate::heap::rt_heap_destroy(&mut self.heap);
+        if std::sync::Arc::strong_count(&self.scheduler.shared) == 1 {
+            self.scheduler.lock().release_heaps();
+        }
     }
 }
 impl RT {
+    pub fn enable_heap_verification(&mut self) {
+        crate::heap::rt_heap_enable_verification(&mut self.heap);
+    }
+    pub fn check_alloc(&self, ptr: *const Allocation) -> Result<(), RtError> {
+        crate::heap::rt_heap_check(&self.heap, ptr).map_err(|error| RtError::Heap {
+            ip: self.ip,
+            error,
+        })
+    }
+    pub(crate) fn check_null<T>(&self, ptr: *const T) -> Result<(), RtError> {
+        if ptr.is_null() {
+            return Err(RtError::NullPointer { ip: self.ip });
+        }
+        Ok(())
+    }
+    pub(crate) fn int_div(&self, l: i64, r: i64) -> Result<i64, RtError> {
+        match r {
+            0 => Err(RtError::DivideByZero { ip: self.ip }),
+            _ => l.checked_div(r).ok_or(RtError::IntegerOverflow { ip: self.ip }),
+        }
+    }
+    fn check_str(&self, ptr: *const BStr) -> Result<(), RtError> {
+        if self.heap.verifier.is_none() {
+            return Ok(());
+        }
+        unsafe {
+            self.check_alloc((ptr as *const Allocation).sub(1))?;
+            self.check_alloc(((*ptr).start as *const Allocation).sub(1))
+        }
+    }
+    pub fn check_var(&self, v: &Var) -> Result<(), RtError> {
+        match v.get().tag {
+            Ptr => {
+                let p = v.get_ptr();
+                if !p.is_null() {
+                    self.check_alloc(p as *const Allocation)?;
+                }
+            }
+            Weak => {
+                let p = self.weak_of(v)?;
+                if !p.is_null() {
+                    self.check_alloc(p as *const Allocation)?;
+                }
+            }
+            String => {
+                let p = v.get_string();
+                if !p.is_null() {
+                    self.check_str(p)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    pub fn verify_heap(&self) -> Result<(), RtError> {
+        if self.heap.verifier.is_none() {
+            return Ok(());
+        }
+        for i in 0..self.var_stack_ptr {
+            self.check_var(&self.var_stack[i])?;
+        }
+        for i in 0..self.op_stack_ptr {
+            self.check_var(&self.op_stack[i])?;
+        }
+        unsafe {
+            for al in self.heap.allocations() {
+                self.check_alloc(*al)?;
+                let base = *al as *const Var;
+                for i in 1..(**al).num_objects as usize + 1 {
+                    self.check_var(&*base.add(i))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn leak_report(&mut self) -> Vec<crate::heap::LeakEntry> {
+        crate::heap::rt_heap_mark_all_unreachable(&mut self.heap);
+        self.gc_info.mark_stack.clear();
+        self.gc_mark_roots();
+        self.gc_drain(usize::MAX);
+        self.gc_info.fault = None;
+        self.gc_info.phase = GcPhase::Idle;
+        crate::heap::rt_heap_leak_report(&self.heap)
+    }
+    pub fn print_leak_report(&mut self) {
+        let report = self.leak_report();
+        if report.is_empty() {
+            println!("leak report: no unreachable allocations");
+            return;
+        }
+        let total: usize = report.iter().map(|i| i.count).sum();
+        println!("leak report: {} unreachable allocations", total);
+        for i in &report {
+            let name = self
+                .types
+                .get(i.type_idx as usize)
+                .map(|t| t.name.as_str())
+                .unwrap_or("?");
+            println!(
+                "  type:{}({}) count:{} bytes:{}",
+                name, i.type_idx, i.count, i.bytes
+            );
+        }
+    }
     pub fn next_instruction(&mut self) -> Instr {
         unsafe {
-            let i = self.instructions.get_unchecked(self.ip).clone();
-            self.ip += 1;
+            let i = *self.instructions.get_unchecked(self.ip);
+            self.ip += OPCODE_WIDTH;
             std::mem::transmute(i)
         }
     }
@@ -446,27 +860,36 @@ impl RT {
         unsafe { std::mem::transmute(bytes) }
     }
     pub fn next_u64(&mut self) -> u64 {
-        unsafe {
-            let bytes0: MaybeUninit<[u8; 8]> = MaybeUninit::zeroed();
-            let mut bytes = bytes0.assume_init();
-            for i in 0..8 {
-                bytes[i] = std::mem::transmute(*self.instructions.get_unchecked(self.ip));
-                self.ip += 1;
-            }
-            u64::from_le_bytes(bytes)
-        }
+        debug_assert!(self.ip.is_multiple_of(8) && self.ip + 8 <= self.instructions.len);
+        let v = unsafe {
+            std::ptr::read_unaligned(self.instructions.as_ptr().add(self.ip) as *const u64)
+        };
+        self.ip += 8;
+        u64::from_le(v)
     }
     pub fn op_pop(&mut self) -> Var {
         self.op_stack_ptr -= 1;
         unsafe { self.op_stack.get_unchecked(self.op_stack_ptr).clone() }
     }
+    #[cold]
+    fn grow_op_stack(&mut self) {
+        let len = (self.op_stack.len * 2).max(INITIAL_STACK_SIZE);
+        self.op_stack.grow(len, Var::new());
+    }
     pub fn op_push(&mut self, var: Var) {
+        if self.op_stack_ptr == self.op_stack.len {
+            self.grow_op_stack();
+        }
         unsafe {
             *self.op_stack.get_mut_unchecked(self.op_stack_ptr) = var;
             self.op_stack_ptr += 1;
         }
     }
     pub fn ret_push(&mut self, r: RetInfo) {
+        if self.ret_info_ptr == self.ret_info.len {
+            let len = (self.ret_info.len * 2).max(INITIAL_STACK_SIZE);
+            self.ret_info.grow(len, r.clone());
+        }
         self.ret_info[self.ret_info_ptr] = r;
         self.ret_info_ptr += 1;
     }
@@ -480,12 +903,53 @@ impl RT {
             Some(r)
         }
     }
+    fn ret(&mut self) -> Result<bool, RtError> {
+        if let Some(r) = self.ret_pop() {
+            self.ip = r.ip;
+            self.var_stack_ptr = r.var_sp;
+            self.var_base_ptr = r.var_bp;
+            if r.discard {
+                let _ = self.op_pop();
+            }
+        } else if self.scheduler.current != 0 {
+            self.exit_process();
+        } else {
+            let s = self.op_stack[self.op_stack_ptr - 1].clone();
+            self.result = Some(self.var_to_value(&s));
+            self.halted = true;
+            self.close_process_fds(0);
+            self.gc_collect()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
     pub fn def_local_var(&mut self, vtype: Tag) {
         let mut tmp = Var::new();
         tmp.get_mut().tag = vtype;
+        if self.var_stack_ptr == self.var_stack.len {
+            let len = (self.var_stack.len * 2).max(INITIAL_STACK_SIZE);
+            self.var_stack.grow(len, Var::new());
+        }
         self.var_stack[self.var_stack_ptr] = tmp;
         self.var_stack_ptr += 1;
     }
+    /// Moves the top `n` operands into fresh locals, in argument order.
+    fn bind_args(&mut self, n: usize) {
+        if self.var_stack_ptr + n > self.var_stack.len {
+            let len = (self.var_stack.len * 2).max(self.var_stack_ptr + n).max(INITIAL_STACK_SIZE);
+            self.var_stack.grow(len, Var::new());
+        }
+        let base = self.op_stack_ptr - n;
+        for k in 0..n {
+            let v = self.op_stack[base + k].clone();
+            if matches!(v.get().tag, Ptr | String) {
+                self.gc_write_barrier(&v);
+            }
+            self.var_stack[self.var_stack_ptr + k] = v;
+        }
+        self.var_stack_ptr += n;
+        self.op_stack_ptr = base;
+    }
     pub fn get_local_var(&mut self, idx: usize) -> Var {
         self.var_stack[self.var_base_ptr + idx].clone()
     }
@@ -494,25 +958,102 @@ impl RT {
         Var::l_value(x)
     }
     pub fn allocate_str(&mut self, s: &str) -> Var {
+        self.allocate_bytes(s.as_bytes())
+    }
+    pub fn allocate_bytes(&mut self, s: &[u8]) -> Var {
         unsafe {
-            let slen = s.as_bytes().len();
-            let s_h_ptr = crate::heap::rt_heap_allocate(&mut self.heap, slen, 0, 4) as *mut u8;
+            let slen = s.len();
+            let s_h_ptr = crate::heap::rt_heap_allocate(&mut self.heap, slen, 0, 3) as *mut u8;
             let s_ptr = s_h_ptr.add(size_of::<crate::heap::Allocation>());
-            for i in 0..slen {
-                (*s_ptr.add(i)) = s.as_bytes()[i];
-            }
+            std::ptr::copy_nonoverlapping(s.as_ptr(), s_ptr, slen);
             let bx_ptr = crate::heap::rt_heap_allocate(&mut self.heap, size_of::<BStr>(), 0, 3)
                 as *mut crate::heap::Allocation;
+            self.gc_allocated(s_h_ptr as *mut Allocation);
+            self.gc_allocated(bx_ptr);
             let bstr = bx_ptr.add(1) as *mut BStr;
             (*bstr).start = s_ptr;
             (*bstr).len = slen;
             return Var::string(bstr);
         }
     }
-    pub fn try_take_ptr(&mut self, ptr: *mut Var) -> bool {
+    pub fn var_to_value(&self, v: &Var) -> crate::mach::Value {
+        match v.get().tag {
+            Void => crate::mach::Value::Unit,
+            Integer => crate::mach::Value::Integer { v: v.get_int() },
+            Float => crate::mach::Value::Float { v: v.get_float() },
+            Bool => crate::mach::Value::Bool { v: v.get_bool() },
+            String => {
+                let p = v.get_string();
+                let s = if p.is_null() {
+                    "".into()
+                } else {
+                    unsafe { (*p).as_str() }
+                };
+                crate::mach::Value::String { v: s.as_ref().into() }
+            }
+            Ptr => crate::mach::Value::Object {
+                ptr: v.get_ptr() as u64,
+            },
+            LValue => self.var_to_value(v.get_l_value()),
+            Weak => crate::mach::Value::Weak {
+                ptr: v.get_weak().unwrap_or(std::ptr::null()) as u64,
+            },
+            Pid => crate::mach::Value::Pid {
+                v: v.get_pid().unwrap_or_default(),
+            },
+        }
+    }
+    pub fn value_to_var(&mut self, v: &crate::mach::Value) -> Var {
+        match v {
+            crate::mach::Value::Unit => Var::void(()),
+            crate::mach::Value::Integer { v } => Var::integer(*v),
+            crate::mach::Value::Float { v } => Var::float(*v),
+            crate::mach::Value::Bool { v } => Var::boolean(*v),
+            crate::mach::Value::String { v } => self.allocate_str(v),
+            crate::mach::Value::Object { ptr } => Var::ptr(*ptr as *mut Var),
+            crate::mach::Value::Weak { ptr } => Var::weak(*ptr as *mut Var),
+            crate::mach::Value::Pid { v } => Var::pid(*v),
+            crate::mach::Value::ObjectHeader {
+                information: _,
+                size: _,
+            } => {
+                todo!()
+            }
+        }
+    }
+    pub fn pin(&mut self, v: &Var) -> Handle {
+        let val = self.var_to_value(v);
+        self.natives.handles.pin(val)
+    }
+    pub fn unpin(&mut self, h: Handle) {
+        self.natives.handles.unpin(h);
+    }
+    pub fn get_pinned(&mut self, h: Handle) -> Option<Var> {
+        let v = self.natives.handles.get(h)?;
+        Some(self.value_to_var(&v))
+    }
+    pub fn call_native(&mut self, name: &str, argc: usize) -> Result<Var, RtError> {
+        let mut vars = Vec::new();
+        for _ in 0..argc {
+            vars.push(self.op_pop());
+        }
+        vars.reverse();
+        if let Some(rv) = self.replay_native(name, &vars) {
+            return rv;
+        }
+        let Some(f) = self.natives.funcs.get(name).cloned() else {
+            return Err(RtError::UnknownNative { name: name.to_string() });
+        };
+        let args: Vec<crate::mach::Value> = vars.iter().map(|v| self.var_to_value(v)).collect();
+        let rv = f(&args);
+        let rv = self.value_to_var(&rv);
+        self.record_native(name, &vars, &rv);
+        Ok(rv)
+    }
+    pub fn try_take_ptr(&self, ptr: *mut Var) -> bool {
         unsafe {
             let al = ptr as *mut Allocation;
-            let atm = &mut (*al).in_use;
+            let atm = &(*al).in_use;
             let mut idx = 0;
             while let Err(_) = atm.compare_exchange_weak(
                 0,
@@ -528,16 +1069,270 @@ impl RT {
         }
         true
     }
-    pub fn release_ptr(&mut self, ptr: *mut Var) {
+    pub fn release_ptr(&self, ptr: *mut Var) {
         unsafe {
             let al = ptr as *mut Allocation;
-            let atm = &mut (*al).in_use;
+            let atm = &(*al).in_use;
             atm.store(0, std::sync::atomic::Ordering::Release);
         }
     }
-    pub fn step(&mut self) -> Option<bool> {
+    pub fn lock_object(&self, ptr: *mut Var) {
+        if !self.heap_locking || ptr.is_null() {
+            return;
+        }
+        while !self.try_take_ptr(ptr) {
+            std::thread::yield_now();
+        }
+    }
+    pub fn unlock_object(&self, ptr: *mut Var) {
+        if !self.heap_locking || ptr.is_null() {
+            return;
+        }
+        self.release_ptr(ptr);
+    }
+    pub fn release_held(&mut self) {
+        let held = std::mem::replace(&mut self.held_lock, std::ptr::null_mut());
+        self.unlock_object(held);
+    }
+    pub(crate) fn new_object(&mut self, info: AllocInfo) -> Var {
+        let ptr = crate::heap::rt_heap_allocate(
+            &mut self.heap,
+            info.field_count as usize * size_of::<Var>(),
+            info.field_count as u16,
+            info.type_info as u16,
+        ) as *mut Var;
+        self.gc_allocated(ptr as *mut Allocation);
+        for i in 1..info.field_count as usize + 1 {
+            let mut tmp = Var::new();
+            tmp.get_mut().tag = self.types[info.type_info as usize].fields[i - 1];
+            unsafe {
+                *ptr.add(i) = tmp;
+            }
+        }
+        Var::ptr(ptr)
+    }
+    pub(crate) fn read_fields(&self, ptr: *mut Var) -> Vec<Var> {
+        self.lock_object(ptr);
+        let n = unsafe { (*(ptr as *mut Allocation)).num_objects as usize };
+        let out = (1..n + 1)
+            .map(|i| unsafe { (*ptr.add(i)).clone() })
+            .collect();
+        self.unlock_object(ptr);
+        out
+    }
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    fn peek_u64(&self, at: usize) -> u64 {
+        u64::from_le_bytes(self.instructions[at..at + 8].try_into().unwrap())
+    }
+    pub(crate) fn reserve_heap(&mut self, bytes: usize) -> Result<(), RtError> {
+        let Some(limit) = self.limits.heap_bytes else {
+            return Ok(());
+        };
+        if self.heap.pool_bytes() + bytes <= limit {
+            return Ok(());
+        }
+        self.gc_collect()?;
+        if self.heap.pool_bytes() + bytes <= limit {
+            return Ok(());
+        }
+        Err(RtError::HeapExhausted {
+            requested: bytes,
+            limit,
+        })
+    }
+    /// Fails if allocations that could not be reserved up front, such as
+    /// message copies and native results, pushed the VM past its heap limit.
+    /// The target of weak reference `v`, or a type error if `v` is not one.
+    pub(crate) fn weak_of(&self, v: &Var) -> Result<*const Var, RtError> {
+        v.get_weak().ok_or(RtError::TypeMismatch {
+            ip: self.ip,
+            expected: Weak,
+            found: v.get().tag,
+        })
+    }
+    pub(crate) fn pid_of(&self, v: &Var) -> Result<u64, RtError> {
+        v.get_pid().ok_or(RtError::TypeMismatch {
+            ip: self.ip,
+            expected: Pid,
+            found: v.get().tag,
+        })
+    }
+    pub(crate) fn check_heap(&self) -> Result<(), RtError> {
+        match self.limits.heap_bytes {
+            Some(limit) if self.heap.pool_bytes() > limit => Err(RtError::HeapExhausted {
+                requested: self.heap.pool_bytes() - limit,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+    fn check_string(&self, len: usize) -> Result<(), RtError> {
+        match self.limits.string_bytes {
+            Some(limit) if len > limit => Err(RtError::StringTooLarge { len, limit }),
+            _ => Ok(()),
+        }
+    }
+    pub(crate) fn string_cost(len: usize) -> usize {
+        len + size_of::<BStr>() + 2 * size_of::<Allocation>()
+    }
+    pub fn check_limits(&mut self) -> Result<(), RtError> {
+        if self.limits.fuel == Some(0) {
+            return Err(RtError::OutOfFuel);
+        }
+        let op_limit = self.limits.op_stack;
+        if self.op_stack_ptr >= op_limit {
+            return Err(RtError::OpStackOverflow { limit: op_limit });
+        }
+        let n: Instr = unsafe { std::mem::transmute(self.instructions[self.ip]) };
+        match n {
+            Call => {
+                let limit = self.limits.call_depth;
+                if self.ret_info_ptr >= limit {
+                    return Err(RtError::CallStackOverflow { limit });
+                }
+            }
+            DefLocalVoid | DefLocalInt | DefLocalFloat | DefLocalBool | DefLocalPtr
+            | DefLocalStr | DefLocalWeak | DefLocalPid => {
+                let limit = self.limits.var_stack;
+                if self.var_stack_ptr >= limit {
+                    return Err(RtError::VarStackOverflow { limit });
+                }
+            }
+            RegArgs => {
+                let limit = self.limits.var_stack;
+                let n = self.peek_u64(self.ip + OPCODE_WIDTH) as usize;
+                if self.var_stack_ptr + n > limit {
+                    return Err(RtError::VarStackOverflow { limit });
+                }
+            }
+            New | RegNew => {
+                let at = if matches!(n, New) { self.ip + OPCODE_WIDTH } else { self.ip + OPCODE_WIDTH + 8 };
+                let info: AllocInfo = unsafe { std::mem::transmute(self.peek_u64(at)) };
+                let bytes = (info.field_count as usize + 1) * size_of::<Var>();
+                self.reserve_heap(bytes)?;
+            }
+            ConstStr => {
+                let idx = self.peek_u64(self.ip + OPCODE_WIDTH) as usize;
+                let len = self.strings[idx].len();
+                self.check_string(len)?;
+                self.reserve_heap(Self::string_cost(len))?;
+            }
+            StrAdd => {
+                let slen = |p: *const BStr| if p.is_null() { 0 } else { unsafe { (*p).len } };
+                let r = self.op_stack[self.op_stack_ptr - 1].get_string();
+                let l = self.op_stack[self.op_stack_ptr - 2].get_string();
+                let len = slen(r) + slen(l);
+                self.check_string(len)?;
+                self.reserve_heap(Self::string_cost(len))?;
+            }
+            _ => {}
+        }
+        if let Some(f) = &mut self.limits.fuel {
+            *f -= 1;
+        }
+        Ok(())
+    }
+    pub fn run(&mut self, fuel: u64) -> RunResult {
+        if self.has_old_code() {
+            self.purge_old_code();
+        }
+        if self.scheduler.threads > 1 {
+            return self.run_parallel(fuel);
+        }
+        let mut left = fuel;
+        while left > 0 && !self.halted {
+            let (r, used) = self.step_threaded(left);
+            if let Err(e) = r {
+                return RunResult::Error(Box::new(e));
+            }
+            left -= used;
+        }
+        if self.halted {
+            RunResult::Finished(self.result.clone().unwrap_or(crate::mach::Value::Unit))
+        } else {
+            RunResult::Paused
+        }
+    }
+    pub fn step_threaded(&mut self, max: u64) -> (Result<bool, RtError>, u64) {
+        let r = self.step();
+        if r != Ok(false)
+            || self.scheduler.is_idle()
+            || self.heap.verifier.is_some()
+            || self.gc_info.phase != GcPhase::Idle
+            || !self.gc_info.finalize_queue.is_empty()
+        {
+            return (r, 1);
+        }
+        let mut used = 1;
+        while used < max {
+            if self.jit.is_some()
+                && let Some(n) = self.run_native(max - used)
+                && n > 0
+            {
+                used += n;
+                continue;
+            }
+            let pid = self.scheduler.current;
+            match self.exec_threaded() {
+                Ok(true) => used += 1,
+                Ok(false) => break,
+                Err(e) => return (self.fail_step(pid, e), used + 1),
+            }
+        }
+        (Ok(false), used)
+    }
+    #[inline(always)]
+    fn exec_threaded(&mut self) -> Result<bool, RtError> {
+        if self.limits.fuel == Some(0)
+            || self.op_stack_ptr >= self.limits.op_stack
+            || self.scheduler.reductions + 1 >= self.scheduler.budget
+        {
+            return Ok(false);
+        }
+        let n: Instr = unsafe { std::mem::transmute(*self.instructions.get_unchecked(self.ip)) };
+        if !matches!(n, Jmp) && !crate::regs::is_threadable(n) {
+            return Ok(false);
+        }
+        self.ip += OPCODE_WIDTH;
+        if let Some(p) = &mut self.profile {
+            p.record(n);
+        }
+        match n {
+            Jmp => self.ip = self.next_u64() as usize,
+            _ => self.exec_reg(n)?,
+        }
+        if let Some(f) = &mut self.limits.fuel {
+            *f -= 1;
+        }
+        self.scheduler.reductions += 1;
+        Ok(true)
+    }
+    pub fn step(&mut self) -> Result<bool, RtError> {
+        if self.scheduler.is_idle() {
+            return self.idle_step();
+        }
+        let pid = self.scheduler.current;
+        match self.exec() {
+            Err(e) => self.fail_step(pid, e),
+            r => r,
+        }
+    }
+    fn fail_step(&mut self, pid: u64, e: RtError) -> Result<bool, RtError> {
+        self.release_held();
+        if pid != 0 && self.scheduler.current == pid && e.is_process_fault() {
+            self.process_exit(pid, &e.reason())?;
+            return Ok(self.halted);
+        }
+        Err(e)
+    }
+    fn exec(&mut self) -> Result<bool, RtError> {
+        self.check_limits()?;
         let n = self.next_instruction();
-        //        println!("{:#?}", n);
+        if let Some(p) = &mut self.profile {
+            p.record(n);
+        }
         match n {
             Jmp => {
                 self.ip = self.next_u64() as usize;
@@ -558,24 +1353,32 @@ impl RT {
                     ip: old,
                     var_sp: old_var_ptr,
                     var_bp: old_var_bp,
+                    discard: false,
                 });
                 self.var_base_ptr = self.var_stack_ptr;
                 self.ip = rst;
+                if self.jit.is_some() {
+                    self.jit_note_call(rst);
+                }
             }
             CallObj => {
                 todo!()
             }
             Ret => {
-                if let Some(r) = self.ret_pop() {
-                    self.ip = r.ip;
-                    self.var_stack_ptr = r.var_sp;
-                    self.var_base_ptr = r.var_bp;
-                } else {
-                    let s = self.op_pop();
-                    println!("returned: {:#?}", s);
-                    self.halted = true;
-                    self.gc_collect();
-                    return Some(true);
+                if self.ret()? {
+                    return Ok(true);
+                }
+            }
+            RegArgs => {
+                let n = self.next_u64() as usize;
+                self.bind_args(n);
+            }
+            RegRet => {
+                let idx = self.next_u64() as usize;
+                let v = self.get_local_var(idx);
+                self.op_push(v);
+                if self.ret()? {
+                    return Ok(true);
                 }
             }
             DefLocalVoid => {
@@ -593,6 +1396,9 @@ impl RT {
             DefLocalPtr => {
                 self.def_local_var(Ptr);
             }
+            DefLocalWeak => {
+                self.def_local_var(Weak);
+            }
             DefLocalStr => {
                 self.def_local_var(String);
             }
@@ -635,10 +1441,12 @@ impl RT {
             LoadMember => {
                 let s = self.op_pop();
                 let offset = self.next_u64() as usize;
-                unsafe {
-                    let ptr = s.get_ptr().add(offset + 1);
-                    self.op_push((*ptr).clone());
-                }
+                self.check_null(s.get_ptr())?;
+                self.check_alloc(s.get_ptr() as *const Allocation)?;
+                self.lock_object(s.get_ptr() as *mut Var);
+                let v = unsafe { (*s.get_ptr().add(offset + 1)).clone() };
+                self.unlock_object(s.get_ptr() as *mut Var);
+                self.op_push(v);
             }
             LoadVarAddr => {
                 let idx = self.next_u64() as usize;
@@ -648,6 +1456,11 @@ impl RT {
             LoadMemberAddr => {
                 let s = self.op_pop();
                 let offset = self.next_u64() as usize;
+                self.check_null(s.get_ptr())?;
+                self.check_alloc(s.get_ptr() as *const Allocation)?;
+                self.release_held();
+                self.lock_object(s.get_ptr() as *mut Var);
+                self.held_lock = s.get_ptr() as *mut Var;
                 unsafe {
                     let ptr = s.get_ptr().add(offset + 1);
                     let v = Var::l_value(ptr as *mut Var);
@@ -664,6 +1477,7 @@ impl RT {
                 unsafe {
                     *f.0.get() = other.get().clone();
                 }
+                self.release_held();
             }
             StoreInt => {
                 let ptr = self.op_pop();
@@ -677,6 +1491,7 @@ impl RT {
                 unsafe {
                     *f.0.get() = other.get().clone();
                 }
+                self.release_held();
             }
             StoreFloat => {
                 let ptr = self.op_pop();
@@ -690,6 +1505,7 @@ impl RT {
                 unsafe {
                     *f.0.get() = other.get().clone();
                 }
+                self.release_held();
             }
             StoreBool => {
                 let ptr = self.op_pop();
@@ -703,6 +1519,7 @@ impl RT {
                 unsafe {
                     *f.0.get() = other.get().clone();
                 }
+                self.release_held();
             }
             StorePtr => {
                 let ptr = self.op_pop();
@@ -711,9 +1528,11 @@ impl RT {
                 if f.get().tag != Ptr || other.get().tag != Ptr {
                     todo!();
                 }
+                self.gc_write_barrier(&other);
                 unsafe {
                     *f.0.get() = other.get().clone();
                 }
+                self.release_held();
             }
             StoreStr => {
                 let ptr = self.op_pop();
@@ -722,9 +1541,11 @@ impl RT {
                 if f.get().tag != String || other.get().tag != String {
                     todo!();
                 }
+                self.gc_write_barrier(&other);
                 unsafe {
                     *f.0.get() = other.get().clone();
                 }
+                self.release_held();
             }
             ConstVoid => {
                 let _ = self.next_u64();
@@ -769,7 +1590,8 @@ impl RT {
             IntDiv => {
                 let r = self.op_pop();
                 let l = self.op_pop();
-                self.op_push(Var::integer(l.get_int() / r.get_int()));
+                let v = self.int_div(l.get_int(), r.get_int())?;
+                self.op_push(Var::integer(v));
             }
             IntEq => {
                 let r = self.op_pop();
@@ -854,47 +1676,228 @@ impl RT {
             StrAdd => {
                 let r = self.op_pop();
                 let l = self.op_pop();
+                self.check_str(r.get_string())?;
+                self.check_str(l.get_string())?;
                 let sr = unsafe { (*r.get_string()).clone() };
                 let sl = unsafe { (*l.get_string()).clone() };
-                let s0 = sl.as_str().to_string() + sr.as_str();
-                let ts = self.allocate_str(&s0);
+                let s0 = [sl.as_bytes(), sr.as_bytes()].concat();
+                let ts = self.allocate_bytes(&s0);
                 self.op_push(ts);
             }
             StrEq => {
                 let r = self.op_pop();
                 let l = self.op_pop();
+                self.check_str(r.get_string())?;
+                self.check_str(l.get_string())?;
                 let sr = unsafe { (*r.get_string()).clone() };
                 let sl = unsafe { (*l.get_string()).clone() };
-                let b = sr.as_str() == sl.as_str();
+                let b = sr.as_bytes() == sl.as_bytes();
                 self.op_push(Var::boolean(b));
             }
             StrNeq => {
                 let r = self.op_pop();
                 let l = self.op_pop();
+                self.check_str(r.get_string())?;
+                self.check_str(l.get_string())?;
                 let sr = unsafe { (*r.get_string()).clone() };
                 let sl = unsafe { (*l.get_string()).clone() };
-                let b = sr.as_str() != sl.as_str();
+                let b = sr.as_bytes() != sl.as_bytes();
                 self.op_push(Var::boolean(b))
             }
             New => unsafe {
                 let info: AllocInfo = std::mem::transmute(self.next_u64());
-                let ptr = crate::heap::rt_heap_allocate(
-                    &mut self.heap,
-                    info.field_count as usize * size_of::<Var>(),
-                    info.field_count as u16,
-                    info.type_info as u16,
-                ) as *mut Var;
-                for i in 0..info.field_count as usize {
-                    (*ptr.add(i)) = Var::new()
-                }
-                self.op_push(Var::ptr(ptr));
+                let v = self.new_object(info);
+                self.op_push(v);
             },
+            CallNative => {
+                let at = self.ip - OPCODE_WIDTH;
+                let idx = self.next_u64() as usize;
+                let argc = self.next_u64() as usize;
+                let name = self.strings[idx].clone();
+                if crate::io::is_io_native(&name) {
+                    self.call_io(&name, argc, at)?;
+                } else {
+                    let v = self.call_native(&name, argc)?;
+                    self.op_push(v);
+                }
+                self.check_heap()?;
+            }
+            Pop => {
+                let _ = self.op_pop();
+            }
+            StoreWeak => {
+                let ptr = self.op_pop();
+                let other = self.op_pop();
+                let f = ptr.get_l_value();
+                if f.get().tag != Weak || other.get().tag != Weak {
+                    let found = if f.get().tag != Weak { f.get().tag } else { other.get().tag };
+                    return Err(RtError::TypeMismatch {
+                        ip: self.ip,
+                        expected: Weak,
+                        found,
+                    });
+                }
+                unsafe {
+                    *f.0.get() = other.get().clone();
+                }
+                self.release_held();
+            }
+            MakeWeak => {
+                let p = self.op_pop();
+                self.op_push(Var::weak(p.get_ptr() as *mut Var));
+            }
+            Upgrade => {
+                let w = self.op_pop();
+                let p = self.weak_of(&w)?;
+                self.op_push(Var::ptr(p as *mut Var));
+            }
+            Alive => {
+                let w = self.op_pop();
+                let p = self.weak_of(&w)?;
+                self.op_push(Var::boolean(!p.is_null()));
+            }
+            DefLocalPid => {
+                self.def_local_var(Pid);
+            }
+            LoadPid => {
+                let idx = self.next_u64() as usize;
+                let tmp = self.get_local_var(idx);
+                self.op_push(tmp);
+            }
+            StorePid => {
+                let ptr = self.op_pop();
+                let other = self.op_pop();
+                let f = ptr.get_l_value();
+                if f.get().tag != Pid || other.get().tag != Pid {
+                    let found = if f.get().tag != Pid { f.get().tag } else { other.get().tag };
+                    return Err(RtError::TypeMismatch {
+                        ip: self.ip,
+                        expected: Pid,
+                        found,
+                    });
+                }
+                unsafe {
+                    *f.0.get() = *other.get();
+                }
+                self.release_held();
+            }
+            Spawn => {
+                let loc = self.next_u64() as usize;
+                let argc = self.next_u64() as usize;
+                let mut args = Vec::new();
+                for _ in 0..argc {
+                    args.push(self.op_pop());
+                }
+                args.reverse();
+                let pid = self.spawn_at(loc, args)?;
+                self.op_push(Var::pid(pid));
+            }
+            SelfPid => {
+                self.op_push(Var::pid(self.scheduler.current));
+            }
+            Sleep => {
+                let ms = self.op_pop().get_int();
+                if ms > 0 {
+                    // skip the logged firing of this sleep's timer
+                    self.replay_timeout();
+                }
+                self.sleep(ms);
+            }
+            Now => {
+                let t = self.clock_now()?;
+                self.op_push(t);
+            }
+            Send => {
+                let msg = self.op_pop();
+                let to = self.op_pop();
+                let to = self.pid_of(&to)?;
+                self.send_message(to, msg.get_ptr() as *mut Var)?;
+            }
+            Receive => {
+                let type_idx = self.next_u64() as u16;
+                self.receive(type_idx, None, 0);
+            }
+            ReceiveAfter => {
+                let type_idx = self.next_u64() as u16;
+                let timeout_ip = self.next_u64() as usize;
+                let ms = self.op_pop().get_int();
+                match self.replay_timeout() {
+                    Some(true) => self.ip = timeout_ip,
+                    Some(false) => self.receive(type_idx, None, timeout_ip),
+                    None => self.receive(type_idx, Some(ms), timeout_ip),
+                }
+            }
+            SpawnNamed => {
+                let s = self.op_pop();
+                let name = unsafe { (*s.get_string()).as_str().to_string() };
+                let Some(loc) = self.symbol_table.get(&name).copied() else {
+                    return Err(RtError::ProcessFailed {
+                        pid: self.scheduler.current,
+                        reason: format!("cannot spawn unknown function {}", name),
+                    });
+                };
+                if self.arity.get(&name) != Some(&0) {
+                    return Err(RtError::ProcessFailed {
+                        pid: self.scheduler.current,
+                        reason: format!("cannot spawn {} by name, it takes arguments", name),
+                    });
+                }
+                let pid = self.spawn_at(loc, Vec::new())?;
+                self.op_push(Var::pid(pid));
+            }
+            Link => {
+                let to = self.op_pop();
+                let to = self.pid_of(&to)?;
+                self.link(to)?;
+            }
+            Monitor => {
+                let to = self.op_pop();
+                let to = self.pid_of(&to)?;
+                self.monitor(to);
+            }
+            TrapExit => {
+                self.trap_exits();
+            }
+            ExitSignal => {
+                let r = self.op_pop();
+                let to = self.op_pop();
+                let to = self.pid_of(&to)?;
+                let reason = unsafe { (*r.get_string()).as_str().to_string() };
+                if to == self.scheduler.current {
+                    return Err(RtError::ProcessFailed { pid: to, reason });
+                }
+                self.exit_signal(to, &reason)?;
+            }
+            Fail => {
+                let r = self.op_pop();
+                let reason = unsafe { (*r.get_string()).as_str().to_string() };
+                return Err(RtError::ProcessFailed {
+                    pid: self.scheduler.current,
+                    reason,
+                });
+            }
+            PidEq => {
+                let r = self.op_pop();
+                let l = self.op_pop();
+                let eq = self.pid_of(&l)? == self.pid_of(&r)?;
+                self.op_push(Var::boolean(eq));
+            }
+            PidNeq => {
+                let r = self.op_pop();
+                let l = self.op_pop();
+                let neq = self.pid_of(&l)? != self.pid_of(&r)?;
+                self.op_push(Var::boolean(neq));
+            }
+            _ => self.exec_reg(n)?,
         }
-        self.gc_update();
-        Some(self.halted)
+        self.gc_update()?;
+        self.schedule_tick();
+        Ok(self.halted)
     }
     pub fn debug_instrs(&self) {
         let mut tmp = self.clone();
+        tmp.heap = RtHeap::new();
+        tmp.scheduler = Scheduler::new();
         tmp.ip = 0;
         while tmp.ip < tmp.instructions.len {
             print!("{}:", tmp.ip);
@@ -1014,6 +2017,68 @@ impl RT {
                     let sz = tmp.next_u64();
                     println!("{:#?}", sz);
                 }
+                CallNative => {
+                    let idx = tmp.next_u64() as usize;
+                    let argc = tmp.next_u64();
+                    print!("{:#?} {:#?}", tmp.strings[idx], argc);
+                }
+                Pop => {}
+                DefLocalPid => {}
+                LoadPid | StorePid => {
+                    if matches!(i, LoadPid) {
+                        print!("{:#?}", tmp.next_u64());
+                    }
+                }
+                Spawn => {
+                    let loc = tmp.next_u64();
+                    let argc = tmp.next_u64();
+                    print!("{:#?} {:#?}", loc, argc);
+                }
+                SelfPid => {}
+                Sleep | Now => {}
+                SpawnNamed | Link | Monitor | TrapExit | ExitSignal | Fail | PidEq | PidNeq => {}
+                Send => {}
+                Receive => {
+                    print!("{:#?}", tmp.next_u64());
+                }
+                ReceiveAfter => {
+                    let t = tmp.next_u64();
+                    let to = tmp.next_u64();
+                    print!("{:#?} {:#?}", t, to);
+                }
+                DefLocalWeak => {}
+                StoreWeak => {}
+                MakeWeak => {}
+                Upgrade => {}
+                Alive => {}
+                RegMov | RegNew | RegSend | RegConstInt | RegConstFloat | RegConstBool
+                | RegJmpCond => {
+                    let a = tmp.next_u64();
+                    let b = tmp.next_u64();
+                    print!("{:#?} {:#?}", a, b);
+                }
+                RegIntEqJmp | RegIntNEqJmp | RegIntLessJmp | RegIntGreaterJmp | RegIntEqImmJmp
+                | RegIntNEqImmJmp | RegIntLessImmJmp | RegIntGreaterImmJmp => {
+                    let a = tmp.next_u64();
+                    let b = tmp.next_u64();
+                    let c = tmp.next_u64();
+                    let d = tmp.next_u64();
+                    print!("{:#?} {:#?} {:#?} {:#?}", a, b, c, d);
+                }
+                RegArgs | RegPop | RegRet => {
+                    print!("{:#?}", tmp.next_u64());
+                }
+                RegIntAddLessJmp | RegIntAddLessImmJmp => {
+                    for _ in 0..6 {
+                        print!("{:#?} ", tmp.next_u64());
+                    }
+                }
+                _ => {
+                    let a = tmp.next_u64();
+                    let b = tmp.next_u64();
+                    let c = tmp.next_u64();
+                    print!("{:#?} {:#?} {:#?}", a, b, c);
+                }
             }
             println!("");
         }
@@ -1032,9 +2097,161 @@ impl RT {
         }
         println!("]")
     }
-    pub fn gc_update(&mut self) {
-        return;
-        //self.gc_collect();
+    pub fn gc_update(&mut self) -> Result<(), RtError> {
+        if self.gc_info.deferred {
+            return Ok(());
+        }
+        match self.gc_info.phase {
+            GcPhase::Idle => {
+                if self.heap.bytes >= self.gc_info.threshold {
+                    self.gc_start_cycle();
+                }
+            }
+            GcPhase::Marking => {
+                if self.gc_drain(self.gc_info.slice_budget) {
+                    self.gc_finish_cycle()?;
+                }
+            }
+        }
+        self.run_pending_finalizer();
+        Ok(())
+    }
+    pub fn run_pending_finalizer(&mut self) {
+        if self.gc_info.finalize_queue.is_empty()
+            || self.op_stack_ptr != 0
+            || self.halted
+            || self.scheduler.is_idle()
+        {
+            return;
+        }
+        if self.ret_info_ptr >= self.limits.call_depth {
+            return;
+        }
+        let (obj, loc) = self.gc_info.finalize_queue.remove(0);
+        self.op_push(Var::ptr(obj));
+        self.ret_push(RetInfo {
+            ip: self.ip,
+            var_sp: self.var_stack_ptr,
+            var_bp: self.var_base_ptr,
+            discard: true,
+        });
+        self.var_base_ptr = self.var_stack_ptr;
+        self.ip = loc;
+    }
+    pub fn gc_queue_finalizers(&mut self) {
+        let allocs = self.heap.allocations().to_vec();
+        for al in allocs {
+            unsafe {
+                if (*al).reachable != 0 || (*al).flags & crate::heap::FLAG_FINALIZED != 0 {
+                    continue;
+                }
+                let Some(f) = self
+                    .types
+                    .get((*al).type_idx as usize)
+                    .and_then(|t| t.finalizer.as_ref())
+                else {
+                    continue;
+                };
+                let loc = self.symbol_table[f];
+                (*al).flags |= crate::heap::FLAG_FINALIZED;
+                self.gc_info.finalize_queue.push((al as *mut Var, loc));
+                self.gc_mark(al as *mut Var);
+            }
+        }
+    }
+    pub fn gc_start_cycle(&mut self) {
+        crate::heap::rt_heap_mark_all_unreachable(&mut self.heap);
+        self.gc_info.mark_stack.clear();
+        self.gc_info.phase = GcPhase::Marking;
+        self.gc_mark_roots();
+    }
+    pub fn gc_finish_cycle(&mut self) -> Result<(), RtError> {
+        self.gc_mark_roots();
+        self.gc_drain(usize::MAX);
+        self.gc_queue_finalizers();
+        self.gc_drain(usize::MAX);
+        self.gc_clear_weak();
+        let freed = crate::heap::rt_heap_free_all_unreachable(&mut self.heap);
+        self.gc_info.phase = GcPhase::Idle;
+        self.gc_info.threshold = (self.heap.bytes * 2).max(self.gc_info.min_threshold);
+        if let Some(e) = self.gc_info.fault.take() {
+            return Err(e);
+        }
+        freed.map_err(|error| RtError::Heap { ip: self.ip, error })?;
+        self.verify_heap()
+    }
+    pub fn gc_clear_weak(&mut self) {
+        fn clear(v: &mut Var) {
+            if v.get().tag != Weak {
+                return;
+            }
+            let al = v.get_weak().unwrap_or(std::ptr::null()) as *const Allocation;
+            if !al.is_null() && unsafe { (*al).reachable } == 0 {
+                *v = Var::weak(std::ptr::null_mut());
+            }
+        }
+        for i in 0..self.var_stack_ptr {
+            clear(&mut self.var_stack[i]);
+        }
+        for i in 0..self.op_stack_ptr {
+            clear(&mut self.op_stack[i]);
+        }
+        unsafe {
+            for al in self.heap.allocations() {
+                if (**al).reachable == 0 {
+                    continue;
+                }
+                let base = *al as *mut Var;
+                for i in 1..(**al).num_objects as usize + 1 {
+                    clear(&mut *base.add(i));
+                }
+            }
+        }
+    }
+    pub fn gc_mark_roots(&mut self) {
+        for i in 0..self.var_stack_ptr {
+            let obj = self.var_stack[i].clone();
+            self.gc_mark_var(&obj);
+        }
+        for i in 0..self.op_stack_ptr {
+            let obj = self.op_stack[i].clone();
+            self.gc_mark_var(&obj);
+        }
+        for i in self.natives.handles.roots() {
+            if let crate::mach::Value::Object { ptr } = i
+                && crate::heap::rt_heap_contains(&self.heap, ptr as *const Allocation)
+            {
+                self.gc_mark(ptr as *mut Var);
+            }
+        }
+        for i in self.gc_info.finalize_queue.clone() {
+            self.gc_mark(i.0);
+        }
+    }
+    pub fn gc_mark_var(&mut self, v: &Var) {
+        match v.get().tag {
+            Ptr => self.gc_mark(v.get_ptr() as *mut Var),
+            String => self.gc_mark_string(v.get_string() as *mut BStr),
+            LValue => {
+                let ptr = unsafe { v.get().value.lvalue } as *const u8;
+                if let Some(al) = crate::heap::rt_heap_owner(&self.heap, ptr) {
+                    self.gc_mark(al as *mut Var);
+                }
+            }
+            _ => {}
+        }
+    }
+    pub fn gc_write_barrier(&mut self, v: &Var) {
+        if self.gc_info.phase == GcPhase::Marking {
+            self.gc_mark_var(v);
+        }
+    }
+    pub fn gc_allocated(&mut self, al: *mut Allocation) {
+        if self.gc_info.phase == GcPhase::Marking {
+            unsafe {
+                (*al).reachable = 1;
+            }
+        }
     }
     pub fn gc_mark(&mut self, ptr: *mut Var) {
         unsafe {
@@ -1042,20 +2259,33 @@ impl RT {
             if al == std::ptr::null_mut() {
                 return;
             }
+            if let Err(e) = self.check_alloc(al) {
+                self.gc_info.fault.get_or_insert(e);
+                return;
+            }
             if (*al).reachable != 0 {
                 return;
             }
             (*al).reachable = 1;
-            for i in 1..(*al).num_objects as usize + 1 {
-                let f = ptr.add(i);
-                let v = &mut *f;
-                match v.get().tag {
-                    Ptr => self.gc_mark(v.get_ptr() as *mut Var),
-                    String => self.gc_mark_string(v.get_string() as *mut BStr),
-                    _ => {}
+            self.gc_info.mark_stack.push(al);
+        }
+    }
+    pub fn gc_drain(&mut self, budget: usize) -> bool {
+        let mut done = 0;
+        while done < budget {
+            let Some(al) = self.gc_info.mark_stack.pop() else {
+                return true;
+            };
+            unsafe {
+                let ptr = al as *mut Var;
+                for i in 1..(*al).num_objects as usize + 1 {
+                    let v = (*ptr.add(i)).clone();
+                    self.gc_mark_var(&v);
                 }
             }
+            done += 1;
         }
+        self.gc_info.mark_stack.is_empty()
     }
 
     pub fn gc_mark_string(&mut self, ptr: *mut BStr) {
@@ -1063,6 +2293,10 @@ impl RT {
             if ptr == std::ptr::null_mut() {
                 return;
             }
+            if let Err(e) = self.check_str(ptr) {
+                self.gc_info.fault.get_or_insert(e);
+                return;
+            }
             let al = (ptr as *mut Allocation).sub(1);
             if (*al).reachable != 0 {
                 return;
@@ -1072,35 +2306,26 @@ impl RT {
             (*al_ptr).reachable = 1;
         }
     }
-    pub fn gc_collect(&mut self) {
-        crate::heap::rt_heap_mark_all_unreachable(&mut self.heap);
-        for i in 0..self.var_stack_ptr {
-            let obj = self.var_stack[i].clone();
-            match obj.get().tag {
-                Ptr => self.gc_mark(obj.get_ptr() as *mut Var),
-                String => self.gc_mark_string(obj.get_string() as *mut BStr),
-                _ => {}
-            }
-        }
-        for i in 0..self.op_stack_ptr {
-            let obj = self.op_stack[i].clone();
-            match obj.get().tag {
-                Ptr => self.gc_mark(obj.get_ptr() as *mut Var),
-                String => self.gc_mark_string(obj.get_string() as *mut BStr),
-                _ => {}
-            }
+    pub fn gc_collect(&mut self) -> Result<(), RtError> {
+        if self.gc_info.deferred {
+            return Ok(());
         }
-        crate::heap::rt_heap_free_all_unreachable(&mut self.heap);
+        crate::heap::rt_heap_mark_all_unreachable(&mut self.heap);
+        self.gc_info.mark_stack.clear();
+        self.gc_info.phase = GcPhase::Marking;
+        self.gc_finish_cycle()
     }
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InstructionList {
     pub iv: Vec<u8>,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct IntermediateRt {
     pub strings: Vec<string::String>,
+    pub types: Vec<RtType>,
     pub symbol_table: HashMap<string::String, usize>,
+    pub arity: HashMap<string::String, usize>,
     pub data: InstructionList,
     pub ip: u64,
 }
@@ -1110,6 +2335,7 @@ impl InstructionList {
     }
     pub fn push_instr(&mut self, ins: Instr) {
         self.iv.push(unsafe { std::mem::transmute(ins) });
+        self.iv.resize(self.iv.len() + OPCODE_WIDTH - 1, 0);
     }
     pub fn push_bool(&mut self, v: bool) {
         let vs = if v { 1 } else { 0 };
@@ -1145,6 +2371,71 @@ impl InstructionList {
         }
     }
 }
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    UnsupportedFieldType { type_name: string::String, field: string::String },
+    UnresolvedSymbol { name: string::String },
+    Unsupported { function: string::String, what: string::String },
+}
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::UnsupportedFieldType { type_name, field } => {
+                write!(f, "field {} of struct {} has a type the fast vm cannot store", field, type_name)
+            }
+            CompileError::UnresolvedSymbol { name } => write!(f, "unresolved symbol {}", name),
+            CompileError::Unsupported { function, what } => {
+                write!(f, "{} in function {} is not supported by the fast vm", what, function)
+            }
+        }
+    }
+}
+impl std::error::Error for CompileError {}
+pub fn type_tag(t: &Type) -> Option<Tag> {
+    match t {
+        Type::Void => Some(Void),
+        Type::Integer => Some(Integer),
+        Type::Float => Some(Float),
+        Type::Bool => Some(Bool),
+        Type::String => Some(String),
+        Type::Ptr { to: _ } => Some(Ptr),
+        Type::Weak { to: _ } => Some(Weak),
+        Type::Pid => Some(Pid),
+        Type::Struct { .. } | Type::Function { .. } => None,
+    }
+}
+pub fn compile_types(rt: &mut IntermediateRt, prg: &Program) -> Result<(), CompileError> {
+    for i in &prg.types {
+        if rt.types.iter().any(|t| t.name == i.0.as_ref()) {
+            continue;
+        }
+        let (fields, finalizer) = match &i.1 {
+            Type::Struct {
+                fields, finalizer, ..
+            } => (
+                fields
+                    .iter()
+                    .map(|f| {
+                        type_tag(&f.1.as_type(&prg.types)).ok_or_else(|| {
+                            CompileError::UnsupportedFieldType {
+                                type_name: i.0.to_string(),
+                                field: f.0.to_string(),
+                            }
+                        })
+                    })
+                    .collect::<Result<_, _>>()?,
+                finalizer.as_ref().map(|f| f.to_string()),
+            ),
+            _ => (Vec::new(), None),
+        };
+        rt.types.push(RtType {
+            name: i.0.to_string(),
+            fields,
+            finalizer,
+        });
+    }
+    Ok(())
+}
 pub fn compile_var(rt: &mut IntermediateRt, v: &crate::mach::Var, prg: &Program) {
     match v {
         crate::mach::Var::Stack {
@@ -1172,11 +2463,15 @@ pub fn compile_var(rt: &mut IntermediateRt, v: &crate::mach::Var, prg: &Program)
                 rt.data.push_instr(LoadStr);
                 rt.data.push_u64(*index as u64);
             }
-            crate::mach::Type::Ptr { to: _ } => {
+            crate::mach::Type::Ptr { to: _ } | crate::mach::Type::Weak { to: _ } => {
                 rt.data.push_instr(LoadPtr);
                 rt.data.push_u64(*index as u64);
             }
-            crate::mach::Type::Struct { name: _, fields: _ } => {
+            crate::mach::Type::Pid => {
+                rt.data.push_instr(LoadPid);
+                rt.data.push_u64(*index as u64);
+            }
+            crate::mach::Type::Struct { .. } => {
                 todo!()
             }
             crate::mach::Type::Function {
@@ -1221,26 +2516,66 @@ pub fn compile_var(rt: &mut IntermediateRt, v: &crate::mach::Var, prg: &Program)
         crate::mach::Var::FunctionLiteral { name: _, idx: _ } => {
             todo!()
         }
+        crate::mach::Var::MakeWeak { of } => {
+            compile_var(rt, of, prg);
+            rt.data.push_instr(MakeWeak);
+        }
+        crate::mach::Var::Upgrade { of } => {
+            compile_var(rt, of, prg);
+            rt.data.push_instr(Upgrade);
+        }
+        crate::mach::Var::Alive { of } => {
+            compile_var(rt, of, prg);
+            rt.data.push_instr(Alive);
+        }
+        crate::mach::Var::SelfPid => {
+            rt.data.push_instr(SelfPid);
+        }
+        crate::mach::Var::Now => {
+            rt.data.push_instr(Now);
+        }
         crate::mach::Var::OperatorNew { new_type } => {
-            let t = new_type.as_type(&prg.types);
-            match t {
-                Type::Struct { name: _, fields } => {
-                    let info = AllocInfo {
-                        field_count: fields.len() as u32,
-                        type_info: new_type.index as u32,
-                    };
-                    rt.data.push_instr(New);
-                    unsafe {
-                        rt.data.push_u64(std::mem::transmute(info));
-                    }
-                }
-                _ => {
-                    todo!()
-                }
+            let info = alloc_info(rt, new_type, prg);
+            rt.data.push_instr(New);
+            unsafe {
+                rt.data.push_u64(std::mem::transmute(info));
             }
         }
     }
 }
+pub fn store_instr(t: &Type) -> Instr {
+    match t {
+        Type::Void => StoreVoid,
+        Type::Integer => StoreInt,
+        Type::Float => StoreFloat,
+        Type::Bool => StoreBool,
+        Type::String => StoreStr,
+        Type::Ptr { to: _ } => StorePtr,
+        Type::Weak { to: _ } => StoreWeak,
+        Type::Pid => StorePid,
+        _ => {
+            todo!()
+        }
+    }
+}
+pub fn alloc_info(rt: &IntermediateRt, new_type: &ShallowType, prg: &Program) -> AllocInfo {
+    match new_type.as_type(&prg.types) {
+        Type::Struct { fields, .. } => {
+            let type_info = rt
+                .types
+                .iter()
+                .position(|i| i.name == new_type.name.as_ref())
+                .unwrap();
+            AllocInfo {
+                field_count: fields.len() as u32,
+                type_info: type_info as u32,
+            }
+        }
+        _ => {
+            todo!()
+        }
+    }
+}
 pub fn compile_l_var(rt: &mut IntermediateRt, v: &crate::mach::Var, prg: &Program) {
     match v {
         crate::mach::Var::Stack {
@@ -1265,31 +2600,80 @@ pub fn compile_l_var(rt: &mut IntermediateRt, v: &crate::mach::Var, prg: &Progra
         }
     }
 }
-pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Backend {
+    Stack,
+    #[default]
+    Register,
+    /// Register code without the fused superinstructions, used to profile
+    /// and check the sequences they replace.
+    Unfused,
+}
+pub fn compile_mach_to_ir(progs: &[Program]) -> Result<IntermediateRt, CompileError> {
+    compile_mach_to_ir_with(progs, Backend::default())
+}
+pub fn compile_mach_to_ir_with(
+    progs: &[Program],
+    backend: Backend,
+) -> Result<IntermediateRt, CompileError> {
+    let (out, imports) = compile_module_ir_with(progs, backend)?;
+    if let Some((_, name)) = imports.first() {
+        return Err(CompileError::UnresolvedSymbol { name: name.clone() });
+    }
+    Ok(out)
+}
+pub type ModuleIr = (IntermediateRt, Vec<(usize, string::String)>);
+pub fn compile_module_ir(progs: &[Program]) -> Result<ModuleIr, CompileError> {
+    compile_module_ir_with(progs, Backend::default())
+}
+pub fn compile_module_ir_with(progs: &[Program], backend: Backend) -> Result<ModuleIr, CompileError> {
     let mut out = IntermediateRt {
         symbol_table: HashMap::new(),
+        arity: HashMap::new(),
         data: InstructionList::new(),
         strings: Vec::new(),
+        types: Vec::new(),
         ip: 0,
     };
+    for p in progs {
+        compile_types(&mut out, p)?;
+    }
     let mut fixup_table: HashMap<usize, string::String> = HashMap::new();
     let mut idx = 0;
     let mut start_ptr = 0;
     for p in progs {
-        for i in &p.functions {
+        let mut functions: Vec<_> = p.functions.iter().collect();
+        functions.sort_by(|a, b| a.0.cmp(b.0));
+        for i in functions {
+            if i.1.is_header {
+                continue;
+            }
             if i.1.display_name == "main" {
                 start_ptr = out.data.iv.len();
             }
             let mut labels = HashMap::new();
             for j in &i.1.labels {
-                labels.insert(*j.1 + idx + 1, j.0.clone());
+                labels
+                    .entry(*j.1 + idx + i.1.arguments.len())
+                    .or_insert_with(Vec::new)
+                    .push(j.0.clone());
             }
             if out.symbol_table.contains_key(i.0) {
                 todo!();
             }
             out.symbol_table.insert(i.0.clone(), out.data.iv.len());
+            out.arity.insert(i.0.clone(), i.1.arguments.len());
             //    println!("inserted:{:#?} at {}", i.0, out.data.iv.len());
+            let reg_args = backend != Backend::Stack && !i.1.arguments.is_empty();
+            if reg_args {
+                out.data.push_instr(RegArgs);
+                out.data.push_u64(i.1.arguments.len() as u64);
+            }
             for j in &*i.1.arguments {
+                idx += 1;
+                if reg_args {
+                    continue;
+                }
                 match j.1.as_type(&p.types) {
                     crate::mach::Type::Bool => {
                         out.data.push_instr(DefLocalBool);
@@ -1303,6 +2687,12 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                     crate::mach::Type::Ptr { to: _ } => {
                         out.data.push_instr(DefLocalPtr);
                     }
+                    crate::mach::Type::Weak { to: _ } => {
+                        out.data.push_instr(DefLocalWeak);
+                    }
+                    crate::mach::Type::Pid => {
+                        out.data.push_instr(DefLocalPid);
+                    }
                     crate::mach::Type::String => {
                         out.data.push_instr(DefLocalStr);
                     }
@@ -1313,9 +2703,11 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                         todo!()
                     }
                 }
-                idx += 1;
             }
             let mut rvs = (&*i.1.arguments).to_vec();
+            if reg_args {
+                rvs.clear();
+            }
             rvs.reverse();
             let mut ix = rvs.len();
             for j in &rvs {
@@ -1351,17 +2743,48 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                         out.data.push_u64(ix as u64);
                         out.data.push_instr(StorePtr);
                     }
+                    crate::mach::Type::Weak { to: _ } => {
+                        out.data.push_instr(LoadVarAddr);
+                        out.data.push_u64(ix as u64);
+                        out.data.push_instr(StoreWeak);
+                    }
+                    crate::mach::Type::Pid => {
+                        out.data.push_instr(LoadVarAddr);
+                        out.data.push_u64(ix as u64);
+                        out.data.push_instr(StorePid);
+                    }
                     _ => {
                         todo!()
                     }
                 }
             }
-            for j in &i.1.cmds {
+            let mut fused = 0;
+            for (k, j) in i.1.cmds.iter().enumerate() {
                 let rt = &mut out;
-                if labels.contains_key(&idx) {
-                    //          println!("inserted:{:#?} at {}", labels[&idx], rt.data.iv.len());
-                    rt.symbol_table
-                        .insert(labels[&idx].clone(), rt.data.iv.len());
+                for l in labels.get(&idx).into_iter().flatten() {
+                    rt.symbol_table.insert(l.clone(), rt.data.iv.len());
+                }
+                if fused > 0 {
+                    fused -= 1;
+                    idx += 1;
+                    continue;
+                }
+                if backend == Backend::Register {
+                    let at = idx;
+                    let n = crate::regs::compile_fused(rt, &i.1.cmds[k..], p, &mut fixup_table, |n| {
+                        labels.contains_key(&(at + n))
+                    });
+                    if n > 0 {
+                        fused = n - 1;
+                        idx += 1;
+                        continue;
+                    }
+                }
+                if backend != Backend::Stack
+                    && crate::regs::compile_reg_cmd(rt, j, p, &mut fixup_table)
+                {
+                    idx += 1;
+                    continue;
                 }
                 match j {
                     crate::mach::Cmd::Binop { l, r, out, op } => {
@@ -1483,6 +2906,24 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                                     todo!()
                                 }
                             },
+                            Type::Pid => match op {
+                                Binop::Equal => {
+                                    rt.data.push_instr(PidEq);
+                                    compile_l_var(rt, out, p);
+                                    rt.data.push_instr(StoreBool);
+                                }
+                                Binop::NotEqual => {
+                                    rt.data.push_instr(PidNeq);
+                                    compile_l_var(rt, out, p);
+                                    rt.data.push_instr(StoreBool);
+                                }
+                                _ => {
+                                    return Err(CompileError::Unsupported {
+                                        function: i.1.display_name.to_string(),
+                                        what: format!("{:?} on pids", op),
+                                    });
+                                }
+                            },
                             Type::String => match op {
                                 Binop::Add => {
                                     rt.data.push_instr(StrAdd);
@@ -1524,6 +2965,12 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                             Type::Ptr { to: _ } => {
                                 out.data.push_instr(StorePtr);
                             }
+                            Type::Weak { to: _ } => {
+                                out.data.push_instr(StoreWeak);
+                            }
+                            Type::Pid => {
+                                out.data.push_instr(StorePid);
+                            }
                             Type::Void => {
                                 out.data.push_instr(StoreVoid);
                             }
@@ -1569,6 +3016,12 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                                 Type::Ptr { to: _ } => {
                                     rt.data.push_instr(DefLocalPtr);
                                 }
+                                Type::Weak { to: _ } => {
+                                    rt.data.push_instr(DefLocalWeak);
+                                }
+                                Type::Pid => {
+                                    rt.data.push_instr(DefLocalPid);
+                                }
                                 Type::Void => {
                                     rt.data.push_instr(DefLocalVoid);
                                 }
@@ -1590,65 +3043,156 @@ pub fn compile_mach_to_ir(progs: &[Program]) -> IntermediateRt {
                         let c = match to_call {
                             crate::mach::Var::FunctionLiteral { name, idx: _ } => name.to_string(),
                             _ => {
-                                todo!()
+                                return Err(CompileError::Unsupported {
+                                    function: i.1.display_name.to_string(),
+                                    what: "spawn of a function value".to_string(),
+                                });
                             }
                         };
                         fixup_table.insert(rt.data.iv.len(), c);
                         rt.data.push_u64(42069);
-                        compile_l_var(rt, returned, p);
-                        match returned.get_type(&p.types) {
-                            Type::Void => {
-                                rt.data.push_instr(StoreVoid);
-                            }
-                            Type::Integer => {
-                                rt.data.push_instr(StoreInt);
-                            }
-                            Type::Float => {
-                                rt.data.push_instr(StoreFloat);
-                            }
-                            Type::Bool => {
-                                rt.data.push_instr(StoreBool);
-                            }
-                            Type::String => {
-                                rt.data.push_instr(StoreStr);
-                            }
-                            Type::Ptr { to: _ } => {
-                                rt.data.push_instr(StorePtr);
-                            }
-                            _ => {
-                                todo!()
-                            }
+                        if *returned == crate::mach::Var::Unit {
+                            rt.data.push_instr(Pop);
+                        } else if backend != Backend::Stack
+                            && let crate::mach::Var::Stack { index, .. } = returned
+                        {
+                            rt.data.push_instr(RegPop);
+                            rt.data.push_u64(*index as u64);
+                        } else {
+                            compile_l_var(rt, returned, p);
+                            rt.data.push_instr(store_instr(&returned.get_type(&p.types)));
                         }
                     }
                     crate::mach::Cmd::Return { to_return } => {
                         compile_var(rt, to_return, p);
                         rt.data.push_instr(Ret);
                     }
+                    crate::mach::Cmd::Spawn {
+                        to_call,
+                        returned,
+                        args,
+                    } => {
+                        for k in args.iter() {
+                            compile_var(rt, k, p);
+                        }
+                        rt.data.push_instr(Spawn);
+                        let c = match to_call {
+                            crate::mach::Var::FunctionLiteral { name, idx: _ } => name.to_string(),
+                            _ => {
+                                todo!()
+                            }
+                        };
+                        fixup_table.insert(rt.data.iv.len(), c);
+                        rt.data.push_u64(42069);
+                        rt.data.push_u64(args.len() as u64);
+                        compile_l_var(rt, returned, p);
+                        rt.data.push_instr(StorePid);
+                    }
+                    crate::mach::Cmd::SpawnNamed { name, returned } => {
+                        compile_var(rt, name, p);
+                        rt.data.push_instr(SpawnNamed);
+                        compile_l_var(rt, returned, p);
+                        rt.data.push_instr(StorePid);
+                    }
+                    crate::mach::Cmd::Link { to } => {
+                        compile_var(rt, to, p);
+                        rt.data.push_instr(Link);
+                    }
+                    crate::mach::Cmd::Monitor { to } => {
+                        compile_var(rt, to, p);
+                        rt.data.push_instr(Monitor);
+                    }
+                    crate::mach::Cmd::TrapExit => {
+                        rt.data.push_instr(TrapExit);
+                    }
+                    crate::mach::Cmd::Exit { to, reason } => {
+                        compile_var(rt, to, p);
+                        compile_var(rt, reason, p);
+                        rt.data.push_instr(ExitSignal);
+                    }
+                    crate::mach::Cmd::Sleep { ms } => {
+                        compile_var(rt, ms, p);
+                        rt.data.push_instr(Sleep);
+                    }
+                    crate::mach::Cmd::Fail { reason } => {
+                        compile_var(rt, reason, p);
+                        rt.data.push_instr(Fail);
+                    }
+                    crate::mach::Cmd::Send { to, msg } => {
+                        compile_var(rt, to, p);
+                        compile_var(rt, msg, p);
+                        rt.data.push_instr(Send);
+                    }
+                    crate::mach::Cmd::Receive {
+                        returned,
+                        msg_type,
+                        timeout,
+                        to,
+                        to_idx: _,
+                    } => {
+                        let type_idx = rt
+                            .types
+                            .iter()
+                            .position(|i| i.name == msg_type.name.as_ref())
+                            .unwrap();
+                        if let Some(t) = timeout {
+                            compile_var(rt, t, p);
+                            rt.data.push_instr(ReceiveAfter);
+                            rt.data.push_u64(type_idx as u64);
+                            fixup_table.insert(rt.data.iv.len(), to.to_string());
+                            rt.data.push_u64(42069);
+                        } else {
+                            rt.data.push_instr(Receive);
+                            rt.data.push_u64(type_idx as u64);
+                        }
+                        compile_l_var(rt, returned, p);
+                        rt.data.push_instr(StorePtr);
+                    }
                     crate::mach::Cmd::CallNative { to_call, returned, args }=>{
-                            todo!();
+                        for k in args.iter() {
+                            compile_var(rt, k, p);
+                        }
+                        let name_idx = rt.strings.len();
+                        rt.strings.push(to_call.clone());
+                        rt.data.push_instr(CallNative);
+                        rt.data.push_u64(name_idx as u64);
+                        rt.data.push_u64(args.len() as u64);
+                        if *returned == crate::mach::Var::Unit {
+                            rt.data.push_instr(Pop);
+                        } else {
+                            compile_l_var(rt, returned, p);
+                            rt.data.push_instr(store_instr(&returned.get_type(&p.types)));
+                        }
                     }
                 }
                 idx += 1;
             }
         }
     }
+    let mut imports = Vec::new();
     for i in &fixup_table {
-        let ix = u64::to_le_bytes(out.symbol_table[i.1] as u64);
+        let Some(loc) = out.symbol_table.get(i.1) else {
+            imports.push((*i.0, i.1.clone()));
+            continue;
+        };
+        let ix = u64::to_le_bytes(*loc as u64);
         for j in 0..8 {
             out.data.iv[i.0 + j] = unsafe { std::mem::transmute(ix[j]) };
         }
     }
+    imports.sort();
     out.ip = start_ptr as u64;
-    out
+    Ok((out, imports))
 }
 pub fn rt_from_intermediate_rt(prg: IntermediateRt) -> RT {
     let out = prg;
+    let scheduler = Scheduler::new();
     let tmp = RT {
-        op_stack: OwnedSlice::new([const { Var::new() }; 4096 * 16]),
+        op_stack: OwnedSlice::new([const { Var::new() }; INITIAL_STACK_SIZE]),
         op_stack_ptr: 0,
         instructions: OwnedSlice::from_vec(out.data.iv),
         ip: out.ip as usize,
-        var_stack: OwnedSlice::new([const { Var::new() }; 4096 * 16]),
+        var_stack: OwnedSlice::new([const { Var::new() }; INITIAL_STACK_SIZE]),
         var_stack_ptr: 0,
         var_base_ptr: 0,
         ret_info: OwnedSlice::new(
@@ -1657,15 +3201,506 @@ pub fn rt_from_intermediate_rt(prg: IntermediateRt) -> RT {
                     ip: 0,
                     var_sp: 0,
                     var_bp: 0,
+                    discard: false,
                 }
-            }; 4096 * 16],
+            }; INITIAL_STACK_SIZE],
         ),
         ret_info_ptr: 0,
         halted: false,
         symbol_table: out.symbol_table,
+        arity: out.arity,
         strings: OwnedSlice::from_vec(out.strings),
-        heap: RtHeap::new(),
+        types: OwnedSlice::from_vec(out.types),
+        heap: RtHeap::pooled(scheduler.shared.heap_bytes.clone()),
         gc_info: GcInfo::new(),
+        natives: NativeInterface::new(),
+        limits: Limits::default(),
+        result: None,
+        scheduler,
+        heap_locking: false,
+        held_lock: std::ptr::null_mut(),
+        code: CodeTable::default(),
+        profile: None,
+        jit: None,
     };
     tmp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rt(src: &str) -> RT {
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        let mut rt = rt_from_intermediate_rt(compile_mach_to_ir(&[p]).unwrap());
+        rt.enable_heap_verification();
+        rt
+    }
+
+    #[test]
+    fn process_misuse_is_an_error() {
+        for spawn in ["spawn", "spawn main", "spawn main("] {
+            let src = format!("fn int main:\n\tc:pid = {}\n\treturn 0\nend\n", spawn);
+            assert!(crate::parser::parse_to_program(src, "test.beam".into()).is_err(), "{}", spawn);
+        }
+        let src = "fn int main:
+	a:pid = self
+	c:pid = self
+	b:bool = a < c
+	if b goto out
+	label out
+	return 0
+end
+";
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        assert!(matches!(compile_mach_to_ir(&[p]), Err(CompileError::Unsupported { .. })));
+    }
+
+    const WEAK: &str = "struct Node
+	next Node
+	value int
+end
+fn int main:
+	n:Node = new Node
+	w:weak Node = weak n
+	n = new Node
+	b:bool = true
+	label spin
+	b = alive w
+	if b goto spin
+	u:Node = upgrade w
+	x:int = u.value
+	return x
+end
+";
+
+    #[test]
+    fn weak_refs_clear_when_the_target_is_swept() {
+        let mut rt = rt(WEAK);
+        assert!(matches!(rt.run(1000), RunResult::Paused), "target died before a collection");
+        rt.gc_collect().unwrap();
+        let RunResult::Error(e) = rt.run(1000) else {
+            panic!("upgrade of a swept target succeeded");
+        };
+        assert!(matches!(e.downcast_ref::<RtError>(), Some(RtError::NullPointer { .. })), "{}", e);
+
+        let p = crate::parser::parse_to_program(WEAK.to_string(), "test.beam".into()).unwrap();
+        let mut m = crate::parser::link(&[p]);
+        // the tree walker collects after every allocation, so it may have
+        // swept the target already
+        let r = match m.run(1000) {
+            RunResult::Paused => {
+                m.gc_collect();
+                m.run(1000)
+            }
+            r => r,
+        };
+        let RunResult::Error(e) = r else {
+            panic!("upgrade of a swept target succeeded");
+        };
+        assert!(e.to_string().contains("null pointer"), "{}", e);
+    }
+
+    #[test]
+    fn weak_of_a_non_struct_is_a_type_error() {
+        for body in ["\ti:int = 0\n\tw:weak Node = weak i\n", "\tn:Node = new Node\n\tu:Node = upgrade n\n"] {
+            let src = format!("struct Node\n\tvalue int\nend\nfn int main:\n{}\treturn 0\nend\n", body);
+            assert!(crate::parser::parse_to_program(src, "test.beam".into()).is_err(), "{}", body);
+        }
+    }
+
+    // drop_node counts its calls in the shared Log and resurrects the node
+    // through log.saved. main waits for the first node to be finalized,
+    // checks it is still intact, drops it again and then spins so later
+    // collections get the chance to finalize it a second time.
+    const FINALIZE: &str = "struct Log
+	count int
+	saved Node
+end
+struct Node
+	log Log
+	value int
+	finalize drop_node
+end
+fn void drop_node n Node:
+	l:Log = n.log
+	c:int = l.count
+	c = c + 1
+	l.count = c
+	l.saved = n
+	return unit
+end
+fn int main:
+	log:Log = new Log
+	keep:Node = new Node
+	keep.log = log
+	n:Node = new Node
+	n.log = log
+	n.value = 7
+	n = keep
+	c:int = 0
+	b:bool = true
+	label wait
+	c = log.count
+	b = c < 1
+	if b goto wait
+	r:Node = log.saved
+	v:int = r.value
+	log.saved = keep
+	r = keep
+	i:int = 0
+	label spin
+	i = i + 1
+	b = i < 5000
+	if b goto spin
+	c = log.count
+	c = c * 100
+	c = c + v
+	return c
+end
+";
+
+    #[test]
+    fn finalizers_run_once_and_may_resurrect() {
+        let want = crate::mach::Value::Integer { v: 107 };
+        let mut rt = rt(FINALIZE);
+        let v = loop {
+            match rt.run(200) {
+                RunResult::Paused => rt.gc_collect().unwrap(),
+                RunResult::Finished(v) => break v,
+                RunResult::Error(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(v, want);
+
+        let p = crate::parser::parse_to_program(FINALIZE.to_string(), "test.beam".into()).unwrap();
+        let mut m = crate::parser::link(&[p]);
+        let v = loop {
+            match m.run(200) {
+                RunResult::Paused => m.gc_collect(),
+                RunResult::Finished(v) => break v,
+                RunResult::Error(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(v, want);
+    }
+
+    fn recurse(depth: usize, limits: Limits) -> (RT, RunResult) {
+        let mut rt = rt(&format!(
+            "fn int down n int:
+	b:bool = n < 1
+	if b goto base
+	m:int = n - 1
+	m = down(m)
+	m = m + 1
+	return m
+	label base
+	return 0
+end
+fn int main:
+	r:int = down({})
+	return r
+end
+",
+            depth
+        ));
+        rt.set_limits(limits);
+        let r = rt.run(1_000_000);
+        (rt, r)
+    }
+
+    #[test]
+    fn stacks_grow_until_the_limit() {
+        let limits = Limits {
+            call_depth: 1000,
+            ..Limits::default()
+        };
+        let (rt, r) = recurse(900, limits.clone());
+        let RunResult::Finished(v) = r else {
+            panic!("recursion below the limit failed: {:?}", r);
+        };
+        assert_eq!(v, crate::mach::Value::Integer { v: 900 });
+        assert!(rt.ret_info.len >= 900 && rt.var_stack.len >= 3 * 900);
+
+        let (rt, r) = recurse(1000, limits);
+        let RunResult::Error(e) = r else {
+            panic!("recursion past the limit finished");
+        };
+        assert!(matches!(e.downcast_ref::<RtError>(), Some(RtError::CallStackOverflow { limit: 1000 })), "{}", e);
+        assert_eq!(rt.ret_info_ptr, 1000);
+
+        let (rt, r) = recurse(
+            900,
+            Limits {
+                var_stack: 1000,
+                ..Limits::default()
+            },
+        );
+        let RunResult::Error(e) = r else {
+            panic!("locals past the limit did not fail");
+        };
+        assert!(matches!(e.downcast_ref::<RtError>(), Some(RtError::VarStackOverflow { limit: 1000 })), "{}", e);
+        assert!(rt.var_stack_ptr <= 1000);
+    }
+
+    #[test]
+    fn leak_report_ignores_rooted_objects() {
+        let mut rt = rt("struct Node
+	next Node
+	value int
+end
+fn int main:
+	head:Node = new Node
+	n:Node = new Node
+	i:int = 0
+	b:bool = false
+	lost:Node = new Node
+	lost = new Node
+	label top
+	b = i < 100
+	if b goto body
+	label spin
+	goto spin
+	label body
+	n = new Node
+	n.next = head
+	head = n
+	i = i + 1
+	goto top
+end
+");
+        assert!(matches!(rt.run(100_000), RunResult::Paused));
+        let report = rt.leak_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].count, 2);
+    }
+
+    #[test]
+    fn field_address_keeps_its_object_alive() {
+        let mut rt = rt("struct Node
+	next Node
+	value int
+end
+fn int main:
+	return 0
+end
+");
+        let node = rt.types.as_slice().iter().position(|t| t.name == "Node").unwrap();
+        let obj = rt.new_object(AllocInfo {
+            field_count: 2,
+            type_info: node as u32,
+        });
+        let field = unsafe { obj.get_ptr().add(2) } as *mut Var;
+        rt.op_push(Var::l_value(field));
+        rt.gc_collect().unwrap();
+        assert_eq!(rt.heap.allocations(), &[obj.get_ptr() as *mut Allocation]);
+        rt.op_pop();
+        rt.gc_collect().unwrap();
+        assert!(rt.heap.allocations().is_empty());
+    }
+
+    #[test]
+    fn unknown_native_is_an_error() {
+        let mut rt = rt("extern fn int nt_missing x int: end
+fn int main:
+	out:int = nt_missing(1)
+	return out
+end
+");
+        let RunResult::Error(e) = rt.run(1000) else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            e.downcast_ref::<RtError>(),
+            Some(&RtError::UnknownNative {
+                name: "nt_missing".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn faults_only_fail_the_offending_process() {
+        let mut rt = rt("struct Node
+	next Node
+	value int
+end
+fn void divide d int:
+	x:int = 10 / d
+	return unit
+end
+fn void deref n Node:
+	x:int = n.value
+	return unit
+end
+fn int main:
+	n:Node = new Node
+	n = n.next
+	a:pid = spawn divide(0)
+	b:pid = spawn deref(n)
+	sleep 1
+	return 7
+end
+");
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("main should survive its children failing");
+        };
+        assert_eq!(v, crate::mach::Value::Integer { v: 7 });
+    }
+
+    #[test]
+    fn division_faults_are_reported() {
+        for (d, want) in [(0, "division by zero"), (1, "integer overflow")] {
+            let mut rt = rt(&format!(
+                "fn int main:
+	m:int = 0 - 9223372036854775807
+	m = m - 1
+	d:int = 0 - {d}
+	x:int = m / d
+	return x
+end
+"
+            ));
+            let RunResult::Error(e) = rt.run(1000) else {
+                panic!("expected an error");
+            };
+            assert!(e.to_string().contains(want), "{e}");
+        }
+    }
+
+    #[test]
+    fn register_calls_match_stack_calls() {
+        let src = "struct Node
+	next Node
+	value int
+end
+fn int sum3 a int b Node c int:
+	t:int = b.value
+	t = t + a
+	t = t - c
+	return t
+end
+fn int main:
+	n:Node = new Node
+	n.value = 2
+	i:int = 0
+	s:int = 0
+	b:bool = false
+	label top
+	b = i < 50
+	if b goto body
+	return s
+	label body
+	s = sum3(s n i)
+	i = i + 1
+	goto top
+end
+";
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        for backend in [Backend::Stack, Backend::Register, Backend::Unfused] {
+            let ir = compile_mach_to_ir_with(std::slice::from_ref(&p), backend).unwrap();
+            let mut rt = rt_from_intermediate_rt(ir);
+            rt.enable_heap_verification();
+            let r = rt.run(100_000);
+            let RunResult::Finished(v) = r else {
+                panic!("{backend:?} did not finish: {r:?}");
+            };
+            assert_eq!(v, crate::mach::Value::Integer { v: 100 - 1225 }, "{backend:?}");
+        }
+    }
+
+    // Every fused shape: increment-compare-branch against an immediate and
+    // a register bound, and compare-and-branch for each operator against
+    // both a register and an immediate, some taken and some not. The last
+    // compare result is read again after its branch.
+    const FUSED: &str = "fn int main:
+	i:int = 0
+	n:int = 7
+	s:int = 0
+	b:bool = false
+	label imm
+	s = s + i
+	i = i + 1
+	b = i < 10
+	if b goto imm
+	i = 0
+	label reg
+	s = s + i
+	i = i + 1
+	b = i < n
+	if b goto reg
+	b = i == 7
+	if b goto eq
+	s = s + 1000
+	label eq
+	b = i != n
+	if b goto ne
+	s = s + 2000
+	label ne
+	b = i < n
+	if b goto lt
+	s = s + 4000
+	label lt
+	b = i > 6
+	if b goto gt
+	s = s + 8000
+	label gt
+	b = s == n
+	if b goto eqr
+	s = s + 16000
+	label eqr
+	b = s != 0
+	if b goto nei
+	s = s + 32000
+	label nei
+	b = n > i
+	if b goto gtr
+	s = s + 64000
+	label gtr
+	b = s < 10
+	if b goto lti
+	s = s + 128000
+	label lti
+	if b goto done
+	s = s * 3
+	label done
+	return s
+end
+";
+
+    #[test]
+    fn fused_branches_match_unfused_code() {
+        let p = crate::parser::parse_to_program(FUSED.to_string(), "test.beam".into()).unwrap();
+        let run = |backend| {
+            let ir = compile_mach_to_ir_with(std::slice::from_ref(&p), backend).unwrap();
+            let mut rt = rt_from_intermediate_rt(ir);
+            rt.start_profile();
+            let r = rt.run(100_000);
+            let RunResult::Finished(v) = r else {
+                panic!("{backend:?} did not finish: {r:?}");
+            };
+            (v, rt.take_profile().unwrap())
+        };
+        let (want, _) = run(Backend::Stack);
+        assert_eq!(want, crate::mach::Value::Integer { v: 3 * (45 + 21 + 2000 + 4000 + 16000 + 64000 + 128000) });
+        let (fused, fp) = run(Backend::Register);
+        let (unfused, up) = run(Backend::Unfused);
+        assert_eq!(fused, want);
+        assert_eq!(unfused, want);
+        assert!(fp.count(RegIntAddLessImmJmp) > 0 && fp.count(RegIntAddLessJmp) > 0);
+        assert!(fp.total() < up.total());
+        for i in [
+            RegIntEqJmp,
+            RegIntEqImmJmp,
+            RegIntNEqJmp,
+            RegIntNEqImmJmp,
+            RegIntLessJmp,
+            RegIntLessImmJmp,
+            RegIntGreaterJmp,
+            RegIntGreaterImmJmp,
+        ] {
+            assert_eq!(fp.count(i), 1, "{i:?}");
+            assert_eq!(up.count(i), 0, "{i:?}");
+        }
+        assert!(up.pair_count(RegIntLessImm, RegJmpCond) > 0, "{up}");
+    }
+}
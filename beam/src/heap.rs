@@ -1,27 +1,147 @@
 use libc::c_void;
-use std::{sync::atomic::AtomicU16};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+    },
+};
+pub const ALLOC_LIVE: u16 = 0xA11C;
+pub const ALLOC_FREED: u16 = 0xDEAD;
+pub const POISON_BYTE: u8 = 0xDD;
+pub const QUARANTINE_BYTES: usize = 16 << 20;
+pub const FLAG_FINALIZED: u16 = 1;
 #[repr(C)]
 pub struct Allocation {
     pub in_use: AtomicU16,
     pub reachable: u16,
     pub type_idx: u16,
     pub num_objects: u16,
+    pub magic: u16,
+    pub flags: u16,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeapError {
+    Null,
+    Unknown { ptr: usize },
+    Dangling { ptr: usize, type_idx: u16 },
+    DoubleFree { ptr: usize, type_idx: u16 },
+    Corrupt { ptr: usize, magic: u16 },
+}
+impl std::fmt::Display for HeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapError::Null => write!(f, "null heap pointer"),
+            HeapError::Unknown { ptr } => write!(f, "pointer {:#x} is not a heap allocation", ptr),
+            HeapError::Dangling { ptr, type_idx } => {
+                write!(f, "dangling pointer {:#x} to freed object of type {}", ptr, type_idx)
+            }
+            HeapError::DoubleFree { ptr, type_idx } => {
+                write!(f, "double free of {:#x} (type {})", ptr, type_idx)
+            }
+            HeapError::Corrupt { ptr, magic } => {
+                write!(f, "corrupt allocation header at {:#x} (magic {:#x})", ptr, magic)
+            }
+        }
+    }
+}
+impl std::error::Error for HeapError {}
+
+#[derive(Clone)]
+pub struct HeapVerifier {
+    pub live: HashSet<*mut Allocation>,
+    pub quarantine: HashSet<*mut Allocation>,
+    pub quarantine_order: VecDeque<*mut Allocation>,
+    pub quarantine_bytes: usize,
+    pub quarantine_limit: usize,
+}
+impl Default for HeapVerifier {
+    fn default() -> Self {
+        Self {
+            live: HashSet::new(),
+            quarantine: HashSet::new(),
+            quarantine_order: VecDeque::new(),
+            quarantine_bytes: 0,
+            quarantine_limit: QUARANTINE_BYTES,
+        }
+    }
+}
+impl HeapVerifier {
+    fn quarantine(&mut self, ptr: *mut Allocation) {
+        unsafe {
+            self.quarantine_bytes += (*ptr).size as usize + size_of::<Allocation>();
+        }
+        self.quarantine.insert(ptr);
+        self.quarantine_order.push_back(ptr);
+        while self.quarantine_bytes > self.quarantine_limit {
+            let Some(old) = self.quarantine_order.pop_front() else {
+                break;
+            };
+            self.quarantine.remove(&old);
+            unsafe {
+                self.quarantine_bytes -= (*old).size as usize + size_of::<Allocation>();
+                libc::free(old as *mut c_void);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LeakEntry {
+    pub type_idx: u16,
+    pub count: usize,
+    pub bytes: usize,
 }
 
 #[repr(C)]
 #[derive(Clone)]
 pub struct RtHeap {
     allocations: Vec<*mut Allocation>,
+    pub bytes: usize,
+    pub verifier: Option<HeapVerifier>,
+    /// Bytes held by every heap sharing this pool, so that a quota can cover
+    /// all of a VM's process and message heaps together.
+    pub pool: Option<Arc<AtomicUsize>>,
 }
 impl RtHeap {
     pub fn new() -> Self {
         Self {
             allocations: Vec::new(),
+            bytes: 0,
+            verifier: None,
+            pool: None,
+        }
+    }
+    pub fn pooled(pool: Arc<AtomicUsize>) -> Self {
+        Self {
+            pool: Some(pool),
+            ..Self::new()
+        }
+    }
+    pub fn allocations(&self) -> &[*mut Allocation] {
+        &self.allocations
+    }
+    /// The pool's total, or this heap's own bytes if it has no pool.
+    pub fn pool_bytes(&self) -> usize {
+        self.pool.as_ref().map_or(self.bytes, |p| p.load(Ordering::Relaxed))
+    }
+    fn add_bytes(&mut self, n: usize) {
+        self.bytes += n;
+        if let Some(p) = &self.pool {
+            p.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+    fn sub_bytes(&mut self, n: usize) {
+        self.bytes -= n;
+        if let Some(p) = &self.pool {
+            p.fetch_sub(n, Ordering::Relaxed);
         }
     }
 }
 #[unsafe(no_mangle)]
-pub fn rt_heap_allocate(
+pub extern "C" fn rt_heap_allocate(
     heap: &mut RtHeap,
     size: usize,
     num_objects: u16,
@@ -29,31 +149,289 @@ pub fn rt_heap_allocate(
 ) -> *mut c_void {
     unsafe {
         let out = libc::malloc(size + size_of::<Allocation>()) as *mut Allocation;
+        (*out).in_use = AtomicU16::new(0);
         (*out).reachable = 0;
         (*out).num_objects = num_objects;
         (*out).type_idx = type_ptr;
+        (*out).magic = ALLOC_LIVE;
+        (*out).flags = 0;
+        (*out).size = size as u32;
         heap.allocations.push(out);
+        heap.add_bytes(size + size_of::<Allocation>());
+        if let Some(v) = &mut heap.verifier {
+            v.live.insert(out);
+        }
         return out as *mut c_void;
     }
 }
+/// Finds the block whose payload contains `ptr`, for interior pointers such as
+/// the field addresses pushed by `LoadMemberAddr`. This is a linear scan, so it
+/// is only meant for the handful of such pointers live at a collection.
+pub fn rt_heap_owner(heap: &RtHeap, ptr: *const u8) -> Option<*mut Allocation> {
+    heap.allocations.iter().copied().find(|al| unsafe {
+        let start = (*al as *const u8).add(size_of::<Allocation>());
+        start <= ptr && ptr < start.add((**al).size as usize)
+    })
+}
+#[unsafe(no_mangle)]
+pub extern "C" fn rt_heap_enable_verification(heap: &mut RtHeap) {
+    if heap.verifier.is_some() {
+        return;
+    }
+    let mut v = HeapVerifier::default();
+    for i in &heap.allocations {
+        v.live.insert(*i);
+    }
+    heap.verifier = Some(v);
+}
+pub(crate) fn rt_heap_check(heap: &RtHeap, ptr: *const Allocation) -> Result<(), HeapError> {
+    let Some(v) = &heap.verifier else {
+        return Ok(());
+    };
+    if ptr.is_null() {
+        return Err(HeapError::Null);
+    }
+    let p = ptr as *mut Allocation;
+    unsafe {
+        if v.quarantine.contains(&p) {
+            return Err(HeapError::Dangling {
+                ptr: p as usize,
+                type_idx: (*p).type_idx,
+            });
+        }
+        if !v.live.contains(&p) {
+            return Err(HeapError::Unknown { ptr: p as usize });
+        }
+        if (*p).magic != ALLOC_LIVE {
+            return Err(HeapError::Corrupt {
+                ptr: p as usize,
+                magic: (*p).magic,
+            });
+        }
+    }
+    Ok(())
+}
+pub(crate) fn rt_heap_free(heap: &mut RtHeap, ptr: *mut Allocation) -> Result<(), HeapError> {
+    unsafe {
+        let Some(v) = &mut heap.verifier else {
+            heap.sub_bytes((*ptr).size as usize + size_of::<Allocation>());
+            libc::free(ptr as *mut c_void);
+            return Ok(());
+        };
+        if v.quarantine.contains(&ptr) {
+            return Err(HeapError::DoubleFree {
+                ptr: ptr as usize,
+                type_idx: (*ptr).type_idx,
+            });
+        }
+        if !v.live.remove(&ptr) {
+            return Err(HeapError::Unknown { ptr: ptr as usize });
+        }
+        if (*ptr).magic != ALLOC_LIVE {
+            return Err(HeapError::Corrupt {
+                ptr: ptr as usize,
+                magic: (*ptr).magic,
+            });
+        }
+        let size = (*ptr).size as usize;
+        (*ptr).magic = ALLOC_FREED;
+        let payload = (ptr as *mut u8).add(size_of::<Allocation>());
+        std::ptr::write_bytes(payload, POISON_BYTE, size);
+        v.quarantine(ptr);
+        heap.sub_bytes(size + size_of::<Allocation>());
+    }
+    Ok(())
+}
 #[unsafe(no_mangle)]
-pub fn rt_heap_mark_all_unreachable(heap: &mut RtHeap) {
+pub extern "C" fn rt_heap_mark_all_unreachable(heap: &mut RtHeap) {
     unsafe {
         for i in &heap.allocations {
             (**i).reachable = 0;
         }
     }
 }
-#[unsafe(no_mangle)]
-pub fn rt_heap_free_all_unreachable(heap: &mut RtHeap) {
+pub fn rt_heap_free_all_unreachable(heap: &mut RtHeap) -> Result<(), HeapError> {
+    let mut failed = None;
     unsafe {
         let mut new_allocs = Vec::new();
-        for i in &heap.allocations {
-            if (**i).reachable == 0 {
-                libc::free(*i as *mut c_void);
+        let old = std::mem::take(&mut heap.allocations);
+        for i in old {
+            if (*i).reachable == 0 {
+                if let Err(e) = rt_heap_free(heap, i) {
+                    failed.get_or_insert(e);
+                }
             } else {
-                new_allocs.push(*i);
+                new_allocs.push(i);
+            }
+        }
+        heap.allocations = new_allocs;
+    }
+    failed.map_or(Ok(()), Err)
+}
+pub fn rt_heap_take(heap: &mut RtHeap) -> (Vec<*mut Allocation>, usize) {
+    let bytes = heap.bytes;
+    heap.sub_bytes(bytes);
+    (std::mem::take(&mut heap.allocations), bytes)
+}
+pub fn rt_heap_adopt(heap: &mut RtHeap, allocations: Vec<*mut Allocation>, bytes: usize) {
+    if let Some(v) = &mut heap.verifier {
+        v.live.extend(allocations.iter().copied());
+    }
+    heap.allocations.extend(allocations);
+    heap.add_bytes(bytes);
+}
+/// Moves every block of `from` into `heap`, leaving `from` empty.
+pub fn rt_heap_merge(heap: &mut RtHeap, from: &mut RtHeap) {
+    let (allocations, bytes) = rt_heap_take(from);
+    rt_heap_adopt(heap, allocations, bytes);
+}
+pub fn rt_heap_contains(heap: &RtHeap, ptr: *const Allocation) -> bool {
+    let p = ptr as *mut Allocation;
+    match &heap.verifier {
+        Some(v) => v.live.contains(&p),
+        None => heap.allocations.contains(&p),
+    }
+}
+pub fn rt_heap_leak_report(heap: &RtHeap) -> Vec<LeakEntry> {
+    let mut groups: BTreeMap<u16, LeakEntry> = BTreeMap::new();
+    unsafe {
+        for i in heap.allocations.iter().filter(|i| (***i).reachable == 0) {
+            let e = groups.entry((**i).type_idx).or_insert(LeakEntry {
+                type_idx: (**i).type_idx,
+                count: 0,
+                bytes: 0,
+            });
+            e.count += 1;
+            e.bytes += (**i).size as usize + size_of::<Allocation>();
+        }
+    }
+    groups.into_values().collect()
+}
+#[unsafe(no_mangle)]
+pub extern "C" fn rt_heap_destroy(heap: &mut RtHeap) {
+    unsafe {
+        for i in heap.allocations.drain(..) {
+            libc::free(i as *mut c_void);
+        }
+        heap.sub_bytes(heap.bytes);
+        if let Some(v) = &mut heap.verifier {
+            for i in v.quarantine_order.drain(..) {
+                libc::free(i as *mut c_void);
+            }
+            v.quarantine.clear();
+            v.quarantine_bytes = 0;
+            v.live.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verified_heap() -> RtHeap {
+        let mut heap = RtHeap::new();
+        rt_heap_enable_verification(&mut heap);
+        heap
+    }
+
+    #[test]
+    fn detects_double_free() {
+        let mut heap = verified_heap();
+        let a = rt_heap_allocate(&mut heap, 16, 0, 7) as *mut Allocation;
+        assert_eq!(rt_heap_free(&mut heap, a), Ok(()));
+        assert_eq!(
+            rt_heap_free(&mut heap, a),
+            Err(HeapError::DoubleFree {
+                ptr: a as usize,
+                type_idx: 7
+            })
+        );
+        heap.allocations.retain(|i| *i != a);
+        rt_heap_destroy(&mut heap);
+    }
+
+    #[test]
+    fn detects_dangling_pointer() {
+        let mut heap = verified_heap();
+        let a = rt_heap_allocate(&mut heap, 16, 0, 2) as *mut Allocation;
+        assert_eq!(rt_heap_check(&heap, a), Ok(()));
+        rt_heap_free(&mut heap, a).unwrap();
+        assert_eq!(
+            rt_heap_check(&heap, a),
+            Err(HeapError::Dangling {
+                ptr: a as usize,
+                type_idx: 2
+            })
+        );
+        let payload = unsafe { *(a as *const u8).add(size_of::<Allocation>()) };
+        assert_eq!(payload, POISON_BYTE);
+        heap.allocations.retain(|i| *i != a);
+        rt_heap_destroy(&mut heap);
+    }
+
+    #[test]
+    fn collector_reports_verifier_errors() {
+        let mut heap = verified_heap();
+        let freed = rt_heap_allocate(&mut heap, 8, 0, 1) as *mut Allocation;
+        let garbage = rt_heap_allocate(&mut heap, 8, 0, 1) as *mut Allocation;
+        let kept = rt_heap_allocate(&mut heap, 8, 0, 1) as *mut Allocation;
+        rt_heap_free(&mut heap, freed).unwrap();
+        rt_heap_mark_all_unreachable(&mut heap);
+        unsafe {
+            (*kept).reachable = 1;
+        }
+        let r = rt_heap_free_all_unreachable(&mut heap);
+        assert!(matches!(r, Err(HeapError::DoubleFree { .. })));
+        assert_eq!(heap.allocations(), &[kept]);
+        assert_eq!(
+            rt_heap_check(&heap, garbage),
+            Err(HeapError::Dangling {
+                ptr: garbage as usize,
+                type_idx: 1
+            })
+        );
+        rt_heap_destroy(&mut heap);
+    }
+
+    #[test]
+    fn quarantine_releases_oldest_blocks_past_its_budget() {
+        let mut heap = verified_heap();
+        let block = 64 + size_of::<Allocation>();
+        heap.verifier.as_mut().unwrap().quarantine_limit = 3 * block;
+        let blocks: Vec<_> = (0..5)
+            .map(|_| rt_heap_allocate(&mut heap, 64, 0, 0) as *mut Allocation)
+            .collect();
+        for b in &blocks {
+            rt_heap_free(&mut heap, *b).unwrap();
+        }
+        let v = heap.verifier.as_ref().unwrap();
+        assert_eq!(v.quarantine_bytes, 3 * block);
+        assert!(v.quarantine_order.iter().eq(&blocks[2..]));
+        assert!(blocks[2..].iter().all(|b| v.quarantine.contains(b)));
+        assert!(!v.quarantine.contains(&blocks[0]));
+        heap.allocations.clear();
+        rt_heap_destroy(&mut heap);
+    }
+
+    #[test]
+    fn leak_report_skips_reachable_blocks() {
+        let mut heap = verified_heap();
+        let rooted: Vec<_> = (0..4)
+            .map(|_| rt_heap_allocate(&mut heap, 16, 0, 3) as *mut Allocation)
+            .collect();
+        let leaked = rt_heap_allocate(&mut heap, 24, 0, 5) as *mut Allocation;
+        rt_heap_mark_all_unreachable(&mut heap);
+        for r in &rooted {
+            unsafe {
+                (**r).reachable = 1;
             }
         }
+        let report = rt_heap_leak_report(&heap);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].type_idx, 5);
+        assert_eq!(report[0].count, 1);
+        assert_eq!(report[0].bytes, unsafe { (*leaked).size } as usize + size_of::<Allocation>());
+        rt_heap_destroy(&mut heap);
     }
 }
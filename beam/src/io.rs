@@ -0,0 +1,683 @@
+use crate::fast::{RT, RtError, Var};
+use crate::heap::Allocation;
+use crate::process::{IDLE_PID, NO_MESSAGE, Process};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+pub const IO_NATIVES: [&str; 9] = [
+    "io_open",
+    "io_read",
+    "io_write",
+    "io_close",
+    "io_pipe",
+    "io_listen",
+    "io_port",
+    "io_accept",
+    "io_connect",
+];
+const MAX_EVENTS: usize = 64;
+const MAX_READ: usize = 1 << 20;
+
+pub fn is_io_native(name: &str) -> bool {
+    IO_NATIVES.contains(&name)
+}
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+struct Interest {
+    events: u32,
+    pids: Vec<u64>,
+}
+
+pub struct Reactor {
+    epfd: i32,
+    interests: Mutex<HashMap<i32, Interest>>,
+    parked: AtomicUsize,
+}
+impl Reactor {
+    pub fn new() -> Self {
+        Self {
+            epfd: unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) },
+            interests: Mutex::new(HashMap::new()),
+            parked: AtomicUsize::new(0),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.parked.load(Ordering::Acquire) == 0
+    }
+    pub fn register(&self, fd: i32, pid: u64, events: u32) -> bool {
+        let mut m = self.interests.lock().unwrap();
+        let wanted = m.get(&fd).map_or(0, |i| i.events) | events;
+        let mut ev = libc::epoll_event {
+            events: wanted | libc::EPOLLONESHOT as u32,
+            u64: fd as u64,
+        };
+        let mut r = unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, &mut ev) };
+        if r < 0 && errno() == libc::EEXIST {
+            r = unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_MOD, fd, &mut ev) };
+        }
+        if r < 0 {
+            return false;
+        }
+        let i = m.entry(fd).or_insert(Interest {
+            events: 0,
+            pids: Vec::new(),
+        });
+        i.events = wanted;
+        i.pids.push(pid);
+        self.parked.fetch_add(1, Ordering::AcqRel);
+        true
+    }
+    pub fn poll(&self, timeout: Option<u64>) -> Vec<u64> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let timeout = timeout.map_or(-1, |t| t.min(i32::MAX as u64) as i32);
+        let n = unsafe {
+            libc::epoll_wait(self.epfd, events.as_mut_ptr(), MAX_EVENTS as i32, timeout)
+        };
+        let mut out = Vec::new();
+        if n <= 0 {
+            return out;
+        }
+        let mut m = self.interests.lock().unwrap();
+        for e in &events[..n as usize] {
+            let fd = e.u64 as i32;
+            if let Some(i) = m.remove(&fd) {
+                self.parked.fetch_sub(i.pids.len(), Ordering::AcqRel);
+                out.extend(i.pids);
+            }
+        }
+        out
+    }
+    pub fn forget(&self, fd: i32) -> Vec<u64> {
+        let Some(i) = self.interests.lock().unwrap().remove(&fd) else {
+            return Vec::new();
+        };
+        unsafe {
+            libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut());
+        }
+        self.parked.fetch_sub(i.pids.len(), Ordering::AcqRel);
+        i.pids
+    }
+    pub fn forget_pid(&self, pid: u64) {
+        let mut m = self.interests.lock().unwrap();
+        for i in m.values_mut() {
+            let before = i.pids.len();
+            i.pids.retain(|p| *p != pid);
+            self.parked.fetch_sub(before - i.pids.len(), Ordering::AcqRel);
+        }
+        m.retain(|_, i| !i.pids.is_empty());
+    }
+}
+impl Default for Reactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        if self.epfd >= 0 {
+            unsafe {
+                libc::close(self.epfd);
+            }
+        }
+    }
+}
+
+enum IoResult {
+    Done(Var),
+    Block { fd: i32, events: u32 },
+}
+
+fn loopback(port: i64) -> libc::sockaddr_in {
+    libc::sockaddr_in {
+        sin_family: libc::AF_INET as u16,
+        sin_port: (port as u16).to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_be_bytes([127, 0, 0, 1]).to_be(),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+fn tcp_socket() -> i32 {
+    unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    }
+}
+
+fn or_errno(r: i64) -> Var {
+    Var::integer(if r < 0 { -(errno() as i64) } else { r })
+}
+
+fn bad_fd() -> Var {
+    Var::integer(-(libc::EBADF as i64))
+}
+
+/// Closes fds left open by a process that has exited.
+pub(crate) fn close_fds(reactor: &Reactor, fds: HashSet<i32>) {
+    for fd in fds {
+        reactor.forget(fd);
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+impl RT {
+    fn io_arg(&self, argc: usize, i: usize) -> Var {
+        self.op_stack[self.op_stack_ptr - argc + i].clone()
+    }
+    fn io_str(&self, argc: usize, i: usize) -> Option<String> {
+        self.io_bytes(argc, i)
+            .map(|b| String::from_utf8_lossy(&b).into_owned())
+    }
+    fn io_bytes(&self, argc: usize, i: usize) -> Option<Vec<u8>> {
+        let s = self.io_arg(argc, i).get_string();
+        if s.is_null() {
+            return None;
+        }
+        Some(unsafe { (*s).as_bytes().to_vec() })
+    }
+    /// The fd in argument `i`, if the current process opened it. Processes
+    /// only ever see fds they opened, never the host's or each other's.
+    fn io_fd(&self, argc: usize, i: usize) -> Option<i32> {
+        let fd = self.io_arg(argc, i).get_int();
+        let s = self.scheduler.lock();
+        let owned = s.fds.get(&self.scheduler.current)?;
+        owned.contains(&(fd as i32)).then_some(fd as i32)
+    }
+    fn own_fd(&self, fd: i32) {
+        if fd >= 0 {
+            let pid = self.scheduler.current;
+            self.scheduler.lock().fds.entry(pid).or_default().insert(fd);
+        }
+    }
+    pub(crate) fn close_process_fds(&self, pid: u64) {
+        let fds = self.scheduler.lock().fds.remove(&pid);
+        if let Some(fds) = fds {
+            close_fds(&self.scheduler.shared.reactor, fds);
+        }
+    }
+    fn io_struct(&mut self, type_name: &str, fields: &[Var]) -> Var {
+        let Some(type_idx) = self.types.as_slice().iter().position(|t| t.name == type_name) else {
+            return Var::ptr(std::ptr::null_mut());
+        };
+        let n = self.types[type_idx].fields.len();
+        let obj = crate::heap::rt_heap_allocate(
+            &mut self.heap,
+            n * size_of::<Var>(),
+            n as u16,
+            type_idx as u16,
+        ) as *mut Var;
+        self.gc_allocated(obj as *mut Allocation);
+        for i in 0..n {
+            let t = self.types[type_idx].fields[i];
+            let v = match fields.get(i) {
+                Some(f) if f.get().tag == t => f.clone(),
+                _ => {
+                    let mut v = Var::new();
+                    v.get_mut().tag = t;
+                    v
+                }
+            };
+            unsafe {
+                *obj.add(i + 1) = v;
+            }
+        }
+        Var::ptr(obj)
+    }
+    fn io_op(&mut self, name: &str, argc: usize) -> Result<IoResult, RtError> {
+        let out = match name {
+            "io_open" => {
+                let (Some(path), Some(mode)) = (self.io_str(argc, 0), self.io_str(argc, 1)) else {
+                    return Ok(IoResult::Done(Var::integer(-(libc::EFAULT as i64))));
+                };
+                let flags = match mode.as_str() {
+                    "r" => libc::O_RDONLY,
+                    "w" => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                    "a" => libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+                    "rw" => libc::O_RDWR | libc::O_CREAT,
+                    _ => return Ok(IoResult::Done(Var::integer(-(libc::EINVAL as i64)))),
+                };
+                let Ok(path) = CString::new(path) else {
+                    return Ok(IoResult::Done(Var::integer(-(libc::EINVAL as i64))));
+                };
+                let fd = unsafe {
+                    libc::open(
+                        path.as_ptr(),
+                        flags | libc::O_NONBLOCK | libc::O_CLOEXEC,
+                        0o644,
+                    )
+                };
+                self.own_fd(fd);
+                or_errno(fd as i64)
+            }
+            "io_read" => {
+                let Some(fd) = self.io_fd(argc, 0) else {
+                    let data = self.allocate_bytes(&[]);
+                    return Ok(IoResult::Done(self.io_struct("Read", &[data, bad_fd()])));
+                };
+                let mut max = self.io_arg(argc, 1).get_int().clamp(0, MAX_READ as i64) as usize;
+                if let Some(limit) = self.limits.string_bytes {
+                    max = max.min(limit);
+                }
+                self.reserve_heap(Self::string_cost(max))?;
+                let mut buf = vec![0u8; max];
+                let n = loop {
+                    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, max) };
+                    if n >= 0 || errno() != libc::EINTR {
+                        break n;
+                    }
+                };
+                if n < 0 && errno() == libc::EAGAIN {
+                    return Ok(IoResult::Block {
+                        fd,
+                        events: libc::EPOLLIN as u32,
+                    });
+                }
+                let r = or_errno(n as i64);
+                buf.truncate(n.max(0) as usize);
+                let data = self.allocate_bytes(&buf);
+                self.io_struct("Read", &[data, r])
+            }
+            "io_write" => {
+                let Some(fd) = self.io_fd(argc, 0) else {
+                    return Ok(IoResult::Done(bad_fd()));
+                };
+                let Some(data) = self.io_bytes(argc, 1) else {
+                    return Ok(IoResult::Done(Var::integer(-(libc::EFAULT as i64))));
+                };
+                let n = loop {
+                    let n = unsafe {
+                        libc::write(fd, data.as_ptr() as *const libc::c_void, data.len())
+                    };
+                    if n >= 0 || errno() != libc::EINTR {
+                        break n;
+                    }
+                };
+                if n < 0 && errno() == libc::EAGAIN {
+                    return Ok(IoResult::Block {
+                        fd,
+                        events: libc::EPOLLOUT as u32,
+                    });
+                }
+                or_errno(n as i64)
+            }
+            "io_close" => {
+                let Some(fd) = self.io_fd(argc, 0) else {
+                    return Ok(IoResult::Done(bad_fd()));
+                };
+                let pid = self.scheduler.current;
+                if let Some(fds) = self.scheduler.lock().fds.get_mut(&pid) {
+                    fds.remove(&fd);
+                }
+                let woken = self.scheduler.shared.reactor.forget(fd);
+                let r = unsafe { libc::close(fd) };
+                let v = or_errno(r as i64);
+                self.wake_io(woken);
+                v
+            }
+            "io_pipe" => {
+                let mut fds = [0; 2];
+                let r = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+                if r < 0 {
+                    fds = [-errno(); 2];
+                }
+                self.own_fd(fds[0]);
+                self.own_fd(fds[1]);
+                self.io_struct("Pipe", &[Var::integer(fds[0] as i64), Var::integer(fds[1] as i64)])
+            }
+            "io_listen" => {
+                let addr = loopback(self.io_arg(argc, 0).get_int());
+                let fd = tcp_socket();
+                if fd < 0 {
+                    return Ok(IoResult::Done(or_errno(-1)));
+                }
+                let one: libc::c_int = 1;
+                let r = unsafe {
+                    libc::setsockopt(
+                        fd,
+                        libc::SOL_SOCKET,
+                        libc::SO_REUSEADDR,
+                        &one as *const libc::c_int as *const libc::c_void,
+                        size_of::<libc::c_int>() as u32,
+                    );
+                    if libc::bind(
+                        fd,
+                        &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                        size_of::<libc::sockaddr_in>() as u32,
+                    ) < 0
+                    {
+                        -1
+                    } else {
+                        libc::listen(fd, 128)
+                    }
+                };
+                if r < 0 {
+                    let v = or_errno(-1);
+                    unsafe { libc::close(fd) };
+                    v
+                } else {
+                    self.own_fd(fd);
+                    Var::integer(fd as i64)
+                }
+            }
+            "io_port" => {
+                let Some(fd) = self.io_fd(argc, 0) else {
+                    return Ok(IoResult::Done(bad_fd()));
+                };
+                let mut addr = loopback(0);
+                let mut len = size_of::<libc::sockaddr_in>() as u32;
+                let r = unsafe {
+                    libc::getsockname(
+                        fd,
+                        &mut addr as *mut libc::sockaddr_in as *mut libc::sockaddr,
+                        &mut len,
+                    )
+                };
+                if r < 0 {
+                    or_errno(-1)
+                } else {
+                    Var::integer(u16::from_be(addr.sin_port) as i64)
+                }
+            }
+            "io_accept" => {
+                let Some(fd) = self.io_fd(argc, 0) else {
+                    return Ok(IoResult::Done(bad_fd()));
+                };
+                let r = unsafe {
+                    libc::accept4(
+                        fd,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    )
+                };
+                if r < 0 && matches!(errno(), libc::EAGAIN | libc::EINTR) {
+                    return Ok(IoResult::Block {
+                        fd,
+                        events: libc::EPOLLIN as u32,
+                    });
+                }
+                self.own_fd(r);
+                or_errno(r as i64)
+            }
+            "io_connect" => {
+                let addr = loopback(self.io_arg(argc, 0).get_int());
+                let fd = tcp_socket();
+                if fd < 0 {
+                    return Ok(IoResult::Done(or_errno(-1)));
+                }
+                let r = unsafe {
+                    libc::connect(
+                        fd,
+                        &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                        size_of::<libc::sockaddr_in>() as u32,
+                    )
+                };
+                if r < 0 && errno() != libc::EINPROGRESS {
+                    let v = or_errno(-1);
+                    unsafe { libc::close(fd) };
+                    v
+                } else {
+                    self.own_fd(fd);
+                    Var::integer(fd as i64)
+                }
+            }
+            _ => unreachable!(),
+        };
+        Ok(IoResult::Done(out))
+    }
+    pub fn call_io(&mut self, name: &str, argc: usize, at: usize) -> Result<(), RtError> {
+        let args: Vec<Var> = (0..argc).map(|i| self.io_arg(argc, i)).collect();
+        if let Some(v) = self.replay_native(name, &args) {
+            self.op_stack_ptr -= argc;
+            self.op_push(v?);
+            return Ok(());
+        }
+        if !self.limits.io {
+            return Err(RtError::IoDisabled {
+                name: name.to_string(),
+            });
+        }
+        match self.io_op(name, argc)? {
+            IoResult::Done(v) => {
+                self.record_native(name, &args, &v);
+                self.op_stack_ptr -= argc;
+                self.op_push(v);
+            }
+            IoResult::Block { fd, events } => self.park_io(fd, events, at),
+        }
+        Ok(())
+    }
+    fn park_io(&mut self, fd: i32, events: u32, at: usize) {
+        let pid = self.scheduler.current;
+        self.ip = at;
+        let mut p = Process::empty(IDLE_PID);
+        self.swap_process(&mut p);
+        self.scheduler.reductions = 0;
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        s.wait(p, NO_MESSAGE, None, at, at);
+        if !shared.reactor.register(fd, pid, events) {
+            let w = s.unwait(pid).unwrap();
+            s.push(self.scheduler.worker, w.process);
+        }
+        drop(s);
+        self.schedule_next();
+    }
+    fn wake_io(&mut self, pids: Vec<u64>) -> bool {
+        if pids.is_empty() {
+            return false;
+        }
+        let worker = self.scheduler.worker;
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        let mut woke = false;
+        for pid in pids {
+            if let Some(mut w) = s.unwait(pid) {
+                w.process.ip = w.resume_ip;
+                s.push(worker, w.process);
+                woke = true;
+            }
+        }
+        drop(s);
+        if woke {
+            self.scheduler.notify_work();
+        }
+        woke
+    }
+    pub fn poll_io(&mut self, timeout: Option<u64>) -> bool {
+        if self.scheduler.shared.reactor.is_empty() {
+            return false;
+        }
+        let ready = self.scheduler.shared.reactor.poll(timeout);
+        self.wake_io(ready)
+    }
+    pub fn has_io_waiters(&self) -> bool {
+        !self.scheduler.shared.reactor.is_empty()
+    }
+    pub fn wait_io(&mut self, deadline: Option<u64>) {
+        let timeout = deadline.map(|d| d.saturating_sub(self.scheduler.now()));
+        if !self.poll_io(timeout)
+            && let Some(d) = deadline
+        {
+            self.scheduler.clock.sleep_until(d);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fast::{Limits, RT, RtError};
+    use crate::mach::{RunResult, Value};
+
+    fn rt(body: &str) -> RT {
+        let src = format!("{}{}", include_str!("../io.beam"), body);
+        let p = crate::parser::parse_to_program(src, "test.beam".into()).unwrap();
+        let mut rt = crate::fast::rt_from_intermediate_rt(crate::fast::compile_mach_to_ir(&[p]).unwrap());
+        rt.set_limits(Limits {
+            io: true,
+            ..Limits::default()
+        });
+        rt
+    }
+
+    fn run(body: &str) -> i64 {
+        match rt(&format!("fn int main:\n{}end\n", body)).run(100_000) {
+            RunResult::Finished(Value::Integer { v }) => v,
+            _ => panic!("program did not return an int"),
+        }
+    }
+
+    #[test]
+    fn read_tells_eof_from_errors() {
+        let pipe = "	p:Pipe = io_pipe()
+	w:int = p.write
+	r:int = p.read
+	n:int = io_write(w \"hi\")
+	x:int = io_close(w)
+	got:Read = io_read(r 16)
+";
+        assert_eq!(run(&format!("{pipe}	n = got.n\n	return n\n")), 2);
+        let eof = format!("{pipe}	got = io_read(r 16)\n	n = got.n\n	return n\n");
+        assert_eq!(run(&eof), 0);
+        let closed = format!("{pipe}	x = io_close(r)\n	got = io_read(r 16)\n	n = got.n\n	return n\n");
+        assert_eq!(run(&closed), -(libc::EBADF as i64));
+    }
+
+    #[test]
+    fn null_strings_are_rejected() {
+        let body = "	p:Pipe = io_pipe()
+	w:int = p.write
+	s:string
+	n:int = io_write(w s)
+	return n
+";
+        assert_eq!(run(body), -(libc::EFAULT as i64));
+    }
+
+    #[test]
+    fn io_is_off_by_default() {
+        let src = format!("{}fn int main:\n	p:Pipe = io_pipe()\n	return 0\nend\n", include_str!("../io.beam"));
+        let p = crate::parser::parse_to_program(src, "test.beam".into()).unwrap();
+        let mut rt = crate::fast::rt_from_intermediate_rt(crate::fast::compile_mach_to_ir(&[p]).unwrap());
+        let RunResult::Error(e) = rt.run(100_000) else {
+            panic!("io ran without being enabled");
+        };
+        assert!(matches!(e.downcast_ref::<RtError>(), Some(RtError::IoDisabled { .. })), "{}", e);
+    }
+
+    #[test]
+    fn fds_not_opened_by_the_process_are_refused() {
+        assert_eq!(run("	n:int = io_write(1 \"x\")\n	return n\n"), -(libc::EBADF as i64));
+        let other = "fn void opener parent pid:
+	p:Pipe = io_pipe()
+	send parent p
+	return unit
+end
+fn int main:
+	me:pid = self
+	c:pid = spawn opener(me)
+	p:Pipe = receive Pipe
+	w:int = p.write
+	n:int = io_write(w \"x\")
+	return n
+end
+";
+        let RunResult::Finished(Value::Integer { v }) = rt(other).run(100_000) else {
+            panic!("main did not finish");
+        };
+        assert_eq!(v, -(libc::EBADF as i64));
+    }
+
+    #[test]
+    fn fds_close_when_their_process_exits() {
+        let src = "fn void opener parent pid:
+	p:Pipe = io_pipe()
+	send parent p
+	p = receive Pipe
+	return unit
+end
+fn int main:
+	me:pid = self
+	c:pid = spawn opener(me)
+	p:Pipe = receive Pipe
+	r:int = p.read
+	return r
+end
+";
+        let mut rt = rt(src);
+        let RunResult::Finished(Value::Integer { v }) = rt.run(100_000) else {
+            panic!("main did not finish");
+        };
+        let mut buf = [0u8; 1];
+        let dup = unsafe { libc::dup(v as i32) };
+        assert!(dup >= 0);
+        let n = unsafe { libc::read(dup, buf.as_mut_ptr() as *mut libc::c_void, 1) };
+        assert_eq!((n, super::errno()), (-1, libc::EAGAIN), "the opener still holds the write end");
+        rt.process_exit(1, "kill").unwrap();
+        let n = unsafe { libc::read(dup, buf.as_mut_ptr() as *mut libc::c_void, 1) };
+        unsafe { libc::close(dup) };
+        assert_eq!(n, 0, "the write end outlived its process");
+    }
+
+    #[test]
+    fn read_keeps_binary_data_intact() {
+        let dir = std::env::temp_dir();
+        let src = dir.join(format!("beam-io-src-{}", std::process::id()));
+        let dst = dir.join(format!("beam-io-dst-{}", std::process::id()));
+        let data = [0xffu8, 0xfe, 0x00, b'a', 0x80, 0xc3];
+        std::fs::write(&src, data).unwrap();
+        let body = format!(
+            "	i:int = io_open(\"{}\" \"r\")
+	o:int = io_open(\"{}\" \"w\")
+	got:Read = io_read(i 64)
+	d:string = got.data
+	n:int = io_write(o d)
+	return n
+",
+            src.display(),
+            dst.display()
+        );
+        let n = run(&body);
+        let out = std::fs::read(&dst).unwrap();
+        let _ = std::fs::remove_file(&src);
+        let _ = std::fs::remove_file(&dst);
+        assert_eq!(n, data.len() as i64);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn reads_are_charged_to_the_heap_limit() {
+        let src = "fn int main:
+	p:Pipe = io_pipe()
+	r:int = p.read
+	got:Read = io_read(r 65536)
+	return 0
+end
+";
+        let mut rt = rt(src);
+        rt.set_limits(Limits {
+            io: true,
+            heap_bytes: Some(1024),
+            ..Limits::default()
+        });
+        let RunResult::Error(e) = rt.run(100_000) else {
+            panic!("read was not charged");
+        };
+        assert!(matches!(e.downcast_ref::<RtError>(), Some(RtError::HeapExhausted { .. })), "{}", e);
+    }
+}
@@ -0,0 +1,758 @@
+use crate::fast::{Instr, OPCODE_WIDTH, RT, Tag, Var};
+use crate::reload::operands;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const DEFAULT_JIT_THRESHOLD: u32 = 64;
+pub const DEOPT_LIMIT: u32 = 256;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JitStats {
+    pub compiled: usize,
+    pub discarded: usize,
+    pub native_instrs: u64,
+    pub entries: u64,
+    pub deopts: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct JitExit {
+    ip: u64,
+    budget: u64,
+    deopt: u64,
+}
+
+struct ExecBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+impl ExecBuf {
+    fn new(code: &[u8]) -> Option<Self> {
+        let len = code.len().max(1);
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                libc::munmap(ptr, len);
+                return None;
+            }
+            Some(Self {
+                ptr: ptr as *mut u8,
+                len,
+            })
+        }
+    }
+}
+impl Drop for ExecBuf {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+// SAFETY: the buffer is never written after it is made executable.
+unsafe impl Send for ExecBuf {}
+unsafe impl Sync for ExecBuf {}
+
+#[derive(Clone)]
+struct Compiled {
+    start: usize,
+    end: usize,
+    deopts: u32,
+    _code: Arc<ExecBuf>,
+}
+
+#[derive(Clone)]
+pub struct Jit {
+    pub threshold: u32,
+    calls: HashMap<usize, u32>,
+    entries: Vec<*const u8>,
+    compiled: Vec<Compiled>,
+    pub stats: JitStats,
+}
+impl JitStats {
+    pub fn merge(&mut self, other: &JitStats) {
+        self.compiled += other.compiled;
+        self.discarded += other.discarded;
+        self.native_instrs += other.native_instrs;
+        self.entries += other.entries;
+        self.deopts += other.deopts;
+    }
+}
+impl Jit {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            calls: HashMap::new(),
+            entries: Vec::new(),
+            compiled: Vec::new(),
+            stats: JitStats::default(),
+        }
+    }
+    pub fn hot(&self) -> Vec<usize> {
+        self.compiled.iter().map(|c| c.start).collect()
+    }
+    pub fn reset(&mut self) {
+        self.calls.clear();
+        self.entries.clear();
+        self.compiled.clear();
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Ip(usize),
+    Exit(usize, bool),
+}
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+
+struct Asm {
+    code: Vec<u8>,
+    labels: HashMap<usize, usize>,
+    fixups: Vec<(usize, Target)>,
+}
+impl Asm {
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+    fn d32(&mut self, v: i32) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn tag(slot: u64) -> i32 {
+        (slot * size_of::<Var>() as u64) as i32
+    }
+    fn val(slot: u64) -> i32 {
+        Self::tag(slot) + 8
+    }
+    fn load(&mut self, reg: u8, disp: i32) {
+        self.bytes(&[0x48, 0x8b, 0x87 | reg << 3]);
+        self.d32(disp);
+    }
+    fn load_byte(&mut self, reg: u8, disp: i32) {
+        self.bytes(&[0x0f, 0xb6, 0x87 | reg << 3]);
+        self.d32(disp);
+    }
+    fn store(&mut self, reg: u8, disp: i32) {
+        self.bytes(&[0x48, 0x89, 0x87 | reg << 3]);
+        self.d32(disp);
+    }
+    fn imm(&mut self, reg: u8, v: u64) {
+        self.bytes(&[0x48, 0xb8 + reg]);
+        self.bytes(&v.to_le_bytes());
+    }
+    fn set_tag(&mut self, slot: u64, tag: Tag) {
+        self.bytes(&[0x48, 0xc7, 0x87]);
+        self.d32(Self::tag(slot));
+        self.d32(tag as i32);
+    }
+    fn jump(&mut self, opcode: &[u8], to: Target) {
+        self.bytes(opcode);
+        self.fixups.push((self.code.len(), to));
+        self.d32(0);
+    }
+    fn guard(&mut self, slot: u64, tag: Tag, ip: usize) {
+        self.bytes(&[0x48, 0x83, 0xbf]);
+        self.d32(Self::tag(slot));
+        self.bytes(&[tag as u8]);
+        self.jump(&[0x0f, 0x85], Target::Exit(ip, true));
+    }
+    fn consume(&mut self) {
+        self.bytes(&[0x48, 0xff, 0xca]);
+    }
+    fn setcc(&mut self, cc: u8) {
+        self.bytes(&[0x0f, cc, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+    fn store_bool(&mut self, slot: u64) {
+        self.store(RAX, Self::val(slot));
+        self.set_tag(slot, Tag::Bool);
+    }
+    fn branch_if_set(&mut self, to: usize) {
+        self.bytes(&[0x84, 0xc0]);
+        self.jump(&[0x0f, 0x85], Target::Ip(to));
+    }
+    fn load_floats(&mut self, l: u64, r: u64, imm: bool) {
+        self.bytes(&[0xf2, 0x0f, 0x10, 0x87]);
+        self.d32(Self::val(l));
+        if imm {
+            self.imm(RCX, r);
+            self.bytes(&[0x66, 0x48, 0x0f, 0x6e, 0xc9]);
+        } else {
+            self.bytes(&[0xf2, 0x0f, 0x10, 0x8f]);
+            self.d32(Self::val(r));
+        }
+    }
+}
+
+const SETE: u8 = 0x94;
+const SETNE: u8 = 0x95;
+const SETL: u8 = 0x9c;
+const SETG: u8 = 0x9f;
+const SETA: u8 = 0x97;
+
+fn int_cc(i: Instr) -> Option<u8> {
+    use Instr::*;
+    Some(match i {
+        RegIntEq | RegIntEqImm | RegIntEqJmp | RegIntEqImmJmp => SETE,
+        RegIntNEq | RegIntNEqImm | RegIntNEqJmp | RegIntNEqImmJmp => SETNE,
+        RegIntLess | RegIntLessImm | RegIntLessJmp | RegIntLessImmJmp => SETL,
+        RegIntGreater | RegIntGreaterImm | RegIntGreaterJmp | RegIntGreaterImmJmp => SETG,
+        _ => return None,
+    })
+}
+
+fn emit(a: &mut Asm, ip: usize, i: Instr, ops: &[u64]) -> bool {
+    use Instr::*;
+    let imm = matches!(
+        i,
+        RegIntAddImm
+            | RegIntSubImm
+            | RegIntMulImm
+            | RegIntDivImm
+            | RegIntEqImm
+            | RegIntNEqImm
+            | RegIntLessImm
+            | RegIntGreaterImm
+            | RegFloatAddImm
+            | RegFloatSubImm
+            | RegFloatMulImm
+            | RegFloatDivImm
+            | RegFloatEqImm
+            | RegFloatNeqImm
+            | RegFloatLessImm
+            | RegFloatGreaterImm
+            | RegIntEqImmJmp
+            | RegIntNEqImmJmp
+            | RegIntLessImmJmp
+            | RegIntGreaterImmJmp
+            | RegIntAddLessImmJmp
+    );
+    match i {
+        Jmp => {
+            a.consume();
+            a.jump(&[0xe9], Target::Ip(ops[0] as usize));
+        }
+        RegMov => {
+            a.load(RAX, Asm::tag(ops[1]));
+            a.bytes(&[0x48, 0x83, 0xf8, Tag::String as u8]);
+            a.jump(&[0x0f, 0x84], Target::Exit(ip, false));
+            a.bytes(&[0x48, 0x83, 0xf8, Tag::Ptr as u8]);
+            a.jump(&[0x0f, 0x84], Target::Exit(ip, false));
+            a.load(RCX, Asm::val(ops[1]));
+            a.store(RAX, Asm::tag(ops[0]));
+            a.store(RCX, Asm::val(ops[0]));
+            a.consume();
+        }
+        RegConstInt | RegConstFloat | RegConstBool => {
+            let (tag, v) = match i {
+                RegConstInt => (Tag::Integer, ops[1]),
+                RegConstFloat => (Tag::Float, ops[1]),
+                _ => (Tag::Bool, (ops[1] != 0) as u64),
+            };
+            a.imm(RAX, v);
+            a.store(RAX, Asm::val(ops[0]));
+            a.set_tag(ops[0], tag);
+            a.consume();
+        }
+        RegJmpCond => {
+            a.guard(ops[0], Tag::Bool, ip);
+            a.consume();
+            a.load_byte(RAX, Asm::val(ops[0]));
+            a.branch_if_set(ops[1] as usize);
+        }
+        RegIntAdd | RegIntSub | RegIntMul | RegIntDiv | RegIntEq | RegIntNEq | RegIntLess
+        | RegIntGreater | RegIntAddImm | RegIntSubImm | RegIntMulImm | RegIntDivImm
+        | RegIntEqImm | RegIntNEqImm | RegIntLessImm | RegIntGreaterImm | RegIntEqJmp
+        | RegIntNEqJmp | RegIntLessJmp | RegIntGreaterJmp | RegIntEqImmJmp | RegIntNEqImmJmp
+        | RegIntLessImmJmp | RegIntGreaterImmJmp => {
+            let (dst, l, r) = (ops[0], ops[1], ops[2]);
+            a.guard(l, Tag::Integer, ip);
+            if !imm {
+                a.guard(r, Tag::Integer, ip);
+            }
+            a.load(RAX, Asm::val(l));
+            if imm {
+                a.imm(RCX, r);
+            } else {
+                a.load(RCX, Asm::val(r));
+            }
+            if let Some(cc) = int_cc(i) {
+                a.bytes(&[0x48, 0x39, 0xc8]);
+                a.setcc(cc);
+                a.store_bool(dst);
+                a.consume();
+                if ops.len() == 4 {
+                    a.branch_if_set(ops[3] as usize);
+                }
+                return true;
+            }
+            match i {
+                RegIntAdd | RegIntAddImm => a.bytes(&[0x48, 0x01, 0xc8]),
+                RegIntSub | RegIntSubImm => a.bytes(&[0x48, 0x29, 0xc8]),
+                RegIntMul | RegIntMulImm => a.bytes(&[0x48, 0x0f, 0xaf, 0xc1]),
+                _ => {
+                    a.bytes(&[0x48, 0x85, 0xc9]);
+                    a.jump(&[0x0f, 0x84], Target::Exit(ip, true));
+                    a.bytes(&[0x48, 0x83, 0xf9, 0xff]);
+                    a.jump(&[0x0f, 0x84], Target::Exit(ip, true));
+                    a.bytes(&[0x49, 0x89, 0xd0, 0x48, 0x99, 0x48, 0xf7, 0xf9, 0x4c, 0x89, 0xc2]);
+                }
+            }
+            a.store(RAX, Asm::val(dst));
+            a.set_tag(dst, Tag::Integer);
+            a.consume();
+        }
+        RegIntAddLessJmp | RegIntAddLessImmJmp => {
+            let (dst, l, k, c, n, to) = (ops[0], ops[1], ops[2], ops[3], ops[4], ops[5]);
+            a.guard(l, Tag::Integer, ip);
+            if !imm {
+                a.guard(n, Tag::Integer, ip);
+            }
+            a.load(RAX, Asm::val(l));
+            a.imm(RCX, k);
+            a.bytes(&[0x48, 0x01, 0xc8]);
+            a.store(RAX, Asm::val(dst));
+            a.set_tag(dst, Tag::Integer);
+            if imm {
+                a.imm(RCX, n);
+            } else {
+                a.load(RCX, Asm::val(n));
+            }
+            a.bytes(&[0x48, 0x39, 0xc8]);
+            a.setcc(SETL);
+            a.store_bool(c);
+            a.consume();
+            a.branch_if_set(to as usize);
+        }
+        RegFloatAdd | RegFloatSub | RegFloatMul | RegFloatDiv | RegFloatEq | RegFloatNeq
+        | RegFloatLess | RegFloatGreater | RegFloatAddImm | RegFloatSubImm | RegFloatMulImm
+        | RegFloatDivImm | RegFloatEqImm | RegFloatNeqImm | RegFloatLessImm
+        | RegFloatGreaterImm => {
+            let (dst, l, r) = (ops[0], ops[1], ops[2]);
+            a.guard(l, Tag::Float, ip);
+            if !imm {
+                a.guard(r, Tag::Float, ip);
+            }
+            a.load_floats(l, r, imm);
+            let arith = match i {
+                RegFloatAdd | RegFloatAddImm => Some(0x58),
+                RegFloatSub | RegFloatSubImm => Some(0x5c),
+                RegFloatMul | RegFloatMulImm => Some(0x59),
+                RegFloatDiv | RegFloatDivImm => Some(0x5e),
+                _ => None,
+            };
+            if let Some(op) = arith {
+                a.bytes(&[0xf2, 0x0f, op, 0xc1, 0xf2, 0x0f, 0x11, 0x87]);
+                a.d32(Asm::val(dst));
+                a.set_tag(dst, Tag::Float);
+            } else {
+                match i {
+                    RegFloatEq | RegFloatEqImm => {
+                        a.bytes(&[0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x94, 0xc0, 0x0f, 0x9b, 0xc1, 0x20, 0xc8]);
+                    }
+                    RegFloatNeq | RegFloatNeqImm => {
+                        a.bytes(&[0x66, 0x0f, 0x2e, 0xc1, 0x0f, 0x95, 0xc0, 0x0f, 0x9a, 0xc1, 0x08, 0xc8]);
+                    }
+                    RegFloatLess | RegFloatLessImm => {
+                        a.bytes(&[0x66, 0x0f, 0x2e, 0xc8, 0x0f, SETA, 0xc0]);
+                    }
+                    _ => {
+                        a.bytes(&[0x66, 0x0f, 0x2e, 0xc1, 0x0f, SETA, 0xc0]);
+                    }
+                }
+                a.bytes(&[0x0f, 0xb6, 0xc0]);
+                a.store_bool(dst);
+            }
+            a.consume();
+        }
+        RegBoolEq | RegBoolNeq | RegBoolAnd | RegBoolOr => {
+            let (dst, l, r) = (ops[0], ops[1], ops[2]);
+            a.guard(l, Tag::Bool, ip);
+            a.guard(r, Tag::Bool, ip);
+            a.load_byte(RAX, Asm::val(l));
+            a.load_byte(RCX, Asm::val(r));
+            match i {
+                RegBoolEq => {
+                    a.bytes(&[0x39, 0xc8]);
+                    a.setcc(SETE);
+                }
+                RegBoolNeq => {
+                    a.bytes(&[0x39, 0xc8]);
+                    a.setcc(SETNE);
+                }
+                RegBoolAnd => a.bytes(&[0x21, 0xc8]),
+                _ => a.bytes(&[0x09, 0xc8]),
+            }
+            a.store_bool(dst);
+            a.consume();
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn compile(code: &[u8], start: usize, end: usize) -> (Vec<u8>, Vec<usize>, HashMap<usize, usize>) {
+    let mut a = Asm {
+        code: Vec::new(),
+        labels: HashMap::new(),
+        fixups: Vec::new(),
+    };
+    let mut native = Vec::new();
+    let mut ip = start;
+    while ip < end {
+        let i: Instr = unsafe { std::mem::transmute(code[ip]) };
+        let count = operands(i).len();
+        let ops: Vec<u64> = (0..count)
+            .map(|k| {
+                let at = ip + OPCODE_WIDTH + k * 8;
+                u64::from_le_bytes(code[at..at + 8].try_into().unwrap())
+            })
+            .collect();
+        a.labels.insert(ip, a.code.len());
+        a.bytes(&[0x48, 0x85, 0xd2]);
+        a.jump(&[0x0f, 0x84], Target::Exit(ip, false));
+        let mark = a.code.len();
+        if emit(&mut a, ip, i, &ops) {
+            native.push(ip);
+        } else {
+            a.code.truncate(mark - 9);
+            a.fixups.pop();
+            a.jump(&[0xe9], Target::Exit(ip, false));
+        }
+        ip += OPCODE_WIDTH + count * 8;
+    }
+    a.jump(&[0xe9], Target::Exit(end, false));
+    let mut exits = HashMap::new();
+    for (at, to) in std::mem::take(&mut a.fixups) {
+        let dest = match to {
+            Target::Ip(t) if a.labels.contains_key(&t) => a.labels[&t],
+            Target::Ip(t) => exit_stub(&mut a, &mut exits, t, false),
+            Target::Exit(t, deopt) => exit_stub(&mut a, &mut exits, t, deopt),
+        };
+        let rel = dest as i64 - (at as i64 + 4);
+        a.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+    (a.code, native, a.labels)
+}
+
+fn exit_stub(a: &mut Asm, exits: &mut HashMap<(usize, bool), usize>, ip: usize, deopt: bool) -> usize {
+    if let Some(at) = exits.get(&(ip, deopt)) {
+        return *at;
+    }
+    let at = a.code.len();
+    a.imm(RAX, ip as u64);
+    a.bytes(&[0x48, 0x89, 0x06, 0x48, 0x89, 0x56, 0x08, 0x48, 0xc7, 0x46, 0x10]);
+    a.d32(deopt as i32);
+    a.bytes(&[0xc3]);
+    exits.insert((ip, deopt), at);
+    at
+}
+
+pub fn supported() -> bool {
+    cfg!(all(target_arch = "x86_64", unix))
+}
+
+impl RT {
+    pub fn enable_jit(&mut self, threshold: u32) -> bool {
+        if !supported() {
+            return false;
+        }
+        self.jit = Some(Box::new(Jit::new(threshold)));
+        true
+    }
+    pub fn disable_jit(&mut self) {
+        self.jit = None;
+    }
+    pub fn jit_stats(&self) -> Option<JitStats> {
+        self.jit.as_ref().map(|j| j.stats)
+    }
+    pub(crate) fn jit_reset(&mut self) {
+        if let Some(j) = &mut self.jit {
+            j.reset();
+        }
+    }
+    pub(crate) fn jit_note_call(&mut self, loc: usize) {
+        let Some(j) = &mut self.jit else {
+            return;
+        };
+        let n = j.calls.entry(loc).or_insert(0);
+        *n = n.saturating_add(1);
+        if *n == j.threshold {
+            self.jit_compile(loc);
+        }
+    }
+    pub fn jit_precompile(&mut self, starts: &[usize]) {
+        for s in starts {
+            let Some(j) = &mut self.jit else {
+                return;
+            };
+            if j.calls.get(s).is_some_and(|n| *n >= j.threshold) {
+                continue;
+            }
+            j.calls.insert(*s, j.threshold);
+            self.jit_compile(*s);
+        }
+    }
+    fn jit_compile(&mut self, start: usize) {
+        let len = self.instructions.len();
+        let end = self
+            .arity
+            .keys()
+            .filter_map(|n| self.symbol_table.get(n))
+            .filter(|s| **s > start)
+            .min()
+            .copied()
+            .unwrap_or(len);
+        let (code, native, labels) = compile(self.instructions.as_slice(), start, end);
+        let Some(buf) = ExecBuf::new(&code) else {
+            return;
+        };
+        let j = self.jit.as_mut().unwrap();
+        j.entries.resize(len / OPCODE_WIDTH, std::ptr::null());
+        for ip in native {
+            j.entries[ip / OPCODE_WIDTH] = unsafe { buf.ptr.add(labels[&ip]) };
+        }
+        j.compiled.push(Compiled {
+            start,
+            end,
+            deopts: 0,
+            _code: Arc::new(buf),
+        });
+        j.stats.compiled += 1;
+    }
+    fn jit_deopt(&mut self, ip: usize) {
+        let j = self.jit.as_mut().unwrap();
+        j.stats.deopts += 1;
+        let Some(k) = j.compiled.iter().position(|c| c.start <= ip && ip < c.end) else {
+            return;
+        };
+        j.compiled[k].deopts += 1;
+        if j.compiled[k].deopts < DEOPT_LIMIT {
+            return;
+        }
+        let c = j.compiled.remove(k);
+        for e in &mut j.entries[c.start / OPCODE_WIDTH..c.end / OPCODE_WIDTH] {
+            *e = std::ptr::null();
+        }
+        j.calls.insert(c.start, u32::MAX);
+        j.stats.discarded += 1;
+    }
+    pub(crate) fn run_native(&mut self, max: u64) -> Option<u64> {
+        if self.profile.is_some() {
+            return None;
+        }
+        let entry = *self.jit.as_ref()?.entries.get(self.ip / OPCODE_WIDTH)?;
+        if entry.is_null() {
+            return None;
+        }
+        let slice = (self.scheduler.budget - self.scheduler.reductions).saturating_sub(1) as u64;
+        let budget = max.min(slice).min(self.limits.fuel.unwrap_or(u64::MAX));
+        if budget == 0 {
+            return None;
+        }
+        let regs = unsafe { self.var_stack.as_slice_mut().as_mut_ptr().add(self.var_base_ptr) };
+        let mut exit = JitExit::default();
+        let f: extern "sysv64" fn(*mut Var, *mut JitExit, u64) = unsafe { std::mem::transmute(entry) };
+        f(regs, &mut exit, budget);
+        let used = budget - exit.budget;
+        let j = self.jit.as_mut().unwrap();
+        j.stats.entries += 1;
+        j.stats.native_instrs += used;
+        self.ip = exit.ip as usize;
+        if let Some(f) = &mut self.limits.fuel {
+            *f -= used;
+        }
+        self.scheduler.reductions += used as usize;
+        if exit.deopt != 0 {
+            self.jit_deopt(self.ip);
+        }
+        Some(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast::{compile_mach_to_ir, rt_from_intermediate_rt};
+    use crate::mach::{RunResult, Value};
+
+    fn run(src: &str, jit: bool) -> (Result<Value, String>, Option<JitStats>) {
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        let mut rt = rt_from_intermediate_rt(compile_mach_to_ir(&[p]).unwrap());
+        // sleeping only moves the clock once every process is blocked
+        rt.set_clock(Arc::new(crate::timer::VirtualClock::new()));
+        if jit {
+            rt.enable_jit(1);
+        }
+        let r = match rt.run(10_000_000) {
+            RunResult::Finished(v) => Ok(v),
+            RunResult::Error(e) => Err(e.to_string()),
+            RunResult::Paused => panic!("program did not finish"),
+        };
+        (r, rt.jit_stats())
+    }
+
+    /// Runs `src` with and without the jit, checks both agree and returns
+    /// the result with the jit's stats.
+    fn agree(src: &str) -> (Result<Value, String>, JitStats) {
+        let (want, _) = run(src, false);
+        let (got, stats) = run(src, true);
+        assert_eq!(got, want);
+        (got, stats.unwrap())
+    }
+
+    #[test]
+    fn compiled_loops_match_the_interpreter() {
+        if !supported() {
+            return;
+        }
+        let (r, stats) = agree("fn int sum n int:
+	i:int = 0
+	s:int = 0
+	k:int = 0
+	b:bool = false
+	label top
+	b = i < n
+	if b goto body
+	return s
+	label body
+	k = i * 3
+	s = s + k
+	s = s - 1
+	i = i + 1
+	goto top
+end
+fn int main:
+	i:int = 0
+	t:int = 0
+	k:int = 0
+	b:bool = true
+	label top
+	k = sum(i)
+	t = t + k
+	i = i + 1
+	b = i < 100
+	if b goto top
+	return t
+end
+");
+        assert_eq!(r, Ok(Value::Integer { v: 480150 }));
+        assert!(stats.compiled > 0 && stats.native_instrs > 0, "{:?}", stats);
+    }
+
+    const DIVIDE: &str = "fn int divide a int b int:
+	x:int = a / b
+	return x
+end
+fn int main:
+	i:int = 1
+	t:int = 0
+	k:int = 0
+	b:bool = true
+	label top
+	k = divide(1000 i)
+	t = t + k
+	i = i + 1
+	b = i < 100
+	if b goto top
+";
+
+    #[test]
+    fn faulting_division_deopts_to_the_interpreter() {
+        if !supported() {
+            return;
+        }
+        let (r, stats) = agree(&format!("{DIVIDE}	return t\nend\n"));
+        assert!(r.is_ok());
+        assert_eq!(stats.deopts, 0);
+        let zero = format!("{DIVIDE}	k = divide(t 0)\n	return k\nend\n");
+        let (r, stats) = agree(&zero);
+        assert!(r.unwrap_err().contains("division by zero"));
+        assert_eq!(stats.deopts, 1);
+        let min = format!("{DIVIDE}	k = 0 - 9223372036854775807\n	k = k - 1\n	m:int = 0 - 1\n	k = divide(k m)\n	return k\nend\n");
+        let (r, stats) = agree(&min);
+        assert!(r.unwrap_err().contains("integer overflow"));
+        assert_eq!(stats.deopts, 1);
+    }
+
+    #[test]
+    fn nan_compares_match_the_interpreter() {
+        if !supported() {
+            return;
+        }
+        for (op, want) in [("==", false), ("!=", true), ("<", false), (">", false)] {
+            for rhs in ["y", "1.0"] {
+                let src = format!(
+                    "fn bool cmp x float y float:
+	b:bool = x {op} {rhs}
+	return b
+end
+fn bool main:
+	z:float = 0.0
+	n:float = z / z
+	i:int = 0
+	b:bool = false
+	c:bool = true
+	label top
+	b = cmp(n 1.0)
+	i = i + 1
+	c = i < 100
+	if c goto top
+	return b
+end
+"
+                );
+                let (r, stats) = agree(&src);
+                assert_eq!(r, Ok(Value::Bool { v: want }), "NaN {op} {rhs}");
+                assert!(stats.native_instrs > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn code_that_keeps_deopting_is_discarded() {
+        if !supported() {
+            return;
+        }
+        let src = format!(
+            "fn int divide a int b int:
+	x:int = a / b
+	return x
+end
+fn void worker:
+	k:int = divide(1 0)
+	return unit
+end
+fn int main:
+	i:int = 0
+	b:bool = true
+	c:pid = self
+	label top
+	c = spawn worker()
+	i = i + 1
+	b = i < {}
+	if b goto top
+	sleep 20
+	return i
+end
+",
+            DEOPT_LIMIT + 10
+        );
+        let (r, stats) = agree(&src);
+        assert_eq!(r, Ok(Value::Integer { v: DEOPT_LIMIT as i64 + 10 }));
+        assert_eq!(stats.discarded, 1, "{:?}", stats);
+        assert_eq!(stats.deopts, DEOPT_LIMIT as u64);
+    }
+}
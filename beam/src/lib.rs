@@ -0,0 +1,19 @@
+pub mod aot;
+pub mod checkpoint;
+pub mod fast;
+pub mod heap;
+pub mod io;
+pub mod jit;
+pub mod lower;
+pub mod mach;
+pub mod opt;
+pub mod parser;
+pub mod process;
+pub mod profile;
+pub mod regs;
+pub mod reload;
+pub mod replay;
+pub mod smp;
+pub mod snapshot;
+pub mod timer;
+pub mod wasm;
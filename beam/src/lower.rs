@@ -0,0 +1,758 @@
+use crate::mach::{Binop, Cmd, Frame, Machine, Rc, Type, Value, Var};
+use std::error::Error;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Src {
+    Local(usize),
+    Field(usize, usize),
+    Const(Value),
+    Var(Var),
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum Dst {
+    Local(usize),
+    Field(usize, usize),
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Logic {
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StrOp {
+    Add,
+    Mul,
+    Equal,
+    NotEqual,
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    AddIntLocalLocal {
+        l: usize,
+        r: usize,
+        out: usize,
+    },
+    AddIntLocalConst {
+        l: usize,
+        r: i64,
+        out: usize,
+    },
+    SubIntLocalLocal {
+        l: usize,
+        r: usize,
+        out: usize,
+    },
+    SubIntLocalConst {
+        l: usize,
+        r: i64,
+        out: usize,
+    },
+    MulIntLocalLocal {
+        l: usize,
+        r: usize,
+        out: usize,
+    },
+    LessIntLocalLocal {
+        l: usize,
+        r: usize,
+        out: usize,
+    },
+    LessIntLocalConst {
+        l: usize,
+        r: i64,
+        out: usize,
+    },
+    GreaterIntLocalConst {
+        l: usize,
+        r: i64,
+        out: usize,
+    },
+    EqualIntLocalConst {
+        l: usize,
+        r: i64,
+        out: usize,
+    },
+    Int {
+        op: Arith,
+        l: Src,
+        r: Src,
+        out: Dst,
+    },
+    Float {
+        op: Arith,
+        l: Src,
+        r: Src,
+        out: Dst,
+    },
+    Bool {
+        op: Logic,
+        l: Src,
+        r: Src,
+        out: Dst,
+    },
+    Str {
+        op: StrOp,
+        l: Src,
+        r: Src,
+        out: Dst,
+    },
+    MoveLocalLocal {
+        from: usize,
+        to: usize,
+    },
+    Move {
+        from: Src,
+        to: Dst,
+    },
+    New {
+        to: Dst,
+        type_idx: u32,
+        defaults: Rc<[Value]>,
+    },
+    Jmp {
+        to: usize,
+    },
+    JmpIfLocal {
+        cond: usize,
+        to: usize,
+    },
+    JmpIf {
+        cond: Src,
+        to: usize,
+    },
+    Declare {
+        defaults: Rc<[Value]>,
+    },
+    Call {
+        to: usize,
+        args: Rc<[Src]>,
+        returned: Option<Var>,
+    },
+    Return {
+        value: Src,
+    },
+    Cmd(Rc<Cmd>),
+}
+
+fn arith(op: &Binop) -> Option<Arith> {
+    Some(match op {
+        Binop::Add => Arith::Add,
+        Binop::Sub => Arith::Sub,
+        Binop::Mul => Arith::Mul,
+        Binop::Div => Arith::Div,
+        Binop::Equal => Arith::Equal,
+        Binop::NotEqual => Arith::NotEqual,
+        Binop::Less => Arith::Less,
+        Binop::Greater => Arith::Greater,
+        Binop::And | Binop::Or => return None,
+    })
+}
+fn is_compare(op: Arith) -> bool {
+    matches!(
+        op,
+        Arith::Equal | Arith::NotEqual | Arith::Less | Arith::Greater
+    )
+}
+fn int_op(op: Arith, a: i64, b: i64) -> Result<Value, String> {
+    Ok(match op {
+        Arith::Add => Value::Integer { v: a + b },
+        Arith::Sub => Value::Integer { v: a - b },
+        Arith::Mul => Value::Integer { v: a * b },
+        Arith::Div => match a.checked_div(b) {
+            Some(v) => Value::Integer { v },
+            None if b == 0 => return Err("integer division by zero".into()),
+            None => return Err("integer overflow".into()),
+        },
+        Arith::Equal => Value::Bool { v: a == b },
+        Arith::NotEqual => Value::Bool { v: a != b },
+        Arith::Less => Value::Bool { v: a < b },
+        Arith::Greater => Value::Bool { v: a > b },
+    })
+}
+fn float_op(op: Arith, a: f64, b: f64) -> Value {
+    match op {
+        Arith::Add => Value::Float { v: a + b },
+        Arith::Sub => Value::Float { v: a - b },
+        Arith::Mul => Value::Float { v: a * b },
+        Arith::Div => Value::Float { v: a / b },
+        Arith::Equal => Value::Bool { v: a == b },
+        Arith::NotEqual => Value::Bool { v: a != b },
+        Arith::Less => Value::Bool { v: a < b },
+        Arith::Greater => Value::Bool { v: a > b },
+    }
+}
+fn default_of(t: &Type, types: &[(Rc<str>, Type)]) -> Option<Value> {
+    match t {
+        Type::Struct { .. } | Type::Function { .. } => None,
+        _ => t.as_default(types).ok(),
+    }
+}
+
+impl Machine {
+    pub fn resolve(&mut self) {
+        let ops: Vec<Op> = self.cmds.iter().map(|c| self.lower(c)).collect();
+        self.ops = ops.into();
+        self.gc_dirty = true;
+    }
+    fn src(&self, v: &Var) -> Src {
+        match v {
+            Var::Stack { index, .. } => Src::Local(*index),
+            Var::FieldAccess { of, index, .. } => match of.as_ref() {
+                Var::Stack { index: o, .. } => Src::Field(*o, index + 1),
+                _ => Src::Var(v.clone()),
+            },
+            Var::ConstInt { .. }
+            | Var::ConstFloat { .. }
+            | Var::ConstString { .. }
+            | Var::ConstBool { .. }
+            | Var::Unit => match self.get_value(v.clone()) {
+                Ok(c) => Src::Const(c),
+                Err(_) => Src::Var(v.clone()),
+            },
+            _ => Src::Var(v.clone()),
+        }
+    }
+    fn dst(&self, v: &Var) -> Option<Dst> {
+        match v {
+            Var::Stack { index, .. } => Some(Dst::Local(*index)),
+            Var::FieldAccess { of, index, .. } => match of.as_ref() {
+                Var::Stack { index: o, .. } => Some(Dst::Field(*o, index + 1)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    fn lower(&self, c: &Cmd) -> Op {
+        let op = match c {
+            Cmd::Binop { l, r, out, op } => self.lower_binop(l, r, out, op),
+            Cmd::Assign { l, r } => match (self.dst(l), r) {
+                (Some(to), Var::OperatorNew { new_type }) => {
+                    let vt = new_type.as_type(&self.type_table);
+                    let Type::Struct { fields, .. } = &vt else {
+                        return Op::Cmd(Rc::new(c.clone()));
+                    };
+                    let defaults: Option<Vec<Value>> = fields
+                        .iter()
+                        .map(|f| default_of(&f.1.as_type(&self.type_table), &self.type_table))
+                        .collect();
+                    match defaults {
+                        Some(d) if d.len() == vt.get_size(&self.type_table) => Some(Op::New {
+                            to,
+                            type_idx: new_type.index as u32,
+                            defaults: d.into(),
+                        }),
+                        _ => None,
+                    }
+                }
+                (Some(Dst::Local(to)), Var::Stack { index, .. }) => {
+                    Some(Op::MoveLocalLocal { from: *index, to })
+                }
+                (Some(to), _) => Some(Op::Move {
+                    from: self.src(r),
+                    to,
+                }),
+                (None, _) => None,
+            },
+            Cmd::Jmp { to_idx, .. } => Some(Op::Jmp { to: *to_idx }),
+            Cmd::JmpCond { cond, to_idx, .. } => Some(match self.src(cond) {
+                Src::Local(i) => Op::JmpIfLocal {
+                    cond: i,
+                    to: *to_idx,
+                },
+                s => Op::JmpIf {
+                    cond: s,
+                    to: *to_idx,
+                },
+            }),
+            Cmd::DeclareVariables { values } => values
+                .iter()
+                .map(|t| default_of(t, &self.type_table))
+                .collect::<Option<Vec<Value>>>()
+                .map(|d| Op::Declare { defaults: d.into() }),
+            Cmd::Call {
+                to_call: Var::FunctionLiteral { idx, .. },
+                returned,
+                args,
+            } => Some(Op::Call {
+                to: *idx,
+                args: args.iter().map(|a| self.src(a)).collect(),
+                returned: (*returned != Var::Unit).then(|| returned.clone()),
+            }),
+            Cmd::Return { to_return } => Some(Op::Return {
+                value: self.src(to_return),
+            }),
+            _ => None,
+        };
+        op.unwrap_or_else(|| Op::Cmd(Rc::new(c.clone())))
+    }
+    fn lower_binop(&self, l: &Var, r: &Var, out: &Var, op: &Binop) -> Option<Op> {
+        let lt = l.get_type(&self.type_table);
+        let ot = out.get_type(&self.type_table);
+        let out = self.dst(out)?;
+        let (l, r) = (self.src(l), self.src(r));
+        match lt {
+            Type::Integer => {
+                let op = arith(op)?;
+                if ot
+                    != if is_compare(op) {
+                        Type::Bool
+                    } else {
+                        Type::Integer
+                    }
+                {
+                    return None;
+                }
+                Some(match (op, &l, &r, &out) {
+                    (Arith::Add, Src::Local(l), Src::Local(r), Dst::Local(out)) => {
+                        Op::AddIntLocalLocal {
+                            l: *l,
+                            r: *r,
+                            out: *out,
+                        }
+                    }
+                    (Arith::Sub, Src::Local(l), Src::Local(r), Dst::Local(out)) => {
+                        Op::SubIntLocalLocal {
+                            l: *l,
+                            r: *r,
+                            out: *out,
+                        }
+                    }
+                    (Arith::Mul, Src::Local(l), Src::Local(r), Dst::Local(out)) => {
+                        Op::MulIntLocalLocal {
+                            l: *l,
+                            r: *r,
+                            out: *out,
+                        }
+                    }
+                    (Arith::Less, Src::Local(l), Src::Local(r), Dst::Local(out)) => {
+                        Op::LessIntLocalLocal {
+                            l: *l,
+                            r: *r,
+                            out: *out,
+                        }
+                    }
+                    (op, Src::Local(l), Src::Const(Value::Integer { v }), Dst::Local(out)) => {
+                        let (l, r, out) = (*l, *v, *out);
+                        match op {
+                            Arith::Add => Op::AddIntLocalConst { l, r, out },
+                            Arith::Sub => Op::SubIntLocalConst { l, r, out },
+                            Arith::Less => Op::LessIntLocalConst { l, r, out },
+                            Arith::Greater => Op::GreaterIntLocalConst { l, r, out },
+                            Arith::Equal => Op::EqualIntLocalConst { l, r, out },
+                            _ => Op::Int {
+                                op,
+                                l: Src::Local(l),
+                                r: Src::Const(Value::Integer { v: r }),
+                                out: Dst::Local(out),
+                            },
+                        }
+                    }
+                    _ => Op::Int { op, l, r, out },
+                })
+            }
+            Type::Float => {
+                let op = arith(op)?;
+                if ot
+                    != if is_compare(op) {
+                        Type::Bool
+                    } else {
+                        Type::Float
+                    }
+                {
+                    return None;
+                }
+                Some(Op::Float { op, l, r, out })
+            }
+            Type::Bool => {
+                let op = match op {
+                    Binop::Equal => Logic::Equal,
+                    Binop::NotEqual => Logic::NotEqual,
+                    Binop::And => Logic::And,
+                    Binop::Or => Logic::Or,
+                    _ => return None,
+                };
+                if ot != Type::Bool {
+                    return None;
+                }
+                Some(Op::Bool { op, l, r, out })
+            }
+            Type::String => {
+                let (op, want) = match op {
+                    Binop::Add => (StrOp::Add, Type::String),
+                    Binop::Mul => (StrOp::Mul, Type::String),
+                    Binop::Equal => (StrOp::Equal, Type::Bool),
+                    Binop::NotEqual => (StrOp::NotEqual, Type::Bool),
+                    _ => return None,
+                };
+                if ot != want {
+                    return None;
+                }
+                Some(Op::Str { op, l, r, out })
+            }
+            _ => None,
+        }
+    }
+
+    fn put(&mut self, at: usize, v: Value) -> Result<(), String> {
+        let Some(slot) = self.stack.get_mut(at) else {
+            return Err(format!(
+                "local {} is past the end of the stack ({} slots)",
+                at - self.v_start as usize,
+                self.stack.len()
+            ));
+        };
+        if matches!(slot, Value::Object { .. }) {
+            self.gc_dirty = true;
+        }
+        *slot = v;
+        Ok(())
+    }
+    fn push_slot(&mut self, v: Value) -> Result<(), String> {
+        if self.stack.len() as u64 > self.v_end + 1 {
+            self.put(self.v_end as usize, v)?;
+        } else {
+            self.stack.push(v);
+        }
+        self.v_end += 1;
+        Ok(())
+    }
+    fn int_at(&self, l: usize) -> Result<i64, String> {
+        match self.stack[self.v_start as usize + l] {
+            Value::Integer { v } => Ok(v),
+            _ => Err("accessed non int as int".into()),
+        }
+    }
+    fn value(&mut self, s: &Src) -> Result<Value, String> {
+        match s {
+            Src::Local(i) => Ok(self.stack[self.v_start as usize + i].clone()),
+            Src::Field(o, f) => match self.stack[self.v_start as usize + o] {
+                Value::Object { ptr: 0 } => Err("null pointer dereference".into()),
+                Value::Object { ptr } => Ok(self.heap.get(ptr as usize + f)),
+                _ => Err("accessed field of non object".into()),
+            },
+            Src::Const(v) => Ok(v.clone()),
+            Src::Var(v) => {
+                self.gc_dirty = true;
+                self.get_value(v.clone())
+            }
+        }
+    }
+    fn peek<'a>(&'a self, s: &'a Src) -> Option<&'a Value> {
+        match s {
+            Src::Local(i) => Some(&self.stack[self.v_start as usize + i]),
+            Src::Const(v) => Some(v),
+            _ => None,
+        }
+    }
+    fn read<T>(
+        &mut self,
+        s: &Src,
+        f: impl Fn(&Value) -> Option<T>,
+        err: &str,
+    ) -> Result<T, String> {
+        let v = match self.peek(s) {
+            Some(v) => f(v),
+            None => f(&self.value(s)?),
+        };
+        v.ok_or_else(|| err.into())
+    }
+    fn int(&mut self, s: &Src) -> Result<i64, String> {
+        self.read(
+            s,
+            |v| {
+                if let Value::Integer { v } = v {
+                    Some(*v)
+                } else {
+                    None
+                }
+            },
+            "accessed non int as int",
+        )
+    }
+    fn float(&mut self, s: &Src) -> Result<f64, String> {
+        self.read(
+            s,
+            |v| {
+                if let Value::Float { v } = v {
+                    Some(*v)
+                } else {
+                    None
+                }
+            },
+            "accessed non float as float",
+        )
+    }
+    fn boolean(&mut self, s: &Src) -> Result<bool, String> {
+        self.read(
+            s,
+            |v| {
+                if let Value::Bool { v } = v {
+                    Some(*v)
+                } else {
+                    None
+                }
+            },
+            "accessed non bool as bool",
+        )
+    }
+    fn string(&mut self, s: &Src) -> Result<Rc<str>, String> {
+        match self.value(s)? {
+            Value::String { v } => Ok(v),
+            _ => Err("accessed non string as string".into()),
+        }
+    }
+    fn store(&mut self, d: &Dst, v: Value) -> Result<(), String> {
+        match d {
+            Dst::Local(i) => self.put(self.v_start as usize + i, v),
+            Dst::Field(o, f) => {
+                let Value::Object { ptr } = self.stack[self.v_start as usize + o] else {
+                    return Err("assigned field of non object".into());
+                };
+                if ptr == 0 {
+                    return Err("null pointer dereference".into());
+                }
+                let slot = self.heap.get_mut(ptr as usize + f);
+                if matches!(slot, Value::Object { .. }) {
+                    self.gc_dirty = true;
+                }
+                *slot = v;
+                Ok(())
+            }
+        }
+    }
+    fn set_local(&mut self, out: usize, v: Value) -> Result<(), String> {
+        self.put(self.v_start as usize + out, v)
+    }
+
+    pub fn exec(&mut self, op: &Op) -> Result<bool, Box<dyn Error>> {
+        match op {
+            Op::AddIntLocalLocal { l, r, out } => {
+                let v = self.int_at(*l)? + self.int_at(*r)?;
+                self.set_local(*out, Value::Integer { v })?;
+            }
+            Op::AddIntLocalConst { l, r, out } => {
+                let v = self.int_at(*l)? + r;
+                self.set_local(*out, Value::Integer { v })?;
+            }
+            Op::SubIntLocalLocal { l, r, out } => {
+                let v = self.int_at(*l)? - self.int_at(*r)?;
+                self.set_local(*out, Value::Integer { v })?;
+            }
+            Op::SubIntLocalConst { l, r, out } => {
+                let v = self.int_at(*l)? - r;
+                self.set_local(*out, Value::Integer { v })?;
+            }
+            Op::MulIntLocalLocal { l, r, out } => {
+                let v = self.int_at(*l)? * self.int_at(*r)?;
+                self.set_local(*out, Value::Integer { v })?;
+            }
+            Op::LessIntLocalLocal { l, r, out } => {
+                let v = self.int_at(*l)? < self.int_at(*r)?;
+                self.set_local(*out, Value::Bool { v })?;
+            }
+            Op::LessIntLocalConst { l, r, out } => {
+                let v = self.int_at(*l)? < *r;
+                self.set_local(*out, Value::Bool { v })?;
+            }
+            Op::GreaterIntLocalConst { l, r, out } => {
+                let v = self.int_at(*l)? > *r;
+                self.set_local(*out, Value::Bool { v })?;
+            }
+            Op::EqualIntLocalConst { l, r, out } => {
+                let v = self.int_at(*l)? == *r;
+                self.set_local(*out, Value::Bool { v })?;
+            }
+            Op::Int { op, l, r, out } => {
+                let a = self.int(l)?;
+                let b = self.int(r)?;
+                self.store(out, int_op(*op, a, b)?)?;
+            }
+            Op::Float { op, l, r, out } => {
+                let a = self.float(l)?;
+                let b = self.float(r)?;
+                self.store(out, float_op(*op, a, b))?;
+            }
+            Op::Bool { op, l, r, out } => {
+                let a = self.boolean(l)?;
+                let b = self.boolean(r)?;
+                let v = match op {
+                    Logic::Equal => a == b,
+                    Logic::NotEqual => a != b,
+                    Logic::And => a && b,
+                    Logic::Or => a || b,
+                };
+                self.store(out, Value::Bool { v })?;
+            }
+            Op::Str { op, l, r, out } => {
+                let a = self.string(l)?;
+                let v = match op {
+                    StrOp::Add => Value::String {
+                        v: (a.to_string() + &self.string(r)?).into(),
+                    },
+                    StrOp::Mul => {
+                        let n = self.int(r)?;
+                        if n < 0 {
+                            return Err("negative string repeat count".into());
+                        }
+                        Value::String {
+                            v: a.repeat(n as usize).into(),
+                        }
+                    }
+                    StrOp::Equal => Value::Bool {
+                        v: a == self.string(r)?,
+                    },
+                    StrOp::NotEqual => Value::Bool {
+                        v: a != self.string(r)?,
+                    },
+                };
+                self.store(out, v)?;
+            }
+            Op::MoveLocalLocal { from, to } => {
+                let v = self.stack[self.v_start as usize + from].clone();
+                self.set_local(*to, v)?;
+            }
+            Op::Move { from, to } => {
+                let v = self.value(from)?;
+                self.store(to, v)?;
+            }
+            Op::New {
+                to,
+                type_idx,
+                defaults,
+            } => {
+                let ptr = self.heap.allocate(defaults.len(), *type_idx).unwrap();
+                for (i, d) in defaults.iter().enumerate() {
+                    *self.heap.get_mut(ptr as usize + i + 1) = d.clone();
+                }
+                self.gc_dirty = true;
+                self.store(to, Value::Object { ptr: ptr as u64 })?;
+            }
+            Op::Jmp { to } => {
+                self.ip = *to as u64;
+            }
+            Op::JmpIfLocal { cond, to } => match self.stack[self.v_start as usize + cond] {
+                Value::Bool { v } => {
+                    if v {
+                        self.ip = *to as u64;
+                    }
+                }
+                _ => return Err("accessed non bool as bool".into()),
+            },
+            Op::JmpIf { cond, to } => {
+                if self.boolean(cond)? {
+                    self.ip = *to as u64;
+                }
+            }
+            Op::Declare { defaults } => {
+                for d in defaults.iter() {
+                    self.push_slot(d.clone())?;
+                }
+            }
+            Op::Call { to, args, returned } => {
+                let f = Frame {
+                    ip: self.ip,
+                    v_start: self.v_start,
+                    v_end: self.v_end,
+                    to_return: self.to_return.take(),
+                };
+                let old = self.v_end;
+                for a in args.iter() {
+                    let v = self.value(a)?;
+                    self.push_slot(v)?;
+                }
+                self.ip = *to as u64;
+                self.v_start = old;
+                self.frames.push(f);
+                self.to_return = returned.clone();
+            }
+            Op::Return { value } => {
+                let Some(base) = self.frames.pop() else {
+                    self.result = Some(self.value(value)?);
+                    self.done = true;
+                    return Ok(false);
+                };
+                let rv = self.value(value)?;
+                self.ip = base.ip;
+                self.v_start = base.v_start;
+                self.v_end = base.v_end;
+                let Some(ret) = self.to_return.take() else {
+                    self.to_return = base.to_return;
+                    return Ok(false);
+                };
+                let t = self.get_l_value(ret)?;
+                let dropped = matches!(t, Value::Object { .. });
+                *t = rv;
+                self.gc_dirty |= dropped;
+                self.to_return = base.to_return;
+            }
+            Op::Cmd(c) => {
+                self.gc_dirty = true;
+                return self.exec_cmd(c.as_ref().clone());
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mach::{RunResult, Value};
+
+    fn run(src: &str) -> RunResult {
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        crate::parser::link(&[p]).run(100_000)
+    }
+
+    #[test]
+    fn lowered_loop_matches_source_semantics() {
+        let r = run("fn int main:
+	i:int = 0
+	sum:int = 0
+	b:bool = false
+	label top
+	b = i < 100
+	if b goto body
+	return sum
+	label body
+	sum = sum + i
+	i = i + 1
+	goto top
+end
+");
+        assert!(matches!(r, RunResult::Finished(Value::Integer { v: 4950 })));
+    }
+
+    #[test]
+    fn runtime_faults_are_errors() {
+        let cases = [
+            (
+                "	d:int = 0\n	x:int = 10 / d\n	return x\n",
+                "division by zero",
+            ),
+            (
+                "	m:int = 0 - 9223372036854775807\n	m = m - 1\n	d:int = 0 - 1\n	x:int = m / d\n	return x\n",
+                "integer overflow",
+            ),
+        ];
+        for (body, want) in cases {
+            let src = format!("fn int main:\n{body}end\n");
+            let RunResult::Error(e) = run(&src) else {
+                panic!("expected {want}");
+            };
+            assert!(e.to_string().contains(want), "{e}");
+        }
+    }
+}
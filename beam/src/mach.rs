@@ -1,12 +1,19 @@
 pub use std::collections::HashSet;
 pub use std::rc::Rc;
-use std::{cell::UnsafeCell, collections::HashMap, error::Error, sync::Arc};
+use crate::lower::Op;
+use std::{
+    cell::{RefCell, UnsafeCell},
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+};
 #[derive(Clone, Debug, PartialEq)]
 pub struct ShallowType {
     pub name: Rc<str>,
     pub index: u64,
     pub array_count: u64,
     pub is_ptr: bool,
+    pub is_weak: bool,
 }
 #[derive(Clone, Debug, PartialEq)]
 pub enum Type {
@@ -15,12 +22,17 @@ pub enum Type {
     Float,
     Bool,
     String,
+    Pid,
     Ptr {
         to: ShallowType,
     },
+    Weak {
+        to: ShallowType,
+    },
     Struct {
         name: Rc<str>,
         fields: Rc<[(Rc<str>, ShallowType)]>,
+        finalizer: Option<Rc<str>>,
     },
     Function {
         from: Vec<Type>,
@@ -36,6 +48,7 @@ impl Type {
             Self::Float => true,
             Self::Bool => true,
             Self::String => true,
+            Self::Pid => true,
             _ => false,
         }
     }
@@ -73,6 +86,17 @@ pub enum Var {
     OperatorNew {
         new_type: ShallowType,
     },
+    MakeWeak {
+        of: Rc<Var>,
+    },
+    Upgrade {
+        of: Rc<Var>,
+    },
+    Alive {
+        of: Rc<Var>,
+    },
+    SelfPid,
+    Now,
 }
 #[derive(Clone, Debug, PartialEq)]
 pub enum Binop {
@@ -121,6 +145,43 @@ pub enum Cmd {
         returned: Var,
         args: Rc<[Var]>,
     },
+    Spawn {
+        to_call: Var,
+        returned: Var,
+        args: Rc<[Var]>,
+    },
+    Send {
+        to: Var,
+        msg: Var,
+    },
+    Receive {
+        returned: Var,
+        msg_type: ShallowType,
+        timeout: Option<Var>,
+        to: Rc<str>,
+        to_idx: usize,
+    },
+    SpawnNamed {
+        name: Var,
+        returned: Var,
+    },
+    Link {
+        to: Var,
+    },
+    Monitor {
+        to: Var,
+    },
+    TrapExit,
+    Exit {
+        to: Var,
+        reason: Var,
+    },
+    Fail {
+        reason: Var,
+    },
+    Sleep {
+        ms: Var,
+    },
     Return {
         to_return: Var,
     },
@@ -133,6 +194,8 @@ pub enum Value {
     String { v: Rc<str> },
     Bool { v: bool },
     Object { ptr: u64 },
+    Weak { ptr: u64 },
+    Pid { v: u64 },
     ObjectHeader { information: u32, size: u32 },
 }
 #[derive(Clone, Debug)]
@@ -142,16 +205,87 @@ pub struct Frame {
     pub v_end: u64,
     pub to_return: Option<Var>,
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle(pub u64);
+#[derive(Clone, Debug, Default)]
+pub struct HandleTable {
+    pub next: u64,
+    pub slots: HashMap<u64, Value>,
+}
+#[derive(Clone, Debug, Default)]
+pub struct Handles {
+    pub v: Rc<RefCell<HandleTable>>,
+}
+impl Handles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn pin(&self, v: Value) -> Handle {
+        let mut t = self.v.borrow_mut();
+        t.next += 1;
+        let h = t.next;
+        t.slots.insert(h, v);
+        Handle(h)
+    }
+    pub fn unpin(&self, h: Handle) -> Option<Value> {
+        self.v.borrow_mut().slots.remove(&h.0)
+    }
+    pub fn get(&self, h: Handle) -> Option<Value> {
+        self.v.borrow().slots.get(&h.0).cloned()
+    }
+    pub fn set(&self, h: Handle, v: Value) -> bool {
+        match self.v.borrow_mut().slots.get_mut(&h.0) {
+            Some(s) => {
+                *s = v;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn roots(&self) -> Vec<Value> {
+        self.v.borrow().slots.values().cloned().collect()
+    }
+    pub fn scope(&self) -> HandleScope {
+        HandleScope {
+            handles: self.clone(),
+            pinned: Vec::new(),
+        }
+    }
+}
+pub struct HandleScope {
+    pub handles: Handles,
+    pub pinned: Vec<Handle>,
+}
+impl HandleScope {
+    pub fn pin(&mut self, v: Value) -> Handle {
+        let h = self.handles.pin(v);
+        self.pinned.push(h);
+        h
+    }
+    pub fn get(&self, h: Handle) -> Option<Value> {
+        self.handles.get(h)
+    }
+}
+impl Drop for HandleScope {
+    fn drop(&mut self) {
+        for h in self.pinned.drain(..) {
+            self.handles.unpin(h);
+        }
+    }
+}
+pub type NativeFn = Arc<dyn Fn(&[Value]) -> Value + Send + Sync>;
 #[derive(Clone)]
 pub struct NativeInterface{
-    pub funcs:HashMap<String,&'static dyn Fn(&[Value])->Value>,
+    pub funcs:HashMap<String,NativeFn>,
     pub to_load:HashSet<String>,
+    pub handles: Handles,
+    pub log: Option<Arc<Mutex<crate::replay::NativeLog>>>,
 }
 impl std::fmt::Debug for NativeInterface{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = Vec::new();
         for i in &self.funcs{
-            s.push((i.0, i.1 as *const _ as *const ()));
+            s.push((i.0, Arc::as_ptr(i.1) as *const ()));
         }
         write!(f, "{:#?}",s)
     }
@@ -159,14 +293,17 @@ impl std::fmt::Debug for NativeInterface{
 
 impl NativeInterface{
     pub fn new()->Self{
-        let mut out = Self { funcs: HashMap::new() , to_load:HashSet::new()};
+        let mut out = Self { funcs: HashMap::new() , to_load:HashSet::new(), handles: Handles::new(), log: None};
         fn f(args:&[Value])->Value{
             println!("testing 1 2 3{:#?}", args);
             Value::Integer { v: 10 }
         }
-        out.funcs.insert("nt_test".to_string(), &f);
+        out.funcs.insert("nt_test".to_string(), Arc::new(f));
         out
     }
+    pub fn register(&mut self, name: &str, f: impl Fn(&[Value]) -> Value + Send + Sync + 'static) {
+        self.funcs.insert(name.to_string(), Arc::new(f));
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -183,6 +320,17 @@ pub struct Machine {
     pub stack: Vec<Value>,
     pub done: bool,
     pub native_fns:NativeInterface,
+    pub finalized: HashSet<u32>,
+    pub finalize_queue: Vec<(u32, usize)>,
+    pub result: Option<Value>,
+    pub ops: Rc<[Op]>,
+    pub gc_dirty: bool,
+}
+#[derive(Debug)]
+pub enum RunResult {
+    Finished(Value),
+    Paused,
+    Error(Box<dyn Error>),
 }
 #[derive(Clone, Debug)]
 pub struct Function {
@@ -252,12 +400,10 @@ impl HeapInternal {
     pub fn allocate(&mut self, count: usize, typeheader: u32) -> Option<u32> {
         let needed = count as u32 + 1;
         let mut base = 0;
-        let mut end = 0;
         let mut idx = -1;
         for i in 0..self.free_list.len() {
             if self.free_list[i].1 - self.free_list[i].0 >= needed {
                 base = self.free_list[i].0;
-                end = self.free_list[i].1;
                 idx = i as i64;
             }
         }
@@ -266,7 +412,6 @@ impl HeapInternal {
             for i in 0..self.free_list.len() {
                 if self.free_list[i].1 - self.free_list[i].0 >= needed {
                     base = self.free_list[i].0;
-                    end = self.free_list[i].1;
                     idx = i as i64;
                 }
             }
@@ -274,7 +419,7 @@ impl HeapInternal {
                 return None;
             }
         }
-        for i in base..end {
+        for i in base..base + needed {
             self.tracking[i as usize] = true;
         }
         let udx = idx as usize;
@@ -292,7 +437,7 @@ impl HeapInternal {
         Some(base)
     }
     pub fn free(&mut self, start: u32) -> Result<(), u32> {
-        let base = start - 1;
+        let base = start;
         if !self.tracking[base as usize] {
             println!("attempted to free unallocated object:{start}");
             return Err(start);
@@ -308,6 +453,7 @@ impl HeapInternal {
         for i in base..base + size + 1 {
             self.tracking[i as usize] = false;
         }
+        self.allocations.remove(&(base, size));
         self.free_list.push((base, base + size + 1));
         Ok(())
     }
@@ -360,7 +506,9 @@ impl Type {
             Type::Bool => Ok(Value::Bool { v: false }),
             Type::String => Ok(Value::String { v: "".into() }),
             Type::Ptr { to: _ } => Ok(Value::Object { ptr: 0 }),
-            Type::Struct { name: _, fields: _ } => todo!(),
+            Type::Weak { to: _ } => Ok(Value::Weak { ptr: 0 }),
+            Type::Pid => Ok(Value::Pid { v: 0 }),
+            Type::Struct { .. } => todo!(),
             Type::Function {
                 from: _,
                 to: _,
@@ -370,7 +518,7 @@ impl Type {
     }
     pub fn get_size(&self, types: &[(Rc<str>, Type)]) -> usize {
         match self {
-            Type::Struct { name: _, fields } => {
+            Type::Struct { fields, .. } => {
                 let mut out = 0;
                 for i in fields.iter() {
                     out += i.1.as_type(types).get_size(types);
@@ -383,7 +531,12 @@ impl Type {
 }
 impl ShallowType {
     pub fn as_type(&self, type_table: &[(Rc<str>, Type)]) -> Type {
-        if self.is_ptr {
+        if self.is_weak {
+            let mut tmp = self.clone();
+            tmp.is_weak = false;
+            tmp.is_ptr = false;
+            Type::Weak { to: tmp }
+        } else if self.is_ptr {
             let mut tmp = self.clone();
             tmp.is_ptr = false;
             Type::Ptr { to: tmp }
@@ -400,7 +553,9 @@ impl Var {
                 index: _,
                 name: _,
             } => {
-                if vtype.is_ptr {
+                if vtype.is_weak {
+                    vtype.as_type(type_table)
+                } else if vtype.is_ptr {
                     let mut vt = vtype.clone();
                     vt.is_ptr = false;
                     Type::Ptr { to: vt }
@@ -418,7 +573,9 @@ impl Var {
                 index: _,
                 return_type,
             } => {
-                if return_type.is_ptr {
+                if return_type.is_weak {
+                    return_type.as_type(type_table)
+                } else if return_type.is_ptr {
                     let mut vt = return_type.clone();
                     vt.is_ptr = false;
                     Type::Ptr { to: vt }
@@ -434,6 +591,19 @@ impl Var {
             Var::OperatorNew { new_type } => Type::Ptr {
                 to: new_type.clone(),
             },
+            // The parser rejects weak and upgrade of anything else, and void
+            // matches no variable, so a bad operand still fails type checking.
+            Var::MakeWeak { of } => match of.get_type(type_table) {
+                Type::Ptr { to } => Type::Weak { to },
+                _ => Type::Void,
+            },
+            Var::Upgrade { of } => match of.get_type(type_table) {
+                Type::Weak { to } => Type::Ptr { to },
+                _ => Type::Void,
+            },
+            Var::Alive { of: _ } => Type::Bool,
+            Var::SelfPid => Type::Pid,
+            Var::Now => Type::Integer,
         }
     }
 }
@@ -463,6 +633,7 @@ impl Machine {
             } => {
                 let v = self.get_value((*of).clone())?;
                 match v {
+                    Value::Object { ptr: 0 } => return Err("null pointer dereference".into()),
                     Value::Object { ptr } => {
                         let var = self.heap.get_mut(ptr as usize + index + 1);
                         return Ok(var);
@@ -495,6 +666,7 @@ impl Machine {
             } => {
                 let v = self.get_value((*of).clone())?;
                 match v {
+                    Value::Object { ptr: 0 } => Err("null pointer dereference".into()),
                     Value::Object { ptr } => Ok(self.heap.get(ptr as usize + index + 1)),
                     _ => {
                         todo!();
@@ -504,7 +676,7 @@ impl Machine {
             Var::OperatorNew { new_type } => {
                 let vt = new_type.as_type(&self.type_table);
                 let fields = match &vt {
-                    Type::Struct { name: _, fields } => fields.clone(),
+                    Type::Struct { fields, .. } => fields.clone(),
                     _ => todo!(),
                 };
                 let sz = vt.get_size(&self.type_table);
@@ -518,6 +690,20 @@ impl Machine {
                 }
                 Ok(Value::Object { ptr: ptr as u64 })
             }
+            Var::MakeWeak { of } => match self.get_value((*of).clone())? {
+                Value::Object { ptr } => Ok(Value::Weak { ptr }),
+                _ => Err("weak of non pointer".into()),
+            },
+            Var::Upgrade { of } => match self.get_value((*of).clone())? {
+                Value::Weak { ptr } => Ok(Value::Object { ptr }),
+                _ => Err("upgrade of non weak".into()),
+            },
+            Var::Alive { of } => match self.get_value((*of).clone())? {
+                Value::Weak { ptr } => Ok(Value::Bool { v: ptr != 0 }),
+                _ => Err("alive of non weak".into()),
+            },
+            Var::SelfPid => Err("pids are only supported by the fast vm".into()),
+            Var::Now => Err("timers are only supported by the fast vm".into()),
             _ => {
                 todo!()
             }
@@ -547,10 +733,87 @@ impl Machine {
             _ => Err("accessed non string as string".into()),
         }
     }
+    pub fn finalizer_for(&self, ptr: u32) -> Option<usize> {
+        let Value::ObjectHeader {
+            information,
+            size: _,
+        } = self.heap.get(ptr as usize)
+        else {
+            return None;
+        };
+        match &self.type_table.get(information as usize)?.1 {
+            Type::Struct {
+                finalizer: Some(f),
+                ..
+            } => self.symbol_table.get(f.as_ref()).copied(),
+            _ => None,
+        }
+    }
+    pub fn run_pending_finalizer(&mut self) {
+        if self.finalize_queue.is_empty() || self.done {
+            return;
+        }
+        let (ptr, loc) = self.finalize_queue.remove(0);
+        self.gc_dirty = true;
+        let f = Frame {
+            ip: self.ip,
+            v_start: self.v_start,
+            v_end: self.v_end,
+            to_return: self.to_return.take(),
+        };
+        let old = self.v_end;
+        if self.stack.len() as u64 > self.v_end + 1 {
+            self.stack[self.v_end as usize] = Value::Object { ptr: ptr as u64 };
+        } else {
+            self.stack.push(Value::Object { ptr: ptr as u64 });
+        }
+        self.v_end += 1;
+        self.ip = loc as u64;
+        self.v_start = old;
+        self.frames.push(f);
+    }
+    pub fn run(&mut self, fuel: u64) -> RunResult {
+        if self.ops.len() != self.cmds.len() {
+            self.resolve();
+        }
+        let ops = self.ops.clone();
+        for _ in 0..fuel {
+            if self.done {
+                break;
+            }
+            if let Err(e) = self.step(&ops) {
+                return RunResult::Error(e);
+            }
+        }
+        if self.done {
+            RunResult::Finished(self.result.clone().unwrap_or(Value::Unit))
+        } else {
+            RunResult::Paused
+        }
+    }
     pub fn update(&mut self) -> Result<(), Box<dyn Error>> {
-        let ins = self.cmds[self.ip as usize].clone();
-        //println!("{:#?}",ins);
+        if self.ops.len() != self.cmds.len() {
+            self.resolve();
+        }
+        let ops = self.ops.clone();
+        self.step(&ops)
+    }
+    #[inline(always)]
+    fn step(&mut self, ops: &[Op]) -> Result<(), Box<dyn Error>> {
+        let op = &ops[self.ip as usize];
         self.ip += 1;
+        if self.exec(op)? {
+            if self.gc_dirty {
+                self.gc_dirty = false;
+                self.gc_check();
+            }
+            if !self.finalize_queue.is_empty() {
+                self.run_pending_finalizer();
+            }
+        }
+        Ok(())
+    }
+    pub fn exec_cmd(&mut self, ins: Cmd) -> Result<bool, Box<dyn Error>> {
         match ins {
             Cmd::Binop { l, r, out, op } => {
                 let lt = l.get_type(&self.type_table);
@@ -759,7 +1022,13 @@ impl Machine {
                     Type::Ptr { to: _ } => {
                         return Err("binop not supported on pointers".into());
                     }
-                    Type::Struct { name: _, fields: _ } => {
+                    Type::Weak { to: _ } => {
+                        return Err("binop not supported on weak pointers".into());
+                    }
+                    Type::Pid => {
+                        return Err("binop not supported on pids".into());
+                    }
+                    Type::Struct { .. } => {
                         return Err("binop not supported on structures".into());
                     }
                     Type::Function {
@@ -843,9 +1112,9 @@ impl Machine {
             }
             Cmd::Return { to_return } => {
                 let Some(base) = self.frames.pop() else {
-                    println!("exited with:{:#?}", self.get_value(to_return));
+                    self.result = Some(self.get_value(to_return)?);
                     self.done = true;
-                    return Ok(());
+                    return Ok(false);
                 };
                 let rv = self.get_value(to_return)?;
                 self.ip = base.ip;
@@ -853,7 +1122,7 @@ impl Machine {
                 self.v_end = base.v_end;
                 if self.to_return.is_none() {
                     self.to_return = base.to_return;
-                    return Ok(());
+                    return Ok(false);
                 }
                 let ret = self.to_return.take().unwrap();
                 if let Ok(t) = self.get_l_value(ret) {
@@ -864,21 +1133,44 @@ impl Machine {
                 }
             }
             Cmd::CallNative { to_call, returned, args }=>{
-                let Some(f)= self.native_fns.funcs.get(&to_call)else {
-                    todo!()
-                };
+                if crate::io::is_io_native(&to_call) {
+                    return Err("io natives are only supported by the fast vm".into());
+                }
                 let mut vals = Vec::new();
                 for i in args.iter(){
                     vals.push(self.get_value(i.clone())?);
                 }
-                let rv = (*f)(&vals);
+                let rv = match self.replay_native(&to_call, &vals) {
+                    Some(rv) => rv?,
+                    None => {
+                        let Some(f)= self.native_fns.funcs.get(&to_call)else {
+                            return Err(format!("unknown native {}", to_call).into());
+                        };
+                        let rv = (*f)(&vals);
+                        self.record_native(&to_call, &vals, &rv);
+                        rv
+                    }
+                };
                 if let Ok(lv ) = self.get_l_value(returned){
                    *lv = rv; 
                 }
             }
+            Cmd::Spawn { .. }
+            | Cmd::Send { .. }
+            | Cmd::Receive { .. }
+            | Cmd::SpawnNamed { .. }
+            | Cmd::Link { .. }
+            | Cmd::Monitor { .. }
+            | Cmd::TrapExit
+            | Cmd::Exit { .. }
+            | Cmd::Fail { .. } => {
+                return Err("processes are only supported by the fast vm".into());
+            }
+            Cmd::Sleep { .. } => {
+                return Err("timers are only supported by the fast vm".into());
+            }
         }
-        self.gc_check();
-        Ok(())
+        Ok(true)
     }
     pub fn gc_mark(&mut self, var: Value, reachable: &mut HashSet<u32>) {
         match var {
@@ -926,9 +1218,27 @@ impl Machine {
         for i in self.stack.clone() {
             self.gc_mark(i, &mut reachable_list);
         }
-        for i in self.stack.clone() {
+        for i in self.native_fns.handles.roots() {
             self.gc_mark(i, &mut reachable_list);
         }
+        for i in self.finalize_queue.clone() {
+            self.gc_mark(Value::Object { ptr: i.0 as u64 }, &mut reachable_list);
+        }
+        let m = unsafe { &*self.heap.v.get() };
+        let mut to_finalize = Vec::new();
+        for i in &m.allocations {
+            if !reachable_list.contains(&i.0)
+                && !self.finalized.contains(&i.0)
+                && let Some(f) = self.finalizer_for(i.0)
+            {
+                to_finalize.push((i.0, f));
+            }
+        }
+        for i in to_finalize {
+            self.finalized.insert(i.0);
+            self.finalize_queue.push(i);
+            self.gc_mark(Value::Object { ptr: i.0 as u64 }, &mut reachable_list);
+        }
         let m = unsafe { &*self.heap.v.get() };
         let mut to_free_list = Vec::new();
         for i in &m.allocations {
@@ -936,9 +1246,33 @@ impl Machine {
                 to_free_list.push(i.0);
             }
         }
+        let freed: HashSet<u32> = to_free_list.iter().copied().collect();
         for i in to_free_list {
+            self.finalized.remove(&i);
             self.heap.free(i).unwrap();
         }
+        if !freed.is_empty() {
+            self.gc_clear_weak(&freed);
+        }
+    }
+    pub fn gc_clear_weak(&mut self, freed: &HashSet<u32>) {
+        for i in self.stack.iter_mut() {
+            if let Value::Weak { ptr } = i
+                && freed.contains(&(*ptr as u32))
+            {
+                *ptr = 0;
+            }
+        }
+        let m = unsafe { &*self.heap.v.get() };
+        for i in &m.allocations {
+            for j in 1..i.1 + 1 {
+                if let Value::Weak { ptr } = self.heap.get_mut((i.0 + j) as usize)
+                    && freed.contains(&(*ptr as u32))
+                {
+                    *ptr = 0;
+                }
+            }
+        }
     }
     pub fn gc_check(&mut self) {
         self.gc_collect();
@@ -1,9 +1,7 @@
-use crate::fast::IntermediateRt;
+use beam::fast::IntermediateRt;
+use beam::mach::RunResult;
+use beam::{aot, fast, jit, parser, profile, timer, wasm};
 
-pub mod fast;
-pub mod heap;
-pub mod mach;
-pub mod parser;
 pub struct Timer {
     start: std::time::Instant,
 }
@@ -28,7 +26,9 @@ pub fn compile() -> IntermediateRt {
     println!("{:#?}", p);
     let std = include_str!("../std.beam");
     let p2 = parser::parse_to_program(std.to_string(), "std.beam".into()).unwrap();
-    let f = fast::compile_mach_to_ir(&[p, p2]);
+    let sup = include_str!("../supervisor.beam");
+    let p3 = parser::parse_to_program(sup.to_string(), "supervisor.beam".into()).unwrap();
+    let f = fast::compile_mach_to_ir(&[p, p2, p3]).unwrap();
     f
 }
 pub fn fast() {
@@ -43,19 +43,75 @@ pub fn fast() {
         let s = std::fs::read("test.bin").unwrap();
         let rt: IntermediateRt = rmp_serde::from_slice(&s).unwrap();
         let mut f = fast::rt_from_intermediate_rt(rt);
-        while !f.halted {
-            f.step().unwrap();
+        if std::env::var("BEAM_VERIFY_HEAP").is_ok() {
+            f.enable_heap_verification();
+        }
+        if let Ok(threads) = std::env::var("BEAM_THREADS") {
+            f.set_scheduler_threads(threads.parse().unwrap_or(1));
+        }
+        if std::env::var("BEAM_VIRTUAL_CLOCK").is_ok() {
+            f.set_clock(std::sync::Arc::new(timer::VirtualClock::new()));
+        }
+        if let Ok(threshold) = std::env::var("BEAM_JIT") {
+            f.enable_jit(threshold.parse().unwrap_or(jit::DEFAULT_JIT_THRESHOLD));
+        }
+        if std::env::var("BEAM_PROFILE").is_ok() {
+            f.start_profile();
+        }
+        match f.run(u64::MAX) {
+            RunResult::Finished(v) => println!("returned: {:#?}", v),
+            RunResult::Paused => {}
+            RunResult::Error(e) => println!("error: {}", e),
+        }
+        if let Some(p) = f.take_profile() {
+            print!("{}", p);
         }
     }
 }
+fn profile(paths: &str) {
+    let mut total = profile::OpcodeProfile::new();
+    for path in paths.split(',') {
+        let src = std::fs::read_to_string(path).unwrap();
+        let p = parser::parse_to_program(src, path.into()).unwrap();
+        match profile::profile_unfused(&[p], u64::MAX) {
+            Ok(p) => total.merge(&p),
+            Err(e) => println!("error: {}: {}", path, e),
+        }
+    }
+    print!("{}", total);
+}
 fn main() {
+    if let Ok(paths) = std::env::var("BEAM_PAIR_PROFILE") {
+        profile(&paths);
+        return;
+    }
     let s = include_str!("../main.beam");
     let p = parser::parse_to_program(s.to_string(), "main.beam".into()).unwrap();
     println!("{:#?}", p);
     let std = include_str!("../std.beam");
     let p2 = parser::parse_to_program(std.to_string(), "std.beam".into()).unwrap();
-    let mut machine = parser::link(&[p, p2]);
-    while !machine.done {
-        machine.update().unwrap();
+    let progs = [p, p2];
+    if let Ok(out) = std::env::var("BEAM_AOT") {
+        match aot::build_native(&progs, &out) {
+            Ok(()) => println!("wrote {}", out),
+            Err(e) => println!("error: {}", e),
+        }
+        return;
+    }
+    if let Ok(out) = std::env::var("BEAM_WASM") {
+        match wasm::build_wasm(&progs, &out) {
+            Ok(info) => println!("wrote {} ({} functions)", out, info.functions),
+            Err(e) => println!("error: {}", e),
+        }
+        return;
+    }
+    let mut machine = parser::link(&progs);
+    match machine.run(u64::MAX) {
+        RunResult::Finished(v) => println!("exited with:{:#?}", v),
+        RunResult::Paused => {}
+        RunResult::Error(e) => println!("error: {}", e),
+    }
+    if let Ok(path) = std::env::var("BEAM_HEAP_SNAPSHOT") {
+        machine.heap_snapshot().write(&path).unwrap();
     }
 }
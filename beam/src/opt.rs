@@ -0,0 +1,638 @@
+use crate::mach::{Binop, Cmd, Function, Program, Type, Var};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Passes {
+    pub const_fold: bool,
+    pub copy_prop: bool,
+    pub dead_store: bool,
+    pub dead_code: bool,
+    pub jump_thread: bool,
+    pub unused_labels: bool,
+}
+impl Default for Passes {
+    fn default() -> Self {
+        Self {
+            const_fold: true,
+            copy_prop: true,
+            dead_store: true,
+            dead_code: true,
+            jump_thread: true,
+            unused_labels: true,
+        }
+    }
+}
+impl Passes {
+    pub fn none() -> Self {
+        Self {
+            const_fold: false,
+            copy_prop: false,
+            dead_store: false,
+            dead_code: false,
+            jump_thread: false,
+            unused_labels: false,
+        }
+    }
+}
+
+pub const MAX_ROUNDS: usize = 8;
+
+pub fn optimize(mut p: Program, passes: &Passes) -> Program {
+    for f in p.functions.values_mut() {
+        if f.is_header {
+            continue;
+        }
+        optimize_function(f, &p.types, passes);
+    }
+    p
+}
+
+pub fn optimize_function(f: &mut Function, types: &[(Rc<str>, Type)], passes: &Passes) {
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+        if passes.copy_prop {
+            changed |= copy_prop(f);
+        }
+        if passes.const_fold {
+            changed |= const_fold(f, types);
+        }
+        if passes.jump_thread {
+            changed |= jump_thread(f);
+        }
+        if passes.dead_code {
+            changed |= dead_code(f);
+        }
+        if passes.dead_store {
+            changed |= dead_store(f);
+        }
+        if passes.unused_labels {
+            changed |= unused_labels(f);
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+fn branch_target(cmd: &Cmd) -> Option<&Rc<str>> {
+    match cmd {
+        Cmd::Jmp { to, .. } | Cmd::JmpCond { to, .. } => Some(to),
+        Cmd::Receive {
+            timeout: Some(_),
+            to,
+            ..
+        } => Some(to),
+        _ => None,
+    }
+}
+
+fn successors(f: &Function, i: usize) -> Vec<usize> {
+    let target = branch_target(&f.cmds[i]).and_then(|t| f.labels.get(t.as_ref()).copied());
+    match &f.cmds[i] {
+        Cmd::Jmp { .. } => target.into_iter().collect(),
+        Cmd::Return { .. } | Cmd::Fail { .. } => Vec::new(),
+        _ => std::iter::once(i + 1).chain(target).collect(),
+    }
+}
+
+fn leaders(f: &Function) -> HashSet<usize> {
+    let mut out: HashSet<usize> = f.labels.values().copied().collect();
+    for (i, c) in f.cmds.iter().enumerate() {
+        if branch_target(c).is_some() || matches!(c, Cmd::Return { .. } | Cmd::Fail { .. }) {
+            out.insert(i + 1);
+        }
+    }
+    out
+}
+
+fn written(cmd: &Cmd) -> Option<&Var> {
+    match cmd {
+        Cmd::Binop { out, .. } => Some(out),
+        Cmd::Assign { l, .. } => Some(l),
+        Cmd::Call { returned, .. }
+        | Cmd::CallNative { returned, .. }
+        | Cmd::Spawn { returned, .. }
+        | Cmd::SpawnNamed { returned, .. }
+        | Cmd::Receive { returned, .. } => Some(returned),
+        _ => None,
+    }
+}
+
+fn written_slot(cmd: &Cmd) -> Option<usize> {
+    match written(cmd)? {
+        Var::Stack { index, .. } => Some(*index),
+        _ => None,
+    }
+}
+
+fn map_args(args: &mut Rc<[Var]>, f: &mut impl FnMut(&mut Var)) {
+    let mut v = args.to_vec();
+    v.iter_mut().for_each(&mut *f);
+    *args = v.into();
+}
+
+fn map_target(v: &mut Var, f: &mut impl FnMut(&mut Var)) {
+    if let Var::FieldAccess { of, .. } = v {
+        f(Rc::make_mut(of));
+    }
+}
+
+fn map_reads(cmd: &mut Cmd, f: &mut impl FnMut(&mut Var)) {
+    match cmd {
+        Cmd::Binop { l, r, out, .. } => {
+            f(l);
+            f(r);
+            map_target(out, f);
+        }
+        Cmd::Assign { l, r } => {
+            f(r);
+            map_target(l, f);
+        }
+        Cmd::JmpCond { cond, .. } => f(cond),
+        Cmd::Call { returned, args, .. }
+        | Cmd::CallNative { returned, args, .. }
+        | Cmd::Spawn { returned, args, .. } => {
+            map_args(args, f);
+            map_target(returned, f);
+        }
+        Cmd::SpawnNamed { name, returned } => {
+            f(name);
+            map_target(returned, f);
+        }
+        Cmd::Send { to, msg } => {
+            f(to);
+            f(msg);
+        }
+        Cmd::Receive {
+            returned, timeout, ..
+        } => {
+            if let Some(t) = timeout {
+                f(t);
+            }
+            map_target(returned, f);
+        }
+        Cmd::Link { to } | Cmd::Monitor { to } => f(to),
+        Cmd::Exit { to, reason } => {
+            f(to);
+            f(reason);
+        }
+        Cmd::Fail { reason } => f(reason),
+        Cmd::Sleep { ms } => f(ms),
+        Cmd::Return { to_return } => f(to_return),
+        Cmd::Jmp { .. } | Cmd::DeclareVariables { .. } | Cmd::TrapExit => {}
+    }
+}
+
+fn stack_reads(v: &Var, out: &mut Vec<usize>) {
+    match v {
+        Var::Stack { index, .. } => out.push(*index),
+        Var::FieldAccess { of, .. }
+        | Var::MakeWeak { of }
+        | Var::Upgrade { of }
+        | Var::Alive { of } => stack_reads(of, out),
+        _ => {}
+    }
+}
+
+fn reads(cmd: &Cmd) -> Vec<usize> {
+    let mut out = Vec::new();
+    map_reads(&mut cmd.clone(), &mut |v| stack_reads(v, &mut out));
+    out
+}
+
+fn substitute(v: &mut Var, copies: &HashMap<usize, Var>) -> bool {
+    match v {
+        Var::Stack { index, .. } => match copies.get(index) {
+            Some(r) => {
+                *v = r.clone();
+                true
+            }
+            None => false,
+        },
+        Var::FieldAccess { of, .. }
+        | Var::MakeWeak { of }
+        | Var::Upgrade { of }
+        | Var::Alive { of } => substitute(Rc::make_mut(of), copies),
+        _ => false,
+    }
+}
+
+fn remove_cmds(f: &mut Function, keep: &[bool]) -> bool {
+    if keep.iter().all(|k| *k) {
+        return false;
+    }
+    let mut map = Vec::with_capacity(keep.len() + 1);
+    let mut kept = 0;
+    for k in keep {
+        map.push(kept);
+        if *k {
+            kept += 1;
+        }
+    }
+    map.push(kept);
+    for v in f.labels.values_mut() {
+        *v = map[*v];
+    }
+    let mut idx = 0;
+    f.cmds.retain(|_| {
+        idx += 1;
+        keep[idx - 1]
+    });
+    true
+}
+
+pub fn copy_prop(f: &mut Function) -> bool {
+    let leaders = leaders(f);
+    let mut copies: HashMap<usize, Var> = HashMap::new();
+    let mut changed = false;
+    for i in 0..f.cmds.len() {
+        if leaders.contains(&i) {
+            copies.clear();
+        }
+        let cmd = &mut f.cmds[i];
+        map_reads(cmd, &mut |v| changed |= substitute(v, &copies));
+        let Some(slot) = written_slot(cmd) else {
+            continue;
+        };
+        copies.remove(&slot);
+        copies.retain(|_, v| !matches!(v, Var::Stack { index, .. } if *index == slot));
+        if let Cmd::Assign { r, .. } = cmd {
+            match r {
+                Var::Stack { index, .. } if *index == slot => {}
+                Var::Stack { .. }
+                | Var::ConstInt { .. }
+                | Var::ConstFloat { .. }
+                | Var::ConstBool { .. } => {
+                    copies.insert(slot, r.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+    changed
+}
+
+fn fold(l: &Var, r: &Var, op: &Binop) -> Option<Var> {
+    match (l, r) {
+        (Var::ConstInt { value: a }, Var::ConstInt { value: b }) => Some(match op {
+            Binop::Add => Var::ConstInt {
+                value: a.checked_add(*b)?,
+            },
+            Binop::Sub => Var::ConstInt {
+                value: a.checked_sub(*b)?,
+            },
+            Binop::Mul => Var::ConstInt {
+                value: a.checked_mul(*b)?,
+            },
+            Binop::Div => Var::ConstInt {
+                value: a.checked_div(*b)?,
+            },
+            Binop::Equal => Var::ConstBool { value: a == b },
+            Binop::NotEqual => Var::ConstBool { value: a != b },
+            Binop::Less => Var::ConstBool { value: a < b },
+            Binop::Greater => Var::ConstBool { value: a > b },
+            Binop::And | Binop::Or => return None,
+        }),
+        (Var::ConstFloat { value: a }, Var::ConstFloat { value: b }) => Some(match op {
+            Binop::Add => Var::ConstFloat { value: a + b },
+            Binop::Sub => Var::ConstFloat { value: a - b },
+            Binop::Mul => Var::ConstFloat { value: a * b },
+            Binop::Div => Var::ConstFloat { value: a / b },
+            Binop::Equal => Var::ConstBool { value: a == b },
+            Binop::NotEqual => Var::ConstBool { value: a != b },
+            Binop::Less => Var::ConstBool { value: a < b },
+            Binop::Greater => Var::ConstBool { value: a > b },
+            Binop::And | Binop::Or => return None,
+        }),
+        (Var::ConstBool { value: a }, Var::ConstBool { value: b }) => Some(match op {
+            Binop::Equal => Var::ConstBool { value: a == b },
+            Binop::NotEqual => Var::ConstBool { value: a != b },
+            Binop::And => Var::ConstBool { value: *a && *b },
+            Binop::Or => Var::ConstBool { value: *a || *b },
+            _ => return None,
+        }),
+        _ => None,
+    }
+}
+
+pub fn const_fold(f: &mut Function, types: &[(Rc<str>, Type)]) -> bool {
+    let mut changed = false;
+    let mut keep = vec![true; f.cmds.len()];
+    for (i, cmd) in f.cmds.iter_mut().enumerate() {
+        let folded = match cmd {
+            Cmd::Binop { l, r, out, op } => match fold(l, r, op) {
+                Some(v) if v.get_type(types) == out.get_type(types) => Cmd::Assign {
+                    l: out.clone(),
+                    r: v,
+                },
+                _ => continue,
+            },
+            Cmd::JmpCond {
+                cond: Var::ConstBool { value },
+                to,
+                to_idx,
+            } => {
+                if !*value {
+                    keep[i] = false;
+                    continue;
+                }
+                Cmd::Jmp {
+                    to: to.clone(),
+                    to_idx: *to_idx,
+                }
+            }
+            _ => continue,
+        };
+        *cmd = folded;
+        changed = true;
+    }
+    remove_cmds(f, &keep) || changed
+}
+
+fn final_target(f: &Function, to: &Rc<str>) -> Rc<str> {
+    let mut cur = to.clone();
+    let mut seen = HashSet::new();
+    while seen.insert(cur.clone()) {
+        let Some(i) = f.labels.get(cur.as_ref()) else {
+            break;
+        };
+        match f.cmds.get(*i) {
+            Some(Cmd::Jmp { to, .. }) => cur = to.clone(),
+            _ => break,
+        }
+    }
+    cur
+}
+
+pub fn jump_thread(f: &mut Function) -> bool {
+    let mut changed = false;
+    let mut keep = vec![true; f.cmds.len()];
+    for (i, k) in keep.iter_mut().enumerate() {
+        let Some(to) = branch_target(&f.cmds[i]) else {
+            continue;
+        };
+        let threaded = final_target(f, to);
+        if let Cmd::Jmp { .. } = f.cmds[i]
+            && f.labels.get(threaded.as_ref()) == Some(&(i + 1))
+        {
+            *k = false;
+            continue;
+        }
+        if threaded == *to {
+            continue;
+        }
+        match &mut f.cmds[i] {
+            Cmd::Jmp { to, .. } | Cmd::JmpCond { to, .. } | Cmd::Receive { to, .. } => {
+                *to = threaded;
+            }
+            _ => unreachable!(),
+        }
+        changed = true;
+    }
+    remove_cmds(f, &keep) || changed
+}
+
+pub fn dead_code(f: &mut Function) -> bool {
+    let n = f.cmds.len();
+    let mut keep = vec![false; n];
+    let mut stack = vec![0];
+    while let Some(i) = stack.pop() {
+        if i >= n || keep[i] {
+            continue;
+        }
+        keep[i] = true;
+        stack.extend(successors(f, i));
+    }
+    if let Some(k) = keep.first_mut() {
+        *k = true;
+    }
+    remove_cmds(f, &keep)
+}
+
+fn is_pure(v: &Var) -> bool {
+    matches!(
+        v,
+        Var::Stack { .. }
+            | Var::ConstInt { .. }
+            | Var::ConstFloat { .. }
+            | Var::ConstString { .. }
+            | Var::ConstBool { .. }
+            | Var::Unit
+            | Var::SelfPid
+            | Var::Now
+    )
+}
+
+fn removable_store(cmd: &Cmd) -> bool {
+    let primitive = |v: &Var| matches!(v, Var::Stack { vtype, .. } if !vtype.is_ptr && !vtype.is_weak);
+    match cmd {
+        Cmd::Assign { l, r } => primitive(l) && is_pure(r),
+        Cmd::Binop { l, r, out, op } => {
+            primitive(out) && *op != Binop::Div && is_pure(l) && is_pure(r)
+        }
+        _ => false,
+    }
+}
+
+pub fn dead_store(f: &mut Function) -> bool {
+    let n = f.cmds.len();
+    let succ: Vec<Vec<usize>> = (0..n).map(|i| successors(f, i)).collect();
+    let uses: Vec<Vec<usize>> = f.cmds.iter().map(reads).collect();
+    let defs: Vec<Option<usize>> = f.cmds.iter().map(written_slot).collect();
+    let mut live_in: Vec<HashSet<usize>> = vec![HashSet::new(); n];
+    let mut live_out: Vec<HashSet<usize>> = vec![HashSet::new(); n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..n).rev() {
+            let mut out = HashSet::new();
+            for s in succ[i].iter().filter(|s| **s < n) {
+                out.extend(live_in[*s].iter().copied());
+            }
+            let mut inn = out.clone();
+            if let Some(d) = defs[i] {
+                inn.remove(&d);
+            }
+            inn.extend(uses[i].iter().copied());
+            if inn != live_in[i] || out != live_out[i] {
+                live_in[i] = inn;
+                live_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+    let keep: Vec<bool> = (0..n)
+        .map(|i| match defs[i] {
+            Some(d) => live_out[i].contains(&d) || !removable_store(&f.cmds[i]),
+            None => true,
+        })
+        .collect();
+    remove_cmds(f, &keep)
+}
+
+pub fn unused_labels(f: &mut Function) -> bool {
+    let used: HashSet<Rc<str>> = f
+        .cmds
+        .iter()
+        .filter_map(|c| match c {
+            Cmd::Jmp { to, .. } | Cmd::JmpCond { to, .. } | Cmd::Receive { to, .. } => {
+                Some(to.clone())
+            }
+            _ => None,
+        })
+        .collect();
+    let before = f.labels.len();
+    f.labels.retain(|k, _| used.contains(k.as_str()));
+    f.labels.len() != before
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Program {
+        let src = format!("fn int main:\n{}end\n", body);
+        crate::parser::parse_to_program(src, "test.beam".into()).unwrap()
+    }
+
+    fn assert_optimizes(passes: &Passes, input: &str, expected: &str) {
+        let p = parse(input);
+        let mut f = p.functions["main"].clone();
+        optimize_function(&mut f, &p.types, passes);
+        let want = &parse(expected).functions["main"];
+        assert_eq!(f.cmds, want.cmds);
+        assert_eq!(f.labels, want.labels);
+    }
+
+    #[test]
+    fn const_fold_replaces_constant_binops() {
+        let passes = Passes { const_fold: true, ..Passes::none() };
+        assert_optimizes(
+            &passes,
+            "	a:int = 2 + 3
+	b:bool = 4 < 1
+	return a
+",
+            "	a:int = 5
+	b:bool = false
+	return a
+",
+        );
+    }
+
+    #[test]
+    fn const_fold_resolves_constant_branches() {
+        let passes = Passes { const_fold: true, ..Passes::none() };
+        assert_optimizes(
+            &passes,
+            "	a:int = 1
+	if true goto done
+	a = 2
+	label done
+	return a
+",
+            "	a:int = 1
+	goto done
+	a = 2
+	label done
+	return a
+",
+        );
+    }
+
+    #[test]
+    fn copy_prop_forwards_copies() {
+        let passes = Passes { copy_prop: true, ..Passes::none() };
+        assert_optimizes(
+            &passes,
+            "	a:int = 4
+	b:int = a
+	c:int = b + a
+	return c
+",
+            "	a:int = 4
+	b:int = 4
+	c:int = 4 + 4
+	return c
+",
+        );
+    }
+
+    #[test]
+    fn dead_store_drops_overwritten_assigns() {
+        let passes = Passes { dead_store: true, ..Passes::none() };
+        assert_optimizes(
+            &passes,
+            "	a:int = 1
+	a = 2
+	return a
+",
+            "	a:int = 2
+	return a
+",
+        );
+    }
+
+    #[test]
+    fn jumps_are_threaded_and_dead_code_removed() {
+        let passes = Passes {
+            jump_thread: true,
+            dead_code: true,
+            unused_labels: true,
+            ..Passes::none()
+        };
+        assert_optimizes(
+            &passes,
+            "	a:int = 1
+	goto one
+	a = 7
+	label one
+	goto two
+	label two
+	return a
+",
+            "	a:int = 1
+	return a
+",
+        );
+    }
+
+    #[test]
+    fn default_passes_reduce_to_a_constant() {
+        let p = parse(
+            "	a:int = 2
+	b:int = a + 3
+	if false goto skip
+	b = b * 2
+	label skip
+	return b
+",
+        );
+        let mut f = p.functions["main"].clone();
+        optimize_function(&mut f, &p.types, &Passes::default());
+        // The slots stay declared even though every store to them is gone.
+        assert_eq!(
+            f.cmds,
+            vec![
+                p.functions["main"].cmds[0].clone(),
+                Cmd::Return {
+                    to_return: Var::ConstInt { value: 10 }
+                },
+            ]
+        );
+        assert!(f.labels.is_empty());
+    }
+
+    #[test]
+    fn no_passes_leave_the_function_alone() {
+        let src = "	a:int = 2 + 3
+	goto out
+	label out
+	return a
+";
+        assert_optimizes(&Passes::none(), src, src);
+    }
+}
@@ -5,6 +5,7 @@ use std::{
 };
 
 use crate::mach::{self, Binop, Cmd, Function, Heap, Machine, NativeInterface, Program, ShallowType, Type, Var};
+use crate::opt::{Passes, optimize};
 
 #[derive(Clone, Debug)]
 pub struct Token {
@@ -199,6 +200,7 @@ pub fn default_types() -> Vec<(Rc<str>, Type)> {
         ("bool".into(), Type::Bool),
         ("string".into(), Type::String),
         ("void".into(), Type::Void),
+        ("pid".into(), Type::Pid),
     ]
 }
 pub fn skip_fn(tokens: &mut TokenStream) -> Result<(), Box<dyn Error>> {
@@ -246,6 +248,9 @@ pub fn import_file(
     for i in np.functions {
         prog.functions.insert(i.0, i.1);
     }
+    for i in np.externals {
+        prog.externals.insert(i.0, i.1);
+    }
     Ok(())
 }
 pub fn preprocess_file(
@@ -332,12 +337,19 @@ pub fn preprocess_file(
 }
 
 pub fn parse_to_program(string: String, file: String) -> Result<Program, Box<dyn Error>> {
+    parse_to_program_with(string, file, &Passes::default())
+}
+pub fn parse_to_program_with(
+    string: String,
+    file: String,
+    passes: &Passes,
+) -> Result<Program, Box<dyn Error>> {
     let mut imports = HashSet::new();
     let mut out = preprocess_file(string.clone(), file.clone(), &mut imports)?;
     let mut tnew = out.types.clone();
     for i in &mut tnew {
         match &mut i.1 {
-            Type::Struct { name: _, fields } => {
+            Type::Struct { fields, .. } => {
                 let mut f = fields.to_vec();
                 for j in &mut f {
                     let mut strm = TokenStream {
@@ -348,7 +360,12 @@ pub fn parse_to_program(string: String, file: String) -> Result<Program, Box<dyn
                         }],
                         index: 0,
                     };
+                    let is_weak = j.1.is_weak;
                     j.1 = parse_type(&mut strm, &out.types)?;
+                    if is_weak {
+                        j.1.is_ptr = false;
+                        j.1.is_weak = true;
+                    }
                 }
                 *fields = f.into();
             }
@@ -392,7 +409,7 @@ pub fn parse_to_program(string: String, file: String) -> Result<Program, Box<dyn
         }
     }
     println!("out:{:#?}", out);
-    Ok(fixups(out)?)
+    Ok(optimize(fixups(out)?, passes))
 }
 
 pub fn parse_type(
@@ -402,6 +419,15 @@ pub fn parse_type(
     let Some(t) = tokens.next() else {
         todo!();
     };
+    if t.text == "weak" {
+        let mut out = parse_type(tokens, type_table)?;
+        if !out.is_ptr {
+            return Err(format!("weak of non struct type:{:#?}", out.name).into());
+        }
+        out.is_ptr = false;
+        out.is_weak = true;
+        return Ok(out);
+    }
     let mut s = t.text;
     let mut array_count = 0;
     while let Some(n) = s.strip_prefix("[]") {
@@ -416,6 +442,7 @@ pub fn parse_type(
                 index: idx,
                 array_count,
                 is_ptr: !i.1.is_primitive(),
+                is_weak: false,
             });
         }
         idx += 1;
@@ -484,12 +511,21 @@ pub fn parse_struct(
 ) -> Result<(Rc<str>, Type), Box<dyn Error>> {
     let name = tokens.next().unwrap().text;
     let mut v = Vec::new();
+    let mut finalizer = None;
     loop {
         let name1 = tokens.next().unwrap().text;
         if name1 == "end" {
             break;
         }
-        let typ1 = tokens.next().unwrap().text;
+        if name1 == "finalize" {
+            finalizer = Some(tokens.next().unwrap().text.into());
+            continue;
+        }
+        let mut typ1 = tokens.next().unwrap().text;
+        let is_weak = typ1 == "weak";
+        if is_weak {
+            typ1 = tokens.next().unwrap().text;
+        }
         let mut array_count = 0;
         let mut tmp = typ1.as_str();
         while let Some(p) = tmp.strip_prefix("[]") {
@@ -510,7 +546,8 @@ pub fn parse_struct(
                 index: 0,
                 name: tmp.into(),
                 array_count,
-                is_ptr,
+                is_ptr: is_ptr && !is_weak,
+                is_weak,
             },
         ));
     }
@@ -520,6 +557,7 @@ pub fn parse_struct(
         Type::Struct {
             name: t,
             fields: v.into(),
+            finalizer,
         },
     ))
 }
@@ -590,7 +628,7 @@ pub fn parse_var(
                     Type::Ptr { to } => {
                         let typ = to.as_type(type_table);
                         match typ {
-                            Type::Struct { name: _, fields } => {
+                            Type::Struct { fields, .. } => {
                                 let mut idx = 0;
                                 for i in fields.iter() {
                                     if i.0.as_ref() == bst {
@@ -609,7 +647,7 @@ pub fn parse_var(
                         }
                         todo!();
                     }
-                    Type::Struct { name, fields } => {
+                    Type::Struct { name, fields, .. } => {
                         let mut idx = 0;
                         for i in fields.iter() {
                             if i.0.as_ref() == bst {
@@ -671,6 +709,46 @@ pub fn parse_command(
             },
         });
     }
+    if s == "link" || s == "monitor" {
+        let to = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        let cmd = if s == "link" {
+            Cmd::Link { to }
+        } else {
+            Cmd::Monitor { to }
+        };
+        return Ok(ParseCommandOutput::Command { cmd });
+    }
+    if s == "sleep" {
+        let ms = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        return Ok(ParseCommandOutput::Command {
+            cmd: Cmd::Sleep { ms },
+        });
+    }
+    if s == "trap_exit" {
+        return Ok(ParseCommandOutput::Command {
+            cmd: Cmd::TrapExit,
+        });
+    }
+    if s == "exit" {
+        let to = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        let reason = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        return Ok(ParseCommandOutput::Command {
+            cmd: Cmd::Exit { to, reason },
+        });
+    }
+    if s == "fail" {
+        let reason = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        return Ok(ParseCommandOutput::Command {
+            cmd: Cmd::Fail { reason },
+        });
+    }
+    if s == "send" {
+        let to = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        let msg = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+        return Ok(ParseCommandOutput::Command {
+            cmd: Cmd::Send { to, msg },
+        });
+    }
     if s == "if" {
         let v = parse_var(tokens.next().unwrap().text, variables, type_table)?;
         let t = tokens.next().unwrap();
@@ -707,6 +785,98 @@ pub fn parse_command(
             })
         } else if n.text == "=" {
             let Some(ln) = tokens.next() else { todo!() };
+            if ln.text == "self" {
+                return Ok(ParseCommandOutput::Command {
+                    cmd: Cmd::Assign {
+                        l: v,
+                        r: Var::SelfPid,
+                    },
+                });
+            }
+            if ln.text == "now" {
+                return Ok(ParseCommandOutput::Command {
+                    cmd: Cmd::Assign { l: v, r: Var::Now },
+                });
+            }
+            if ln.text == "receive" {
+                let mut msg_type = parse_type(tokens, type_table)?;
+                msg_type.is_ptr = false;
+                let mut timeout = None;
+                let mut to: Rc<str> = "".into();
+                if let Some(t) = tokens.peek()
+                    && t.text == "after"
+                {
+                    let _ = tokens.next();
+                    timeout = Some(parse_var(tokens.next().unwrap().text, variables, type_table)?);
+                    to = (function_name + &tokens.next().unwrap().text).into();
+                }
+                return Ok(ParseCommandOutput::Command {
+                    cmd: Cmd::Receive {
+                        returned: v,
+                        msg_type,
+                        timeout,
+                        to,
+                        to_idx: 0,
+                    },
+                });
+            }
+            if ln.text == "spawn_named" {
+                let name = parse_var(tokens.next().unwrap().text, variables, type_table)?;
+                return Ok(ParseCommandOutput::Command {
+                    cmd: Cmd::SpawnNamed { name, returned: v },
+                });
+            }
+            if ln.text == "spawn" {
+                let Some(name) = tokens.next().map(|t| t.text) else {
+                    return Err(format!("expected function name after spawn line:{}", ln.line).into());
+                };
+                let Some(paren) = tokens.next().filter(|t| t.text == "(") else {
+                    return Err(format!("expected ( after spawn {} line:{}", name, ln.line).into());
+                };
+                let mut args = Vec::new();
+                loop {
+                    let Some(n) = tokens.next() else {
+                        return Err(format!("expected ) to close spawn {} line:{}", name, paren.line).into());
+                    };
+                    if n.text == ")" {
+                        break;
+                    } else {
+                        args.push(parse_var(n.text, variables, type_table)?);
+                    }
+                }
+                return Ok(ParseCommandOutput::Command {
+                    cmd: Cmd::Spawn {
+                        to_call: Var::FunctionLiteral {
+                            name: func_mangle(name, file.clone()).into(),
+                            idx: 0,
+                        },
+                        returned: v,
+                        args: args.into(),
+                    },
+                });
+            }
+            if ln.text == "weak" || ln.text == "upgrade" || ln.text == "alive" {
+                let Some(name) = tokens.next().map(|t| t.text) else {
+                    return Err(format!("expected a variable after {} line:{}", ln.text, ln.line).into());
+                };
+                let of = Rc::new(parse_var(name.clone(), variables, type_table)?);
+                let wants = match ln.text.as_str() {
+                    "weak" => matches!(of.get_type(type_table), Type::Ptr { .. }),
+                    _ => matches!(of.get_type(type_table), Type::Weak { .. }),
+                };
+                if !wants {
+                    let what = if ln.text == "weak" { "a struct" } else { "a weak reference" };
+                    return Err(format!("{} of {} which is not {} line:{}", ln.text, name, what, ln.line).into());
+                }
+                let r = match ln.text.as_str() {
+                    "weak" => Var::MakeWeak { of },
+                    "upgrade" => Var::Upgrade { of },
+                    _ => Var::Alive { of },
+                };
+                return Ok(ParseCommandOutput::Command {
+                    cmd: Cmd::Assign { l: v, r },
+                });
+            }
             if ln.text == "new" {
                 let mut t = parse_type(tokens, type_table)?;
                 t.is_ptr = false;
@@ -841,7 +1011,16 @@ pub fn parse_command(
                 args.push(parse_var(n.text, variables, type_table)?);
             }
         }
-        todo!()
+        Ok(ParseCommandOutput::Command {
+            cmd: Cmd::Call {
+                to_call: Var::FunctionLiteral {
+                    name: func_mangle(base_s, file.clone()).into(),
+                    idx: 0,
+                },
+                returned: Var::Unit,
+                args: args.into(),
+            },
+        })
     }
 }
 
@@ -853,12 +1032,30 @@ pub fn fixups(p: Program) -> Result<Program, String> {
     let mut out = Program {
         types: p.types.clone(),
         functions: HashMap::new(),
-        externals:HashMap::new(),
+        externals:p.externals.clone(),
     };
     for i in &p.functions {
         let f = function_fixups(&p, i.1)?;
         out.functions.insert(i.0.clone(), f);
     }
+    for i in &p.types {
+        let Type::Struct {
+            finalizer: Some(f),
+            ..
+        } = &i.1
+        else {
+            continue;
+        };
+        let Some(func) = p.functions.get(f.as_ref()) else {
+            return Err(format!("unknown finalizer:{:#?} for type:{:#?}", f, i.0));
+        };
+        if func.arguments.len() != 1 || func.arguments[0].1.name != i.0 {
+            return Err(format!(
+                "finalizer:{:#?} must take a single argument of type:{:#?}",
+                f, i.0
+            ));
+        }
+    }
     Ok(out)
 }
 
@@ -959,6 +1156,93 @@ pub fn function_fixups(p: &Program, f: &Function) -> Result<Function, String> {
             Cmd::CallNative { to_call:_, returned:_, args:_ }=>{
                 println!("should validate");
             } 
+            Cmd::Link { to } | Cmd::Monitor { to } => {
+                if to.get_type(&p.types) != Type::Pid {
+                    return Err("link and monitor take a pid".into());
+                }
+            }
+            Cmd::TrapExit => {}
+            Cmd::Exit { to, reason } => {
+                if to.get_type(&p.types) != Type::Pid {
+                    return Err("exit target must be a pid".into());
+                }
+                if reason.get_type(&p.types) != Type::String {
+                    return Err("exit reason must be a string".into());
+                }
+            }
+            Cmd::Fail { reason } => {
+                if reason.get_type(&p.types) != Type::String {
+                    return Err("fail reason must be a string".into());
+                }
+            }
+            Cmd::Sleep { ms } => {
+                if ms.get_type(&p.types) != Type::Integer {
+                    return Err("sleep takes a number of milliseconds".into());
+                }
+            }
+            Cmd::SpawnNamed { name, returned } => {
+                if name.get_type(&p.types) != Type::String {
+                    return Err("spawn_named takes a function name string".into());
+                }
+                if returned.get_type(&p.types) != Type::Pid {
+                    return Err("result of spawn_named must be a pid".into());
+                }
+            }
+            Cmd::Send { to, msg } => {
+                if to.get_type(&p.types) != Type::Pid {
+                    return Err("send target must be a pid".into());
+                }
+                if !matches!(msg.get_type(&p.types), Type::Ptr { .. }) {
+                    return Err("only structs can be sent as messages".into());
+                }
+            }
+            Cmd::Receive {
+                returned,
+                msg_type,
+                timeout,
+                to,
+                to_idx: _,
+            } => {
+                if !matches!(msg_type.as_type(&p.types), Type::Struct { .. }) {
+                    return Err(format!("cannot receive non struct type:{:#?}", msg_type.name));
+                }
+                let mut expected = msg_type.clone();
+                expected.is_ptr = true;
+                if returned.get_type(&p.types) != expected.as_type(&p.types) {
+                    return Err(format!("receive of {:#?} into wrong type", msg_type.name));
+                }
+                if let Some(t) = timeout {
+                    if t.get_type(&p.types) != Type::Integer {
+                        return Err("receive timeout must be an int".into());
+                    }
+                    if !f.labels.contains_key(to.as_ref()) {
+                        return Err(format!("unknown label:{:#?}", to));
+                    }
+                }
+            }
+            Cmd::Spawn {
+                to_call,
+                returned,
+                args,
+            } => {
+                let Var::FunctionLiteral { name, idx: _ } = &to_call else {
+                    return Err("spawn of non function".into());
+                };
+                let Some(f) = p.functions.get(name.as_ref()) else {
+                    return Err(format!("cannot spawn unknown function:{:#?}", name));
+                };
+                if returned.get_type(&p.types) != Type::Pid {
+                    return Err("result of spawn must be a pid".into());
+                }
+                if args.len() != f.arguments.len() {
+                    return Err(format!("wrong number of arguments to spawn {:#?}", name));
+                }
+                for (a, t) in args.iter().zip(f.arguments.iter()) {
+                    if a.get_type(&p.types) != t.1.as_type(&p.types) {
+                        return Err(format!("argument type mismatch spawning {:#?}", name));
+                    }
+                }
+            }
         }
         out.cmds.push(i.clone());
     }
@@ -977,7 +1261,12 @@ pub fn link(progs: &[Program]) -> mach::Machine {
         symbol_table: HashMap::new(),
         stack: Vec::new(),
         done: false,
-        native_fns:NativeInterface::new()
+        native_fns:NativeInterface::new(),
+        finalized: HashSet::new(),
+        finalize_queue: Vec::new(),
+        result: None,
+        ops: Rc::from([]),
+        gc_dirty: true,
     };
     for _ in 0..8 {
         out.cmds.push(Cmd::Jmp {
@@ -1016,6 +1305,11 @@ pub fn link(progs: &[Program]) -> mach::Machine {
                 to_call,
                 returned: _,
                 args: _,
+            }
+            | Cmd::Spawn {
+                to_call,
+                returned: _,
+                args: _,
             } => {
                 if let Var::FunctionLiteral { name, idx } = to_call {
                     if let Some(s) = out.symbol_table.get(name.as_ref()) {
@@ -1023,7 +1317,7 @@ pub fn link(progs: &[Program]) -> mach::Machine {
                     }
                 }
             }
-            Cmd::Jmp { to, to_idx } => {
+            Cmd::Jmp { to, to_idx } | Cmd::Receive { to, to_idx, .. } => {
                 if let Some(s) = out.symbol_table.get(to.as_ref()) {
                     *to_idx = *s;
                 }
@@ -1040,5 +1334,6 @@ pub fn link(progs: &[Program]) -> mach::Machine {
             _ => continue,
         }
     }
+    out.resolve();
     out
 }
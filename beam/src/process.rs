@@ -0,0 +1,1229 @@
+use crate::fast::{DEFAULT_GC_THRESHOLD, GcPhase, INITIAL_STACK_SIZE, OwnedSlice, RT, RetInfo, RtError, Tag, Var};
+use crate::heap::{Allocation, RtHeap};
+use crate::io::Reactor;
+use crate::timer::{Clock, SystemClock, Timer, TimerWheel};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Condvar, Mutex, MutexGuard, atomic::AtomicUsize},
+};
+
+pub const DEFAULT_REDUCTIONS: usize = 2000;
+pub const IDLE_PID: u64 = u64::MAX;
+pub const NO_MESSAGE: u16 = u16::MAX;
+
+#[derive(Clone)]
+pub struct Process {
+    pub pid: u64,
+    pub op_stack: OwnedSlice<Var>,
+    pub op_stack_ptr: usize,
+    pub var_stack: OwnedSlice<Var>,
+    pub var_stack_ptr: usize,
+    pub var_base_ptr: usize,
+    pub ret_info: OwnedSlice<RetInfo>,
+    pub ret_info_ptr: usize,
+    pub ip: usize,
+    pub heap: RtHeap,
+    pub gc_phase: GcPhase,
+    pub mark_stack: Vec<*mut Allocation>,
+    pub gc_threshold: usize,
+    pub finalize_queue: Vec<(*mut Var, usize)>,
+}
+impl Process {
+    pub fn new(pid: u64, ip: usize) -> Self {
+        Self {
+            pid,
+            op_stack: OwnedSlice::new([const { Var::new() }; INITIAL_STACK_SIZE]),
+            op_stack_ptr: 0,
+            var_stack: OwnedSlice::new([const { Var::new() }; INITIAL_STACK_SIZE]),
+            var_stack_ptr: 0,
+            var_base_ptr: 0,
+            ret_info: OwnedSlice::new(
+                [const {
+                    RetInfo {
+                        ip: 0,
+                        var_sp: 0,
+                        var_bp: 0,
+                        discard: false,
+                    }
+                }; INITIAL_STACK_SIZE],
+            ),
+            ret_info_ptr: 0,
+            ip,
+            heap: RtHeap::new(),
+            gc_phase: GcPhase::Idle,
+            mark_stack: Vec::new(),
+            gc_threshold: DEFAULT_GC_THRESHOLD,
+            finalize_queue: Vec::new(),
+        }
+    }
+    pub fn empty(pid: u64) -> Self {
+        Self {
+            pid,
+            op_stack: OwnedSlice::new([]),
+            op_stack_ptr: 0,
+            var_stack: OwnedSlice::new([]),
+            var_stack_ptr: 0,
+            var_base_ptr: 0,
+            ret_info: OwnedSlice::new([]),
+            ret_info_ptr: 0,
+            ip: 0,
+            heap: RtHeap::new(),
+            gc_phase: GcPhase::Idle,
+            mark_stack: Vec::new(),
+            gc_threshold: DEFAULT_GC_THRESHOLD,
+            finalize_queue: Vec::new(),
+        }
+    }
+    /// Frees the process's heap. Finalizers of objects still in it do not run.
+    pub fn release(&mut self) {
+        crate::heap::rt_heap_destroy(&mut self.heap);
+    }
+    pub fn push(&mut self, v: Var) {
+        if self.op_stack_ptr == self.op_stack.len() {
+            let len = (self.op_stack.len() * 2).max(INITIAL_STACK_SIZE);
+            self.op_stack.grow(len, Var::new());
+        }
+        self.op_stack[self.op_stack_ptr] = v;
+        self.op_stack_ptr += 1;
+    }
+    pub fn live_vars(&self) -> impl Iterator<Item = &Var> {
+        self.var_stack.as_slice()[..self.var_stack_ptr]
+            .iter()
+            .chain(self.op_stack.as_slice()[..self.op_stack_ptr].iter())
+    }
+    pub fn live_vars_mut(&mut self) -> impl Iterator<Item = &mut Var> {
+        let op_ptr = self.op_stack_ptr;
+        let var_ptr = self.var_stack_ptr;
+        self.var_stack.as_slice_mut()[..var_ptr]
+            .iter_mut()
+            .chain(self.op_stack.as_slice_mut()[..op_ptr].iter_mut())
+    }
+}
+
+/// A message in flight. Sending deep-copies the message into a heap of its
+/// own, which the receiver merges into its heap when it takes the message.
+#[derive(Clone)]
+pub struct Message {
+    pub obj: *mut Var,
+    pub heap: RtHeap,
+}
+impl Message {
+    pub fn type_idx(&self) -> u16 {
+        unsafe { (*(self.obj as *mut Allocation)).type_idx }
+    }
+    pub fn adopt_into(mut self, heap: &mut RtHeap) -> *mut Var {
+        crate::heap::rt_heap_merge(heap, &mut self.heap);
+        self.obj
+    }
+    pub fn discard(mut self) {
+        crate::heap::rt_heap_destroy(&mut self.heap);
+    }
+}
+
+#[derive(Clone)]
+pub struct Waiting {
+    pub process: Process,
+    pub type_idx: u16,
+    pub deadline: Option<u64>,
+    pub resume_ip: usize,
+    pub timeout_ip: usize,
+}
+
+#[derive(Clone)]
+pub struct SchedulerState {
+    pub run_queues: Vec<VecDeque<Process>>,
+    pub waiting: HashMap<u64, Waiting>,
+    pub mailboxes: HashMap<u64, VecDeque<Message>>,
+    pub links: HashMap<u64, HashSet<u64>>,
+    pub monitors: HashMap<u64, Vec<u64>>,
+    pub trap_exit: HashSet<u64>,
+    pub timers: TimerWheel,
+    /// The fds each process opened through the io natives.
+    pub fds: HashMap<u64, HashSet<i32>>,
+    pub next_pid: u64,
+    pub idle_workers: usize,
+}
+// SAFETY: the raw pointers in queued processes and mailboxes refer to heaps
+// owned by those processes and messages, which move with them between threads.
+unsafe impl Send for SchedulerState {}
+unsafe impl Send for Process {}
+impl SchedulerState {
+    pub fn new(threads: usize) -> Self {
+        let mut mailboxes = HashMap::new();
+        mailboxes.insert(0, VecDeque::new());
+        Self {
+            run_queues: (0..threads.max(1)).map(|_| VecDeque::new()).collect(),
+            waiting: HashMap::new(),
+            mailboxes,
+            links: HashMap::new(),
+            monitors: HashMap::new(),
+            trap_exit: HashSet::new(),
+            timers: TimerWheel::new(),
+            fds: HashMap::new(),
+            next_pid: 1,
+            idle_workers: 0,
+        }
+    }
+    pub fn is_alive(&self, pid: u64) -> bool {
+        self.mailboxes.contains_key(&pid)
+    }
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timers.next_deadline()
+    }
+    pub fn has_runnable(&self) -> bool {
+        self.run_queues.iter().any(|q| !q.is_empty())
+    }
+    pub fn push(&mut self, worker: usize, p: Process) {
+        let w = worker % self.run_queues.len();
+        self.run_queues[w].push_back(p);
+    }
+    pub fn pop(&mut self, worker: usize) -> Option<Process> {
+        let w = worker % self.run_queues.len();
+        if let Some(p) = self.run_queues[w].pop_front() {
+            return Some(p);
+        }
+        let victim = (0..self.run_queues.len()).max_by_key(|i| self.run_queues[*i].len())?;
+        self.run_queues[victim].pop_back()
+    }
+    pub fn processes(&self) -> impl Iterator<Item = &Process> {
+        self.run_queues
+            .iter()
+            .flatten()
+            .chain(self.waiting.values().map(|w| &w.process))
+    }
+    pub fn processes_mut(&mut self) -> impl Iterator<Item = &mut Process> {
+        self.run_queues
+            .iter_mut()
+            .flatten()
+            .chain(self.waiting.values_mut().map(|w| &mut w.process))
+    }
+    pub fn wait(&mut self, p: Process, type_idx: u16, deadline: Option<u64>, resume_ip: usize, timeout_ip: usize) {
+        if let Some(deadline) = deadline {
+            self.timers.insert(Timer { deadline, pid: p.pid });
+        }
+        self.waiting.insert(
+            p.pid,
+            Waiting {
+                process: p,
+                type_idx,
+                deadline,
+                resume_ip,
+                timeout_ip,
+            },
+        );
+    }
+    pub fn unwait(&mut self, pid: u64) -> Option<Waiting> {
+        let w = self.waiting.remove(&pid)?;
+        if let Some(deadline) = w.deadline {
+            self.timers.cancel(Timer { deadline, pid });
+        }
+        Some(w)
+    }
+    /// Frees the heaps of every suspended process and undelivered message.
+    pub fn release_heaps(&mut self) {
+        for p in self.processes_mut() {
+            p.release();
+        }
+        for m in self.mailboxes.values_mut().flat_map(|mb| mb.drain(..)) {
+            m.discard();
+        }
+        for fd in self.fds.drain().flat_map(|(_, fds)| fds) {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+    /// Wakes every process whose timer is due and returns their pids.
+    pub fn wake_expired(&mut self, worker: usize, now: u64) -> Vec<u64> {
+        let expired = self.timers.expire(now);
+        for t in &expired {
+            let mut w = self.waiting.remove(&t.pid).unwrap();
+            w.process.ip = w.timeout_ip;
+            self.push(worker, w.process);
+        }
+        expired.into_iter().map(|t| t.pid).collect()
+    }
+    pub fn deliver(&mut self, worker: usize, to: u64, msg: Message) -> bool {
+        if !self.is_alive(to) {
+            msg.discard();
+            return false;
+        }
+        if let Some(w) = self.waiting.get(&to)
+            && w.type_idx == msg.type_idx()
+        {
+            let mut w = self.unwait(to).unwrap();
+            let obj = msg.adopt_into(&mut w.process.heap);
+            w.process.push(Var::ptr(obj));
+            w.process.ip = w.resume_ip;
+            self.push(worker, w.process);
+            return true;
+        }
+        self.mailboxes.get_mut(&to).unwrap().push_back(msg);
+        false
+    }
+    pub fn take_message(&mut self, pid: u64, type_idx: u16) -> Option<Message> {
+        let mb = self.mailboxes.get_mut(&pid)?;
+        let idx = mb.iter().position(|m| m.type_idx() == type_idx)?;
+        mb.remove(idx)
+    }
+}
+
+pub struct SchedulerShared {
+    pub state: Mutex<SchedulerState>,
+    pub work: Condvar,
+    pub reactor: Reactor,
+    /// Bytes held by all process and message heaps, for `Limits::heap_bytes`.
+    pub heap_bytes: Arc<AtomicUsize>,
+}
+
+pub struct Scheduler {
+    pub shared: Arc<SchedulerShared>,
+    pub clock: Arc<dyn Clock>,
+    pub current: u64,
+    pub reductions: usize,
+    pub budget: usize,
+    pub worker: usize,
+    pub threads: usize,
+}
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_state(SchedulerState::new(1))
+    }
+    pub fn with_state(state: SchedulerState) -> Self {
+        let threads = state.run_queues.len();
+        Self {
+            shared: Arc::new(SchedulerShared {
+                state: Mutex::new(state),
+                work: Condvar::new(),
+                reactor: Reactor::new(),
+                heap_bytes: Arc::new(AtomicUsize::new(0)),
+            }),
+            clock: Arc::new(SystemClock::new()),
+            current: 0,
+            reductions: 0,
+            budget: DEFAULT_REDUCTIONS,
+            worker: 0,
+            threads,
+        }
+    }
+    pub fn lock(&self) -> MutexGuard<'_, SchedulerState> {
+        self.shared.state.lock().unwrap()
+    }
+    pub fn process_count(&self) -> usize {
+        self.lock().mailboxes.len()
+    }
+    pub fn is_idle(&self) -> bool {
+        self.current == IDLE_PID
+    }
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.lock().next_deadline()
+    }
+    pub fn is_alive(&self, pid: u64) -> bool {
+        self.lock().is_alive(pid)
+    }
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+    pub fn notify_work(&self) {
+        if self.threads > 1 {
+            self.shared.work.notify_one();
+        }
+    }
+}
+impl Clone for Scheduler {
+    fn clone(&self) -> Self {
+        let mut out = Self::with_state(self.lock().clone());
+        out.clock = self.clock.clone();
+        out.current = self.current;
+        out.reductions = self.reductions;
+        out.budget = self.budget;
+        out.worker = self.worker;
+        out
+    }
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RT {
+    pub fn set_reduction_budget(&mut self, budget: usize) {
+        self.scheduler.budget = budget.max(1);
+    }
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.scheduler.clock = clock;
+    }
+    pub fn set_scheduler_threads(&mut self, threads: usize) {
+        let threads = threads.max(1);
+        let mut s = self.scheduler.lock();
+        while s.run_queues.len() < threads {
+            s.run_queues.push(VecDeque::new());
+        }
+        while s.run_queues.len() > threads {
+            let q = s.run_queues.pop().unwrap();
+            s.run_queues[0].extend(q);
+        }
+        drop(s);
+        self.scheduler.threads = threads;
+    }
+    pub fn spawn(&mut self, name: &str, args: Vec<Var>) -> Option<Result<u64, RtError>> {
+        let loc = *self.symbol_table.get(name)?;
+        Some(self.spawn_at(loc, args))
+    }
+    pub fn spawn_at(&mut self, loc: usize, args: Vec<Var>) -> Result<u64, RtError> {
+        if let Some(limit) = self.limits.processes
+            && self.scheduler.process_count() >= limit
+        {
+            return Err(RtError::TooManyProcesses { limit });
+        }
+        let mut p = Process::new(0, loc);
+        p.heap = self.new_heap();
+        p.gc_threshold = self.gc_info.min_threshold;
+        if self.heap.verifier.is_some() {
+            crate::heap::rt_heap_enable_verification(&mut p.heap);
+        }
+        for a in self.with_heap(&mut p.heap, |rt| rt.copy_vars(&args)) {
+            p.push(a);
+        }
+        if let Err(e) = self.check_heap() {
+            p.release();
+            return Err(e);
+        }
+        let mut s = self.scheduler.lock();
+        let pid = s.next_pid;
+        s.next_pid += 1;
+        p.pid = pid;
+        s.push(self.scheduler.worker, p);
+        s.mailboxes.insert(pid, VecDeque::new());
+        drop(s);
+        self.scheduler.notify_work();
+        if self.jit.is_some() {
+            self.jit_note_call(loc);
+        }
+        Ok(pid)
+    }
+    /// An empty heap that counts towards the VM's heap quota.
+    pub fn new_heap(&self) -> RtHeap {
+        RtHeap::pooled(self.scheduler.shared.heap_bytes.clone())
+    }
+    pub fn swap_process(&mut self, p: &mut Process) {
+        std::mem::swap(&mut self.scheduler.current, &mut p.pid);
+        std::mem::swap(&mut self.op_stack, &mut p.op_stack);
+        std::mem::swap(&mut self.op_stack_ptr, &mut p.op_stack_ptr);
+        std::mem::swap(&mut self.var_stack, &mut p.var_stack);
+        std::mem::swap(&mut self.var_stack_ptr, &mut p.var_stack_ptr);
+        std::mem::swap(&mut self.var_base_ptr, &mut p.var_base_ptr);
+        std::mem::swap(&mut self.ret_info, &mut p.ret_info);
+        std::mem::swap(&mut self.ret_info_ptr, &mut p.ret_info_ptr);
+        std::mem::swap(&mut self.ip, &mut p.ip);
+        std::mem::swap(&mut self.heap, &mut p.heap);
+        std::mem::swap(&mut self.gc_info.phase, &mut p.gc_phase);
+        std::mem::swap(&mut self.gc_info.mark_stack, &mut p.mark_stack);
+        std::mem::swap(&mut self.gc_info.threshold, &mut p.gc_threshold);
+        std::mem::swap(&mut self.gc_info.finalize_queue, &mut p.finalize_queue);
+        if let Some(p) = &mut self.profile {
+            p.break_sequence();
+        }
+    }
+    pub fn schedule_tick(&mut self) {
+        if self.scheduler.is_idle() {
+            return;
+        }
+        self.scheduler.reductions += 1;
+        if self.scheduler.reductions >= self.scheduler.budget && self.op_stack_ptr == 0 {
+            self.yield_process();
+        }
+    }
+    pub fn yield_process(&mut self) {
+        self.scheduler.reductions = 0;
+        if self.halted {
+            return;
+        }
+        self.poll_io(Some(0));
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        let worker = self.scheduler.worker;
+        let woken = s.wake_expired(worker, self.scheduler.now());
+        self.record_timeouts(&woken);
+        let Some(mut next) = s.pop(worker) else {
+            return;
+        };
+        self.swap_process(&mut next);
+        s.push(worker, next);
+    }
+    pub fn suspend_current(&mut self) {
+        if self.scheduler.is_idle() {
+            return;
+        }
+        let mut p = Process::empty(IDLE_PID);
+        self.swap_process(&mut p);
+        self.scheduler.reductions = 0;
+        self.scheduler.lock().push(self.scheduler.worker, p);
+        self.scheduler.notify_work();
+    }
+    pub fn exit_process(&mut self) {
+        let pid = self.scheduler.current;
+        let _ = self.process_exit(pid, "normal");
+    }
+    fn remove_process(&mut self, s: &mut SchedulerState, pid: u64) -> bool {
+        let Some(mailbox) = s.mailboxes.remove(&pid) else {
+            return false;
+        };
+        mailbox.into_iter().for_each(Message::discard);
+        if self.scheduler.current == pid {
+            let mut p = Process::empty(IDLE_PID);
+            self.swap_process(&mut p);
+            self.scheduler.reductions = 0;
+            p.release();
+        } else if let Some(mut w) = s.unwait(pid) {
+            self.scheduler.shared.reactor.forget_pid(pid);
+            w.process.release();
+        } else {
+            for q in s.run_queues.iter_mut() {
+                if let Some(i) = q.iter().position(|p| p.pid == pid) {
+                    q.remove(i).unwrap().release();
+                }
+            }
+        }
+        s.trap_exit.remove(&pid);
+        if let Some(fds) = s.fds.remove(&pid) {
+            crate::io::close_fds(&self.scheduler.shared.reactor, fds);
+        }
+        true
+    }
+    pub fn process_exit(&mut self, pid: u64, reason: &str) -> Result<(), RtError> {
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        let mut todo = vec![(pid, reason.to_string())];
+        while let Some((pid, reason)) = todo.pop() {
+            if !self.remove_process(&mut s, pid) {
+                continue;
+            }
+            if pid == 0 {
+                return Err(RtError::ProcessFailed { pid, reason });
+            }
+            for l in s.links.remove(&pid).unwrap_or_default() {
+                if let Some(set) = s.links.get_mut(&l) {
+                    set.remove(&pid);
+                }
+                if s.trap_exit.contains(&l) {
+                    self.notify(&mut s, l, "Exit", pid, &reason);
+                } else if reason != "normal" {
+                    todo.push((l, reason.clone()));
+                }
+            }
+            for w in s.monitors.remove(&pid).unwrap_or_default() {
+                self.notify(&mut s, w, "Down", pid, &reason);
+            }
+        }
+        drop(s);
+        self.scheduler.notify_work();
+        self.schedule_next();
+        Ok(())
+    }
+    pub fn exit_signal(&mut self, to: u64, reason: &str) -> Result<(), RtError> {
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        if !s.is_alive(to) || reason == "normal" {
+            return Ok(());
+        }
+        if s.trap_exit.contains(&to) {
+            let from = self.scheduler.current;
+            self.notify(&mut s, to, "Exit", from, reason);
+            return Ok(());
+        }
+        drop(s);
+        self.process_exit(to, reason)
+    }
+    pub fn trap_exits(&mut self) {
+        let me = self.scheduler.current;
+        self.scheduler.lock().trap_exit.insert(me);
+    }
+    pub fn link(&mut self, to: u64) -> Result<(), RtError> {
+        let me = self.scheduler.current;
+        if to == me {
+            return Ok(());
+        }
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        if !s.is_alive(to) {
+            if s.trap_exit.contains(&me) {
+                self.notify(&mut s, me, "Exit", to, "noproc");
+                return Ok(());
+            }
+            return Err(RtError::ProcessFailed {
+                pid: me,
+                reason: "noproc".into(),
+            });
+        }
+        s.links.entry(me).or_default().insert(to);
+        s.links.entry(to).or_default().insert(me);
+        Ok(())
+    }
+    pub fn monitor(&mut self, to: u64) {
+        let me = self.scheduler.current;
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        if !s.is_alive(to) {
+            self.notify(&mut s, me, "Down", to, "noproc");
+            return;
+        }
+        s.monitors.entry(to).or_default().push(me);
+    }
+    pub fn notify(
+        &mut self,
+        s: &mut SchedulerState,
+        to: u64,
+        type_name: &str,
+        from: u64,
+        reason: &str,
+    ) {
+        let Some(type_idx) = self.types.as_slice().iter().position(|t| t.name == type_name) else {
+            return;
+        };
+        let n = self.types[type_idx].fields.len();
+        let mut heap = self.new_heap();
+        let obj = self.with_heap(&mut heap, |rt| {
+            let msg = crate::heap::rt_heap_allocate(
+                &mut rt.heap,
+                n * size_of::<Var>(),
+                n as u16,
+                type_idx as u16,
+            ) as *mut Var;
+            for i in 0..n {
+                let v = match rt.types[type_idx].fields[i] {
+                    Tag::Pid => Var::pid(from),
+                    Tag::String => rt.allocate_str(reason),
+                    t => {
+                        let mut v = Var::new();
+                        v.get_mut().tag = t;
+                        v
+                    }
+                };
+                unsafe {
+                    *msg.add(i + 1) = v;
+                }
+            }
+            msg
+        });
+        s.deliver(self.scheduler.worker, to, Message { obj, heap });
+    }
+    pub fn schedule_next(&mut self) {
+        if !self.scheduler.is_idle() {
+            return;
+        }
+        let next = self.scheduler.lock().pop(self.scheduler.worker);
+        if let Some(mut next) = next {
+            self.swap_process(&mut next);
+        }
+    }
+    pub fn idle_step(&mut self) -> Result<bool, RtError> {
+        self.wake_expired();
+        self.poll_io(Some(0));
+        self.schedule_next();
+        if !self.scheduler.is_idle() {
+            return Ok(false);
+        }
+        if self.has_io_waiters() {
+            let deadline = self.scheduler.next_deadline();
+            self.wait_io(deadline);
+            return Ok(false);
+        }
+        let Some(deadline) = self.scheduler.next_deadline() else {
+            return Err(RtError::Deadlock);
+        };
+        self.scheduler.clock.sleep_until(deadline);
+        Ok(false)
+    }
+    pub fn wake_expired(&mut self) {
+        let worker = self.scheduler.worker;
+        let now = self.scheduler.now();
+        let woken = self.scheduler.lock().wake_expired(worker, now);
+        self.record_timeouts(&woken);
+        if !woken.is_empty() {
+            self.scheduler.notify_work();
+        }
+    }
+    pub fn send_message(&mut self, to: u64, msg: *mut Var) -> Result<(), RtError> {
+        if msg.is_null() || !self.scheduler.is_alive(to) {
+            return Ok(());
+        }
+        let copy = self.copy_message(msg);
+        if let Err(e) = self.check_heap() {
+            copy.discard();
+            return Err(e);
+        }
+        self.deliver(to, copy);
+        Ok(())
+    }
+    pub fn deliver(&mut self, to: u64, msg: Message) {
+        let worker = self.scheduler.worker;
+        if self.scheduler.lock().deliver(worker, to, msg) {
+            self.scheduler.notify_work();
+        }
+    }
+    pub fn receive(&mut self, type_idx: u16, timeout: Option<i64>, timeout_ip: usize) {
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        if let Some(m) = s.take_message(self.scheduler.current, type_idx) {
+            drop(s);
+            let obj = m.adopt_into(&mut self.heap);
+            self.op_push(Var::ptr(obj));
+            return;
+        }
+        if let Some(ms) = timeout
+            && ms <= 0
+        {
+            drop(s);
+            self.record_timeouts(&[self.scheduler.current]);
+            self.ip = timeout_ip;
+            return;
+        }
+        let resume_ip = self.ip;
+        let deadline = timeout.map(|ms| self.scheduler.now() + ms as u64);
+        let mut p = Process::empty(IDLE_PID);
+        self.swap_process(&mut p);
+        self.scheduler.reductions = 0;
+        s.wait(p, type_idx, deadline, resume_ip, timeout_ip);
+        drop(s);
+        self.schedule_next();
+    }
+    pub fn sleep(&mut self, ms: i64) {
+        if ms <= 0 {
+            self.yield_process();
+            return;
+        }
+        let deadline = self.scheduler.now() + ms as u64;
+        let ip = self.ip;
+        let mut p = Process::empty(IDLE_PID);
+        self.swap_process(&mut p);
+        self.scheduler.reductions = 0;
+        self.scheduler.lock().wait(p, NO_MESSAGE, Some(deadline), ip, ip);
+        self.schedule_next();
+    }
+    /// Runs `f` with `heap` standing in for the current process's heap, so
+    /// that everything `f` allocates lands in `heap`.
+    pub fn with_heap<R>(&mut self, heap: &mut RtHeap, f: impl FnOnce(&mut Self) -> R) -> R {
+        std::mem::swap(&mut self.heap, heap);
+        let phase = std::mem::replace(&mut self.gc_info.phase, GcPhase::Idle);
+        let r = f(self);
+        self.gc_info.phase = phase;
+        std::mem::swap(&mut self.heap, heap);
+        r
+    }
+    pub fn copy_message(&mut self, root: *mut Var) -> Message {
+        let mut heap = self.new_heap();
+        let copy = self.with_heap(&mut heap, |rt| rt.copy_vars(&[Var::ptr(root)]));
+        Message {
+            obj: copy[0].get_ptr() as *mut Var,
+            heap,
+        }
+    }
+    /// Deep-copies `roots` and everything they reach into the current heap.
+    /// Weak references to objects that were not copied come out null.
+    pub fn copy_vars(&mut self, roots: &[Var]) -> Vec<Var> {
+        let mut map: HashMap<*mut Var, *mut Var> = HashMap::new();
+        let mut order = Vec::new();
+        let mut todo: Vec<*mut Var> = roots
+            .iter()
+            .filter(|v| v.get().tag == Tag::Ptr && !v.get_ptr().is_null())
+            .map(|v| v.get_ptr() as *mut Var)
+            .collect();
+        while let Some(src) = todo.pop() {
+            if map.contains_key(&src) {
+                continue;
+            }
+            let al = src as *mut Allocation;
+            let dst = unsafe {
+                crate::heap::rt_heap_allocate(
+                    &mut self.heap,
+                    (*al).size as usize,
+                    (*al).num_objects,
+                    (*al).type_idx,
+                ) as *mut Var
+            };
+            map.insert(src, dst);
+            let fields = self.read_fields(src);
+            for f in &fields {
+                if f.get().tag == Tag::Ptr && !f.get_ptr().is_null() {
+                    todo.push(f.get_ptr() as *mut Var);
+                }
+            }
+            order.push((src, fields));
+        }
+        for (src, fields) in order {
+            let dst = map[&src];
+            for (i, f) in fields.into_iter().enumerate() {
+                let v = self.copied(&map, f);
+                unsafe {
+                    *dst.add(i + 1) = v;
+                }
+            }
+        }
+        roots.iter().map(|v| self.copied(&map, v.clone())).collect()
+    }
+    fn copied(&mut self, map: &HashMap<*mut Var, *mut Var>, v: Var) -> Var {
+        match v.get().tag {
+            Tag::Ptr if !v.get_ptr().is_null() => Var::ptr(map[&(v.get_ptr() as *mut Var)]),
+            Tag::Weak => Var::weak(
+                map.get(&(v.get_weak().unwrap_or(std::ptr::null()) as *mut Var))
+                    .copied()
+                    .unwrap_or(std::ptr::null_mut()),
+            ),
+            Tag::String if !v.get_string().is_null() => {
+                let s = unsafe { (*v.get_string()).as_bytes().to_vec() };
+                self.allocate_bytes(&s)
+            }
+            _ => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fast::{Limits, RT, RtError, compile_mach_to_ir, rt_from_intermediate_rt};
+    use crate::mach::{RunResult, Value};
+    use crate::timer::{Clock, VirtualClock};
+    use std::sync::Arc;
+
+    const NODE: &str = "struct Node
+	next Node
+	value int
+end
+";
+
+    fn rt(body: &str) -> RT {
+        let src = format!("{}{}", NODE, body);
+        let p = crate::parser::parse_to_program(src, "test.beam".into()).unwrap();
+        let mut rt = rt_from_intermediate_rt(compile_mach_to_ir(&[p]).unwrap());
+        rt.enable_heap_verification();
+        rt
+    }
+
+    #[test]
+    fn messages_are_copied_between_heaps() {
+        let mut rt = rt("fn void echo parent pid:
+	m:Node = receive Node
+	m.value = m.value + 1
+	t:Node = m.next
+	t.value = 5
+	send parent m
+	return unit
+end
+fn int main:
+	n:Node = new Node
+	t:Node = new Node
+	n.value = 41
+	n.next = t
+	me:pid = self
+	c:pid = spawn echo(me)
+	send c n
+	r:Node = receive Node
+	a:int = r.value
+	a = a * 100
+	b:int = n.value
+	a = a + b
+	t = r.next
+	b = t.value
+	a = a * 10
+	a = a + b
+	t = n.next
+	b = t.value
+	a = a * 10
+	a = a + b
+	return a
+end
+");
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("echo did not finish");
+        };
+        assert_eq!(v, Value::Integer { v: 424150 });
+    }
+
+    #[test]
+    fn collection_only_touches_the_running_process() {
+        let mut rt = rt("fn void hoard:
+	head:Node = new Node
+	n:Node = head
+	i:int = 0
+	b:bool = false
+	label top
+	b = i < 19
+	if b goto body
+	m:Node = receive Node
+	return unit
+	label body
+	n = new Node
+	n.next = head
+	head = n
+	i = i + 1
+	goto top
+end
+fn int main:
+	c:pid = spawn hoard()
+	sleep 0
+	label spin
+	goto spin
+end
+");
+        assert!(matches!(rt.run(10_000), RunResult::Paused));
+        let hoarded = |rt: &RT| {
+            let s = rt.scheduler.lock();
+            s.waiting.values().next().unwrap().process.heap.allocations().to_vec()
+        };
+        let before = hoarded(&rt);
+        assert_eq!(before.len(), 20);
+        rt.gc_collect().unwrap();
+        assert_eq!(hoarded(&rt), before);
+        assert!(rt.heap.allocations().iter().all(|a| !before.contains(a)));
+        assert!(before.iter().all(|a| unsafe { (**a).magic } == crate::heap::ALLOC_LIVE));
+    }
+
+    fn rt_with_clock(body: &str) -> (RT, Arc<VirtualClock>) {
+        let mut rt = rt(body);
+        let clock = Arc::new(VirtualClock::new());
+        rt.set_clock(clock.clone());
+        (rt, clock)
+    }
+
+    #[test]
+    fn receive_times_out_on_virtual_time() {
+        let (mut rt, clock) = rt_with_clock("fn int main:
+	a:int = now
+	x:Node = receive Node after 250 late
+	return 0
+	label late
+	b:int = now
+	b = b - a
+	return b
+end
+");
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("receive did not time out");
+        };
+        assert_eq!(v, Value::Integer { v: 250 });
+        assert_eq!(clock.now(), 250);
+    }
+
+    #[test]
+    fn message_beats_a_later_timeout() {
+        let (mut rt, clock) = rt_with_clock("fn void late parent pid:
+	sleep 40
+	n:Node = new Node
+	n.value = 7
+	send parent n
+	return unit
+end
+fn int main:
+	me:pid = self
+	c:pid = spawn late(me)
+	x:Node = receive Node after 1000 timeout
+	r:int = x.value
+	return r
+	label timeout
+	return 0
+end
+");
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("main did not finish");
+        };
+        assert_eq!(v, Value::Integer { v: 7 });
+        assert_eq!(clock.now(), 40);
+    }
+
+    #[test]
+    fn sleepers_wake_in_deadline_order() {
+        let (mut rt, clock) = rt_with_clock("fn void nap parent pid ms int id int:
+	sleep ms
+	n:Node = new Node
+	n.value = id
+	send parent n
+	return unit
+end
+fn int main:
+	me:pid = self
+	a:pid = spawn nap(me 300 1)
+	b:pid = spawn nap(me 100 2)
+	c:pid = spawn nap(me 200 3)
+	x:Node = receive Node
+	r:int = x.value
+	x = receive Node
+	r = r * 10
+	v:int = x.value
+	r = r + v
+	x = receive Node
+	r = r * 10
+	v = x.value
+	r = r + v
+	return r
+end
+");
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("sleepers did not finish");
+        };
+        assert_eq!(v, Value::Integer { v: 231 });
+        assert_eq!(clock.now(), 300);
+    }
+
+    const HOARDERS: &str = "fn void hoard parent pid:
+	head:Node = new Node
+	n:Node = head
+	i:int = 0
+	b:bool = false
+	label top
+	b = i < 40
+	if b goto body
+	send parent head
+	m:Node = receive Node
+	return unit
+	label body
+	n = new Node
+	n.next = head
+	head = n
+	i = i + 1
+	goto top
+end
+fn int main:
+	me:pid = self
+	i:int = 0
+	b:bool = false
+	label spawn
+	b = i < 50
+	if b goto more
+	got:int = 0
+	label wait
+	m:Node = receive Node after 1000 done
+	got = got + 1
+	goto wait
+	label done
+	return got
+	label more
+	c:pid = spawn hoard(me)
+	i = i + 1
+	goto spawn
+end
+";
+
+    #[test]
+    fn heap_limit_covers_every_process() {
+        let (mut rt, _) = rt_with_clock(HOARDERS);
+        assert!(matches!(rt.run(1_000_000), RunResult::Finished(Value::Integer { v: 50 })));
+        let limit = 64 << 10;
+        let (mut rt, _) = rt_with_clock(HOARDERS);
+        rt.set_limits(Limits {
+            heap_bytes: Some(limit),
+            ..Limits::default()
+        });
+        let RunResult::Finished(Value::Integer { v }) = rt.run(1_000_000) else {
+            panic!("main did not finish");
+        };
+        // Each hoarder holds about 4 KiB, so only some of them fit.
+        assert!(v > 0 && v < 50, "{} hoarders fit in {} bytes", v, limit);
+        assert!(rt.heap.pool_bytes() <= limit);
+    }
+
+    #[test]
+    fn process_limit_fails_the_spawner() {
+        let mut rt = rt(HOARDERS);
+        rt.set_limits(Limits {
+            processes: Some(8),
+            ..Limits::default()
+        });
+        let RunResult::Error(e) = rt.run(1_000_000) else {
+            panic!("spawning past the limit did not fail");
+        };
+        assert_eq!(e.to_string(), RtError::TooManyProcesses { limit: 8 }.to_string());
+        assert_eq!(rt.scheduler.process_count(), 8);
+    }
+
+    const SIGNALS: &str = "struct Exit
+	from pid
+	reason string
+end
+struct Down
+	from pid
+	reason string
+end
+fn void crash:
+	fail \"boom\"
+	return unit
+end
+fn void quit:
+	return unit
+end
+fn void middle:
+	c:pid = spawn crash()
+	link c
+	n:Node = receive Node
+	return unit
+end
+fn int got d Down p pid reason string:
+	f:pid = d.from
+	b:bool = f == p
+	if b goto same
+	return 0
+	label same
+	s:string = d.reason
+	b = s == reason
+	if b goto yes
+	return 0
+	label yes
+	return 1
+end
+";
+
+    fn value(r: RunResult) -> Value {
+        let RunResult::Finished(v) = r else {
+            panic!("main did not finish: {:?}", r);
+        };
+        v
+    }
+
+    fn failure(r: RunResult) -> (u64, String) {
+        let RunResult::Error(e) = r else {
+            panic!("expected a failure, got {:?}", r);
+        };
+        match e.downcast_ref::<RtError>() {
+            Some(RtError::ProcessFailed { pid, reason }) => (*pid, reason.clone()),
+            _ => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn exits_propagate_over_links() {
+        let mut rt = rt(&format!(
+            "{}fn int main:
+	c:pid = spawn middle()
+	link c
+	n:Node = receive Node
+	return 0
+end
+",
+            SIGNALS
+        ));
+        assert_eq!(failure(rt.run(100_000)), (0, "boom".into()));
+        assert_eq!(rt.scheduler.process_count(), 0);
+    }
+
+    #[test]
+    fn trapped_exits_arrive_as_messages() {
+        let mut rt = rt(&format!(
+            "{}fn int main:
+	trap_exit
+	q:pid = spawn quit()
+	link q
+	c:pid = spawn middle()
+	link c
+	e:Exit = receive Exit
+	f:pid = e.from
+	b:bool = f == q
+	if b goto quit
+	return 1
+	label quit
+	s:string = e.reason
+	b = s == \"normal\"
+	if b goto next
+	return 2
+	label next
+	e = receive Exit
+	f = e.from
+	b = f == c
+	if b goto middle
+	return 3
+	label middle
+	s = e.reason
+	b = s == \"boom\"
+	if b goto done
+	return 4
+	label done
+	return 0
+end
+",
+            SIGNALS
+        ));
+        assert_eq!(value(rt.run(100_000)), Value::Integer { v: 0 });
+    }
+
+    #[test]
+    fn monitors_receive_down_messages() {
+        let mut rt = rt(&format!(
+            "{}fn int main:
+	a:pid = spawn quit()
+	monitor a
+	b:pid = spawn crash()
+	monitor b
+	d:Down = receive Down
+	n:int = got(d a \"normal\")
+	d = receive Down
+	k:int = got(d b \"boom\")
+	n = n + k
+	monitor a
+	d = receive Down
+	k = got(d a \"noproc\")
+	n = n + k
+	return n
+end
+",
+            SIGNALS
+        ));
+        assert_eq!(value(rt.run(100_000)), Value::Integer { v: 3 });
+    }
+
+    // main supervises steady, which blocks forever, and crasher, which
+    // fails as soon as it starts. Children start in reverse order of
+    // supervisor_add, so steady is pid 1 and crasher pid 2.
+    fn supervised(strategy: &str, max_restarts: i64) -> RT {
+        rt(&format!(
+            "{}fn void steady:
+	n:Node = receive Node
+	return unit
+end
+fn void crasher:
+	fail \"boom\"
+	return unit
+end
+fn int main:
+	st:string = \"{}\"
+	s:Supervisor = supervisor_new(st {})
+	st = \"crasher\"
+	supervisor_add(s st)
+	st = \"steady\"
+	supervisor_add(s st)
+	supervisor_run(s)
+	return 0
+end
+",
+            include_str!("../supervisor.beam"),
+            strategy,
+            max_restarts
+        ))
+    }
+
+    #[test]
+    fn one_for_one_restarts_only_the_failed_child() {
+        let mut rt = supervised("one_for_one", 1000);
+        assert!(matches!(rt.run(20_000), RunResult::Paused));
+        assert!(rt.scheduler.is_alive(1), "steady was restarted");
+        assert!(rt.scheduler.lock().next_pid > 5, "crasher was not restarted");
+    }
+
+    #[test]
+    fn one_for_all_restarts_every_child() {
+        let mut rt = supervised("one_for_all", 1000);
+        assert!(matches!(rt.run(20_000), RunResult::Paused));
+        assert!(!rt.scheduler.is_alive(1), "steady was left running");
+        assert!(rt.scheduler.lock().next_pid > 5, "crasher was not restarted");
+    }
+
+    #[test]
+    fn supervisors_give_up_after_too_many_restarts() {
+        for (strategy, spawned) in [("one_for_one", 2 + 3), ("one_for_all", 2 + 3 * 2)] {
+            let mut rt = supervised(strategy, 3);
+            assert_eq!(failure(rt.run(100_000)), (0, "too many restarts".into()), "{}", strategy);
+            assert_eq!(rt.scheduler.lock().next_pid, spawned + 1, "{}", strategy);
+            let running: Vec<u64> = (1..=spawned).filter(|p| rt.scheduler.is_alive(*p)).collect();
+            assert!(running.is_empty(), "{} left {:?} running", strategy, running);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::fast::{Backend, Instr, RT, compile_mach_to_ir_with, rt_from_intermediate_rt};
+use crate::mach::{Program, RunResult};
+use std::{error::Error, fmt};
+
+const OPS: usize = 256;
+
+#[derive(Clone)]
+pub struct OpcodeProfile {
+    prev: Option<u8>,
+    ops: Vec<u64>,
+    pairs: Vec<u64>,
+}
+impl Default for OpcodeProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl OpcodeProfile {
+    pub fn new() -> Self {
+        Self {
+            prev: None,
+            ops: vec![0; OPS],
+            pairs: vec![0; OPS * OPS],
+        }
+    }
+    pub fn record(&mut self, i: Instr) {
+        let op = i as u8;
+        self.ops[op as usize] += 1;
+        if let Some(prev) = self.prev {
+            self.pairs[prev as usize * OPS + op as usize] += 1;
+        }
+        self.prev = Some(op);
+    }
+    pub fn break_sequence(&mut self) {
+        self.prev = None;
+    }
+    pub fn total(&self) -> u64 {
+        self.ops.iter().sum()
+    }
+    pub fn count(&self, i: Instr) -> u64 {
+        self.ops[i as u8 as usize]
+    }
+    pub fn pair_count(&self, first: Instr, second: Instr) -> u64 {
+        self.pairs[first as u8 as usize * OPS + second as u8 as usize]
+    }
+    pub fn top_ops(&self, n: usize) -> Vec<(Instr, u64)> {
+        let mut out: Vec<_> = (0..OPS)
+            .filter(|i| self.ops[*i] != 0)
+            .map(|i| (unsafe { std::mem::transmute::<u8, Instr>(i as u8) }, self.ops[i]))
+            .collect();
+        out.sort_by_key(|x| std::cmp::Reverse(x.1));
+        out.truncate(n);
+        out
+    }
+    pub fn top_pairs(&self, n: usize) -> Vec<((Instr, Instr), u64)> {
+        let mut out: Vec<_> = (0..OPS * OPS)
+            .filter(|i| self.pairs[*i] != 0)
+            .map(|i| {
+                let (a, b) = ((i / OPS) as u8, (i % OPS) as u8);
+                unsafe { ((std::mem::transmute::<u8, Instr>(a), std::mem::transmute::<u8, Instr>(b)), self.pairs[i]) }
+            })
+            .collect();
+        out.sort_by_key(|x| std::cmp::Reverse(x.1));
+        out.truncate(n);
+        out
+    }
+    pub fn merge(&mut self, other: &OpcodeProfile) {
+        for (a, b) in self.ops.iter_mut().zip(&other.ops) {
+            *a += b;
+        }
+        for (a, b) in self.pairs.iter_mut().zip(&other.pairs) {
+            *a += b;
+        }
+    }
+}
+impl fmt::Display for OpcodeProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().max(1) as f64;
+        writeln!(f, "{} instructions", self.total())?;
+        writeln!(f, "opcodes:")?;
+        for (i, n) in self.top_ops(20) {
+            writeln!(f, "  {:>12} {:>6.2}% {:?}", n, n as f64 * 100.0 / total, i)?;
+        }
+        writeln!(f, "pairs:")?;
+        for ((a, b), n) in self.top_pairs(20) {
+            writeln!(f, "  {:>12} {:>6.2}% {:?} -> {:?}", n, n as f64 * 100.0 / total, a, b)?;
+        }
+        Ok(())
+    }
+}
+
+impl RT {
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Box::new(OpcodeProfile::new()));
+    }
+    pub fn take_profile(&mut self) -> Option<OpcodeProfile> {
+        self.profile.take().map(|p| *p)
+    }
+}
+
+/// Runs `progs` compiled without superinstructions and returns their opcode
+/// profile, so the pairs show which sequences are worth fusing.
+pub fn profile_unfused(progs: &[Program], fuel: u64) -> Result<OpcodeProfile, Box<dyn Error>> {
+    let ir = compile_mach_to_ir_with(progs, Backend::Unfused)?;
+    let mut rt = rt_from_intermediate_rt(ir);
+    rt.start_profile();
+    if let RunResult::Error(e) = rt.run(fuel) {
+        return Err(e);
+    }
+    Ok(rt.take_profile().unwrap_or_default())
+}
@@ -0,0 +1,515 @@
+use crate::fast::{AllocInfo, Instr, IntermediateRt, RT, RtError, Tag, Var, alloc_info};
+use crate::heap::Allocation;
+use crate::mach::{Binop, Cmd, Program, Type};
+use std::collections::HashMap;
+
+fn slot(v: &crate::mach::Var) -> Option<u64> {
+    match v {
+        crate::mach::Var::Stack { index, .. } => Some(*index as u64),
+        _ => None,
+    }
+}
+
+fn imm(v: &crate::mach::Var) -> Option<u64> {
+    match v {
+        crate::mach::Var::ConstInt { value } => Some(i64::cast_unsigned(*value)),
+        crate::mach::Var::ConstFloat { value } => Some(value.to_bits()),
+        crate::mach::Var::ConstBool { value } => Some(*value as u64),
+        _ => None,
+    }
+}
+
+fn binop_instr(t: &Type, op: &Binop, imm: bool) -> Option<Instr> {
+    use Instr::*;
+    Some(match (t, op, imm) {
+        (Type::Integer, Binop::Add, false) => RegIntAdd,
+        (Type::Integer, Binop::Sub, false) => RegIntSub,
+        (Type::Integer, Binop::Mul, false) => RegIntMul,
+        (Type::Integer, Binop::Div, false) => RegIntDiv,
+        (Type::Integer, Binop::Equal, false) => RegIntEq,
+        (Type::Integer, Binop::NotEqual, false) => RegIntNEq,
+        (Type::Integer, Binop::Less, false) => RegIntLess,
+        (Type::Integer, Binop::Greater, false) => RegIntGreater,
+        (Type::Integer, Binop::Add, true) => RegIntAddImm,
+        (Type::Integer, Binop::Sub, true) => RegIntSubImm,
+        (Type::Integer, Binop::Mul, true) => RegIntMulImm,
+        (Type::Integer, Binop::Div, true) => RegIntDivImm,
+        (Type::Integer, Binop::Equal, true) => RegIntEqImm,
+        (Type::Integer, Binop::NotEqual, true) => RegIntNEqImm,
+        (Type::Integer, Binop::Less, true) => RegIntLessImm,
+        (Type::Integer, Binop::Greater, true) => RegIntGreaterImm,
+        (Type::Float, Binop::Add, false) => RegFloatAdd,
+        (Type::Float, Binop::Sub, false) => RegFloatSub,
+        (Type::Float, Binop::Mul, false) => RegFloatMul,
+        (Type::Float, Binop::Div, false) => RegFloatDiv,
+        (Type::Float, Binop::Equal, false) => RegFloatEq,
+        (Type::Float, Binop::NotEqual, false) => RegFloatNeq,
+        (Type::Float, Binop::Less, false) => RegFloatLess,
+        (Type::Float, Binop::Greater, false) => RegFloatGreater,
+        (Type::Float, Binop::Add, true) => RegFloatAddImm,
+        (Type::Float, Binop::Sub, true) => RegFloatSubImm,
+        (Type::Float, Binop::Mul, true) => RegFloatMulImm,
+        (Type::Float, Binop::Div, true) => RegFloatDivImm,
+        (Type::Float, Binop::Equal, true) => RegFloatEqImm,
+        (Type::Float, Binop::NotEqual, true) => RegFloatNeqImm,
+        (Type::Float, Binop::Less, true) => RegFloatLessImm,
+        (Type::Float, Binop::Greater, true) => RegFloatGreaterImm,
+        (Type::Bool, Binop::Equal, false) => RegBoolEq,
+        (Type::Bool, Binop::NotEqual, false) => RegBoolNeq,
+        (Type::Bool, Binop::And, false) => RegBoolAnd,
+        (Type::Bool, Binop::Or, false) => RegBoolOr,
+        _ => return None,
+    })
+}
+
+fn cmp_jmp_instr(op: &Binop, imm: bool) -> Option<Instr> {
+    use Instr::*;
+    Some(match (op, imm) {
+        (Binop::Equal, false) => RegIntEqJmp,
+        (Binop::NotEqual, false) => RegIntNEqJmp,
+        (Binop::Less, false) => RegIntLessJmp,
+        (Binop::Greater, false) => RegIntGreaterJmp,
+        (Binop::Equal, true) => RegIntEqImmJmp,
+        (Binop::NotEqual, true) => RegIntNEqImmJmp,
+        (Binop::Less, true) => RegIntLessImmJmp,
+        (Binop::Greater, true) => RegIntGreaterImmJmp,
+        _ => return None,
+    })
+}
+
+fn int_operands(
+    l: &crate::mach::Var,
+    r: &crate::mach::Var,
+    p: &Program,
+) -> Option<(u64, u64, bool)> {
+    if l.get_type(&p.types) != Type::Integer {
+        return None;
+    }
+    let lhs = slot(l)?;
+    match (slot(r), imm(r)) {
+        (Some(s), _) => Some((lhs, s, false)),
+        (None, Some(v)) => Some((lhs, v, true)),
+        _ => None,
+    }
+}
+
+fn branch_on(cmd: &Cmd, slot_idx: u64) -> Option<&str> {
+    match cmd {
+        Cmd::JmpCond { cond, to, .. } if slot(cond) == Some(slot_idx) => Some(to),
+        _ => None,
+    }
+}
+
+pub(crate) fn compile_fused(
+    rt: &mut IntermediateRt,
+    cmds: &[Cmd],
+    p: &Program,
+    fixup_table: &mut HashMap<usize, String>,
+    labelled: impl Fn(usize) -> bool,
+) -> usize {
+    let Some(Cmd::Binop { l, r, out, op }) = cmds.first() else {
+        return 0;
+    };
+    let Some(dst) = slot(out) else {
+        return 0;
+    };
+    let Some((lhs, rhs, is_imm)) = int_operands(l, r, p) else {
+        return 0;
+    };
+    if *op == Binop::Add
+        && is_imm
+        && cmds.len() > 2
+        && !labelled(1)
+        && !labelled(2)
+        && let Cmd::Binop {
+            l: cl,
+            r: cr,
+            out: cout,
+            op: Binop::Less,
+        } = &cmds[1]
+        && slot(cl) == Some(dst)
+        && let (Some(c), Some((_, bound, bound_imm))) = (slot(cout), int_operands(cl, cr, p))
+        && let Some(to) = branch_on(&cmds[2], c)
+    {
+        rt.data.push_instr(if bound_imm {
+            Instr::RegIntAddLessImmJmp
+        } else {
+            Instr::RegIntAddLessJmp
+        });
+        for v in [dst, lhs, rhs, c, bound] {
+            rt.data.push_u64(v);
+        }
+        fixup_table.insert(rt.data.iv.len(), to.to_string());
+        rt.data.push_u64(42069);
+        return 3;
+    }
+    if cmds.len() > 1
+        && !labelled(1)
+        && let Some(i) = cmp_jmp_instr(op, is_imm)
+        && let Some(to) = branch_on(&cmds[1], dst)
+    {
+        rt.data.push_instr(i);
+        for v in [dst, lhs, rhs] {
+            rt.data.push_u64(v);
+        }
+        fixup_table.insert(rt.data.iv.len(), to.to_string());
+        rt.data.push_u64(42069);
+        return 2;
+    }
+    0
+}
+
+pub(crate) fn compile_reg_cmd(
+    rt: &mut IntermediateRt,
+    cmd: &Cmd,
+    p: &Program,
+    fixup_table: &mut HashMap<usize, String>,
+) -> bool {
+    use crate::mach::Var as MVar;
+    match cmd {
+        Cmd::Binop { l, r, out, op } => {
+            let (Some(dst), Some(lhs)) = (slot(out), slot(l)) else {
+                return false;
+            };
+            let (rhs, is_imm) = match (slot(r), imm(r)) {
+                (Some(s), _) => (s, false),
+                (None, Some(v)) => (v, true),
+                _ => return false,
+            };
+            let Some(i) = binop_instr(&l.get_type(&p.types), op, is_imm) else {
+                return false;
+            };
+            rt.data.push_instr(i);
+            rt.data.push_u64(dst);
+            rt.data.push_u64(lhs);
+            rt.data.push_u64(rhs);
+            true
+        }
+        Cmd::Return { to_return } => {
+            let Some(src) = slot(to_return) else {
+                return false;
+            };
+            rt.data.push_instr(Instr::RegRet);
+            rt.data.push_u64(src);
+            true
+        }
+        Cmd::Assign { l, r } => match (l, r) {
+            (MVar::Stack { index, .. }, MVar::Stack { index: src, .. }) => {
+                rt.data.push_instr(Instr::RegMov);
+                rt.data.push_u64(*index as u64);
+                rt.data.push_u64(*src as u64);
+                true
+            }
+            (MVar::Stack { index, .. }, MVar::OperatorNew { new_type }) => {
+                let info = alloc_info(rt, new_type, p);
+                rt.data.push_instr(Instr::RegNew);
+                rt.data.push_u64(*index as u64);
+                rt.data
+                    .push_u64(unsafe { std::mem::transmute::<AllocInfo, u64>(info) });
+                true
+            }
+            (
+                MVar::Stack { index, .. },
+                MVar::FieldAccess {
+                    of, index: field, ..
+                },
+            ) => {
+                let Some(obj) = slot(of) else {
+                    return false;
+                };
+                rt.data.push_instr(Instr::RegLoadMember);
+                rt.data.push_u64(*index as u64);
+                rt.data.push_u64(obj);
+                rt.data.push_u64(*field as u64);
+                true
+            }
+            (
+                MVar::FieldAccess {
+                    of, index: field, ..
+                },
+                MVar::Stack { index: src, .. },
+            ) => {
+                let Some(obj) = slot(of) else {
+                    return false;
+                };
+                rt.data.push_instr(Instr::RegStoreMember);
+                rt.data.push_u64(obj);
+                rt.data.push_u64(*field as u64);
+                rt.data.push_u64(*src as u64);
+                true
+            }
+            (MVar::Stack { index, .. }, c) => {
+                let i = match c {
+                    MVar::ConstInt { .. } => Instr::RegConstInt,
+                    MVar::ConstFloat { .. } => Instr::RegConstFloat,
+                    MVar::ConstBool { .. } => Instr::RegConstBool,
+                    _ => return false,
+                };
+                rt.data.push_instr(i);
+                rt.data.push_u64(*index as u64);
+                rt.data.push_u64(imm(c).unwrap());
+                true
+            }
+            _ => false,
+        },
+        Cmd::Send { to, msg } => {
+            let (Some(to), Some(msg)) = (slot(to), slot(msg)) else {
+                return false;
+            };
+            rt.data.push_instr(Instr::RegSend);
+            rt.data.push_u64(to);
+            rt.data.push_u64(msg);
+            true
+        }
+        Cmd::JmpCond { cond, to, .. } => {
+            let Some(c) = slot(cond) else {
+                return false;
+            };
+            rt.data.push_instr(Instr::RegJmpCond);
+            rt.data.push_u64(c);
+            fixup_table.insert(rt.data.iv.len(), to.to_string());
+            rt.data.push_u64(42069);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn is_threadable(i: Instr) -> bool {
+    use Instr::*;
+    !matches!(i, RegNew | RegSend | RegArgs | RegRet) && (i as u8) >= (RegMov as u8)
+}
+
+impl RT {
+    #[inline(always)]
+    pub(crate) fn exec_reg(&mut self, n: Instr) -> Result<(), RtError> {
+        use Instr::*;
+        use Tag::*;
+        match n {
+            RegMov => self.reg_mov(),
+            RegNew => self.reg_new(),
+            RegSend => self.reg_send()?,
+            RegPop => self.reg_pop(),
+            RegConstInt => self.reg_const(Integer),
+            RegConstFloat => self.reg_const(Float),
+            RegConstBool => self.reg_const(Bool),
+            RegLoadMember => self.reg_load_member()?,
+            RegStoreMember => self.reg_store_member()?,
+            RegJmpCond => self.reg_jmp_cond(),
+            RegIntAdd => self.reg_int(false, |l, r| Var::integer(l + r)),
+            RegIntSub => self.reg_int(false, |l, r| Var::integer(l - r)),
+            RegIntMul => self.reg_int(false, |l, r| Var::integer(l * r)),
+            RegIntDiv => self.reg_int_div(false)?,
+            RegIntEq => self.reg_int(false, |l, r| Var::boolean(l == r)),
+            RegIntNEq => self.reg_int(false, |l, r| Var::boolean(l != r)),
+            RegIntLess => self.reg_int(false, |l, r| Var::boolean(l < r)),
+            RegIntGreater => self.reg_int(false, |l, r| Var::boolean(l > r)),
+            RegIntAddImm => self.reg_int(true, |l, r| Var::integer(l + r)),
+            RegIntSubImm => self.reg_int(true, |l, r| Var::integer(l - r)),
+            RegIntMulImm => self.reg_int(true, |l, r| Var::integer(l * r)),
+            RegIntDivImm => self.reg_int_div(true)?,
+            RegIntEqImm => self.reg_int(true, |l, r| Var::boolean(l == r)),
+            RegIntNEqImm => self.reg_int(true, |l, r| Var::boolean(l != r)),
+            RegIntLessImm => self.reg_int(true, |l, r| Var::boolean(l < r)),
+            RegIntGreaterImm => self.reg_int(true, |l, r| Var::boolean(l > r)),
+            RegFloatAdd => self.reg_float(false, |l, r| Var::float(l + r)),
+            RegFloatSub => self.reg_float(false, |l, r| Var::float(l - r)),
+            RegFloatMul => self.reg_float(false, |l, r| Var::float(l * r)),
+            RegFloatDiv => self.reg_float(false, |l, r| Var::float(l / r)),
+            RegFloatEq => self.reg_float(false, |l, r| Var::boolean(l == r)),
+            RegFloatNeq => self.reg_float(false, |l, r| Var::boolean(l != r)),
+            RegFloatLess => self.reg_float(false, |l, r| Var::boolean(l < r)),
+            RegFloatGreater => self.reg_float(false, |l, r| Var::boolean(l > r)),
+            RegFloatAddImm => self.reg_float(true, |l, r| Var::float(l + r)),
+            RegFloatSubImm => self.reg_float(true, |l, r| Var::float(l - r)),
+            RegFloatMulImm => self.reg_float(true, |l, r| Var::float(l * r)),
+            RegFloatDivImm => self.reg_float(true, |l, r| Var::float(l / r)),
+            RegFloatEqImm => self.reg_float(true, |l, r| Var::boolean(l == r)),
+            RegFloatNeqImm => self.reg_float(true, |l, r| Var::boolean(l != r)),
+            RegFloatLessImm => self.reg_float(true, |l, r| Var::boolean(l < r)),
+            RegFloatGreaterImm => self.reg_float(true, |l, r| Var::boolean(l > r)),
+            RegBoolEq => self.reg_bool(|l, r| l == r),
+            RegBoolNeq => self.reg_bool(|l, r| l != r),
+            RegBoolAnd => self.reg_bool(|l, r| l && r),
+            RegBoolOr => self.reg_bool(|l, r| l || r),
+            RegIntEqJmp => self.reg_cmp_jmp(false, |l, r| l == r),
+            RegIntNEqJmp => self.reg_cmp_jmp(false, |l, r| l != r),
+            RegIntLessJmp => self.reg_cmp_jmp(false, |l, r| l < r),
+            RegIntGreaterJmp => self.reg_cmp_jmp(false, |l, r| l > r),
+            RegIntEqImmJmp => self.reg_cmp_jmp(true, |l, r| l == r),
+            RegIntNEqImmJmp => self.reg_cmp_jmp(true, |l, r| l != r),
+            RegIntLessImmJmp => self.reg_cmp_jmp(true, |l, r| l < r),
+            RegIntGreaterImmJmp => self.reg_cmp_jmp(true, |l, r| l > r),
+            RegIntAddLessJmp => self.reg_add_less_jmp(false),
+            RegIntAddLessImmJmp => self.reg_add_less_jmp(true),
+            _ => unreachable!("{:?} is not a register instruction", n),
+        }
+        Ok(())
+    }
+    #[inline(always)]
+    fn reg(&self, idx: u64) -> &Var {
+        &self.var_stack[self.var_base_ptr + idx as usize]
+    }
+    #[inline(always)]
+    fn next_reg(&mut self) -> &Var {
+        let idx = self.next_u64();
+        self.reg(idx)
+    }
+    #[inline(always)]
+    fn set_reg(&mut self, idx: u64, v: Var) {
+        let at = self.var_base_ptr + idx as usize;
+        self.var_stack[at] = v;
+    }
+    #[inline(always)]
+    pub(crate) fn reg_int(&mut self, imm: bool, f: impl Fn(i64, i64) -> Var) {
+        let dst = self.next_u64();
+        let l = self.next_reg().get_int();
+        let r = self.next_u64();
+        let r = if imm {
+            u64::cast_signed(r)
+        } else {
+            self.reg(r).get_int()
+        };
+        self.set_reg(dst, f(l, r));
+    }
+    pub(crate) fn reg_int_div(&mut self, imm: bool) -> Result<(), RtError> {
+        let dst = self.next_u64();
+        let l = self.next_reg().get_int();
+        let r = self.next_u64();
+        let r = if imm {
+            u64::cast_signed(r)
+        } else {
+            self.reg(r).get_int()
+        };
+        let v = self.int_div(l, r)?;
+        self.set_reg(dst, Var::integer(v));
+        Ok(())
+    }
+    #[inline(always)]
+    pub(crate) fn reg_float(&mut self, imm: bool, f: impl Fn(f64, f64) -> Var) {
+        let dst = self.next_u64();
+        let l = self.next_reg().get_float();
+        let r = self.next_u64();
+        let r = if imm {
+            f64::from_bits(r)
+        } else {
+            self.reg(r).get_float()
+        };
+        self.set_reg(dst, f(l, r));
+    }
+    #[inline(always)]
+    pub(crate) fn reg_bool(&mut self, f: impl Fn(bool, bool) -> bool) {
+        let dst = self.next_u64();
+        let l = self.next_reg().get_bool();
+        let r = self.next_reg().get_bool();
+        self.set_reg(dst, Var::boolean(f(l, r)));
+    }
+    pub(crate) fn reg_const(&mut self, tag: Tag) {
+        let dst = self.next_u64();
+        let v = self.next_u64();
+        let v = match tag {
+            Tag::Integer => Var::integer(u64::cast_signed(v)),
+            Tag::Float => Var::float(f64::from_bits(v)),
+            _ => Var::boolean(v != 0),
+        };
+        self.set_reg(dst, v);
+    }
+    fn reg_barrier(&mut self, v: &Var) {
+        if matches!(v.get().tag, Tag::Ptr | Tag::String) {
+            self.gc_write_barrier(v);
+        }
+    }
+    pub(crate) fn reg_mov(&mut self) {
+        let dst = self.next_u64();
+        let v = self.next_reg().clone();
+        self.reg_barrier(&v);
+        self.set_reg(dst, v);
+    }
+    pub(crate) fn reg_pop(&mut self) {
+        let dst = self.next_u64();
+        let v = self.op_pop();
+        self.reg_barrier(&v);
+        self.set_reg(dst, v);
+    }
+    pub(crate) fn reg_new(&mut self) {
+        let dst = self.next_u64();
+        let info: AllocInfo = unsafe { std::mem::transmute(self.next_u64()) };
+        let v = self.new_object(info);
+        self.reg_barrier(&v);
+        self.set_reg(dst, v);
+    }
+    pub(crate) fn reg_send(&mut self) -> Result<(), RtError> {
+        let to = self.next_reg().clone();
+        let to = self.pid_of(&to)?;
+        let msg = self.next_reg().get_ptr() as *mut Var;
+        self.send_message(to, msg)
+    }
+    pub(crate) fn reg_load_member(&mut self) -> Result<(), RtError> {
+        let dst = self.next_u64();
+        let obj = self.next_reg().get_ptr() as *mut Var;
+        let offset = self.next_u64() as usize;
+        self.check_null(obj)?;
+        self.check_alloc(obj as *const Allocation)?;
+        self.lock_object(obj);
+        let v = unsafe { (*obj.add(offset + 1)).clone() };
+        self.unlock_object(obj);
+        self.reg_barrier(&v);
+        self.set_reg(dst, v);
+        Ok(())
+    }
+    pub(crate) fn reg_store_member(&mut self) -> Result<(), RtError> {
+        let obj = self.next_reg().get_ptr() as *mut Var;
+        let offset = self.next_u64() as usize;
+        let v = self.next_reg().clone();
+        self.check_null(obj)?;
+        self.check_alloc(obj as *const Allocation)?;
+        self.release_held();
+        self.lock_object(obj);
+        self.reg_barrier(&v);
+        unsafe {
+            *obj.add(offset + 1) = v;
+        }
+        self.unlock_object(obj);
+        Ok(())
+    }
+    pub(crate) fn reg_cmp_jmp(&mut self, imm: bool, f: impl Fn(i64, i64) -> bool) {
+        let dst = self.next_u64();
+        let l = self.next_reg().get_int();
+        let r = self.next_u64();
+        let r = if imm {
+            u64::cast_signed(r)
+        } else {
+            self.reg(r).get_int()
+        };
+        let to = self.next_u64();
+        let cond = f(l, r);
+        self.set_reg(dst, Var::boolean(cond));
+        if cond {
+            self.ip = to as usize;
+        }
+    }
+    pub(crate) fn reg_add_less_jmp(&mut self, imm: bool) {
+        let dst = self.next_u64();
+        let v = self.next_reg().get_int() + u64::cast_signed(self.next_u64());
+        self.set_reg(dst, Var::integer(v));
+        let c = self.next_u64();
+        let n = self.next_u64();
+        let n = if imm {
+            u64::cast_signed(n)
+        } else {
+            self.reg(n).get_int()
+        };
+        let to = self.next_u64();
+        self.set_reg(c, Var::boolean(v < n));
+        if v < n {
+            self.ip = to as usize;
+        }
+    }
+    pub(crate) fn reg_jmp_cond(&mut self) {
+        let cond = self.next_reg().get_bool();
+        let to = self.next_u64();
+        if cond {
+            self.ip = to as usize;
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::fast::{
+    AllocInfo, CompileError, Instr, IntermediateRt, OPCODE_WIDTH, OwnedSlice, RT, compile_module_ir,
+};
+use crate::mach::Program;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Module {
+    pub code: IntermediateRt,
+    pub imports: Vec<(usize, String)>,
+}
+
+pub fn compile_module(progs: &[Program]) -> Result<Module, CompileError> {
+    let (code, imports) = compile_module_ir(progs)?;
+    Ok(Module { code, imports })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReloadError {
+    UnresolvedSymbol { name: String },
+    TypeChanged { name: String },
+}
+impl std::fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadError::UnresolvedSymbol { name } => {
+                write!(f, "module calls {} which is not loaded", name)
+            }
+            ReloadError::TypeChanged { name } => {
+                write!(f, "module changes the layout of struct {}", name)
+            }
+        }
+    }
+}
+impl std::error::Error for ReloadError {}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CodeRange {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub generation: u64,
+}
+impl CodeRange {
+    fn holds(&self, ip: usize) -> bool {
+        self.start <= ip && ip < self.end
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CodeTable {
+    pub generation: u64,
+    pub current: Vec<CodeRange>,
+    pub old: Vec<CodeRange>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Operand {
+    Plain,
+    Addr,
+    Str,
+    Type,
+    Alloc,
+}
+
+pub(crate) fn operands(i: Instr) -> &'static [Operand] {
+    use Instr::*;
+    use Operand::*;
+    match i {
+        Jmp | JmpCond | Call => &[Addr],
+        Spawn => &[Addr, Plain],
+        Receive => &[Type],
+        ReceiveAfter => &[Type, Addr],
+        ConstStr => &[Str],
+        CallNative => &[Str, Plain],
+        New => &[Alloc],
+        LoadVoid | LoadInt | LoadFloat | LoadBool | LoadPtr | LoadStr | LoadMember
+        | LoadVarAddr | LoadMemberAddr | ConstVoid | ConstInt | ConstFloat | ConstBool
+        | LoadPid => &[Plain],
+        RegMov | RegSend | RegConstInt | RegConstFloat | RegConstBool => &[Plain, Plain],
+        RegNew => &[Plain, Alloc],
+        RegArgs | RegPop | RegRet => &[Plain],
+        RegJmpCond => &[Plain, Addr],
+        RegLoadMember | RegStoreMember | RegIntAdd | RegIntSub | RegIntMul | RegIntDiv
+        | RegIntEq | RegIntNEq | RegIntLess | RegIntGreater | RegIntAddImm | RegIntSubImm
+        | RegIntMulImm | RegIntDivImm | RegIntEqImm | RegIntNEqImm | RegIntLessImm
+        | RegIntGreaterImm | RegFloatAdd | RegFloatSub | RegFloatMul | RegFloatDiv
+        | RegFloatEq | RegFloatNeq | RegFloatLess | RegFloatGreater | RegFloatAddImm
+        | RegFloatSubImm | RegFloatMulImm | RegFloatDivImm | RegFloatEqImm | RegFloatNeqImm
+        | RegFloatLessImm | RegFloatGreaterImm | RegBoolEq | RegBoolNeq | RegBoolAnd
+        | RegBoolOr => &[Plain, Plain, Plain],
+        RegIntEqJmp | RegIntNEqJmp | RegIntLessJmp | RegIntGreaterJmp | RegIntEqImmJmp
+        | RegIntNEqImmJmp | RegIntLessImmJmp | RegIntGreaterImmJmp => &[Plain, Plain, Plain, Addr],
+        RegIntAddLessJmp | RegIntAddLessImmJmp => &[Plain, Plain, Plain, Plain, Plain, Addr],
+        _ => &[],
+    }
+}
+
+fn rewrite(code: &mut [u8], start: usize, end: usize, mut f: impl FnMut(Instr, Operand, u64) -> u64) {
+    let mut ip = start;
+    while ip < end {
+        let i: Instr = unsafe { std::mem::transmute(code[ip]) };
+        ip += OPCODE_WIDTH;
+        for op in operands(i) {
+            let old = u64::from_le_bytes(code[ip..ip + 8].try_into().unwrap());
+            let new = f(i, *op, old);
+            code[ip..ip + 8].copy_from_slice(&new.to_le_bytes());
+            ip += 8;
+        }
+    }
+}
+
+fn ranges(symbols: &HashMap<String, usize>, arity: &HashMap<String, usize>, base: usize, end: usize, generation: u64) -> Vec<CodeRange> {
+    let mut starts: Vec<(usize, String)> = arity
+        .keys()
+        .filter_map(|n| symbols.get(n).map(|s| (*s + base, n.clone())))
+        .collect();
+    starts.sort();
+    let mut out = Vec::new();
+    for (k, (start, name)) in starts.iter().enumerate() {
+        let stop = starts.get(k + 1).map_or(end, |n| n.0);
+        out.push(CodeRange {
+            name: name.clone(),
+            start: *start,
+            end: stop,
+            generation,
+        });
+    }
+    out
+}
+
+impl RT {
+    pub fn code_generation(&self) -> u64 {
+        self.code.generation
+    }
+    pub fn has_old_code(&self) -> bool {
+        !self.code.old.is_empty()
+    }
+    pub fn load_module(&mut self, module: Module) -> Result<u64, ReloadError> {
+        let Module { code, imports } = module;
+        if self.code.current.is_empty() {
+            self.code.current = ranges(&self.symbol_table, &self.arity, 0, self.instructions.len(), 0);
+        }
+        let mut types = self.types.as_slice().to_vec();
+        let mut type_map = Vec::new();
+        for t in &code.types {
+            match types.iter().position(|x| x.name == t.name) {
+                Some(idx) if types[idx].fields != t.fields => {
+                    return Err(ReloadError::TypeChanged { name: t.name.clone() });
+                }
+                Some(idx) => type_map.push(idx as u64),
+                None => {
+                    type_map.push(types.len() as u64);
+                    types.push(t.clone());
+                }
+            }
+        }
+        for (_, name) in &imports {
+            if !self.symbol_table.contains_key(name) {
+                return Err(ReloadError::UnresolvedSymbol { name: name.clone() });
+            }
+        }
+        let base = self.instructions.len();
+        let str_base = self.strings.len() as u64;
+        let mut data = code.data.iv;
+        let len = data.len();
+        rewrite(&mut data, 0, len, |_, op, v| match op {
+            Operand::Addr => v + base as u64,
+            Operand::Str => v + str_base,
+            Operand::Type => type_map[v as usize],
+            Operand::Alloc => {
+                let mut info: AllocInfo = unsafe { std::mem::transmute(v) };
+                info.type_info = type_map[info.type_info as usize] as u32;
+                unsafe { std::mem::transmute::<AllocInfo, u64>(info) }
+            }
+            Operand::Plain => v,
+        });
+        for (at, name) in &imports {
+            data[*at..*at + 8].copy_from_slice(&(self.symbol_table[name] as u64).to_le_bytes());
+        }
+        self.code.generation += 1;
+        let generation = self.code.generation;
+        let mut redirect = HashMap::new();
+        for name in code.arity.keys() {
+            if let Some(old) = self.symbol_table.get(name) {
+                redirect.insert(*old as u64, (code.symbol_table[name] + base) as u64);
+            }
+        }
+        let mut instructions = self.instructions.as_slice().to_vec();
+        instructions.extend(data);
+        rewrite(&mut instructions, 0, base, |i, op, v| {
+            if op == Operand::Addr && matches!(i, Instr::Call | Instr::Spawn) {
+                redirect.get(&v).copied().unwrap_or(v)
+            } else {
+                v
+            }
+        });
+        self.instructions = OwnedSlice::from_vec(instructions);
+        self.jit_reset();
+        let mut strings = self.strings.as_slice().to_vec();
+        strings.extend(code.strings);
+        self.strings = OwnedSlice::from_vec(strings);
+        self.types = OwnedSlice::from_vec(types);
+        for (name, loc) in &code.symbol_table {
+            self.symbol_table.insert(name.clone(), loc + base);
+        }
+        for (name, arity) in &code.arity {
+            self.arity.insert(name.clone(), *arity);
+        }
+        let loaded = ranges(&code.symbol_table, &code.arity, base, base + len, generation);
+        let (replaced, kept) = std::mem::take(&mut self.code.current)
+            .into_iter()
+            .partition(|r| code.arity.contains_key(&r.name));
+        self.code.current = kept;
+        self.code.current.extend(loaded);
+        self.code.old.extend(replaced);
+        self.purge_old_code();
+        Ok(generation)
+    }
+    fn code_refs(&self) -> Vec<usize> {
+        let mut out = Vec::new();
+        // A halted main leaves its ip one past its last instruction, which may
+        // already be the start of the next function.
+        if !self.scheduler.is_idle() && !self.halted {
+            out.push(self.ip);
+            out.extend(self.ret_info.as_slice()[..self.ret_info_ptr].iter().map(|r| r.ip));
+        }
+        let s = self.scheduler.lock();
+        for p in s.processes() {
+            out.push(p.ip);
+            out.extend(p.ret_info.as_slice()[..p.ret_info_ptr].iter().map(|r| r.ip));
+            out.extend(p.finalize_queue.iter().map(|f| f.1));
+        }
+        for w in s.waiting.values() {
+            out.push(w.resume_ip);
+            if w.deadline.is_some() {
+                out.push(w.timeout_ip);
+            }
+        }
+        out.extend(self.gc_info.finalize_queue.iter().map(|f| f.1));
+        out
+    }
+    pub fn purge_old_code(&mut self) -> usize {
+        let refs = self.code_refs();
+        let (mut stale, kept): (Vec<CodeRange>, Vec<CodeRange>) = std::mem::take(&mut self.code.old)
+            .into_iter()
+            .partition(|r| !refs.iter().any(|ip| r.holds(*ip)));
+        self.code.old = kept;
+        stale.sort_by_key(|r| std::cmp::Reverse(r.start));
+        for r in &stale {
+            self.remove_code(r.start, r.end);
+        }
+        stale.len()
+    }
+    fn remove_code(&mut self, start: usize, end: usize) {
+        let len = end - start;
+        let map = |x: usize| if x >= end { x - len } else { x };
+        let mut instructions = self.instructions.as_slice().to_vec();
+        let total = instructions.len();
+        let mut fix = |_, op, v: u64| {
+            if op == Operand::Addr {
+                map(v as usize) as u64
+            } else {
+                v
+            }
+        };
+        rewrite(&mut instructions, 0, start, &mut fix);
+        rewrite(&mut instructions, end, total, &mut fix);
+        instructions.drain(start..end);
+        self.instructions = OwnedSlice::from_vec(instructions);
+        self.jit_reset();
+        self.symbol_table.retain(|_, v| *v < start || *v >= end);
+        for v in self.symbol_table.values_mut() {
+            *v = map(*v);
+        }
+        for r in self.code.current.iter_mut().chain(self.code.old.iter_mut()) {
+            r.start = map(r.start);
+            r.end = map(r.end);
+        }
+        if !self.scheduler.is_idle() {
+            self.ip = map(self.ip);
+            for r in &mut self.ret_info.as_slice_mut()[..self.ret_info_ptr] {
+                r.ip = map(r.ip);
+            }
+        }
+        let shared = self.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        for p in s.processes_mut() {
+            p.ip = map(p.ip);
+            let n = p.ret_info_ptr;
+            for r in &mut p.ret_info.as_slice_mut()[..n] {
+                r.ip = map(r.ip);
+            }
+            for f in &mut p.finalize_queue {
+                f.1 = map(f.1);
+            }
+        }
+        for w in s.waiting.values_mut() {
+            w.resume_ip = map(w.resume_ip);
+            w.timeout_ip = map(w.timeout_ip);
+        }
+        for f in &mut self.gc_info.finalize_queue {
+            f.1 = map(f.1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fast::{RT, compile_mach_to_ir, rt_from_intermediate_rt};
+    use crate::mach::{Program, RunResult, Value};
+
+    const NODE: &str = "struct Node
+	next Node
+	value int
+end
+";
+
+    fn parse(body: &str) -> Program {
+        let src = format!("{}{}", NODE, body);
+        crate::parser::parse_to_program(src, "test.beam".into()).unwrap()
+    }
+
+    fn server(version: i64) -> String {
+        format!(
+            "fn void server parent pid:
+	m:Node = receive Node
+	m.value = {version}
+	send parent m
+	return unit
+end
+"
+        )
+    }
+
+    fn rt() -> RT {
+        let main = "fn int main:
+	me:pid = self
+	a:pid = spawn server(me)
+	sleep 0
+	i:int = 0
+	b:bool = false
+	label spin
+	i = i + 1
+	b = i < 1000
+	if b goto spin
+	n:Node = new Node
+	send a n
+	n = receive Node
+	r:int = n.value
+	c:pid = spawn server(me)
+	send c n
+	n = receive Node
+	v:int = n.value
+	r = r * 10
+	r = r + v
+	return r
+end
+";
+        let p = parse(&format!("{}{}", server(1), main));
+        let mut rt = rt_from_intermediate_rt(compile_mach_to_ir(&[p]).unwrap());
+        rt.enable_heap_verification();
+        rt
+    }
+
+    #[test]
+    fn old_code_lives_until_its_last_process_leaves() {
+        let mut rt = rt();
+        assert!(matches!(rt.run(500), RunResult::Paused));
+        let module = super::compile_module(&[parse(&server(2))]).unwrap();
+        assert_eq!(rt.load_module(module), Ok(1));
+        // The first server still waits in the code it was spawned with.
+        assert!(rt.has_old_code());
+        assert_eq!(rt.code.old.len(), 1);
+        assert_eq!(rt.code.old[0].name, "server");
+        assert_eq!(rt.purge_old_code(), 0);
+        let before = rt.instructions.len();
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("program did not finish");
+        };
+        // Old code answers the first message, current code the second.
+        assert_eq!(v, Value::Integer { v: 12 });
+        assert_eq!(rt.purge_old_code(), 1);
+        assert!(!rt.has_old_code());
+        assert!(rt.instructions.len() < before);
+        assert!(rt.code.current.iter().all(|r| r.generation == 0 || r.name == "server"));
+    }
+
+    #[test]
+    fn unreferenced_old_code_is_purged_on_load() {
+        let mut rt = rt();
+        let module = super::compile_module(&[parse(&server(2))]).unwrap();
+        assert_eq!(rt.load_module(module), Ok(1));
+        assert!(!rt.has_old_code());
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("program did not finish");
+        };
+        assert_eq!(v, Value::Integer { v: 22 });
+    }
+}
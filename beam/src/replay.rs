@@ -0,0 +1,640 @@
+use crate::fast::{RT, RtError, Tag, Var};
+use crate::heap::Allocation;
+use crate::mach::{Machine, NativeInterface, Type, Value};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+/// Names under which clock readings and timer firings go in the log, next
+/// to the native calls. Neither can clash with the name of a real native.
+pub const NOW: &str = "clock.now";
+pub const TIMEOUT: &str = "clock.timeout";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LoggedValue {
+    Unit,
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    /// A string that is not valid UTF-8, kept byte for byte.
+    Bytes(Vec<u8>),
+    Pid(u64),
+    Null,
+    Weak,
+    Object {
+        type_name: String,
+        fields: Vec<LoggedValue>,
+    },
+}
+impl std::fmt::Display for LoggedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoggedValue::Unit => write!(f, "unit"),
+            LoggedValue::Integer(v) => write!(f, "{}", v),
+            LoggedValue::Float(v) => write!(f, "{}", v),
+            LoggedValue::Bool(v) => write!(f, "{}", v),
+            LoggedValue::String(v) => write!(f, "{:?}", v),
+            LoggedValue::Bytes(v) => write!(f, "{:?}", String::from_utf8_lossy(v)),
+            LoggedValue::Pid(v) => write!(f, "pid:{}", v),
+            LoggedValue::Null => write!(f, "null"),
+            LoggedValue::Weak => write!(f, "weak"),
+            LoggedValue::Object { type_name, fields } => {
+                write!(f, "{}{{", type_name)?;
+                for (i, v) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NativeCall {
+    pub pid: u64,
+    pub name: String,
+    pub args: Vec<LoggedValue>,
+    pub result: LoggedValue,
+}
+impl NativeCall {
+    fn signature(name: &str, args: &[LoggedValue]) -> String {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        format!("{}({})", name, args.join(" "))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub calls: Vec<NativeCall>,
+}
+impl Recording {
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, rmp_serde::to_vec(self)?)?;
+        Ok(())
+    }
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(rmp_serde::from_slice(&std::fs::read(path)?)?)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub pid: u64,
+    pub call: usize,
+    pub expected: Option<NativeCall>,
+    pub name: String,
+    pub args: Vec<LoggedValue>,
+}
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let got = NativeCall::signature(&self.name, &self.args);
+        match &self.expected {
+            Some(e) => write!(
+                f,
+                "process {} native call {}: recorded {} but got {}",
+                self.pid,
+                self.call,
+                NativeCall::signature(&e.name, &e.args),
+                got
+            ),
+            None => write!(
+                f,
+                "process {} native call {}: recording ended but got {}",
+                self.pid, self.call, got
+            ),
+        }
+    }
+}
+impl Error for Divergence {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogMode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug)]
+pub struct NativeLog {
+    pub mode: LogMode,
+    pub recording: Recording,
+    pub divergence: Option<Divergence>,
+    cursors: HashMap<u64, (usize, usize)>,
+}
+impl NativeLog {
+    pub fn new(mode: LogMode, recording: Recording) -> Self {
+        Self {
+            mode,
+            recording,
+            divergence: None,
+            cursors: HashMap::new(),
+        }
+    }
+    pub fn record(&mut self, pid: u64, name: &str, args: Vec<LoggedValue>, result: LoggedValue) {
+        self.recording.calls.push(NativeCall {
+            pid,
+            name: name.to_string(),
+            args,
+            result,
+        });
+    }
+    pub fn replay(&mut self, pid: u64, name: &str, args: Vec<LoggedValue>) -> Result<LoggedValue, Box<Divergence>> {
+        let (from, call) = self.cursors.get(&pid).copied().unwrap_or((0, 0));
+        let found = self.recording.calls[from..]
+            .iter()
+            .position(|c| c.pid == pid)
+            .map(|i| i + from);
+        if let Some(i) = found {
+            let c = &self.recording.calls[i];
+            if c.name == name && c.args == args {
+                self.cursors.insert(pid, (i + 1, call + 1));
+                return Ok(c.result.clone());
+            }
+        }
+        let d = Box::new(Divergence {
+            pid,
+            call,
+            expected: found.map(|i| self.recording.calls[i].clone()),
+            name: name.to_string(),
+            args,
+        });
+        if self.divergence.is_none() {
+            self.divergence = Some((*d).clone());
+        }
+        Err(d)
+    }
+    /// Takes the next entry of `pid` if it is a timer firing.
+    pub fn replay_timeout(&mut self, pid: u64) -> bool {
+        let (from, call) = self.cursors.get(&pid).copied().unwrap_or((0, 0));
+        let found = self.recording.calls[from..]
+            .iter()
+            .position(|c| c.pid == pid)
+            .map(|i| i + from);
+        match found {
+            Some(i) if self.recording.calls[i].name == TIMEOUT => {
+                self.cursors.insert(pid, (i + 1, call + 1));
+                true
+            }
+            _ => false,
+        }
+    }
+    pub fn unreplayed(&self) -> Vec<NativeCall> {
+        let mut out = Vec::new();
+        for (i, c) in self.recording.calls.iter().enumerate() {
+            let (from, _) = self.cursors.get(&c.pid).copied().unwrap_or((0, 0));
+            if i >= from {
+                out.push(c.clone());
+            }
+        }
+        out
+    }
+}
+
+impl NativeInterface {
+    pub fn start_recording(&mut self) {
+        self.log = Some(Arc::new(Mutex::new(NativeLog::new(
+            LogMode::Record,
+            Recording::default(),
+        ))));
+    }
+    pub fn start_replay(&mut self, recording: Recording) {
+        self.log = Some(Arc::new(Mutex::new(NativeLog::new(LogMode::Replay, recording))));
+    }
+    pub fn stop_logging(&mut self) -> Option<Recording> {
+        let log = self.log.take()?;
+        let r = log.lock().unwrap().recording.clone();
+        Some(r)
+    }
+    pub fn recording(&self) -> Option<Recording> {
+        Some(self.log.as_ref()?.lock().unwrap().recording.clone())
+    }
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.log.as_ref()?.lock().unwrap().divergence.clone()
+    }
+    pub fn unreplayed(&self) -> Vec<NativeCall> {
+        self.log
+            .as_ref()
+            .map(|l| l.lock().unwrap().unreplayed())
+            .unwrap_or_default()
+    }
+    fn log_mode(&self) -> Option<LogMode> {
+        Some(self.log.as_ref()?.lock().unwrap().mode)
+    }
+    fn replay_call(&self, pid: u64, name: &str, args: Vec<LoggedValue>) -> Option<Result<LoggedValue, Box<Divergence>>> {
+        if self.log_mode()? != LogMode::Replay {
+            return None;
+        }
+        Some(self.log.as_ref()?.lock().unwrap().replay(pid, name, args))
+    }
+    fn replay_timeout(&self, pid: u64) -> Option<bool> {
+        if self.log_mode()? != LogMode::Replay {
+            return None;
+        }
+        Some(self.log.as_ref()?.lock().unwrap().replay_timeout(pid))
+    }
+    fn record_call(&self, pid: u64, name: &str, args: Vec<LoggedValue>, result: LoggedValue) {
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap();
+            if log.mode == LogMode::Record {
+                log.record(pid, name, args, result);
+            }
+        }
+    }
+}
+
+impl RT {
+    fn log_var(&self, v: &Var, depth: usize) -> LoggedValue {
+        match v.get().tag {
+            Tag::Void => LoggedValue::Unit,
+            Tag::LValue => self.log_var(v.get_l_value(), depth),
+            Tag::Integer => LoggedValue::Integer(v.get_int()),
+            Tag::Float => LoggedValue::Float(v.get_float()),
+            Tag::Bool => LoggedValue::Bool(v.get_bool()),
+            Tag::String => {
+                let s = v.get_string();
+                if s.is_null() {
+                    return LoggedValue::String(String::new());
+                }
+                let bytes = unsafe { (*s).as_bytes() };
+                match std::str::from_utf8(bytes) {
+                    Ok(t) => LoggedValue::String(t.to_string()),
+                    Err(_) => LoggedValue::Bytes(bytes.to_vec()),
+                }
+            }
+            Tag::Pid => LoggedValue::Pid(v.get_pid().unwrap_or_default()),
+            Tag::Weak => LoggedValue::Weak,
+            Tag::Ptr => {
+                let p = v.get_ptr();
+                if p.is_null() {
+                    return LoggedValue::Null;
+                }
+                if depth > 0 {
+                    return LoggedValue::Unit;
+                }
+                let fields = self.read_fields(p as *mut Var);
+                let type_idx = unsafe { (*(p as *const Allocation)).type_idx } as usize;
+                LoggedValue::Object {
+                    type_name: self.types[type_idx].name.clone(),
+                    fields: fields.iter().map(|f| self.log_var(f, depth + 1)).collect(),
+                }
+            }
+        }
+    }
+    fn unlog_var(&mut self, v: &LoggedValue) -> Var {
+        match v {
+            LoggedValue::Unit | LoggedValue::Weak => Var::new(),
+            LoggedValue::Integer(v) => Var::integer(*v),
+            LoggedValue::Float(v) => Var::float(*v),
+            LoggedValue::Bool(v) => Var::boolean(*v),
+            LoggedValue::String(v) => self.allocate_str(v),
+            LoggedValue::Bytes(v) => self.allocate_bytes(v),
+            LoggedValue::Pid(v) => Var::pid(*v),
+            LoggedValue::Null => Var::ptr(std::ptr::null_mut()),
+            LoggedValue::Object { type_name, fields } => {
+                let Some(type_idx) = self.types.as_slice().iter().position(|t| &t.name == type_name) else {
+                    return Var::ptr(std::ptr::null_mut());
+                };
+                let n = self.types[type_idx].fields.len();
+                let obj = crate::heap::rt_heap_allocate(
+                    &mut self.heap,
+                    n * size_of::<Var>(),
+                    n as u16,
+                    type_idx as u16,
+                ) as *mut Var;
+                self.gc_allocated(obj as *mut Allocation);
+                for i in 0..n {
+                    let tag = self.types[type_idx].fields[i];
+                    let v = match fields.get(i) {
+                        Some(f) if !matches!(f, LoggedValue::Unit | LoggedValue::Weak) => self.unlog_var(f),
+                        _ => {
+                            let mut v = Var::new();
+                            v.get_mut().tag = tag;
+                            v
+                        }
+                    };
+                    unsafe {
+                        *obj.add(i + 1) = v;
+                    }
+                }
+                Var::ptr(obj)
+            }
+        }
+    }
+    pub(crate) fn replay_native(&mut self, name: &str, args: &[Var]) -> Option<Result<Var, RtError>> {
+        self.natives.log.as_ref()?;
+        let logged = args.iter().map(|a| self.log_var(a, 0)).collect();
+        match self.natives.replay_call(self.scheduler.current, name, logged)? {
+            Ok(r) => Some(Ok(self.unlog_var(&r))),
+            Err(d) => Some(Err(RtError::ReplayDiverged(d))),
+        }
+    }
+    pub(crate) fn record_native(&self, name: &str, args: &[Var], result: &Var) {
+        if self.natives.log.is_none() {
+            return;
+        }
+        let logged = args.iter().map(|a| self.log_var(a, 0)).collect();
+        let result = self.log_var(result, 0);
+        self.natives
+            .record_call(self.scheduler.current, name, logged, result);
+    }
+    /// Reads the clock, taking the reading from the log when replaying.
+    pub(crate) fn clock_now(&mut self) -> Result<Var, RtError> {
+        if let Some(v) = self.replay_native(NOW, &[]) {
+            return v;
+        }
+        let v = Var::integer(self.scheduler.now() as i64);
+        self.record_native(NOW, &[], &v);
+        Ok(v)
+    }
+    /// When replaying, whether the current process's timer fired at this
+    /// point of the recording.
+    pub(crate) fn replay_timeout(&self) -> Option<bool> {
+        self.natives.replay_timeout(self.scheduler.current)
+    }
+    pub(crate) fn record_timeouts(&self, pids: &[u64]) {
+        for &pid in pids {
+            self.natives.record_call(pid, TIMEOUT, Vec::new(), LoggedValue::Unit);
+        }
+    }
+}
+
+impl Machine {
+    fn log_value(&self, v: &Value, depth: usize) -> LoggedValue {
+        match v {
+            Value::Unit | Value::ObjectHeader { .. } => LoggedValue::Unit,
+            Value::Integer { v } => LoggedValue::Integer(*v),
+            Value::Float { v } => LoggedValue::Float(*v),
+            Value::Bool { v } => LoggedValue::Bool(*v),
+            Value::String { v } => LoggedValue::String(v.to_string()),
+            Value::Pid { v } => LoggedValue::Pid(*v),
+            Value::Weak { .. } => LoggedValue::Weak,
+            Value::Object { ptr: 0 } => LoggedValue::Null,
+            Value::Object { .. } if depth > 0 => LoggedValue::Unit,
+            Value::Object { ptr } => {
+                let Value::ObjectHeader { information, size } = self.heap.get(*ptr as usize) else {
+                    return LoggedValue::Null;
+                };
+                LoggedValue::Object {
+                    type_name: self.type_table[information as usize].0.to_string(),
+                    fields: (1..=size as usize)
+                        .map(|i| self.log_value(&self.heap.get(*ptr as usize + i), depth + 1))
+                        .collect(),
+                }
+            }
+        }
+    }
+    fn unlog_value(&mut self, v: &LoggedValue) -> Value {
+        match v {
+            LoggedValue::Unit | LoggedValue::Weak => Value::Unit,
+            LoggedValue::Integer(v) => Value::Integer { v: *v },
+            LoggedValue::Float(v) => Value::Float { v: *v },
+            LoggedValue::Bool(v) => Value::Bool { v: *v },
+            LoggedValue::String(v) => Value::String { v: v.as_str().into() },
+            LoggedValue::Bytes(v) => Value::String {
+                v: String::from_utf8_lossy(v).as_ref().into(),
+            },
+            LoggedValue::Pid(v) => Value::Pid { v: *v },
+            LoggedValue::Null => Value::Object { ptr: 0 },
+            LoggedValue::Object { type_name, fields } => {
+                let Some(idx) = self.type_table.iter().position(|t| t.0.as_ref() == type_name) else {
+                    return Value::Object { ptr: 0 };
+                };
+                let Type::Struct { fields: decl, .. } = self.type_table[idx].1.clone() else {
+                    return Value::Object { ptr: 0 };
+                };
+                let Some(ptr) = self.heap.allocate(decl.len(), idx as u32) else {
+                    return Value::Object { ptr: 0 };
+                };
+                for (i, d) in decl.iter().enumerate() {
+                    let v = match fields.get(i) {
+                        Some(f) if !matches!(f, LoggedValue::Unit | LoggedValue::Weak) => self.unlog_value(f),
+                        _ => d
+                            .1
+                            .as_type(&self.type_table)
+                            .as_default(&self.type_table)
+                            .unwrap_or(Value::Unit),
+                    };
+                    *self.heap.get_mut(ptr as usize + i + 1) = v;
+                }
+                Value::Object { ptr: ptr as u64 }
+            }
+        }
+    }
+    pub(crate) fn replay_native(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, Box<Divergence>>> {
+        self.native_fns.log.as_ref()?;
+        let logged = args.iter().map(|a| self.log_value(a, 0)).collect();
+        match self.native_fns.replay_call(0, name, logged)? {
+            Ok(r) => Some(Ok(self.unlog_value(&r))),
+            Err(d) => Some(Err(d)),
+        }
+    }
+    pub(crate) fn record_native(&self, name: &str, args: &[Value], result: &Value) {
+        if self.native_fns.log.is_none() {
+            return;
+        }
+        let logged = args.iter().map(|a| self.log_value(a, 0)).collect();
+        let result = self.log_value(result, 0);
+        self.native_fns.record_call(0, name, logged, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast::Limits;
+    use crate::mach::RunResult;
+
+    const PIPE: &str = "	p:Pipe = io_pipe()
+	w:int = p.write
+	r:int = p.read
+";
+
+    fn rt(body: &str) -> RT {
+        let src = format!("{}fn int main:\n{}end\n", include_str!("../io.beam"), body);
+        let p = crate::parser::parse_to_program(src, "test.beam".into()).unwrap();
+        let mut rt = crate::fast::rt_from_intermediate_rt(crate::fast::compile_mach_to_ir(&[p]).unwrap());
+        rt.set_limits(Limits {
+            io: true,
+            ..Limits::default()
+        });
+        rt
+    }
+
+    fn record(body: &str) -> (Value, Recording) {
+        let mut rt = rt(body);
+        rt.natives.start_recording();
+        let RunResult::Finished(v) = rt.run(100_000) else {
+            panic!("recorded run did not finish");
+        };
+        (v, rt.natives.stop_logging().unwrap())
+    }
+
+    fn written(data: &str) -> String {
+        format!("{PIPE}	n:int = io_write(w \"{data}\")\n	got:Read = io_read(r 16)\n	n = got.n\n	return n\n")
+    }
+
+    #[test]
+    fn replay_reproduces_recorded_results() {
+        let (want, recording) = record(&written("hello"));
+        assert_eq!(want, Value::Integer { v: 5 });
+        assert_eq!(recording.calls.len(), 3);
+        let mut rt = rt(&written("hello"));
+        rt.natives.start_replay(recording);
+        let RunResult::Finished(got) = rt.run(100_000) else {
+            panic!("replay did not finish");
+        };
+        assert_eq!(got, want);
+        assert_eq!(rt.natives.divergence(), None);
+        assert!(rt.natives.unreplayed().is_empty());
+    }
+
+    #[test]
+    fn replay_detects_different_arguments() {
+        let (_, recording) = record(&written("hello"));
+        let expected = recording.calls[1].clone();
+        let pid = expected.pid;
+        let mut rt = rt(&written("help"));
+        rt.natives.start_replay(recording);
+        let RunResult::Error(e) = rt.run(100_000) else {
+            panic!("diverging replay did not fail");
+        };
+        assert!(e.to_string().contains("replay diverged"));
+        let w = expected.args[0].clone();
+        assert_eq!(
+            rt.natives.divergence(),
+            Some(Divergence {
+                pid,
+                call: 1,
+                expected: Some(expected),
+                name: "io_write".to_string(),
+                args: vec![w, LoggedValue::String("help".to_string())],
+            })
+        );
+    }
+
+    #[test]
+    fn replay_detects_calls_past_the_recording() {
+        let (_, recording) = record(&format!("{PIPE}	return w\n"));
+        let pid = recording.calls[0].pid;
+        let mut rt = rt(&written("hello"));
+        rt.natives.start_replay(recording);
+        assert!(matches!(rt.run(100_000), RunResult::Error(_)));
+        let d = rt.natives.divergence().unwrap();
+        assert_eq!((d.pid, d.call, d.expected, d.name.as_str()), (pid, 1, None, "io_write"));
+    }
+
+    const BY_THE_CLOCK: &str = "	p:Pipe = io_pipe()
+	w:int = p.write
+	t:int = now
+	b:bool = t < 100
+	n:int = 0
+	if b goto early
+	n = io_write(w \"late\")
+	return n
+	label early
+	n = io_write(w \"early\")
+	return n
+";
+
+    #[test]
+    fn replay_reads_the_recorded_clock() {
+        use crate::timer::VirtualClock;
+        let mut recorded = rt(BY_THE_CLOCK);
+        let clock = Arc::new(VirtualClock::new());
+        clock.advance(150);
+        recorded.set_clock(clock);
+        recorded.natives.start_recording();
+        let RunResult::Finished(want) = recorded.run(100_000) else {
+            panic!("recorded run did not finish");
+        };
+        assert_eq!(want, Value::Integer { v: 4 });
+        let recording = recorded.natives.stop_logging().unwrap();
+        assert!(recording.calls.iter().any(|c| c.name == NOW));
+        let mut rt = rt(BY_THE_CLOCK);
+        rt.set_clock(Arc::new(VirtualClock::new()));
+        rt.natives.start_replay(recording);
+        let RunResult::Finished(got) = rt.run(100_000) else {
+            panic!("replay did not finish");
+        };
+        assert_eq!(got, want);
+        assert_eq!(rt.natives.divergence(), None);
+        assert!(rt.natives.unreplayed().is_empty());
+    }
+
+    #[test]
+    fn replay_fires_recorded_timeouts() {
+        use crate::timer::VirtualClock;
+        // The busy sender outlasts a 1 ms timeout on the real clock, but on
+        // a virtual clock that stands still the message gets there first.
+        let src = format!(
+            "struct Node
+	next Node
+end
+{}fn void busy parent pid:
+	i:int = 0
+	b:bool = false
+	label spin
+	b = i < 500000
+	if b goto more
+	n:Node = new Node
+	send parent n
+	return unit
+	label more
+	i = i + 1
+	goto spin
+end
+fn int main:
+	me:pid = self
+	c:pid = spawn busy(me)
+	p:Pipe = io_pipe()
+	w:int = p.write
+	n:int = 0
+	m:Node = receive Node after 1 late
+	n = io_write(w \"in time\")
+	return n
+	label late
+	n = io_write(w \"late\")
+	return n
+end
+",
+            include_str!("../io.beam")
+        );
+        let build = || {
+            let p = crate::parser::parse_to_program(src.clone(), "test.beam".into()).unwrap();
+            let mut rt = crate::fast::rt_from_intermediate_rt(crate::fast::compile_mach_to_ir(&[p]).unwrap());
+            rt.set_limits(Limits {
+                io: true,
+                ..Limits::default()
+            });
+            rt
+        };
+        let mut recorded = build();
+        recorded.natives.start_recording();
+        let RunResult::Finished(want) = recorded.run(10_000_000) else {
+            panic!("recorded run did not finish");
+        };
+        assert_eq!(want, Value::Integer { v: 4 });
+        let recording = recorded.natives.stop_logging().unwrap();
+        assert!(recording.calls.iter().any(|c| c.name == TIMEOUT && c.pid == 0));
+        let mut free = build();
+        free.set_clock(Arc::new(VirtualClock::new()));
+        let RunResult::Finished(v) = free.run(10_000_000) else {
+            panic!("unrecorded run did not finish");
+        };
+        assert_eq!(v, Value::Integer { v: 7 });
+        let mut rt = build();
+        rt.set_clock(Arc::new(VirtualClock::new()));
+        rt.natives.start_replay(recording);
+        let RunResult::Finished(got) = rt.run(10_000_000) else {
+            panic!("replay did not finish");
+        };
+        assert_eq!(got, want);
+        assert_eq!(rt.natives.divergence(), None);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::fast::{InstructionList, IntermediateRt, RT, RtError, rt_from_intermediate_rt};
+use crate::mach::{RunResult, Value};
+use crate::process::{IDLE_PID, Scheduler, SchedulerShared};
+use crate::jit::JitStats;
+use crate::profile::OpcodeProfile;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+pub const SLICE_STEPS: u64 = 256;
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+enum Outcome {
+    Finished(Value),
+    Failed(RtError),
+}
+// SAFETY: the returned value is built by a worker and only read by the owning
+// thread after the workers exit, so its string is never shared.
+unsafe impl Send for Outcome {}
+
+struct Hub {
+    stop: AtomicBool,
+    fuel: AtomicU64,
+    outcome: Mutex<Option<Outcome>>,
+    profile: Mutex<Option<OpcodeProfile>>,
+    jit_stats: Mutex<JitStats>,
+    jit_hot: Mutex<Vec<usize>>,
+}
+
+impl Hub {
+    fn claim(&self, want: u64) -> u64 {
+        let mut got = 0;
+        let _ = self
+            .fuel
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |f| {
+                got = f.min(want);
+                Some(f - got)
+            });
+        got
+    }
+    fn refund(&self, amount: u64) {
+        if amount > 0 {
+            self.fuel.fetch_add(amount, Ordering::AcqRel);
+        }
+    }
+    fn finish(&self, outcome: Outcome) {
+        let mut o = self.outcome.lock().unwrap();
+        if o.is_none() {
+            *o = Some(outcome);
+        }
+        drop(o);
+        self.halt();
+    }
+    fn halt(&self) {
+        self.stop.store(true, Ordering::Release);
+    }
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Acquire)
+    }
+    fn idle(&self, rt: &mut RT) {
+        rt.poll_io(Some(0));
+        let shared = rt.scheduler.shared.clone();
+        let mut s = shared.state.lock().unwrap();
+        let now = rt.scheduler.now();
+        let woken = s.wake_expired(rt.scheduler.worker, now);
+        rt.record_timeouts(&woken);
+        if s.has_runnable() || self.stopped() {
+            return;
+        }
+        let deadline = s.next_deadline();
+        if s.idle_workers + 1 == rt.scheduler.threads {
+            drop(s);
+            if rt.has_io_waiters() {
+                rt.wait_io(deadline);
+                return;
+            }
+            match deadline {
+                Some(d) => rt.scheduler.clock.sleep_until(d),
+                None => {
+                    self.finish(Outcome::Failed(RtError::Deadlock));
+                    shared.work.notify_all();
+                }
+            }
+            return;
+        }
+        s.idle_workers += 1;
+        let wait = deadline
+            .map(|d| Duration::from_millis(d.saturating_sub(now)).min(IDLE_POLL))
+            .unwrap_or(IDLE_POLL);
+        let mut s = shared.work.wait_timeout(s, wait).unwrap().0;
+        s.idle_workers -= 1;
+    }
+    fn work(&self, mut rt: RT) {
+        while !self.stopped() {
+            rt.schedule_next();
+            if rt.scheduler.is_idle() {
+                self.idle(&mut rt);
+                continue;
+            }
+            let claimed = self.claim(SLICE_STEPS);
+            if claimed == 0 {
+                rt.suspend_current();
+                self.halt();
+                break;
+            }
+            let mut used = 0;
+            let mut r = Ok(false);
+            while used < claimed && !rt.scheduler.is_idle() {
+                let n;
+                (r, n) = rt.step_threaded(claimed - used);
+                used += n;
+                if r.is_err() || rt.halted {
+                    break;
+                }
+            }
+            while r == Ok(false) && !rt.halted && !rt.scheduler.is_idle() && rt.op_stack_ptr != 0 {
+                r = rt.step();
+            }
+            self.refund(claimed.saturating_sub(used));
+            match r {
+                Err(e) => {
+                    rt.suspend_current();
+                    self.finish(Outcome::Failed(e));
+                }
+                Ok(_) if rt.halted => {
+                    let v = rt.op_stack[rt.op_stack_ptr - 1].clone();
+                    self.finish(Outcome::Finished(rt.var_to_value(&v)));
+                }
+                Ok(_) => rt.suspend_current(),
+            }
+        }
+        if let Some(p) = rt.take_profile() {
+            let mut total = self.profile.lock().unwrap();
+            match &mut *total {
+                Some(t) => t.merge(&p),
+                None => *total = Some(p),
+            }
+        }
+        if let Some(j) = &rt.jit {
+            self.jit_stats.lock().unwrap().merge(&j.stats);
+            self.jit_hot.lock().unwrap().extend(j.hot());
+        }
+        rt.scheduler.shared.work.notify_all();
+    }
+}
+
+impl RT {
+    pub(crate) fn code_image(&self) -> IntermediateRt {
+        IntermediateRt {
+            strings: self.strings.as_slice().to_vec(),
+            types: self.types.as_slice().to_vec(),
+            symbol_table: self.symbol_table.clone(),
+            arity: self.arity.clone(),
+            data: InstructionList {
+                iv: self.instructions.as_slice().to_vec(),
+            },
+            ip: 0,
+        }
+    }
+    pub fn run_parallel(&mut self, fuel: u64) -> RunResult {
+        if self.halted {
+            return RunResult::Finished(self.result.clone().unwrap_or(crate::mach::Value::Unit));
+        }
+        if self.limits.fuel == Some(0) {
+            return RunResult::Error(Box::new(RtError::OutOfFuel));
+        }
+        let fuel = self.limits.fuel.map_or(fuel, |f| f.min(fuel));
+        self.suspend_current();
+        let hub = Hub {
+            stop: AtomicBool::new(false),
+            fuel: AtomicU64::new(fuel),
+            outcome: Mutex::new(None),
+            profile: Mutex::new(None),
+            jit_stats: Mutex::new(JitStats::default()),
+            jit_hot: Mutex::new(Vec::new()),
+        };
+        let profiling = self.profile.is_some();
+        let jit = self.jit.as_ref().map(|j| (j.threshold, j.hot()));
+        let threads = self.scheduler.threads;
+        let code = self.code_image();
+        let shared: Arc<SchedulerShared> = self.scheduler.shared.clone();
+        let funcs = self.natives.funcs.clone();
+        let log = self.natives.log.clone();
+        let mut limits = self.limits.clone();
+        limits.fuel = None;
+        let (slice_budget, min_threshold) = (self.gc_info.slice_budget, self.gc_info.min_threshold);
+        let budget = self.scheduler.budget;
+        let clock = self.scheduler.clock.clone();
+        std::thread::scope(|sc| {
+            for worker in 0..threads {
+                let code = code.clone();
+                let shared = shared.clone();
+                let funcs = funcs.clone();
+                let log = log.clone();
+                let limits = limits.clone();
+                let jit = jit.clone();
+                let clock = clock.clone();
+                let hub = &hub;
+                sc.spawn(move || {
+                    let mut rt = rt_from_intermediate_rt(code);
+                    rt.natives.funcs = funcs;
+                    rt.natives.log = log;
+                    rt.limits = limits;
+                    rt.gc_info.slice_budget = slice_budget;
+                    rt.gc_info.min_threshold = min_threshold;
+                    if profiling {
+                        rt.start_profile();
+                    }
+                    if let Some((threshold, hot)) = &jit {
+                        rt.enable_jit(*threshold);
+                        rt.jit_precompile(hot);
+                    }
+                    rt.scheduler = Scheduler {
+                        shared,
+                        clock: clock.clone(),
+                        current: IDLE_PID,
+                        reductions: 0,
+                        budget,
+                        worker,
+                        threads,
+                    };
+                    hub.work(rt);
+                });
+            }
+        });
+        if let (Some(p), Some(w)) = (&mut self.profile, hub.profile.lock().unwrap().take()) {
+            p.merge(&w);
+        }
+        if let Some(j) = &mut self.jit {
+            j.stats.merge(&hub.jit_stats.lock().unwrap());
+        }
+        let hot = std::mem::take(&mut *hub.jit_hot.lock().unwrap());
+        self.jit_precompile(&hot);
+        let left = hub.fuel.load(Ordering::Acquire);
+        if let Some(f) = &mut self.limits.fuel {
+            *f -= fuel - left;
+        }
+        match hub.outcome.into_inner().unwrap() {
+            Some(Outcome::Finished(v)) => {
+                self.result = Some(v.clone());
+                self.halted = true;
+                RunResult::Finished(v)
+            }
+            Some(Outcome::Failed(e)) => RunResult::Error(Box::new(e)),
+            None if self.limits.fuel == Some(0) => RunResult::Error(Box::new(RtError::OutOfFuel)),
+            None => RunResult::Paused,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast::compile_mach_to_ir;
+
+    const WORKERS: &str = "struct Sum
+	v int
+end
+fn void worker parent pid n int:
+	i:int = 0
+	s:int = 0
+	b:bool = true
+	label top
+	s = s + i
+	i = i + 1
+	b = i < n
+	if b goto top
+	m:Sum = new Sum
+	m.v = s
+	send parent m
+	return unit
+end
+fn int main:
+	me:pid = self
+	c:pid = self
+	i:int = 0
+	t:int = 0
+	b:bool = true
+	label spawn
+	c = spawn worker(me i)
+	i = i + 1
+	b = i < 32
+	if b goto spawn
+	i = 0
+	label gather
+	m:Sum = receive Sum
+	t = t + m.v
+	i = i + 1
+	b = i < 32
+	if b goto gather
+	return t
+end
+";
+
+    fn rt(src: &str, threads: usize) -> RT {
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        let mut rt = rt_from_intermediate_rt(compile_mach_to_ir(&[p]).unwrap());
+        rt.set_scheduler_threads(threads);
+        rt
+    }
+
+    #[test]
+    fn parallel_runs_match_a_single_thread() {
+        let want = match rt(WORKERS, 1).run(10_000_000) {
+            RunResult::Finished(Value::Integer { v }) => v,
+            _ => panic!("single threaded run did not finish"),
+        };
+        assert_eq!(want, (0..32).map(|n: i64| n * (n - 1) / 2).sum::<i64>());
+        for threads in [2, 4] {
+            let mut rt = rt(WORKERS, threads);
+            let got = rt.run_parallel(10_000_000);
+            assert!(matches!(got, RunResult::Finished(Value::Integer { v }) if v == want), "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn messages_pass_between_workers() {
+        // Each process forwards the token to a new process, so the chain
+        // hops between workers many times before it gets back to main.
+        let src = "struct Token
+	hops int
+	home pid
+end
+fn void relay:
+	t:Token = receive Token
+	t.hops = t.hops + 1
+	b:bool = t.hops < 200
+	c:pid = self
+	if b goto pass
+	send t.home t
+	return unit
+	label pass
+	c = spawn relay()
+	send c t
+	return unit
+end
+fn int main:
+	t:Token = new Token
+	t.home = self
+	c:pid = spawn relay()
+	send c t
+	t = receive Token
+	n:int = t.hops
+	return n
+end
+";
+        let mut rt = rt(src, 4);
+        assert!(matches!(rt.run_parallel(10_000_000), RunResult::Finished(Value::Integer { v: 200 })));
+    }
+
+    #[test]
+    fn blocked_workers_report_a_deadlock() {
+        let src = "struct Never
+	v int
+end
+fn void wait:
+	m:Never = receive Never
+	return unit
+end
+fn int main:
+	c:pid = spawn wait()
+	m:Never = receive Never
+	return 0
+end
+";
+        let mut rt = rt(src, 4);
+        let RunResult::Error(e) = rt.run_parallel(10_000_000) else {
+            panic!("deadlocked program did not fail");
+        };
+        assert_eq!(e.downcast_ref::<RtError>(), Some(&RtError::Deadlock));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::mach::{Machine, Value};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotRoot {
+    pub kind: String,
+    pub slot: u64,
+    pub target: u32,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotNode {
+    pub id: u32,
+    pub type_name: String,
+    pub size: u64,
+    pub edges: Vec<u32>,
+    pub dominator: Option<u32>,
+    pub retained_size: u64,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeapSnapshot {
+    pub roots: Vec<SnapshotRoot>,
+    pub nodes: Vec<SnapshotNode>,
+}
+
+impl HeapSnapshot {
+    pub fn take(m: &Machine) -> Self {
+        let mut roots = Vec::new();
+        for (i, v) in m.stack.iter().enumerate() {
+            if let Value::Object { ptr } = v
+                && *ptr != 0
+            {
+                roots.push(SnapshotRoot {
+                    kind: "stack".into(),
+                    slot: i as u64,
+                    target: *ptr as u32,
+                });
+            }
+        }
+        for v in m.native_fns.handles.roots() {
+            if let Value::Object { ptr } = v
+                && ptr != 0
+            {
+                roots.push(SnapshotRoot {
+                    kind: "handle".into(),
+                    slot: 0,
+                    target: ptr as u32,
+                });
+            }
+        }
+        for (i, v) in m.finalize_queue.iter().enumerate() {
+            roots.push(SnapshotRoot {
+                kind: "finalize_queue".into(),
+                slot: i as u64,
+                target: v.0,
+            });
+        }
+        let mut index: HashMap<u32, usize> = HashMap::new();
+        let mut nodes: Vec<SnapshotNode> = Vec::new();
+        let mut todo: Vec<u32> = roots.iter().map(|r| r.target).collect();
+        while let Some(ptr) = todo.pop() {
+            if index.contains_key(&ptr) {
+                continue;
+            }
+            let Value::ObjectHeader { information, size } = m.heap.get(ptr as usize) else {
+                continue;
+            };
+            let mut edges = Vec::new();
+            for i in 1..size + 1 {
+                if let Value::Object { ptr: to } = m.heap.get((ptr + i) as usize)
+                    && to != 0
+                {
+                    edges.push(to as u32);
+                    todo.push(to as u32);
+                }
+            }
+            let type_name = m
+                .type_table
+                .get(information as usize)
+                .map(|t| t.0.to_string())
+                .unwrap_or_else(|| "?".into());
+            index.insert(ptr, nodes.len());
+            nodes.push(SnapshotNode {
+                id: ptr,
+                type_name,
+                size: (size as u64 + 1) * size_of::<Value>() as u64,
+                edges,
+                dominator: None,
+                retained_size: 0,
+            });
+        }
+        let mut out = Self { roots, nodes };
+        out.compute_dominators(&index);
+        out
+    }
+    fn compute_dominators(&mut self, index: &HashMap<u32, usize>) {
+        let n = self.nodes.len() + 1;
+        let mut succs: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for r in &self.roots {
+            if let Some(i) = index.get(&r.target) {
+                succs[0].push(i + 1);
+            }
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            for e in &node.edges {
+                if let Some(j) = index.get(e) {
+                    succs[i + 1].push(j + 1);
+                }
+            }
+        }
+        let mut preds: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for (i, s) in succs.iter().enumerate() {
+            for j in s {
+                preds[*j].push(i);
+            }
+        }
+        let mut order = Vec::new();
+        let mut visited = vec![false; n];
+        let mut stack = vec![(0usize, 0usize)];
+        visited[0] = true;
+        while let Some((node, child)) = stack.pop() {
+            if child < succs[node].len() {
+                stack.push((node, child + 1));
+                let next = succs[node][child];
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push((next, 0));
+                }
+            } else {
+                order.push(node);
+            }
+        }
+        order.reverse();
+        let mut rpo = vec![usize::MAX; n];
+        for (i, node) in order.iter().enumerate() {
+            rpo[*node] = i;
+        }
+        let mut idom = vec![usize::MAX; n];
+        idom[0] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in order.iter().skip(1) {
+                let mut new_idom = usize::MAX;
+                for p in &preds[*node] {
+                    if idom[*p] == usize::MAX {
+                        continue;
+                    }
+                    new_idom = if new_idom == usize::MAX {
+                        *p
+                    } else {
+                        let mut a = *p;
+                        let mut b = new_idom;
+                        while a != b {
+                            while rpo[a] > rpo[b] {
+                                a = idom[a];
+                            }
+                            while rpo[b] > rpo[a] {
+                                b = idom[b];
+                            }
+                        }
+                        a
+                    };
+                }
+                if idom[*node] != new_idom {
+                    idom[*node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        let mut retained: Vec<u64> = vec![0; n];
+        for (i, node) in self.nodes.iter().enumerate() {
+            retained[i + 1] = node.size;
+        }
+        for node in order.iter().skip(1).rev() {
+            let d = idom[*node];
+            retained[d] += retained[*node];
+        }
+        let ids: Vec<u32> = self.nodes.iter().map(|n| n.id).collect();
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.retained_size = retained[i + 1];
+            let d = idom[i + 1];
+            node.dominator = if d == 0 || d == usize::MAX {
+                None
+            } else {
+                Some(ids[d - 1])
+            };
+        }
+    }
+    pub fn node(&self, id: u32) -> Option<&SnapshotNode> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+    pub fn retainers(&self, id: u32) -> Vec<u32> {
+        self.nodes
+            .iter()
+            .filter(|n| n.edges.contains(&id))
+            .map(|n| n.id)
+            .collect()
+    }
+    pub fn dominator_chain(&self, id: u32) -> Vec<u32> {
+        let mut out = Vec::new();
+        let mut cur = self.node(id).and_then(|n| n.dominator);
+        while let Some(d) = cur {
+            out.push(d);
+            cur = self.node(d).and_then(|n| n.dominator);
+        }
+        out
+    }
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+    pub fn from_msgpack(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_msgpack()?)?;
+        Ok(())
+    }
+}
+impl Machine {
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot::take(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 16;
+
+    fn graph(roots: &[u32], edges: &[(u32, &[u32])]) -> HeapSnapshot {
+        let mut s = HeapSnapshot {
+            roots: roots
+                .iter()
+                .map(|r| SnapshotRoot {
+                    kind: "stack".into(),
+                    slot: 0,
+                    target: *r,
+                })
+                .collect(),
+            nodes: edges
+                .iter()
+                .map(|(id, to)| SnapshotNode {
+                    id: *id,
+                    type_name: "Node".into(),
+                    size: SIZE,
+                    edges: to.to_vec(),
+                    dominator: None,
+                    retained_size: 0,
+                })
+                .collect(),
+        };
+        let index = s.nodes.iter().enumerate().map(|(i, n)| (n.id, i)).collect();
+        s.compute_dominators(&index);
+        s
+    }
+
+    fn summary(s: &HeapSnapshot, id: u32) -> (Option<u32>, u64) {
+        let n = s.node(id).unwrap();
+        (n.dominator, n.retained_size)
+    }
+
+    #[test]
+    fn diamond_is_dominated_by_its_top() {
+        let s = graph(&[1], &[(1, &[2, 3]), (2, &[4]), (3, &[4]), (4, &[])]);
+        assert_eq!(summary(&s, 1), (None, 4 * SIZE));
+        assert_eq!(summary(&s, 2), (Some(1), SIZE));
+        assert_eq!(summary(&s, 3), (Some(1), SIZE));
+        assert_eq!(summary(&s, 4), (Some(1), SIZE));
+        let mut r = s.retainers(4);
+        r.sort();
+        assert_eq!(r, [2, 3]);
+    }
+
+    #[test]
+    fn chain_retains_everything_below() {
+        let s = graph(&[1], &[(1, &[2]), (2, &[3]), (3, &[4]), (4, &[])]);
+        for (id, dom, retained) in [(1, None, 4), (2, Some(1), 3), (3, Some(2), 2), (4, Some(3), 1)] {
+            assert_eq!(summary(&s, id), (dom, retained * SIZE), "node {}", id);
+        }
+        assert_eq!(s.dominator_chain(4), [3, 2, 1]);
+    }
+
+    #[test]
+    fn objects_shared_by_roots_belong_to_neither() {
+        let s = graph(&[1, 2], &[(1, &[3]), (2, &[3]), (3, &[])]);
+        assert_eq!(summary(&s, 1), (None, SIZE));
+        assert_eq!(summary(&s, 2), (None, SIZE));
+        assert_eq!(summary(&s, 3), (None, SIZE));
+    }
+}
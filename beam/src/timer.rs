@@ -0,0 +1,193 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const WHEEL_SLOTS: usize = 512;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+    fn sleep_until(&self, deadline: u64);
+}
+
+pub struct SystemClock {
+    start: Instant,
+    offset: u64,
+}
+impl SystemClock {
+    pub fn new() -> Self {
+        Self::starting_at(0)
+    }
+    pub fn starting_at(ms: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            offset: ms,
+        }
+    }
+}
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.offset + self.start.elapsed().as_millis() as u64
+    }
+    fn sleep_until(&self, deadline: u64) {
+        let now = self.now();
+        if deadline > now {
+            std::thread::sleep(Duration::from_millis(deadline - now));
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VirtualClock {
+    now: AtomicU64,
+}
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Ordering::AcqRel);
+    }
+    pub fn set(&self, ms: u64) {
+        self.now.fetch_max(ms, Ordering::AcqRel);
+    }
+}
+impl Clock for VirtualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Acquire)
+    }
+    fn sleep_until(&self, deadline: u64) {
+        self.set(deadline);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timer {
+    pub deadline: u64,
+    pub pid: u64,
+}
+
+#[derive(Clone)]
+pub struct TimerWheel {
+    slots: Vec<Vec<Timer>>,
+    tick: u64,
+    count: usize,
+}
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            slots: vec![Vec::new(); WHEEL_SLOTS],
+            tick: 0,
+            count: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.count
+    }
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+    pub fn insert(&mut self, t: Timer) {
+        self.slots[t.deadline.max(self.tick) as usize % WHEEL_SLOTS].push(t);
+        self.count += 1;
+    }
+    pub fn cancel(&mut self, t: Timer) -> bool {
+        let home = t.deadline as usize % WHEEL_SLOTS;
+        let Some(slot) = std::iter::once(home)
+            .chain(0..WHEEL_SLOTS)
+            .find(|s| self.slots[*s].contains(&t))
+        else {
+            return false;
+        };
+        let idx = self.slots[slot].iter().position(|x| *x == t).unwrap();
+        self.slots[slot].swap_remove(idx);
+        self.count -= 1;
+        true
+    }
+    pub fn next_deadline(&self) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        self.slots.iter().flatten().map(|t| t.deadline).min()
+    }
+    pub fn expire(&mut self, now: u64) -> Vec<Timer> {
+        let mut out = Vec::new();
+        if self.count == 0 || now < self.tick {
+            self.tick = self.tick.max(now);
+            return out;
+        }
+        let span = (now - self.tick + 1).min(WHEEL_SLOTS as u64);
+        for i in 0..span {
+            let slot = &mut self.slots[(self.tick + i) as usize % WHEEL_SLOTS];
+            let mut j = 0;
+            while j < slot.len() {
+                if slot[j].deadline <= now {
+                    out.push(slot.swap_remove(j));
+                } else {
+                    j += 1;
+                }
+            }
+        }
+        self.count -= out.len();
+        self.tick = now;
+        out.sort_by_key(|t| (t.deadline, t.pid));
+        out
+    }
+}
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(deadline: u64, pid: u64) -> Timer {
+        Timer { deadline, pid }
+    }
+
+    #[test]
+    fn wheel_expires_in_deadline_order() {
+        let mut w = TimerWheel::new();
+        w.insert(t(30, 1));
+        w.insert(t(10, 2));
+        w.insert(t(10, 3));
+        w.insert(t(20 + WHEEL_SLOTS as u64, 4));
+        assert_eq!(w.next_deadline(), Some(10));
+        assert_eq!(w.expire(9), vec![]);
+        assert_eq!(w.expire(30), vec![t(10, 2), t(10, 3), t(30, 1)]);
+        // Same slot as deadline 20, but a full turn later.
+        assert_eq!(w.expire(20 + WHEEL_SLOTS as u64 - 1), vec![]);
+        assert_eq!(w.len(), 1);
+        assert_eq!(w.expire(10_000), vec![t(20 + WHEEL_SLOTS as u64, 4)]);
+        assert!(w.is_empty());
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let mut w = TimerWheel::new();
+        w.insert(t(5, 1));
+        w.insert(t(5, 2));
+        assert!(w.cancel(t(5, 1)));
+        assert!(!w.cancel(t(5, 1)));
+        assert_eq!(w.expire(5), vec![t(5, 2)]);
+    }
+
+    #[test]
+    fn virtual_clock_only_moves_forward() {
+        let c = VirtualClock::new();
+        c.sleep_until(100);
+        assert_eq!(c.now(), 100);
+        c.set(40);
+        assert_eq!(c.now(), 100);
+        c.advance(5);
+        assert_eq!(c.now(), 105);
+    }
+}
@@ -0,0 +1,2158 @@
+use crate::mach::{Binop, Cmd, Function, Program, Rc, Type, Var};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+pub const HEADER_SIZE: u32 = 16;
+pub const STACK_BYTES: u32 = 1 << 20;
+pub const MIN_GC_THRESHOLD: i32 = 1 << 20;
+pub const STRING_TYPE: u32 = 0;
+const HEAP_LIST: u32 = 8;
+const FREE_LISTS: u32 = 16;
+const MAX_SMALL: i32 = 512;
+const DATA_START: u32 = 512;
+const MARK: i32 = 1;
+const FINALIZED: i32 = 2;
+const PENDING: i32 = 4;
+const STATIC: i32 = 8;
+const PAGE: u32 = 65536;
+
+const G_SP: u32 = 0;
+const G_HEAP_TOP: u32 = 1;
+const G_LIVE: u32 = 2;
+const G_THRESHOLD: u32 = 3;
+const G_FINALIZING: u32 = 4;
+const G_GRAY: u32 = 5;
+const G_STACK_BASE: u32 = 6;
+const G_STACK_END: u32 = 7;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WasmError {
+    Unsupported { function: String, what: String },
+    UnresolvedSymbol { name: String },
+    NoMain,
+    Invalid { offset: usize, reason: String },
+}
+impl std::fmt::Display for WasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WasmError::Unsupported { function, what } => {
+                write!(f, "{} in {} cannot be lowered to wasm", what, function)
+            }
+            WasmError::UnresolvedSymbol { name } => write!(f, "unresolved symbol {}", name),
+            WasmError::NoMain => write!(f, "program has no main function"),
+            WasmError::Invalid { offset, reason } => {
+                write!(f, "invalid module at byte {}: {}", offset, reason)
+            }
+        }
+    }
+}
+impl std::error::Error for WasmError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValType {
+    I32,
+    I64,
+    F64,
+}
+impl ValType {
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7F,
+            ValType::I64 => 0x7E,
+            ValType::F64 => 0x7C,
+        }
+    }
+    fn from_code(b: u8) -> Option<Self> {
+        match b {
+            0x7F => Some(ValType::I32),
+            0x7E => Some(ValType::I64),
+            0x7C => Some(ValType::F64),
+            _ => None,
+        }
+    }
+}
+
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const ELSE: u8 = 0x05;
+    pub const END: u8 = 0x0B;
+    pub const BR: u8 = 0x0C;
+    pub const BR_IF: u8 = 0x0D;
+    pub const BR_TABLE: u8 = 0x0E;
+    pub const RETURN: u8 = 0x0F;
+    pub const CALL: u8 = 0x10;
+    pub const DROP: u8 = 0x1A;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const GLOBAL_GET: u8 = 0x23;
+    pub const GLOBAL_SET: u8 = 0x24;
+    pub const I32_LOAD: u8 = 0x28;
+    pub const I64_LOAD: u8 = 0x29;
+    pub const F64_LOAD: u8 = 0x2B;
+    pub const I32_LOAD8_U: u8 = 0x2D;
+    pub const I32_LOAD16_U: u8 = 0x2F;
+    pub const I32_STORE: u8 = 0x36;
+    pub const I64_STORE: u8 = 0x37;
+    pub const F64_STORE: u8 = 0x39;
+    pub const I32_STORE16: u8 = 0x3B;
+    pub const MEMORY_SIZE: u8 = 0x3F;
+    pub const MEMORY_GROW: u8 = 0x40;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I64_CONST: u8 = 0x42;
+    pub const F64_CONST: u8 = 0x44;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_EQ: u8 = 0x46;
+    pub const I32_NE: u8 = 0x47;
+    pub const I32_GT_U: u8 = 0x4B;
+    pub const I32_GE_U: u8 = 0x4F;
+    pub const I64_EQ: u8 = 0x51;
+    pub const I64_NE: u8 = 0x52;
+    pub const I64_LT_S: u8 = 0x53;
+    pub const I64_GT_S: u8 = 0x55;
+    pub const F64_EQ: u8 = 0x61;
+    pub const F64_NE: u8 = 0x62;
+    pub const F64_LT: u8 = 0x63;
+    pub const F64_GT: u8 = 0x64;
+    pub const I32_ADD: u8 = 0x6A;
+    pub const I32_SUB: u8 = 0x6B;
+    pub const I32_MUL: u8 = 0x6C;
+    pub const I32_AND: u8 = 0x71;
+    pub const I32_OR: u8 = 0x72;
+    pub const I32_SHL: u8 = 0x74;
+    pub const I32_SHR_U: u8 = 0x76;
+    pub const I64_ADD: u8 = 0x7C;
+    pub const I64_SUB: u8 = 0x7D;
+    pub const I64_MUL: u8 = 0x7E;
+    pub const I64_DIV_S: u8 = 0x7F;
+    pub const F64_ADD: u8 = 0xA0;
+    pub const F64_SUB: u8 = 0xA1;
+    pub const F64_MUL: u8 = 0xA2;
+    pub const F64_DIV: u8 = 0xA3;
+    pub const I32_WRAP_I64: u8 = 0xA7;
+    pub const MISC: u8 = 0xFC;
+    pub const MEMORY_COPY: u32 = 10;
+    pub const MEMORY_FILL: u32 = 11;
+}
+
+fn uleb(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let b = (v & 0x7F) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+fn sleb(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let b = (v & 0x7F) as u8;
+        v >>= 7;
+        if (v == 0 && b & 0x40 == 0) || (v == -1 && b & 0x40 != 0) {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+fn name(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[derive(Default)]
+struct Code(Vec<u8>);
+impl Code {
+    fn op(&mut self, o: u8) -> &mut Self {
+        self.0.push(o);
+        self
+    }
+    fn idx(&mut self, o: u8, i: u32) -> &mut Self {
+        self.0.push(o);
+        uleb(&mut self.0, i as u64);
+        self
+    }
+    fn i32_const(&mut self, v: i32) -> &mut Self {
+        self.0.push(op::I32_CONST);
+        sleb(&mut self.0, v as i64);
+        self
+    }
+    fn i64_const(&mut self, v: i64) -> &mut Self {
+        self.0.push(op::I64_CONST);
+        sleb(&mut self.0, v);
+        self
+    }
+    fn f64_const(&mut self, v: f64) -> &mut Self {
+        self.0.push(op::F64_CONST);
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn get(&mut self, i: u32) -> &mut Self {
+        self.idx(op::LOCAL_GET, i)
+    }
+    fn set(&mut self, i: u32) -> &mut Self {
+        self.idx(op::LOCAL_SET, i)
+    }
+    fn tee(&mut self, i: u32) -> &mut Self {
+        self.idx(op::LOCAL_TEE, i)
+    }
+    fn gget(&mut self, i: u32) -> &mut Self {
+        self.idx(op::GLOBAL_GET, i)
+    }
+    fn gset(&mut self, i: u32) -> &mut Self {
+        self.idx(op::GLOBAL_SET, i)
+    }
+    fn call(&mut self, f: u32) -> &mut Self {
+        self.idx(op::CALL, f)
+    }
+    fn mem(&mut self, o: u8, offset: u32) -> &mut Self {
+        let align = match o {
+            op::I32_LOAD8_U => 0,
+            op::I32_LOAD16_U | op::I32_STORE16 => 1,
+            op::I64_LOAD | op::F64_LOAD | op::I64_STORE | op::F64_STORE => 3,
+            _ => 2,
+        };
+        self.0.push(o);
+        uleb(&mut self.0, align);
+        uleb(&mut self.0, offset as u64);
+        self
+    }
+    fn block(&mut self) -> &mut Self {
+        self.op(op::BLOCK).op(0x40)
+    }
+    fn loop_(&mut self) -> &mut Self {
+        self.op(op::LOOP).op(0x40)
+    }
+    fn if_(&mut self) -> &mut Self {
+        self.op(op::IF).op(0x40)
+    }
+    fn end(&mut self) -> &mut Self {
+        self.op(op::END)
+    }
+    fn br(&mut self, d: u32) -> &mut Self {
+        self.idx(op::BR, d)
+    }
+    fn br_if(&mut self, d: u32) -> &mut Self {
+        self.idx(op::BR_IF, d)
+    }
+    fn misc(&mut self, sub: u32) -> &mut Self {
+        self.0.push(op::MISC);
+        uleb(&mut self.0, sub as u64);
+        if sub == op::MEMORY_COPY {
+            self.0.push(0);
+        }
+        self.0.push(0);
+        self
+    }
+    fn block_size(&mut self, len: u32) -> &mut Self {
+        self.get(len)
+            .i32_const(7)
+            .op(op::I32_ADD)
+            .i32_const(-8)
+            .op(op::I32_AND)
+            .i32_const(HEADER_SIZE as i32)
+            .op(op::I32_ADD)
+    }
+    fn flags(&mut self, o: u32) -> &mut Self {
+        self.get(o).mem(op::I32_LOAD16_U, 6)
+    }
+    fn type_info(&mut self, o: u32) -> &mut Self {
+        self.get(o)
+            .mem(op::I32_LOAD16_U, 4)
+            .i32_const(2)
+            .op(op::I32_SHL)
+            .mem(op::I32_LOAD, DATA_START)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Rt {
+    Chk,
+    Alloc,
+    Mark,
+    Drain,
+    Gc,
+    Safepoint,
+    Concat,
+    StrEq,
+    Repeat,
+    Finalize,
+}
+const RUNTIME: [Rt; 10] = [
+    Rt::Chk,
+    Rt::Alloc,
+    Rt::Mark,
+    Rt::Drain,
+    Rt::Gc,
+    Rt::Safepoint,
+    Rt::Concat,
+    Rt::StrEq,
+    Rt::Repeat,
+    Rt::Finalize,
+];
+impl Rt {
+    fn signature(self) -> (Vec<ValType>, Vec<ValType>) {
+        use ValType::*;
+        match self {
+            Rt::Chk => (vec![I32], vec![I32]),
+            Rt::Alloc => (vec![I32, I32], vec![I32]),
+            Rt::Mark => (vec![I32], vec![]),
+            Rt::Drain | Rt::Gc | Rt::Safepoint => (vec![], vec![]),
+            Rt::Concat | Rt::StrEq => (vec![I32, I32], vec![I32]),
+            Rt::Repeat => (vec![I32, I64], vec![I32]),
+            Rt::Finalize => (vec![I32, I32], vec![]),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Loc {
+    Local(u32, ValType),
+    Slot(u32),
+}
+
+struct StructInfo {
+    fields: Vec<Type>,
+    finalizer: Option<String>,
+}
+
+struct FuncBody {
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    locals: Vec<ValType>,
+    code: Code,
+}
+
+struct Lower<'a> {
+    progs: &'a [Program],
+    structs: Vec<StructInfo>,
+    struct_idx: HashMap<(usize, u64), u32>,
+    imports: Vec<(String, Vec<ValType>, ValType)>,
+    import_idx: HashMap<String, u32>,
+    functions: BTreeMap<String, (usize, &'a Function, u32)>,
+    data: Vec<u8>,
+    strings: HashMap<Rc<str>, u32>,
+    empty: u32,
+    strings_start: u32,
+}
+
+fn val_type(t: &Type) -> Option<ValType> {
+    match t {
+        Type::Integer => Some(ValType::I64),
+        Type::Float => Some(ValType::F64),
+        Type::Void | Type::Bool | Type::String | Type::Ptr { .. } | Type::Weak { .. } => {
+            Some(ValType::I32)
+        }
+        _ => None,
+    }
+}
+fn slot_kind(t: &Type) -> i32 {
+    match t {
+        Type::String | Type::Ptr { .. } => 1,
+        Type::Weak { .. } => 2,
+        _ => 0,
+    }
+}
+fn load_op(v: ValType) -> u8 {
+    match v {
+        ValType::I32 => op::I32_LOAD,
+        ValType::I64 => op::I64_LOAD,
+        ValType::F64 => op::F64_LOAD,
+    }
+}
+fn store_op(v: ValType) -> u8 {
+    match v {
+        ValType::I32 => op::I32_STORE,
+        ValType::I64 => op::I64_STORE,
+        ValType::F64 => op::F64_STORE,
+    }
+}
+
+impl<'a> Lower<'a> {
+    fn new(progs: &'a [Program]) -> Result<Self, WasmError> {
+        let mut out = Self {
+            progs,
+            structs: Vec::new(),
+            struct_idx: HashMap::new(),
+            imports: Vec::new(),
+            import_idx: HashMap::new(),
+            functions: BTreeMap::new(),
+            data: Vec::new(),
+            strings: HashMap::new(),
+            empty: 0,
+            strings_start: 0,
+        };
+        for (pi, p) in progs.iter().enumerate() {
+            for (ti, (_, t)) in p.types.iter().enumerate() {
+                let Type::Struct {
+                    fields, finalizer, ..
+                } = t
+                else {
+                    continue;
+                };
+                out.struct_idx
+                    .insert((pi, ti as u64), out.structs.len() as u32 + 1);
+                out.structs.push(StructInfo {
+                    fields: fields.iter().map(|f| f.1.as_type(&p.types)).collect(),
+                    finalizer: finalizer.as_ref().map(|f| f.to_string()),
+                });
+            }
+        }
+        let mut externs = BTreeMap::new();
+        for p in progs {
+            for (n, f) in &p.externals {
+                externs.insert(n.clone(), (p, f));
+            }
+        }
+        for (n, (p, f)) in externs {
+            if crate::io::is_io_native(&n) {
+                continue;
+            }
+            let mut params = Vec::new();
+            for a in f.arguments.iter() {
+                params.push(val_type(&a.1.as_type(&p.types)).ok_or_else(|| {
+                    WasmError::Unsupported {
+                        function: n.clone(),
+                        what: format!("extern argument {}", a.0),
+                    }
+                })?);
+            }
+            let result = val_type(&f.return_type.as_type(&p.types)).ok_or_else(|| {
+                WasmError::Unsupported {
+                    function: n.clone(),
+                    what: "extern return type".into(),
+                }
+            })?;
+            out.import_idx.insert(n.clone(), out.imports.len() as u32);
+            out.imports.push((n, params, result));
+        }
+        let base = out.imports.len() as u32 + RUNTIME.len() as u32 + out.structs.len() as u32;
+        for (pi, p) in progs.iter().enumerate() {
+            for (n, f) in &p.functions {
+                if f.is_header {
+                    continue;
+                }
+                if out.functions.contains_key(n) {
+                    return Err(WasmError::Unsupported {
+                        function: n.clone(),
+                        what: "duplicate definition".into(),
+                    });
+                }
+                out.functions.insert(n.clone(), (pi, f, 0));
+            }
+        }
+        for (i, f) in out.functions.values_mut().enumerate() {
+            f.2 = base + i as u32;
+        }
+        for s in &out.structs {
+            let Some(fin) = &s.finalizer else {
+                continue;
+            };
+            let (_, f, _) = out.function(fin)?;
+            if f.arguments.len() != 1 {
+                return Err(WasmError::Unsupported {
+                    function: fin.clone(),
+                    what: format!("finalizer with {} arguments", f.arguments.len()),
+                });
+            }
+        }
+        let types = out.structs.len() as u32 + 1;
+        let mut infos = Vec::new();
+        let mut table = vec![0u32; types as usize];
+        let mut cursor = DATA_START + types * 4;
+        for (i, s) in out.structs.iter().enumerate() {
+            table[i + 1] = cursor;
+            let mut rec = Vec::new();
+            rec.extend_from_slice(&(s.fields.len() as u32).to_le_bytes());
+            rec.extend_from_slice(&(s.finalizer.is_some() as u32).to_le_bytes());
+            rec.extend(s.fields.iter().map(|f| slot_kind(f) as u8));
+            while !rec.len().is_multiple_of(4) {
+                rec.push(0);
+            }
+            cursor += rec.len() as u32;
+            infos.extend(rec);
+        }
+        table[0] = cursor;
+        infos.extend_from_slice(&[0; 8]);
+        for t in table {
+            out.data.extend_from_slice(&t.to_le_bytes());
+        }
+        out.data.extend(infos);
+        while !out.data.len().is_multiple_of(8) {
+            out.data.push(0);
+        }
+        out.strings_start = DATA_START + out.data.len() as u32;
+        out.empty = out.string(&Rc::from(""));
+        Ok(out)
+    }
+    fn rt(&self, r: Rt) -> u32 {
+        self.imports.len() as u32 + RUNTIME.iter().position(|x| *x == r).unwrap() as u32
+    }
+    fn ctor(&self, idx: u32) -> u32 {
+        self.imports.len() as u32 + RUNTIME.len() as u32 + idx - 1
+    }
+    fn string(&mut self, s: &Rc<str>) -> u32 {
+        if let Some(a) = self.strings.get(s) {
+            return *a;
+        }
+        let addr = DATA_START + self.data.len() as u32;
+        self.data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        self.data
+            .extend_from_slice(&(STRING_TYPE as u16).to_le_bytes());
+        self.data
+            .extend_from_slice(&((MARK | STATIC) as u16).to_le_bytes());
+        self.data.extend_from_slice(&[0; 8]);
+        self.data.extend_from_slice(s.as_bytes());
+        while !self.data.len().is_multiple_of(8) {
+            self.data.push(0);
+        }
+        self.strings.insert(s.clone(), addr);
+        addr
+    }
+    fn function(&self, n: &str) -> Result<(usize, &'a Function, u32), WasmError> {
+        self.functions
+            .get(n)
+            .copied()
+            .ok_or_else(|| WasmError::UnresolvedSymbol { name: n.into() })
+    }
+
+    fn runtime(&self, r: Rt) -> FuncBody {
+        use ValType::*;
+        let (params, results) = r.signature();
+        let mut c = Code::default();
+        let mut locals = Vec::new();
+        match r {
+            Rt::Chk => {
+                c.get(0)
+                    .op(op::I32_EQZ)
+                    .if_()
+                    .op(op::UNREACHABLE)
+                    .end()
+                    .get(0);
+            }
+            Rt::Alloc => {
+                locals = vec![I32; 4];
+                let (total, class, o, prev) = (2, 3, 4, 5);
+                c.block_size(0).set(total);
+                c.get(total).i32_const(3).op(op::I32_SHR_U).set(class);
+                c.get(total)
+                    .i32_const(MAX_SMALL)
+                    .op(op::I32_GT_U)
+                    .if_()
+                    .i32_const(0)
+                    .set(class)
+                    .end();
+                c.get(class)
+                    .i32_const(2)
+                    .op(op::I32_SHL)
+                    .i32_const(FREE_LISTS as i32)
+                    .op(op::I32_ADD)
+                    .set(prev);
+                c.block().loop_();
+                c.get(prev)
+                    .mem(op::I32_LOAD, 0)
+                    .tee(o)
+                    .op(op::I32_EQZ)
+                    .br_if(1);
+                c.get(o).mem(op::I32_LOAD, 0).set(class);
+                c.block_size(class).get(total).op(op::I32_EQ).if_();
+                c.get(prev)
+                    .get(o)
+                    .mem(op::I32_LOAD, 8)
+                    .mem(op::I32_STORE, 0);
+                c.br(2).end();
+                c.get(o).i32_const(8).op(op::I32_ADD).set(prev).br(0);
+                c.end().end();
+                c.get(o).op(op::I32_EQZ).if_();
+                c.gget(G_HEAP_TOP).set(o);
+                c.gget(G_HEAP_TOP)
+                    .get(total)
+                    .op(op::I32_ADD)
+                    .gset(G_HEAP_TOP);
+                c.gget(G_HEAP_TOP)
+                    .op(op::MEMORY_SIZE)
+                    .op(0)
+                    .i32_const(16)
+                    .op(op::I32_SHL)
+                    .op(op::I32_GT_U)
+                    .if_();
+                c.gget(G_HEAP_TOP)
+                    .op(op::MEMORY_SIZE)
+                    .op(0)
+                    .i32_const(16)
+                    .op(op::I32_SHL)
+                    .op(op::I32_SUB);
+                c.i32_const(16)
+                    .op(op::I32_SHR_U)
+                    .i32_const(1)
+                    .op(op::I32_ADD);
+                c.op(op::MEMORY_GROW)
+                    .op(0)
+                    .i32_const(-1)
+                    .op(op::I32_EQ)
+                    .if_()
+                    .op(op::UNREACHABLE)
+                    .end();
+                c.end().end();
+                c.get(o)
+                    .i32_const(HEADER_SIZE as i32)
+                    .op(op::I32_ADD)
+                    .i32_const(0);
+                c.get(total)
+                    .i32_const(HEADER_SIZE as i32)
+                    .op(op::I32_SUB)
+                    .misc(op::MEMORY_FILL);
+                c.get(o).get(0).mem(op::I32_STORE, 0);
+                c.get(o).get(1).mem(op::I32_STORE16, 4);
+                c.get(o).i32_const(0).mem(op::I32_STORE16, 6);
+                c.get(o)
+                    .i32_const(HEAP_LIST as i32)
+                    .mem(op::I32_LOAD, 0)
+                    .mem(op::I32_STORE, 8);
+                c.i32_const(HEAP_LIST as i32).get(o).mem(op::I32_STORE, 0);
+                c.gget(G_LIVE).get(total).op(op::I32_ADD).gset(G_LIVE);
+                c.get(o);
+            }
+            Rt::Mark => {
+                c.get(0).op(op::I32_EQZ).if_().op(op::RETURN).end();
+                c.flags(0)
+                    .i32_const(MARK)
+                    .op(op::I32_AND)
+                    .if_()
+                    .op(op::RETURN)
+                    .end();
+                c.get(0)
+                    .flags(0)
+                    .i32_const(MARK)
+                    .op(op::I32_OR)
+                    .mem(op::I32_STORE16, 6);
+                c.get(0)
+                    .mem(op::I32_LOAD16_U, 4)
+                    .op(op::I32_EQZ)
+                    .if_()
+                    .op(op::RETURN)
+                    .end();
+                c.get(0).gget(G_GRAY).mem(op::I32_STORE, 12);
+                c.get(0).gset(G_GRAY);
+            }
+            Rt::Drain => {
+                locals = vec![I32; 4];
+                let (o, info, i, n) = (0, 1, 2, 3);
+                c.block().loop_();
+                c.gget(G_GRAY).tee(o).op(op::I32_EQZ).br_if(1);
+                c.get(o).mem(op::I32_LOAD, 12).gset(G_GRAY);
+                c.type_info(o).tee(info).mem(op::I32_LOAD, 0).set(n);
+                c.i32_const(0).set(i);
+                c.block().loop_();
+                c.get(i).get(n).op(op::I32_GE_U).br_if(1);
+                c.get(info)
+                    .get(i)
+                    .op(op::I32_ADD)
+                    .mem(op::I32_LOAD8_U, 8)
+                    .i32_const(1)
+                    .op(op::I32_EQ)
+                    .if_();
+                c.get(o).get(i).i32_const(3).op(op::I32_SHL).op(op::I32_ADD);
+                c.mem(op::I32_LOAD, HEADER_SIZE).call(self.rt(Rt::Mark));
+                c.end();
+                c.get(i).i32_const(1).op(op::I32_ADD).set(i).br(0);
+                c.end().end();
+                c.br(0);
+                c.end().end();
+            }
+            Rt::Gc => {
+                locals = vec![I32; 7];
+                let (p, info, i, n, prev, next, target) = (0, 1, 2, 3, 4, 5, 6);
+                c.gget(G_STACK_BASE).set(p);
+                c.block().loop_();
+                c.get(p).gget(G_SP).op(op::I32_GE_U).br_if(1);
+                c.get(p)
+                    .mem(op::I32_LOAD, 4)
+                    .i32_const(1)
+                    .op(op::I32_EQ)
+                    .if_();
+                c.get(p).mem(op::I32_LOAD, 0).call(self.rt(Rt::Mark)).end();
+                c.get(p).i32_const(8).op(op::I32_ADD).set(p).br(0);
+                c.end().end();
+                c.call(self.rt(Rt::Drain));
+                c.i32_const(HEAP_LIST as i32).mem(op::I32_LOAD, 0).set(p);
+                c.block().loop_();
+                c.get(p).op(op::I32_EQZ).br_if(1);
+                c.flags(p)
+                    .i32_const(MARK | FINALIZED)
+                    .op(op::I32_AND)
+                    .op(op::I32_EQZ)
+                    .if_();
+                c.type_info(p).mem(op::I32_LOAD, 4).if_();
+                c.get(p)
+                    .flags(p)
+                    .i32_const(FINALIZED | PENDING)
+                    .op(op::I32_OR)
+                    .mem(op::I32_STORE16, 6);
+                c.get(p).call(self.rt(Rt::Mark));
+                c.end().end();
+                c.get(p).mem(op::I32_LOAD, 8).set(p).br(0);
+                c.end().end();
+                c.call(self.rt(Rt::Drain));
+                c.gget(G_STACK_BASE).set(p);
+                c.block().loop_();
+                c.get(p).gget(G_SP).op(op::I32_GE_U).br_if(1);
+                c.get(p)
+                    .mem(op::I32_LOAD, 4)
+                    .i32_const(2)
+                    .op(op::I32_EQ)
+                    .if_();
+                c.get(p).mem(op::I32_LOAD, 0).tee(target).if_();
+                c.flags(target)
+                    .i32_const(MARK)
+                    .op(op::I32_AND)
+                    .op(op::I32_EQZ)
+                    .if_();
+                c.get(p).i32_const(0).mem(op::I32_STORE, 0);
+                c.end().end().end();
+                c.get(p).i32_const(8).op(op::I32_ADD).set(p).br(0);
+                c.end().end();
+                c.i32_const(HEAP_LIST as i32).mem(op::I32_LOAD, 0).set(p);
+                c.block().loop_();
+                c.get(p).op(op::I32_EQZ).br_if(1);
+                c.get(p).mem(op::I32_LOAD16_U, 4).if_();
+                c.type_info(p).tee(info).mem(op::I32_LOAD, 0).set(n);
+                c.i32_const(0).set(i);
+                c.block().loop_();
+                c.get(i).get(n).op(op::I32_GE_U).br_if(1);
+                c.get(info)
+                    .get(i)
+                    .op(op::I32_ADD)
+                    .mem(op::I32_LOAD8_U, 8)
+                    .i32_const(2)
+                    .op(op::I32_EQ)
+                    .if_();
+                c.get(p)
+                    .get(i)
+                    .i32_const(3)
+                    .op(op::I32_SHL)
+                    .op(op::I32_ADD)
+                    .set(next);
+                c.get(next).mem(op::I32_LOAD, HEADER_SIZE).tee(target).if_();
+                c.flags(target)
+                    .i32_const(MARK)
+                    .op(op::I32_AND)
+                    .op(op::I32_EQZ)
+                    .if_();
+                c.get(next).i32_const(0).mem(op::I32_STORE, HEADER_SIZE);
+                c.end().end().end();
+                c.get(i).i32_const(1).op(op::I32_ADD).set(i).br(0);
+                c.end().end();
+                c.end();
+                c.get(p).mem(op::I32_LOAD, 8).set(p).br(0);
+                c.end().end();
+                c.i32_const(HEAP_LIST as i32).set(prev);
+                c.get(prev).mem(op::I32_LOAD, 0).set(p);
+                c.block().loop_();
+                c.get(p).op(op::I32_EQZ).br_if(1);
+                c.get(p).mem(op::I32_LOAD, 8).set(next);
+                c.flags(p).i32_const(MARK).op(op::I32_AND).if_();
+                c.get(p)
+                    .flags(p)
+                    .i32_const(!MARK)
+                    .op(op::I32_AND)
+                    .mem(op::I32_STORE16, 6);
+                c.get(p).i32_const(8).op(op::I32_ADD).set(prev);
+                c.op(op::ELSE);
+                c.get(prev).get(next).mem(op::I32_STORE, 0);
+                c.get(p).mem(op::I32_LOAD, 0).set(n);
+                c.block_size(n).set(n);
+                c.gget(G_LIVE).get(n).op(op::I32_SUB).gset(G_LIVE);
+                c.get(n).i32_const(3).op(op::I32_SHR_U).set(i);
+                c.get(n)
+                    .i32_const(MAX_SMALL)
+                    .op(op::I32_GT_U)
+                    .if_()
+                    .i32_const(0)
+                    .set(i)
+                    .end();
+                c.get(i)
+                    .i32_const(2)
+                    .op(op::I32_SHL)
+                    .i32_const(FREE_LISTS as i32)
+                    .op(op::I32_ADD)
+                    .set(info);
+                c.get(p)
+                    .get(info)
+                    .mem(op::I32_LOAD, 0)
+                    .mem(op::I32_STORE, 8);
+                c.get(info).get(p).mem(op::I32_STORE, 0);
+                c.end();
+                c.get(next).set(p).br(0);
+                c.end().end();
+                c.gget(G_LIVE).i32_const(1).op(op::I32_SHL).set(n);
+                c.i32_const(MIN_GC_THRESHOLD).gset(G_THRESHOLD);
+                c.get(n)
+                    .i32_const(MIN_GC_THRESHOLD)
+                    .op(op::I32_GT_U)
+                    .if_()
+                    .get(n)
+                    .gset(G_THRESHOLD)
+                    .end();
+            }
+            Rt::Safepoint => {
+                locals = vec![I32];
+                c.gget(G_FINALIZING).if_().op(op::RETURN).end();
+                c.gget(G_THRESHOLD)
+                    .gget(G_LIVE)
+                    .op(op::I32_GT_U)
+                    .if_()
+                    .op(op::RETURN)
+                    .end();
+                c.call(self.rt(Rt::Gc));
+                c.i32_const(1).gset(G_FINALIZING);
+                c.i32_const(HEAP_LIST as i32).mem(op::I32_LOAD, 0).set(0);
+                c.block().loop_();
+                c.get(0).op(op::I32_EQZ).br_if(1);
+                c.flags(0).i32_const(PENDING).op(op::I32_AND).if_();
+                c.get(0)
+                    .flags(0)
+                    .i32_const(!PENDING)
+                    .op(op::I32_AND)
+                    .mem(op::I32_STORE16, 6);
+                c.get(0)
+                    .get(0)
+                    .mem(op::I32_LOAD16_U, 4)
+                    .call(self.rt(Rt::Finalize));
+                c.end();
+                c.get(0).mem(op::I32_LOAD, 8).set(0).br(0);
+                c.end().end();
+                c.i32_const(0).gset(G_FINALIZING);
+            }
+            Rt::Concat => {
+                locals = vec![I32; 3];
+                let (la, lb, out) = (2, 3, 4);
+                c.get(0).mem(op::I32_LOAD, 0).set(la);
+                c.get(1).mem(op::I32_LOAD, 0).set(lb);
+                c.get(la)
+                    .get(lb)
+                    .op(op::I32_ADD)
+                    .i32_const(STRING_TYPE as i32)
+                    .call(self.rt(Rt::Alloc))
+                    .set(out);
+                c.get(out)
+                    .i32_const(16)
+                    .op(op::I32_ADD)
+                    .get(0)
+                    .i32_const(16)
+                    .op(op::I32_ADD);
+                c.get(la).misc(op::MEMORY_COPY);
+                c.get(out)
+                    .i32_const(16)
+                    .op(op::I32_ADD)
+                    .get(la)
+                    .op(op::I32_ADD);
+                c.get(1)
+                    .i32_const(16)
+                    .op(op::I32_ADD)
+                    .get(lb)
+                    .misc(op::MEMORY_COPY);
+                c.get(out);
+            }
+            Rt::StrEq => {
+                locals = vec![I32; 2];
+                let (i, n) = (2, 3);
+                c.get(0)
+                    .mem(op::I32_LOAD, 0)
+                    .tee(n)
+                    .get(1)
+                    .mem(op::I32_LOAD, 0)
+                    .op(op::I32_NE);
+                c.if_().i32_const(0).op(op::RETURN).end();
+                c.i32_const(0).set(i);
+                c.block().loop_();
+                c.get(i).get(n).op(op::I32_GE_U).br_if(1);
+                c.get(0)
+                    .get(i)
+                    .op(op::I32_ADD)
+                    .mem(op::I32_LOAD8_U, HEADER_SIZE);
+                c.get(1)
+                    .get(i)
+                    .op(op::I32_ADD)
+                    .mem(op::I32_LOAD8_U, HEADER_SIZE);
+                c.op(op::I32_NE).if_().i32_const(0).op(op::RETURN).end();
+                c.get(i).i32_const(1).op(op::I32_ADD).set(i).br(0);
+                c.end().end();
+                c.i32_const(1);
+            }
+            Rt::Repeat => {
+                locals = vec![I32; 4];
+                let (len, out, i, n) = (2, 3, 4, 5);
+                c.get(1)
+                    .i64_const(0)
+                    .op(op::I64_LT_S)
+                    .if_()
+                    .op(op::UNREACHABLE)
+                    .end();
+                c.get(1).op(op::I32_WRAP_I64).set(n);
+                c.get(0).mem(op::I32_LOAD, 0).set(len);
+                c.get(len)
+                    .get(n)
+                    .op(op::I32_MUL)
+                    .i32_const(STRING_TYPE as i32)
+                    .call(self.rt(Rt::Alloc))
+                    .set(out);
+                c.i32_const(0).set(i);
+                c.block().loop_();
+                c.get(i).get(n).op(op::I32_GE_U).br_if(1);
+                c.get(out)
+                    .i32_const(16)
+                    .op(op::I32_ADD)
+                    .get(i)
+                    .get(len)
+                    .op(op::I32_MUL)
+                    .op(op::I32_ADD);
+                c.get(0)
+                    .i32_const(16)
+                    .op(op::I32_ADD)
+                    .get(len)
+                    .misc(op::MEMORY_COPY);
+                c.get(i).i32_const(1).op(op::I32_ADD).set(i).br(0);
+                c.end().end();
+                c.get(out);
+            }
+            Rt::Finalize => {
+                for (i, s) in self.structs.iter().enumerate() {
+                    let Some(f) = &s.finalizer else {
+                        continue;
+                    };
+                    let Some((_, _, idx)) = self.functions.get(f) else {
+                        continue;
+                    };
+                    c.get(1).i32_const(i as i32 + 1).op(op::I32_EQ).if_();
+                    c.get(0).call(*idx).op(op::DROP).end();
+                }
+            }
+        }
+        c.end();
+        FuncBody {
+            params,
+            results,
+            locals,
+            code: c,
+        }
+    }
+
+    fn ctor_body(&self, s: &StructInfo, idx: u32) -> FuncBody {
+        let mut c = Code::default();
+        c.i32_const(s.fields.len() as i32 * 8)
+            .i32_const(idx as i32)
+            .call(self.rt(Rt::Alloc))
+            .set(0);
+        for (i, f) in s.fields.iter().enumerate() {
+            if *f == Type::String {
+                c.get(0)
+                    .i32_const(self.empty as i32)
+                    .mem(op::I32_STORE, HEADER_SIZE + 8 * i as u32);
+            }
+        }
+        c.get(0).end();
+        FuncBody {
+            params: Vec::new(),
+            results: vec![ValType::I32],
+            locals: vec![ValType::I32],
+            code: c,
+        }
+    }
+}
+
+struct FnLower<'l, 'a> {
+    l: &'l mut Lower<'a>,
+    pi: usize,
+    name: String,
+    locs: Vec<Loc>,
+    fp: u32,
+    pc: u32,
+    depth: u32,
+    c: Code,
+}
+
+impl FnLower<'_, '_> {
+    fn unsupported(&self, what: String) -> WasmError {
+        WasmError::Unsupported {
+            function: self.name.clone(),
+            what,
+        }
+    }
+    fn types(&self) -> &[(Rc<str>, Type)] {
+        &self.l.progs[self.pi].types
+    }
+    fn type_of(&self, v: &Var) -> Result<ValType, WasmError> {
+        let t = v.get_type(self.types());
+        val_type(&t).ok_or_else(|| self.unsupported(format!("value of type {:?}", t)))
+    }
+    fn push(&mut self, v: &Var) -> Result<ValType, WasmError> {
+        match v {
+            Var::Stack { index, .. } => match self.locs[*index] {
+                Loc::Local(i, t) => {
+                    self.c.get(i);
+                    return Ok(t);
+                }
+                Loc::Slot(k) => {
+                    self.c.get(self.fp).mem(op::I32_LOAD, k * 8);
+                }
+            },
+            Var::ConstInt { value } => {
+                self.c.i64_const(*value);
+            }
+            Var::ConstFloat { value } => {
+                self.c.f64_const(*value);
+            }
+            Var::ConstString { value } => {
+                let a = self.l.string(value);
+                self.c.i32_const(a as i32);
+            }
+            Var::ConstBool { value } => {
+                self.c.i32_const(*value as i32);
+            }
+            Var::Unit => {
+                self.c.i32_const(0);
+            }
+            Var::FieldAccess { of, index, .. } => {
+                let t = self.type_of(v)?;
+                self.push(of)?;
+                self.c.call(self.l.rt(Rt::Chk));
+                self.c.mem(load_op(t), HEADER_SIZE + 8 * *index as u32);
+                return Ok(t);
+            }
+            Var::OperatorNew { new_type } => {
+                let Some(idx) = self.l.struct_idx.get(&(self.pi, new_type.index)) else {
+                    return Err(self.unsupported(format!("new of non struct {}", new_type.name)));
+                };
+                self.c.call(self.l.ctor(*idx));
+            }
+            Var::MakeWeak { of } | Var::Upgrade { of } => {
+                self.push(of)?;
+            }
+            Var::Alive { of } => {
+                self.push(of)?;
+                self.c.i32_const(0).op(op::I32_NE);
+            }
+            Var::FunctionLiteral { .. } | Var::SelfPid | Var::Now => {
+                return Err(self.unsupported(format!("{:?}", v)));
+            }
+        }
+        self.type_of(v)
+    }
+    fn store(
+        &mut self,
+        lv: &Var,
+        value: impl FnOnce(&mut Self) -> Result<(), WasmError>,
+    ) -> Result<(), WasmError> {
+        match lv {
+            Var::Stack { index, .. } => match self.locs[*index] {
+                Loc::Local(i, _) => {
+                    value(self)?;
+                    self.c.set(i);
+                }
+                Loc::Slot(k) => {
+                    self.c.get(self.fp);
+                    value(self)?;
+                    self.c.mem(op::I32_STORE, k * 8);
+                }
+            },
+            Var::FieldAccess { of, index, .. } => {
+                let t = self.type_of(lv)?;
+                self.push(of)?;
+                self.c.call(self.l.rt(Rt::Chk));
+                value(self)?;
+                self.c.mem(store_op(t), HEADER_SIZE + 8 * *index as u32);
+            }
+            _ => return Err(self.unsupported(format!("assignment to {:?}", lv))),
+        }
+        Ok(())
+    }
+    fn binop(&mut self, l: &Var, r: &Var, o: &Binop) -> Result<(), WasmError> {
+        let lt = l.get_type(self.types());
+        let code = match (&lt, o) {
+            (Type::Integer, Binop::Add) => op::I64_ADD,
+            (Type::Integer, Binop::Sub) => op::I64_SUB,
+            (Type::Integer, Binop::Mul) => op::I64_MUL,
+            (Type::Integer, Binop::Div) => op::I64_DIV_S,
+            (Type::Integer, Binop::Equal) => op::I64_EQ,
+            (Type::Integer, Binop::NotEqual) => op::I64_NE,
+            (Type::Integer, Binop::Less) => op::I64_LT_S,
+            (Type::Integer, Binop::Greater) => op::I64_GT_S,
+            (Type::Float, Binop::Add) => op::F64_ADD,
+            (Type::Float, Binop::Sub) => op::F64_SUB,
+            (Type::Float, Binop::Mul) => op::F64_MUL,
+            (Type::Float, Binop::Div) => op::F64_DIV,
+            (Type::Float, Binop::Equal) => op::F64_EQ,
+            (Type::Float, Binop::NotEqual) => op::F64_NE,
+            (Type::Float, Binop::Less) => op::F64_LT,
+            (Type::Float, Binop::Greater) => op::F64_GT,
+            (Type::Bool, Binop::Equal) => op::I32_EQ,
+            (Type::Bool, Binop::NotEqual) => op::I32_NE,
+            (Type::Bool, Binop::And) => op::I32_AND,
+            (Type::Bool, Binop::Or) => op::I32_OR,
+            (Type::String, Binop::Add | Binop::Mul | Binop::Equal | Binop::NotEqual) => {
+                self.push(l)?;
+                self.push(r)?;
+                match o {
+                    Binop::Add => self.c.call(self.l.rt(Rt::Concat)),
+                    Binop::Mul => self.c.call(self.l.rt(Rt::Repeat)),
+                    Binop::Equal => self.c.call(self.l.rt(Rt::StrEq)),
+                    _ => self.c.call(self.l.rt(Rt::StrEq)).op(op::I32_EQZ),
+                };
+                return Ok(());
+            }
+            _ => return Err(self.unsupported(format!("{:?} on {:?}", o, lt))),
+        };
+        self.push(l)?;
+        self.push(r)?;
+        self.c.op(code);
+        Ok(())
+    }
+    fn epilogue(&mut self) {
+        self.c.get(self.fp).gset(G_SP);
+    }
+    fn cmd(
+        &mut self,
+        f: &Function,
+        cmd: &Cmd,
+        blocks: &HashMap<usize, u32>,
+    ) -> Result<(), WasmError> {
+        let label = |to: &str| {
+            f.labels
+                .get(to)
+                .and_then(|k| blocks.get(k))
+                .copied()
+                .ok_or_else(|| WasmError::UnresolvedSymbol { name: to.into() })
+        };
+        match cmd {
+            Cmd::Binop { l, r, out, op: o } => {
+                self.store(out, |s| s.binop(l, r, o))?;
+                if l.get_type(self.types()) == Type::String && matches!(o, Binop::Add | Binop::Mul)
+                {
+                    self.c.call(self.l.rt(Rt::Safepoint));
+                }
+            }
+            Cmd::Assign { l, r } => {
+                self.store(l, |s| s.push(r).map(|_| ()))?;
+                if matches!(r, Var::OperatorNew { .. }) {
+                    self.c.call(self.l.rt(Rt::Safepoint));
+                }
+            }
+            Cmd::Jmp { to, .. } => {
+                let b = label(to)?;
+                self.c.i32_const(b as i32).set(self.pc).br(self.depth);
+            }
+            Cmd::JmpCond { cond, to, .. } => {
+                let b = label(to)?;
+                self.push(cond)?;
+                self.c
+                    .if_()
+                    .i32_const(b as i32)
+                    .set(self.pc)
+                    .br(self.depth + 1)
+                    .end();
+            }
+            Cmd::DeclareVariables { .. } => {}
+            Cmd::Call {
+                to_call,
+                returned,
+                args,
+            } => {
+                let Var::FunctionLiteral { name: callee, .. } = to_call else {
+                    return Err(self.unsupported(format!("call of {:?}", to_call)));
+                };
+                let (_, cf, idx) = self.l.function(callee)?;
+                if cf.arguments.len() != args.len() {
+                    return Err(self.unsupported(format!(
+                        "call of {} with {} arguments",
+                        callee,
+                        args.len()
+                    )));
+                }
+                let call = |s: &mut Self| {
+                    for a in args.iter() {
+                        s.push(a)?;
+                    }
+                    s.c.call(idx);
+                    Ok(())
+                };
+                if *returned == Var::Unit {
+                    call(self)?;
+                    self.c.op(op::DROP);
+                } else {
+                    self.store(returned, call)?;
+                }
+            }
+            Cmd::CallNative {
+                to_call,
+                returned,
+                args,
+            } => {
+                let Some(idx) = self.l.import_idx.get(to_call).copied() else {
+                    return Err(self.unsupported(format!("native {}", to_call)));
+                };
+                let call = |s: &mut Self| {
+                    for a in args.iter() {
+                        s.push(a)?;
+                    }
+                    s.c.call(idx);
+                    Ok(())
+                };
+                if *returned == Var::Unit {
+                    call(self)?;
+                    self.c.op(op::DROP);
+                } else {
+                    self.store(returned, call)?;
+                }
+                self.c.call(self.l.rt(Rt::Safepoint));
+            }
+            Cmd::Return { to_return } => {
+                self.push(to_return)?;
+                self.epilogue();
+                self.c.op(op::RETURN);
+            }
+            Cmd::Spawn { .. }
+            | Cmd::Send { .. }
+            | Cmd::Receive { .. }
+            | Cmd::SpawnNamed { .. }
+            | Cmd::Link { .. }
+            | Cmd::Monitor { .. }
+            | Cmd::TrapExit
+            | Cmd::Exit { .. }
+            | Cmd::Fail { .. }
+            | Cmd::Sleep { .. } => return Err(self.unsupported("process command".into())),
+        }
+        Ok(())
+    }
+}
+
+impl Lower<'_> {
+    fn lower_function(&mut self, n: &str) -> Result<FuncBody, WasmError> {
+        let (pi, f, _) = self.function(n)?;
+        let types = &self.progs[pi].types;
+        let mut all: Vec<Type> = f.arguments.iter().map(|a| a.1.as_type(types)).collect();
+        for c in &f.cmds {
+            if let Cmd::DeclareVariables { values } = c {
+                all.extend(values.iter().cloned());
+            }
+        }
+        let unsupported = |t: &Type| WasmError::Unsupported {
+            function: n.into(),
+            what: format!("value of type {:?}", t),
+        };
+        let nargs = f.arguments.len();
+        let mut params = Vec::new();
+        let mut locals = Vec::new();
+        let mut locs = Vec::new();
+        let mut slots = Vec::new();
+        for (i, t) in all.iter().enumerate() {
+            let v = val_type(t).ok_or_else(|| unsupported(t))?;
+            if i < nargs {
+                params.push(v);
+            }
+            let wasm_idx = if i < nargs {
+                i as u32
+            } else {
+                locals.push(v);
+                (nargs + locals.len() - 1) as u32
+            };
+            if slot_kind(t) != 0 {
+                locs.push(Loc::Slot(slots.len() as u32));
+                slots.push((i, wasm_idx, t.clone()));
+            } else {
+                locs.push(Loc::Local(wasm_idx, v));
+            }
+        }
+        let rt = f.return_type.as_type(types);
+        let result = val_type(&rt).ok_or_else(|| unsupported(&rt))?;
+        locals.push(ValType::I32);
+        let fp = (nargs + locals.len() - 1) as u32;
+        locals.push(ValType::I32);
+        let pc = fp + 1;
+        let mut starts = vec![0];
+        for c in &f.cmds {
+            if let Cmd::Jmp { to, .. } | Cmd::JmpCond { to, .. } = c {
+                let Some(k) = f.labels.get(to.as_ref()) else {
+                    return Err(WasmError::UnresolvedSymbol {
+                        name: to.to_string(),
+                    });
+                };
+                starts.push(*k);
+            }
+        }
+        starts.sort();
+        starts.dedup();
+        let blocks: HashMap<usize, u32> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (*k, i as u32))
+            .collect();
+        let empty = self.empty;
+        let mut fl = FnLower {
+            l: self,
+            pi,
+            name: n.into(),
+            locs,
+            fp,
+            pc,
+            depth: 0,
+            c: Code::default(),
+        };
+        fl.c.gget(G_SP)
+            .tee(fp)
+            .i32_const(slots.len() as i32 * 8)
+            .op(op::I32_ADD)
+            .gset(G_SP);
+        fl.c.gget(G_SP)
+            .gget(G_STACK_END)
+            .op(op::I32_GT_U)
+            .if_()
+            .op(op::UNREACHABLE)
+            .end();
+        for (k, (i, wasm_idx, t)) in slots.iter().enumerate() {
+            fl.c.get(fp);
+            if *i < nargs {
+                fl.c.get(*wasm_idx);
+            } else if *t == Type::String {
+                fl.c.i32_const(empty as i32);
+            } else {
+                fl.c.i32_const(0);
+            }
+            fl.c.mem(op::I32_STORE, k as u32 * 8);
+            fl.c.get(fp)
+                .i32_const(slot_kind(t))
+                .mem(op::I32_STORE, k as u32 * 8 + 4);
+        }
+        let nblocks = starts.len() as u32;
+        fl.c.loop_();
+        for _ in 0..nblocks {
+            fl.c.block();
+        }
+        fl.c.get(pc).op(op::BR_TABLE);
+        uleb(&mut fl.c.0, nblocks as u64);
+        for i in 0..nblocks {
+            uleb(&mut fl.c.0, i as u64);
+        }
+        uleb(&mut fl.c.0, 0);
+        let mut b = 0;
+        for (k, c) in f.cmds.iter().enumerate() {
+            while (b as usize) < starts.len() && starts[b as usize] <= k {
+                fl.c.end();
+                fl.depth = nblocks - 1 - b;
+                b += 1;
+            }
+            fl.cmd(f, c, &blocks)?;
+        }
+        while b < nblocks {
+            fl.c.end();
+            b += 1;
+        }
+        fl.c.end();
+        fl.epilogue();
+        match result {
+            ValType::I32 => fl.c.i32_const(0),
+            ValType::I64 => fl.c.i64_const(0),
+            ValType::F64 => fl.c.f64_const(0.0),
+        };
+        fl.c.end();
+        Ok(FuncBody {
+            params,
+            results: vec![result],
+            locals,
+            code: fl.c,
+        })
+    }
+
+    fn assemble(mut self) -> Result<Vec<u8>, WasmError> {
+        let main = self
+            .functions
+            .iter()
+            .find(|f| f.1.1.display_name == "main")
+            .map(|f| f.1.2)
+            .ok_or(WasmError::NoMain)?;
+        let mut bodies = Vec::new();
+        for r in RUNTIME {
+            bodies.push(self.runtime(r));
+        }
+        for (i, s) in self.structs.iter().enumerate() {
+            bodies.push(self.ctor_body(s, i as u32 + 1));
+        }
+        let names: Vec<String> = self.functions.keys().cloned().collect();
+        for n in &names {
+            bodies.push(self.lower_function(n)?);
+        }
+        let mut sigs: Vec<(Vec<ValType>, Vec<ValType>)> = Vec::new();
+        let mut sig = |p: &[ValType], r: &[ValType]| {
+            let key = (p.to_vec(), r.to_vec());
+            sigs.iter().position(|s| *s == key).unwrap_or_else(|| {
+                sigs.push(key);
+                sigs.len() - 1
+            }) as u32
+        };
+        let import_sigs: Vec<u32> = self.imports.iter().map(|i| sig(&i.1, &[i.2])).collect();
+        let body_sigs: Vec<u32> = bodies.iter().map(|b| sig(&b.params, &b.results)).collect();
+        let stack_base = (DATA_START + self.data.len() as u32).div_ceil(16) * 16;
+        let stack_end = stack_base + STACK_BYTES;
+        let pages = stack_end / PAGE + 1;
+
+        let mut m = b"\0asm".to_vec();
+        m.extend_from_slice(&1u32.to_le_bytes());
+        let section = |m: &mut Vec<u8>, id: u8, body: Vec<u8>| {
+            m.push(id);
+            uleb(m, body.len() as u64);
+            m.extend(body);
+        };
+        let mut s = Vec::new();
+        uleb(&mut s, sigs.len() as u64);
+        for (p, r) in &sigs {
+            s.push(0x60);
+            uleb(&mut s, p.len() as u64);
+            s.extend(p.iter().map(|v| v.code()));
+            uleb(&mut s, r.len() as u64);
+            s.extend(r.iter().map(|v| v.code()));
+        }
+        section(&mut m, 1, s);
+        let mut s = Vec::new();
+        uleb(&mut s, self.imports.len() as u64);
+        for (i, imp) in self.imports.iter().enumerate() {
+            name(&mut s, "env");
+            name(&mut s, &imp.0);
+            s.push(0);
+            uleb(&mut s, import_sigs[i] as u64);
+        }
+        section(&mut m, 2, s);
+        let mut s = Vec::new();
+        uleb(&mut s, bodies.len() as u64);
+        for i in &body_sigs {
+            uleb(&mut s, *i as u64);
+        }
+        section(&mut m, 3, s);
+        let mut s = vec![1, 0];
+        uleb(&mut s, pages as u64);
+        section(&mut m, 5, s);
+        let globals = [
+            stack_base,
+            stack_end,
+            0,
+            MIN_GC_THRESHOLD as u32,
+            0,
+            0,
+            stack_base,
+            stack_end,
+        ];
+        let mut s = Vec::new();
+        uleb(&mut s, globals.len() as u64);
+        for (i, g) in globals.iter().enumerate() {
+            s.push(ValType::I32.code());
+            s.push((i < G_STACK_BASE as usize) as u8);
+            s.push(op::I32_CONST);
+            sleb(&mut s, *g as i32 as i64);
+            s.push(op::END);
+        }
+        section(&mut m, 6, s);
+        let mut s = Vec::new();
+        uleb(&mut s, 3);
+        name(&mut s, "memory");
+        s.extend([2, 0]);
+        name(&mut s, "main");
+        s.push(0);
+        uleb(&mut s, main as u64);
+        name(&mut s, "alloc");
+        s.push(0);
+        uleb(&mut s, self.rt(Rt::Alloc) as u64);
+        section(&mut m, 7, s);
+        let mut s = Vec::new();
+        uleb(&mut s, bodies.len() as u64);
+        for b in &bodies {
+            let mut f = Vec::new();
+            let mut runs: Vec<(u32, ValType)> = Vec::new();
+            for l in &b.locals {
+                match runs.last_mut() {
+                    Some((n, t)) if t == l => *n += 1,
+                    _ => runs.push((1, *l)),
+                }
+            }
+            uleb(&mut f, runs.len() as u64);
+            for (n, t) in runs {
+                uleb(&mut f, n as u64);
+                f.push(t.code());
+            }
+            f.extend_from_slice(&b.code.0);
+            uleb(&mut s, f.len() as u64);
+            s.extend(f);
+        }
+        section(&mut m, 10, s);
+        let mut s = vec![1, 0, op::I32_CONST];
+        sleb(&mut s, DATA_START as i64);
+        s.push(op::END);
+        uleb(&mut s, self.data.len() as u64);
+        s.extend_from_slice(&self.data);
+        section(&mut m, 11, s);
+        self.data.clear();
+        Ok(m)
+    }
+}
+
+pub fn compile_to_wasm(progs: &[Program]) -> Result<Vec<u8>, WasmError> {
+    Lower::new(progs)?.assemble()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ModuleInfo {
+    pub types: usize,
+    pub imports: Vec<String>,
+    pub functions: usize,
+    pub exports: Vec<String>,
+    pub instructions: usize,
+    pub memory_pages: u32,
+}
+
+struct Reader<'b> {
+    b: &'b [u8],
+    pos: usize,
+}
+impl Reader<'_> {
+    fn err<T>(&self, reason: impl Into<String>) -> Result<T, WasmError> {
+        Err(WasmError::Invalid {
+            offset: self.pos,
+            reason: reason.into(),
+        })
+    }
+    fn byte(&mut self) -> Result<u8, WasmError> {
+        let Some(b) = self.b.get(self.pos) else {
+            return self.err("unexpected end of module");
+        };
+        self.pos += 1;
+        Ok(*b)
+    }
+    fn bytes(&mut self, n: usize) -> Result<&[u8], WasmError> {
+        if self.pos + n > self.b.len() {
+            return self.err("unexpected end of module");
+        }
+        self.pos += n;
+        Ok(&self.b[self.pos - n..self.pos])
+    }
+    fn uleb(&mut self) -> Result<u64, WasmError> {
+        let mut out = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= 64 {
+                return self.err("leb128 too long");
+            }
+            out |= ((b & 0x7F) as u64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(out);
+            }
+        }
+    }
+    fn u32(&mut self) -> Result<u32, WasmError> {
+        let v = self.uleb()?;
+        if v > u32::MAX as u64 {
+            return self.err("u32 out of range");
+        }
+        Ok(v as u32)
+    }
+    fn sleb(&mut self) -> Result<i64, WasmError> {
+        let mut out = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= 64 {
+                return self.err("leb128 too long");
+            }
+            out |= ((b & 0x7F) as i64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    out |= -1 << shift;
+                }
+                return Ok(out);
+            }
+        }
+    }
+    fn name(&mut self) -> Result<String, WasmError> {
+        let n = self.u32()? as usize;
+        let b = self.bytes(n)?.to_vec();
+        String::from_utf8(b).or_else(|_| self.err("name is not utf-8"))
+    }
+    fn val_type(&mut self) -> Result<ValType, WasmError> {
+        let b = self.byte()?;
+        ValType::from_code(b).map_or_else(|| self.err(format!("unknown value type {:#x}", b)), Ok)
+    }
+    fn const_expr(&mut self, t: ValType) -> Result<i64, WasmError> {
+        let v = match (self.byte()?, t) {
+            (op::I32_CONST, ValType::I32) | (op::I64_CONST, ValType::I64) => self.sleb()?,
+            (op::F64_CONST, ValType::F64) => i64::from_le_bytes(self.bytes(8)?.try_into().unwrap()),
+            _ => return self.err("unsupported constant expression"),
+        };
+        if self.byte()? != op::END {
+            return self.err("constant expression is not terminated");
+        }
+        Ok(v)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    results: Vec<ValType>,
+    height: usize,
+    unreachable: bool,
+}
+
+struct Validator<'m> {
+    sigs: &'m [(Vec<ValType>, Vec<ValType>)],
+    funcs: &'m [u32],
+    globals: &'m [(ValType, bool)],
+    locals: Vec<ValType>,
+    vals: Vec<Option<ValType>>,
+    frames: Vec<Frame>,
+    pages: u32,
+}
+
+impl Validator<'_> {
+    fn pop(&mut self, r: &Reader, want: Option<ValType>) -> Result<Option<ValType>, WasmError> {
+        let f = self.frames.last().unwrap();
+        if self.vals.len() == f.height {
+            if f.unreachable {
+                return Ok(want);
+            }
+            return r.err("operand stack underflow");
+        }
+        let v = self.vals.pop().unwrap();
+        if let (Some(a), Some(b)) = (v, want)
+            && a != b
+        {
+            return r.err(format!("type mismatch: expected {:?}, found {:?}", b, a));
+        }
+        Ok(v.or(want))
+    }
+    fn pops(&mut self, r: &Reader, ts: &[ValType]) -> Result<(), WasmError> {
+        for t in ts.iter().rev() {
+            self.pop(r, Some(*t))?;
+        }
+        Ok(())
+    }
+    fn push(&mut self, t: ValType) {
+        self.vals.push(Some(t));
+    }
+    fn unreachable(&mut self) {
+        let f = self.frames.last_mut().unwrap();
+        self.vals.truncate(f.height);
+        f.unreachable = true;
+    }
+    fn label(&self, r: &Reader, d: u32) -> Result<Vec<ValType>, WasmError> {
+        if d as usize >= self.frames.len() {
+            return r.err(format!("branch depth {} out of range", d));
+        }
+        let f = &self.frames[self.frames.len() - 1 - d as usize];
+        Ok(if f.kind == op::LOOP {
+            Vec::new()
+        } else {
+            f.results.clone()
+        })
+    }
+    fn block_type(&self, r: &mut Reader) -> Result<Vec<ValType>, WasmError> {
+        let b = r.byte()?;
+        if b == 0x40 {
+            return Ok(Vec::new());
+        }
+        ValType::from_code(b).map_or_else(|| r.err("unsupported block type"), |t| Ok(vec![t]))
+    }
+    fn memarg(&self, r: &mut Reader, max_align: u32) -> Result<(), WasmError> {
+        let align = r.u32()?;
+        if align > max_align {
+            return r.err("alignment larger than natural");
+        }
+        r.u32()?;
+        if self.pages == 0 {
+            return r.err("memory access without memory");
+        }
+        Ok(())
+    }
+    fn body(
+        &mut self,
+        r: &mut Reader,
+        end: usize,
+        sig: &(Vec<ValType>, Vec<ValType>),
+    ) -> Result<usize, WasmError> {
+        use ValType::*;
+        let mut count = 0;
+        self.frames.push(Frame {
+            kind: 0,
+            results: sig.1.clone(),
+            height: 0,
+            unreachable: false,
+        });
+        while r.pos < end {
+            let o = r.byte()?;
+            count += 1;
+            match o {
+                op::UNREACHABLE => self.unreachable(),
+                0x01 => {}
+                op::BLOCK | op::LOOP | op::IF => {
+                    let results = self.block_type(r)?;
+                    if o == op::IF {
+                        self.pop(r, Some(I32))?;
+                    }
+                    self.frames.push(Frame {
+                        kind: o,
+                        results,
+                        height: self.vals.len(),
+                        unreachable: false,
+                    });
+                }
+                op::ELSE => {
+                    let f = self.frames.last().unwrap();
+                    if f.kind != op::IF {
+                        return r.err("else without if");
+                    }
+                    let res = f.results.clone();
+                    self.pops(r, &res)?;
+                    if self.vals.len() != self.frames.last().unwrap().height {
+                        return r.err("values left on stack at else");
+                    }
+                    let f = self.frames.last_mut().unwrap();
+                    f.kind = op::ELSE;
+                    f.unreachable = false;
+                }
+                op::END => {
+                    let f = self.frames.last().unwrap();
+                    let res = f.results.clone();
+                    if f.kind == op::IF && !res.is_empty() {
+                        return r.err("if without else must not produce values");
+                    }
+                    self.pops(r, &res)?;
+                    if self.vals.len() != self.frames.last().unwrap().height {
+                        return r.err("values left on stack at end of block");
+                    }
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        if r.pos != end {
+                            return r.err("code after end of function");
+                        }
+                        return Ok(count);
+                    }
+                    for t in res {
+                        self.push(t);
+                    }
+                }
+                op::BR => {
+                    let d = r.u32()?;
+                    let l = self.label(r, d)?;
+                    self.pops(r, &l)?;
+                    self.unreachable();
+                }
+                op::BR_IF => {
+                    let d = r.u32()?;
+                    self.pop(r, Some(I32))?;
+                    let l = self.label(r, d)?;
+                    self.pops(r, &l)?;
+                    for t in l {
+                        self.push(t);
+                    }
+                }
+                op::BR_TABLE => {
+                    let n = r.u32()?;
+                    let mut targets = Vec::new();
+                    for _ in 0..=n {
+                        targets.push(r.u32()?);
+                    }
+                    self.pop(r, Some(I32))?;
+                    let default = self.label(r, *targets.last().unwrap())?;
+                    for t in &targets {
+                        if self.label(r, *t)?.len() != default.len() {
+                            return r.err("br_table targets have different arities");
+                        }
+                    }
+                    self.pops(r, &default)?;
+                    self.unreachable();
+                }
+                op::RETURN => {
+                    let res = self.frames[0].results.clone();
+                    self.pops(r, &res)?;
+                    self.unreachable();
+                }
+                op::CALL => {
+                    let f = r.u32()? as usize;
+                    let Some(s) = self.funcs.get(f) else {
+                        return r.err(format!("call to unknown function {}", f));
+                    };
+                    let (p, res) = self.sigs[*s as usize].clone();
+                    self.pops(r, &p)?;
+                    for t in res {
+                        self.push(t);
+                    }
+                }
+                op::DROP => {
+                    self.pop(r, None)?;
+                }
+                op::LOCAL_GET | op::LOCAL_SET | op::LOCAL_TEE => {
+                    let i = r.u32()? as usize;
+                    let Some(t) = self.locals.get(i).copied() else {
+                        return r.err(format!("unknown local {}", i));
+                    };
+                    if o != op::LOCAL_GET {
+                        self.pop(r, Some(t))?;
+                    }
+                    if o != op::LOCAL_SET {
+                        self.push(t);
+                    }
+                }
+                op::GLOBAL_GET | op::GLOBAL_SET => {
+                    let i = r.u32()? as usize;
+                    let Some((t, m)) = self.globals.get(i).copied() else {
+                        return r.err(format!("unknown global {}", i));
+                    };
+                    if o == op::GLOBAL_SET {
+                        if !m {
+                            return r.err("write to immutable global");
+                        }
+                        self.pop(r, Some(t))?;
+                    } else {
+                        self.push(t);
+                    }
+                }
+                op::I32_LOAD | op::I64_LOAD | op::F64_LOAD | op::I32_LOAD8_U | op::I32_LOAD16_U => {
+                    let (t, a) = match o {
+                        op::I32_LOAD => (I32, 2),
+                        op::I64_LOAD => (I64, 3),
+                        op::F64_LOAD => (F64, 3),
+                        op::I32_LOAD8_U => (I32, 0),
+                        _ => (I32, 1),
+                    };
+                    self.memarg(r, a)?;
+                    self.pop(r, Some(I32))?;
+                    self.push(t);
+                }
+                op::I32_STORE | op::I64_STORE | op::F64_STORE | op::I32_STORE16 => {
+                    let (t, a) = match o {
+                        op::I32_STORE => (I32, 2),
+                        op::I64_STORE => (I64, 3),
+                        op::F64_STORE => (F64, 3),
+                        _ => (I32, 1),
+                    };
+                    self.memarg(r, a)?;
+                    self.pop(r, Some(t))?;
+                    self.pop(r, Some(I32))?;
+                }
+                op::MEMORY_SIZE | op::MEMORY_GROW => {
+                    if r.byte()? != 0 {
+                        return r.err("memory index must be zero");
+                    }
+                    if o == op::MEMORY_GROW {
+                        self.pop(r, Some(I32))?;
+                    }
+                    self.push(I32);
+                }
+                op::I32_CONST => {
+                    r.sleb()?;
+                    self.push(I32);
+                }
+                op::I64_CONST => {
+                    r.sleb()?;
+                    self.push(I64);
+                }
+                op::F64_CONST => {
+                    r.bytes(8)?;
+                    self.push(F64);
+                }
+                op::MISC => {
+                    let sub = r.u32()?;
+                    match sub {
+                        op::MEMORY_COPY => {
+                            r.byte()?;
+                            r.byte()?;
+                        }
+                        op::MEMORY_FILL => {
+                            r.byte()?;
+                        }
+                        _ => return r.err(format!("unsupported instruction 0xfc {}", sub)),
+                    }
+                    self.pops(r, &[I32, I32, I32])?;
+                }
+                _ => {
+                    let Some((p, res)) = simple(o) else {
+                        return r.err(format!("unsupported instruction {:#x}", o));
+                    };
+                    self.pops(r, p)?;
+                    self.push(res);
+                }
+            }
+        }
+        r.err("function body is not terminated")
+    }
+}
+
+fn simple(o: u8) -> Option<(&'static [ValType], ValType)> {
+    use ValType::*;
+    Some(match o {
+        op::I32_EQZ => (&[I32], I32),
+        op::I32_EQ | op::I32_NE | op::I32_GT_U | op::I32_GE_U => (&[I32, I32], I32),
+        op::I64_EQ | op::I64_NE | op::I64_LT_S | op::I64_GT_S => (&[I64, I64], I32),
+        op::F64_EQ | op::F64_NE | op::F64_LT | op::F64_GT => (&[F64, F64], I32),
+        op::I32_ADD
+        | op::I32_SUB
+        | op::I32_MUL
+        | op::I32_AND
+        | op::I32_OR
+        | op::I32_SHL
+        | op::I32_SHR_U => (&[I32, I32], I32),
+        op::I64_ADD | op::I64_SUB | op::I64_MUL | op::I64_DIV_S => (&[I64, I64], I64),
+        op::F64_ADD | op::F64_SUB | op::F64_MUL | op::F64_DIV => (&[F64, F64], F64),
+        op::I32_WRAP_I64 => (&[I64], I32),
+        _ => return None,
+    })
+}
+
+pub fn validate(bytes: &[u8]) -> Result<ModuleInfo, WasmError> {
+    let mut r = Reader { b: bytes, pos: 0 };
+    if r.bytes(4)? != b"\0asm" {
+        return r.err("bad magic");
+    }
+    if r.bytes(4)? != 1u32.to_le_bytes() {
+        return r.err("unsupported version");
+    }
+    let mut info = ModuleInfo::default();
+    let mut sigs = Vec::new();
+    let mut funcs = Vec::new();
+    let mut imported = 0;
+    let mut globals = Vec::new();
+    let mut declared = 0;
+    let mut last = 0;
+    while r.pos < bytes.len() {
+        let id = r.byte()?;
+        let size = r.u32()? as usize;
+        let end = r.pos + size;
+        if end > bytes.len() {
+            return r.err("section runs past end of module");
+        }
+        if id == 0 || id <= last {
+            return r.err(format!("section {} out of order", id));
+        }
+        last = id;
+        match id {
+            1 => {
+                for _ in 0..r.u32()? {
+                    if r.byte()? != 0x60 {
+                        return r.err("expected function type");
+                    }
+                    let mut p = Vec::new();
+                    for _ in 0..r.u32()? {
+                        p.push(r.val_type()?);
+                    }
+                    let mut res = Vec::new();
+                    for _ in 0..r.u32()? {
+                        res.push(r.val_type()?);
+                    }
+                    sigs.push((p, res));
+                }
+                info.types = sigs.len();
+            }
+            2 => {
+                for _ in 0..r.u32()? {
+                    let m = r.name()?;
+                    let n = r.name()?;
+                    if r.byte()? != 0 {
+                        return r.err("only function imports are supported");
+                    }
+                    let t = r.u32()?;
+                    if t as usize >= sigs.len() {
+                        return r.err(format!("import {} has unknown type {}", n, t));
+                    }
+                    funcs.push(t);
+                    imported += 1;
+                    info.imports.push(format!("{}.{}", m, n));
+                }
+            }
+            3 => {
+                for _ in 0..r.u32()? {
+                    let t = r.u32()?;
+                    if t as usize >= sigs.len() {
+                        return r.err(format!("function has unknown type {}", t));
+                    }
+                    funcs.push(t);
+                    declared += 1;
+                }
+            }
+            5 => {
+                if r.u32()? != 1 {
+                    return r.err("expected exactly one memory");
+                }
+                let flags = r.byte()?;
+                info.memory_pages = r.u32()?;
+                if flags == 1 && r.u32()? < info.memory_pages {
+                    return r.err("memory maximum below minimum");
+                }
+            }
+            6 => {
+                for _ in 0..r.u32()? {
+                    let t = r.val_type()?;
+                    let m = r.byte()? == 1;
+                    r.const_expr(t)?;
+                    globals.push((t, m));
+                }
+            }
+            7 => {
+                for _ in 0..r.u32()? {
+                    let n = r.name()?;
+                    let kind = r.byte()?;
+                    let i = r.u32()? as usize;
+                    let ok = match kind {
+                        0 => i < funcs.len(),
+                        2 => i == 0 && info.memory_pages > 0,
+                        3 => i < globals.len(),
+                        _ => false,
+                    };
+                    if !ok {
+                        return r.err(format!("export {} refers to unknown item", n));
+                    }
+                    info.exports.push(n);
+                }
+            }
+            10 => {
+                let n = r.u32()? as usize;
+                if n != declared {
+                    return r.err("function and code section counts differ");
+                }
+                for i in 0..n {
+                    let size = r.u32()? as usize;
+                    let body_end = r.pos + size;
+                    let sig = sigs[funcs[imported + i] as usize].clone();
+                    let mut locals = sig.0.clone();
+                    for _ in 0..r.u32()? {
+                        let c = r.u32()?;
+                        let t = r.val_type()?;
+                        locals.extend(std::iter::repeat_n(t, c as usize));
+                    }
+                    let mut v = Validator {
+                        sigs: &sigs,
+                        funcs: &funcs,
+                        globals: &globals,
+                        locals,
+                        vals: Vec::new(),
+                        frames: Vec::new(),
+                        pages: info.memory_pages,
+                    };
+                    info.instructions += v.body(&mut r, body_end, &sig)?;
+                }
+                info.functions = n;
+            }
+            11 => {
+                for _ in 0..r.u32()? {
+                    if r.u32()? != 0 {
+                        return r.err("only active data segments are supported");
+                    }
+                    let offset = r.const_expr(ValType::I32)? as u32 as u64;
+                    let len = r.u32()? as u64;
+                    r.bytes(len as usize)?;
+                    if offset + len > info.memory_pages as u64 * PAGE as u64 {
+                        return r.err("data segment does not fit in memory");
+                    }
+                }
+            }
+            _ => return r.err(format!("unsupported section {}", id)),
+        }
+        if r.pos != end {
+            return r.err(format!("section {} size mismatch", id));
+        }
+    }
+    if declared > 0 && info.functions != declared {
+        return r.err("missing code section");
+    }
+    Ok(info)
+}
+
+pub fn build_wasm(progs: &[Program], out: &str) -> Result<ModuleInfo, Box<dyn Error>> {
+    let bytes = compile_to_wasm(progs)?;
+    let info = validate(&bytes)?;
+    std::fs::write(out, bytes)?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "struct Node
+	next Node
+	back weak Node
+	v int
+	name string
+	finalize drop_node
+end
+extern fn int host x int: end
+fn void drop_node n Node:
+	return unit
+end
+fn float scale x float:
+	y:float = x * 2.5
+	return y
+end
+fn int main:
+	head:Node = new Node
+	tmp:Node = new Node
+	i:int = 0
+	c:bool = false
+	s:string = \"a\"
+	f:float = 1.0
+	label top
+	tmp = new Node
+	tmp.next = head
+	tmp.back = weak head
+	tmp.v = i
+	tmp.name = s + \"b\"
+	head = tmp
+	i = i + 1
+	c = i < 10
+	if c goto top
+	f = scale(f)
+	c = s == \"a\"
+	i = host(i)
+	return i
+end
+";
+
+    fn module() -> Vec<u8> {
+        let p = crate::parser::parse_to_program(PROGRAM.to_string(), "test.beam".into()).unwrap();
+        compile_to_wasm(&[p]).unwrap()
+    }
+
+    #[test]
+    fn compiled_modules_validate() {
+        let info = validate(&module()).unwrap();
+        assert_eq!(info.imports, ["env.host"]);
+        assert!(info.exports.iter().any(|e| e == "main"), "{:?}", info);
+    }
+
+    #[test]
+    fn invalid_modules_are_rejected() {
+        let good = module();
+        let mut truncated = good.clone();
+        truncated.pop();
+        let mut magic = good.clone();
+        magic[0] = b'X';
+        let mut out_of_order = good.clone();
+        out_of_order.extend_from_slice(&[1, 0]);
+        for (name, bytes) in [("truncated", truncated), ("bad magic", magic), ("out of order", out_of_order)] {
+            assert!(
+                matches!(validate(&bytes), Err(WasmError::Invalid { .. })),
+                "{} module passed validation",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn processes_cannot_be_lowered() {
+        let src = "fn int main:\n\tc:pid = self\n\treturn 0\nend\n";
+        let p = crate::parser::parse_to_program(src.to_string(), "test.beam".into()).unwrap();
+        assert!(matches!(compile_to_wasm(&[p]), Err(WasmError::Unsupported { .. })));
+    }
+}